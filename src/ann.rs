@@ -0,0 +1,275 @@
+use log::debug;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Training vectors sampled per cluster when fitting the centroids
+const SAMPLES_PER_LIST: usize = 40;
+
+/// Lloyd iterations used to fit the centroids
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Prime stride used to pick training samples
+const SAMPLE_STRIDE: u64 = 2_654_435_761;
+
+/// Upper bound on the number of inverted lists
+const MAX_LISTS: usize = 4096;
+
+/// In-memory inverted-file (IVF) index over chunk embeddings
+///
+/// Vectors are grouped around k-means centroids; a query only scans the lists of its
+/// `probes` nearest centroids instead of every vector, trading a little recall for speed.
+/// Distances are Euclidean, matching sqlite-vec's default for `vec0` tables.
+pub struct AnnIndex {
+    centroids: Vec<Vec<f32>>,
+    lists: Vec<Vec<(i64, Vec<f32>)>>,
+    /// Chunk id -> index of the list holding its vector
+    positions: HashMap<i64, usize>,
+}
+
+impl AnnIndex {
+    /// Build an index from `(chunk id, embedding)` pairs, picking about √n lists
+    pub fn build(vectors: Vec<(i64, Vec<f32>)>) -> Self {
+        let start_time = Instant::now();
+        let num_lists = ((vectors.len() as f64).sqrt() as usize).clamp(1, MAX_LISTS);
+        let centroids = train_centroids(&vectors, num_lists);
+
+        let mut index = Self {
+            lists: vec![Vec::new(); centroids.len()],
+            centroids,
+            positions: HashMap::with_capacity(vectors.len()),
+        };
+        let count = vectors.len();
+        for (id, vector) in vectors {
+            index.insert(id, vector);
+        }
+
+        debug!(
+            "[TIMING] ANN index build ({} vectors, {} lists): {:.3}s",
+            count,
+            index.lists.len(),
+            start_time.elapsed().as_secs_f64()
+        );
+        index
+    }
+
+    /// Add a vector to the list of its nearest centroid, replacing any previous one for `id`
+    pub fn insert(&mut self, id: i64, vector: Vec<f32>) {
+        self.remove(id);
+        if self.centroids.is_empty() {
+            // Index was built empty: the first vector seeds the only list
+            self.centroids.push(vector.clone());
+            self.lists.push(Vec::new());
+        }
+
+        let list = nearest(&self.centroids, &vector);
+        self.lists[list].push((id, vector));
+        self.positions.insert(id, list);
+    }
+
+    pub fn remove(&mut self, id: i64) {
+        if let Some(list) = self.positions.remove(&id) {
+            self.lists[list].retain(|(entry_id, _)| *entry_id != id);
+        }
+    }
+
+    /// Find the `k` nearest vectors to `query`, scanning the `probes` closest lists
+    ///
+    /// Returns `(chunk id, distance)` pairs ordered by increasing distance.
+    pub fn search(&self, query: &[f32], k: usize, probes: usize) -> Vec<(i64, f32)> {
+        let mut centroid_order: Vec<(usize, f32)> = self
+            .centroids
+            .iter()
+            .enumerate()
+            .map(|(i, centroid)| (i, squared_distance(query, centroid)))
+            .collect();
+        centroid_order.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let mut candidates: Vec<(i64, f32)> = centroid_order
+            .iter()
+            .take(probes.max(1))
+            .flat_map(|(list, _)| &self.lists[*list])
+            .map(|(id, vector)| (*id, squared_distance(query, vector)))
+            .collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        candidates.truncate(k);
+
+        candidates
+            .into_iter()
+            .map(|(id, distance)| (id, distance.sqrt()))
+            .collect()
+    }
+}
+
+/// Fit `num_lists` centroids with k-means on an evenly spaced sample of the vectors
+fn train_centroids(vectors: &[(i64, Vec<f32>)], num_lists: usize) -> Vec<Vec<f32>> {
+    if vectors.is_empty() {
+        return Vec::new();
+    }
+
+    // Stride through the vectors by a large prime so the sample is spread across files
+    // and deterministic from build to build
+    let sample_size = (num_lists * SAMPLES_PER_LIST).min(vectors.len());
+    let sample: Vec<&[f32]> = (0..sample_size)
+        .map(|i| {
+            vectors[((i as u64 * SAMPLE_STRIDE) % vectors.len() as u64) as usize]
+                .1
+                .as_slice()
+        })
+        .collect();
+
+    // Seed with the first sample points
+    let mut centroids: Vec<Vec<f32>> = sample[..num_lists].iter().map(|v| v.to_vec()).collect();
+
+    let dimensions = centroids[0].len();
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![vec![0.0f32; dimensions]; num_lists];
+        let mut counts = vec![0usize; num_lists];
+
+        for vector in &sample {
+            let list = nearest(&centroids, vector);
+            counts[list] += 1;
+            for (sum, value) in sums[list].iter_mut().zip(vector.iter()) {
+                *sum += value;
+            }
+        }
+
+        // Empty clusters keep their previous centroid
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count > 0 {
+                *centroid = sum.into_iter().map(|value| value / count as f32).collect();
+            }
+        }
+    }
+
+    centroids
+}
+
+fn nearest(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(i, centroid)| (i, squared_distance(vector, centroid)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random vectors grouped around `clusters` centers
+    fn clustered_vectors(count: usize, clusters: usize, dimensions: usize) -> Vec<(i64, Vec<f32>)> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % 10_000) as f32 / 10_000.0 - 0.5
+        };
+
+        let centers: Vec<Vec<f32>> = (0..clusters)
+            .map(|_| (0..dimensions).map(|_| next() * 4.0).collect())
+            .collect();
+        (0..count)
+            .map(|i| {
+                let center = &centers[i % clusters];
+                let vector = center.iter().map(|value| value + next()).collect();
+                (i as i64, vector)
+            })
+            .collect()
+    }
+
+    fn brute_force(vectors: &[(i64, Vec<f32>)], query: &[f32], k: usize) -> Vec<i64> {
+        let mut scored: Vec<(i64, f32)> = vectors
+            .iter()
+            .map(|(id, vector)| (*id, squared_distance(query, vector)))
+            .collect();
+        scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+        scored.into_iter().take(k).map(|(id, _)| id).collect()
+    }
+
+    fn recall_at_10(vectors: &[(i64, Vec<f32>)], index: &AnnIndex, probes: usize) -> f64 {
+        let queries = &vectors[..50];
+        let mut found = 0;
+        for (_, query) in queries {
+            let expected = brute_force(vectors, query, 10);
+            let actual: Vec<i64> = index
+                .search(query, 10, probes)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            found += expected.iter().filter(|id| actual.contains(id)).count();
+        }
+        found as f64 / (queries.len() * 10) as f64
+    }
+
+    #[test]
+    fn test_search_matches_brute_force() {
+        let vectors = clustered_vectors(2_000, 20, 32);
+        let index = AnnIndex::build(vectors.clone());
+
+        assert_eq!(index.positions.len(), 2_000);
+        assert!(recall_at_10(&vectors, &index, 8) >= 0.9);
+
+        // Probing every list is exact
+        let all_lists = index.centroids.len();
+        assert_eq!(recall_at_10(&vectors, &index, all_lists), 1.0);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut index = AnnIndex::build(Vec::new());
+        index.insert(1, vec![0.0, 0.0]);
+        index.insert(2, vec![1.0, 1.0]);
+        index.insert(2, vec![5.0, 5.0]);
+        assert_eq!(index.positions.len(), 2);
+
+        let results = index.search(&[5.0, 5.0], 1, 1);
+        assert_eq!(results, vec![(2, 0.0)]);
+
+        index.remove(2);
+        let results = index.search(&[5.0, 5.0], 5, 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 1);
+    }
+
+    /// Latency comparison on a larger index:
+    /// `cargo test --release ann_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn ann_benchmark() {
+        let vectors = clustered_vectors(100_000, 500, 256);
+        let index = AnnIndex::build(vectors.clone());
+        let queries: Vec<&[f32]> = vectors
+            .iter()
+            .step_by(1_000)
+            .map(|(_, v)| v.as_slice())
+            .collect();
+
+        let start = Instant::now();
+        for query in &queries {
+            brute_force(&vectors, query, 10);
+        }
+        let brute_force_time = start.elapsed() / queries.len() as u32;
+
+        for probes in [4, 8, 16, 32] {
+            let start = Instant::now();
+            for query in &queries {
+                index.search(query, 10, probes);
+            }
+            let ann_time = start.elapsed() / queries.len() as u32;
+            println!(
+                "probes={:>2}: {:?}/query (brute force {:?}), recall@10 {:.3}",
+                probes,
+                ann_time,
+                brute_force_time,
+                recall_at_10(&vectors, &index, probes)
+            );
+        }
+    }
+}
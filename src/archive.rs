@@ -0,0 +1,472 @@
+use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::Path;
+
+use crate::chunker;
+use crate::constants::constants;
+use crate::db::{self, Database, StoredChunk, StoredFile, StoredSymbol};
+
+/// Version of the archive layout written by `export_index`
+///
+/// Version 1 archives hold only chunks, one `StoredChunk` per line; they still import.
+const ARCHIVE_FORMAT_VERSION: u32 = 2;
+
+/// First line of an index archive, describing how the index was built
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ArchiveHeader {
+    pub format_version: u32,
+    pub ragrep_version: String,
+    pub schema_version: i32,
+    pub embedding_model: String,
+    /// Quantization of the embedding model ("none" or "int8")
+    #[serde(default = "default_quantization")]
+    pub embedding_quantization: String,
+    pub dimensions: usize,
+    pub chunk_count: usize,
+    /// Rows of the files table
+    #[serde(default)]
+    pub file_count: usize,
+    /// Recorded definitions
+    #[serde(default)]
+    pub symbol_count: usize,
+    /// Algorithm of the files' content hashes
+    #[serde(default)]
+    pub content_hash: Option<String>,
+}
+
+fn default_quantization() -> String {
+    "none".to_string()
+}
+
+/// A line of an archive after the header
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ArchiveRecord {
+    Chunk(StoredChunk),
+    File(StoredFile),
+    Symbol(StoredSymbol),
+}
+
+/// Write every chunk and vector in `db`, the files it indexed and their definitions to a
+/// gzip-compressed JSON-lines archive
+///
+/// The archive holds a header line followed by one [`ArchiveRecord`] per line. File paths
+/// are already relative to the project root, so the archive can be imported on any machine.
+pub fn export_index(db: &Database, output: &Path) -> Result<ArchiveHeader> {
+    let header = ArchiveHeader {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        ragrep_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: db::SCHEMA_VERSION,
+        embedding_model: db
+            .get_metadata(db::META_EMBEDDING_MODEL)?
+            .unwrap_or_else(|| constants::EMBEDDING_MODEL.to_string()),
+        embedding_quantization: db
+            .get_metadata(db::META_EMBEDDING_QUANTIZATION)?
+            .unwrap_or_else(default_quantization),
+        dimensions: db.dimensions().unwrap_or(constants::EMBEDDING_DIMENSIONS),
+        // Chunks without a vector (see `ragrep doctor`) can't be exported
+        chunk_count: db.count_chunks_with_vectors()?,
+        file_count: db.count_files()?,
+        symbol_count: db.count_symbols()?,
+        content_hash: db.get_metadata(db::META_CONTENT_HASH)?,
+    };
+
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create archive: {}", output.display()))?;
+    let mut writer = BufWriter::new(GzEncoder::new(file, Compression::default()));
+
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+
+    let mut written = 0;
+    db.for_each_chunk(|chunk| {
+        serde_json::to_writer(&mut writer, &ArchiveRecord::Chunk(chunk))?;
+        writer.write_all(b"\n")?;
+        written += 1;
+        Ok(())
+    })?;
+    let mut files = 0;
+    db.for_each_file(|file| {
+        serde_json::to_writer(&mut writer, &ArchiveRecord::File(file))?;
+        writer.write_all(b"\n")?;
+        files += 1;
+        Ok(())
+    })?;
+    let mut symbols = 0;
+    db.for_each_symbol(|symbol| {
+        serde_json::to_writer(&mut writer, &ArchiveRecord::Symbol(symbol))?;
+        writer.write_all(b"\n")?;
+        symbols += 1;
+        Ok(())
+    })?;
+
+    writer
+        .into_inner()
+        .map_err(|e| anyhow!("Failed to flush archive: {}", e))?
+        .finish()?;
+
+    // The index changed while it was being exported; the archive would not import
+    if (written, files, symbols) != (header.chunk_count, header.file_count, header.symbol_count) {
+        let _ = std::fs::remove_file(output);
+        bail!(
+            "Index changed during export ({} chunks, {} files and {} definitions expected, \
+             {}, {} and {} written); try again",
+            header.chunk_count,
+            header.file_count,
+            header.symbol_count,
+            written,
+            files,
+            symbols
+        );
+    }
+
+    debug!(
+        "Exported {} chunks, {} files and {} definitions to {}",
+        written,
+        files,
+        symbols,
+        output.display()
+    );
+    Ok(header)
+}
+
+/// Open an archive, returning its header and the lines of its chunks
+fn open_archive(input: &Path) -> Result<(ArchiveHeader, Lines<BufReader<GzDecoder<File>>>)> {
+    let file = File::open(input)
+        .with_context(|| format!("Failed to open archive: {}", input.display()))?;
+    let mut lines = BufReader::new(GzDecoder::new(file)).lines();
+
+    let header_line = lines
+        .next()
+        .ok_or_else(|| anyhow!("Archive is empty: {}", input.display()))?
+        .context("Failed to read archive header")?;
+    let header: ArchiveHeader =
+        serde_json::from_str(&header_line).context("Failed to parse archive header")?;
+    Ok((header, lines))
+}
+
+/// Parse a line of an archive of `header`'s format whose vectors have `dimensions`
+/// dimensions
+fn parse_record(
+    line: std::io::Result<String>,
+    header: &ArchiveHeader,
+    dimensions: usize,
+) -> Result<ArchiveRecord> {
+    let line = line.context("Failed to read archive")?;
+    let record = if header.format_version == 1 {
+        serde_json::from_str(&line).map(ArchiveRecord::Chunk)
+    } else {
+        serde_json::from_str(&line)
+    }
+    .context("Failed to parse archive line")?;
+
+    match record {
+        ArchiveRecord::Chunk(chunk) if chunk.embedding.len() != dimensions => bail!(
+            "Archive chunk of {} has a vector of {} dimensions instead of {}",
+            chunk.file_path,
+            chunk.embedding.len(),
+            dimensions
+        ),
+        // Hashes of another algorithm would never match; the next index compares the
+        // files by content instead
+        ArchiveRecord::File(file)
+            if header.content_hash.as_deref() != Some(chunker::CONTENT_HASH_ALGORITHM) =>
+        {
+            Ok(ArchiveRecord::File(StoredFile {
+                content_hash: 0,
+                ..file
+            }))
+        }
+        record => Ok(record),
+    }
+}
+
+/// Replace the contents of `db` with the chunks, files and definitions from an archive
+/// created by `export_index`
+///
+/// The archive is rejected before anything is written if it was built with a different
+/// embedding model or vector dimension than this version of ragrep uses, or if it is
+/// truncated or corrupt: it is read through once to check every line before the index is
+/// replaced, in one transaction, so a failure midway leaves the old index in place.
+pub fn import_index(db: &mut Database, input: &Path) -> Result<ArchiveHeader> {
+    let (header, lines) = open_archive(input)?;
+
+    let dimensions = db.dimensions().unwrap_or(constants::EMBEDDING_DIMENSIONS);
+    validate_header(&header, dimensions)?;
+    info!(
+        "Importing {} chunks (built by ragrep {} with {}, quantization {})",
+        header.chunk_count,
+        header.ragrep_version,
+        header.embedding_model,
+        header.embedding_quantization
+    );
+
+    let (mut chunks, mut files, mut symbols) = (0, 0, 0);
+    for line in lines {
+        match parse_record(line, &header, dimensions)? {
+            ArchiveRecord::Chunk(_) => chunks += 1,
+            ArchiveRecord::File(_) => files += 1,
+            ArchiveRecord::Symbol(_) => symbols += 1,
+        }
+    }
+    let expected = (header.chunk_count, header.file_count, header.symbol_count);
+    if (chunks, files, symbols) != expected {
+        bail!(
+            "Archive is truncated: expected {} chunks, {} files and {} definitions, found {}, \
+             {} and {}",
+            expected.0,
+            expected.1,
+            expected.2,
+            chunks,
+            files,
+            symbols
+        );
+    }
+
+    db.replace_contents(|contents| {
+        let (_, lines) = open_archive(input)?;
+        for line in lines {
+            match parse_record(line, &header, dimensions)? {
+                ArchiveRecord::Chunk(chunk) => contents.chunk(&chunk)?,
+                ArchiveRecord::File(file) => contents.file(&file)?,
+                ArchiveRecord::Symbol(symbol) => contents.symbol(&symbol)?,
+            }
+        }
+        Ok(())
+    })?;
+
+    db.set_metadata(db::META_EMBEDDING_MODEL, &header.embedding_model)?;
+    db.set_metadata(
+        db::META_EMBEDDING_QUANTIZATION,
+        &header.embedding_quantization,
+    )?;
+
+    Ok(header)
+}
+
+/// Check that an archive's vectors are usable with the configured embedding model and an
+/// index of `dimensions`-dimensional vectors
+fn validate_header(header: &ArchiveHeader, dimensions: usize) -> Result<()> {
+    if !(1..=ARCHIVE_FORMAT_VERSION).contains(&header.format_version) {
+        bail!(
+            "Unsupported archive format version {} (expected at most {})",
+            header.format_version,
+            ARCHIVE_FORMAT_VERSION
+        );
+    }
+    if header.embedding_model != constants::EMBEDDING_MODEL {
+        bail!(
+            "Archive was built with embedding model {}, but this index uses {}",
+            header.embedding_model,
+            constants::EMBEDDING_MODEL
+        );
+    }
+    if header.dimensions != dimensions {
+        bail!(
+            "Archive vectors have {} dimensions, but this index uses {}",
+            header.dimensions,
+            dimensions
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ragrep-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let source_root = temp_dir("source");
+        let mut source = Database::new(&source_root.join("ragrep.db"), &source_root).unwrap();
+        let file = source_root.join("src/lib.rs").to_string_lossy().to_string();
+        source
+            .save_chunks_batch(&[db::ChunkRecord {
+                file_path: &file,
+                chunk_index: 0,
+                node_type: "function",
+                node_name: Some("run"),
+                start_line: 1,
+                end_line: 4,
+                text: "fn run() {}",
+                comments: "/// Runs\n",
+                language: Some("sql"),
+                author: Some("Ada"),
+                committed_at: Some(1_700_000_000),
+                hash: 42,
+                embedding: vec![0.5; 1024],
+                updated_at: Some(1_600_000_000),
+            }])
+            .unwrap();
+        source.set_file_hash(&file, 7).unwrap();
+        let run = chunker::Symbol {
+            name: "run".to_string(),
+            kind: "function".to_string(),
+            line: 1,
+        };
+        source
+            .save_symbols(&file, std::slice::from_ref(&run))
+            .unwrap();
+
+        let archive = source_root.join("index.ragrep.gz");
+        let exported = export_index(&source, &archive).unwrap();
+        assert_eq!(
+            (
+                exported.chunk_count,
+                exported.file_count,
+                exported.symbol_count
+            ),
+            (1, 1, 1)
+        );
+
+        let target_root = temp_dir("target");
+        let mut target = Database::new(&target_root.join("ragrep.db"), &target_root).unwrap();
+        let imported = import_index(&mut target, &archive).unwrap();
+        assert_eq!(imported.chunk_count, 1);
+        let target_file = target_root.join("src/lib.rs").to_string_lossy().to_string();
+        assert_eq!(target.get_file_hash(&target_file).unwrap(), Some(7));
+        assert_eq!(target.file_symbols(&target_file).unwrap(), vec![run]);
+
+        let mut chunks = Vec::new();
+        target
+            .for_each_chunk(|chunk| {
+                chunks.push(chunk);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].file_path, "src/lib.rs");
+        assert_eq!(chunks[0].comments, "/// Runs\n");
+        assert_eq!(chunks[0].language.as_deref(), Some("sql"));
+        assert_eq!(chunks[0].category.as_deref(), Some(crate::category::SOURCE));
+        assert_eq!(chunks[0].author.as_deref(), Some("Ada"));
+        assert_eq!(chunks[0].committed_at, Some(1_700_000_000));
+        assert_eq!(chunks[0].updated_at, Some(1_600_000_000));
+        assert_eq!(chunks[0].embedding, vec![0.5; 1024]);
+        assert_eq!(
+            target
+                .get_metadata(db::META_EMBEDDING_QUANTIZATION)
+                .unwrap()
+                .as_deref(),
+            Some("none")
+        );
+    }
+
+    fn save(db: &mut Database, root: &Path, name: &str, hash: u64) {
+        db.save_chunk(
+            &root.join(format!("src/{}.rs", name)).to_string_lossy(),
+            0,
+            "function",
+            Some(name),
+            1,
+            1,
+            &format!("fn {}() {{}}", name),
+            "",
+            hash,
+            &[0.5; 1024],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_truncated_archive_leaves_index_alone() {
+        let source_root = temp_dir("truncated-source");
+        let mut source = Database::new(&source_root.join("ragrep.db"), &source_root).unwrap();
+        save(&mut source, &source_root, "a", 1);
+        save(&mut source, &source_root, "b", 2);
+        let archive = source_root.join("index.ragrep.gz");
+        export_index(&source, &archive).unwrap();
+
+        // Drop the last chunk line
+        let mut content = String::new();
+        std::io::Read::read_to_string(
+            &mut GzDecoder::new(File::open(&archive).unwrap()),
+            &mut content,
+        )
+        .unwrap();
+        let truncated: Vec<&str> = content.lines().take(2).collect();
+        let mut encoder = GzEncoder::new(File::create(&archive).unwrap(), Compression::default());
+        encoder
+            .write_all(format!("{}\n", truncated.join("\n")).as_bytes())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let target_root = temp_dir("truncated-target");
+        let mut target = Database::new(&target_root.join("ragrep.db"), &target_root).unwrap();
+        save(&mut target, &target_root, "existing", 3);
+        assert!(import_index(&mut target, &archive).is_err());
+        assert_eq!(target.count_chunks().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_import_version_1_archive() {
+        let root = temp_dir("version-1");
+        let archive = root.join("index.ragrep.gz");
+        let header = format!(
+            r#"{{"format_version": 1, "ragrep_version": "0.1.0", "schema_version": 1,
+                "embedding_model": "{}", "dimensions": 1024, "chunk_count": 1}}"#,
+            constants::EMBEDDING_MODEL
+        );
+        let chunk = serde_json::json!({
+            "file_path": "src/a.rs", "chunk_index": 0, "node_type": "function",
+            "node_name": "a", "start_line": 1, "end_line": 1, "text": "fn a() {}",
+            "comments": "", "hash": 1, "embedding": vec![0.5; 1024],
+        });
+        let mut encoder = GzEncoder::new(File::create(&archive).unwrap(), Compression::default());
+        writeln!(encoder, "{}\n{}", header.replace('\n', ""), chunk).unwrap();
+        encoder.finish().unwrap();
+
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        assert_eq!(import_index(&mut db, &archive).unwrap().chunk_count, 1);
+        assert_eq!(db.count_chunks().unwrap(), 1);
+        assert_eq!(db.count_files().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_export_skips_chunks_without_vectors() {
+        let source_root = temp_dir("orphans");
+        let mut source = Database::new(&source_root.join("ragrep.db"), &source_root).unwrap();
+        save(&mut source, &source_root, "a", 1);
+        save(&mut source, &source_root, "b", 2);
+        source.delete_vectors(&[2]).unwrap();
+
+        let archive = source_root.join("index.ragrep.gz");
+        assert_eq!(export_index(&source, &archive).unwrap().chunk_count, 1);
+        let target_root = temp_dir("orphans-target");
+        let mut target = Database::new(&target_root.join("ragrep.db"), &target_root).unwrap();
+        assert_eq!(import_index(&mut target, &archive).unwrap().chunk_count, 1);
+        assert_eq!(target.count_chunks().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_mismatched_model_is_rejected() {
+        let header = ArchiveHeader {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            ragrep_version: "0.0.0".to_string(),
+            schema_version: db::SCHEMA_VERSION,
+            embedding_model: "some/other-model".to_string(),
+            embedding_quantization: default_quantization(),
+            dimensions: constants::EMBEDDING_DIMENSIONS,
+            chunk_count: 0,
+            file_count: 0,
+            symbol_count: 0,
+            content_hash: None,
+        };
+        assert!(validate_header(&header, constants::EMBEDDING_DIMENSIONS).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{Context as AnyhowContext, Result};
+use log::{debug, info, warn};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::chunker::Chunker;
+use crate::context::AppContext;
+use crate::indexer::Indexer;
+use crate::protocol::{QueryCombination, SearchRequest};
+use crate::server::{self, SearchTimings};
+
+/// Queries used when no query file is given, covering common code search intents
+pub const DEFAULT_QUERIES: &[&str] = &[
+    "error handling",
+    "parse command line arguments",
+    "read configuration from file",
+    "database connection and queries",
+    "retry with exponential backoff",
+    "serialize data to json",
+    "authentication and permission checks",
+    "spawn background task",
+    "walk directory and filter files",
+    "unit tests for parser",
+];
+
+/// Latency percentiles of each search stage
+pub struct StageLatencies {
+    pub embed: Percentiles,
+    pub vector_search: Percentiles,
+    pub rerank: Percentiles,
+    pub total: Percentiles,
+}
+
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+}
+
+/// Chunking and embedding throughput measured on a sample of files
+pub struct IndexThroughput {
+    pub files: usize,
+    pub chunks: usize,
+    pub elapsed: Duration,
+}
+
+/// Run every query `iterations` times against the index and collect per-stage latencies
+pub async fn bench_search(
+    ctx: &mut AppContext,
+    queries: &[String],
+    iterations: usize,
+) -> Result<StageLatencies> {
+    // Warm up once so model initialization doesn't skew the first sample
+    if let Some(query) = queries.first() {
+        server::execute_search(ctx, search_request(query)).await?;
+    }
+
+    let mut samples: Vec<SearchTimings> = Vec::with_capacity(queries.len() * iterations);
+    for _ in 0..iterations {
+        for query in queries {
+            let (response, timings) =
+                server::execute_search_timed(ctx, search_request(query)).await?;
+            debug!(
+                "{:?}: {} results in {:?}",
+                query, response.stats.num_results, timings.total
+            );
+            samples.push(timings);
+        }
+    }
+
+    let stage =
+        |select: fn(&SearchTimings) -> Duration| percentiles(samples.iter().map(select).collect());
+    Ok(StageLatencies {
+        embed: stage(|t| t.embed),
+        vector_search: stage(|t| t.vector_search),
+        rerank: stage(|t| t.rerank),
+        total: stage(|t| t.total),
+    })
+}
+
+/// Chunk and embed up to `max_files` files under `sample_dir` without touching the index
+pub async fn bench_indexing(
+    ctx: &AppContext,
+    sample_dir: &Path,
+    max_files: usize,
+) -> Result<IndexThroughput> {
+    let files = Indexer::new(&ctx.config_manager.config().index).index_directory(sample_dir)?;
+    let mut chunker = Chunker::new(&ctx.config_manager.config().chunking)?
+        .with_plugins(&ctx.config_manager.config().chunkers, sample_dir)?;
+
+    let start = Instant::now();
+    let mut throughput = IndexThroughput {
+        files: 0,
+        chunks: 0,
+        elapsed: Duration::ZERO,
+    };
+
+    for file in files.iter().take(max_files) {
+        let content = std::fs::read_to_string(&file.path)
+            .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+        let chunks = match chunker.chunk_file(&file.path, &content) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                warn!("Skipping {}: {}", file.path.display(), e);
+                continue;
+            }
+        };
+
+        let file_path = file.path.to_string_lossy();
+        let prefix = &ctx.config_manager.config().embedding.document_prefix;
+        for chunk in &chunks {
+            ctx.embedder
+                .embed_text(&chunker.embedding_text(chunk), &file_path, prefix)
+                .await?;
+        }
+
+        throughput.files += 1;
+        throughput.chunks += chunks.len();
+    }
+
+    throughput.elapsed = start.elapsed();
+    Ok(throughput)
+}
+
+/// Print the benchmark results as a small table
+pub fn print_report(
+    latencies: &StageLatencies,
+    num_searches: usize,
+    throughput: Option<&IndexThroughput>,
+) {
+    println!("Search latency over {} searches", num_searches);
+    println!("  {:<15} {:>10} {:>10}", "stage", "p50", "p95");
+    for (name, stage) in [
+        ("embed", &latencies.embed),
+        ("vector search", &latencies.vector_search),
+        ("rerank", &latencies.rerank),
+        ("total", &latencies.total),
+    ] {
+        println!(
+            "  {:<15} {:>8.1}ms {:>8.1}ms",
+            name,
+            stage.p50.as_secs_f64() * 1000.0,
+            stage.p95.as_secs_f64() * 1000.0
+        );
+    }
+
+    if let Some(throughput) = throughput {
+        let seconds = throughput.elapsed.as_secs_f64().max(f64::EPSILON);
+        println!();
+        println!(
+            "Indexing throughput: {} files, {} chunks in {:.2}s ({:.1} files/s, {:.1} chunks/s)",
+            throughput.files,
+            throughput.chunks,
+            seconds,
+            throughput.files as f64 / seconds,
+            throughput.chunks as f64 / seconds
+        );
+    }
+}
+
+/// Read benchmark queries from a file (one per line, blank lines and `#` comments skipped)
+pub fn load_queries(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read query file: {}", path.display()))?;
+    let queries: Vec<String> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    info!("Loaded {} queries from {}", queries.len(), path.display());
+    Ok(queries)
+}
+
+fn search_request(query: &str) -> SearchRequest {
+    SearchRequest {
+        query: query.to_string(),
+        top_n: 10,
+        files_only: false,
+        project_root: None,
+        languages: Vec::new(),
+        profile: None,
+        categories: Vec::new(),
+        exclude_categories: Vec::new(),
+        max_tokens: None,
+        kinds: Vec::new(),
+        paths: Vec::new(),
+        comments: false,
+        author: None,
+        since: None,
+        changed_since: None,
+        rerank_depth: None,
+        queries: Vec::new(),
+        combine: QueryCombination::default(),
+        explain: false,
+    }
+}
+
+/// Nearest-rank percentiles of the samples
+pub fn percentiles(mut samples: Vec<Duration>) -> Percentiles {
+    samples.sort();
+    let rank = |p: f64| -> Duration {
+        if samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((p * samples.len() as f64).ceil() as usize).clamp(1, samples.len()) - 1;
+        samples[index]
+    };
+    Percentiles {
+        p50: rank(0.50),
+        p95: rank(0.95),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let result = percentiles(samples);
+        assert_eq!(result.p50, Duration::from_millis(50));
+        assert_eq!(result.p95, Duration::from_millis(95));
+
+        let empty = percentiles(Vec::new());
+        assert_eq!(empty.p50, Duration::ZERO);
+    }
+}
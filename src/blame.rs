@@ -0,0 +1,273 @@
+use anyhow::Result;
+use git2::{BlameOptions, Repository};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// The last commit to change a line: its author as "Name <email>" and its time in seconds
+/// since the Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineCommit {
+    pub author: String,
+    pub time: i64,
+}
+
+/// Blame of a file's working copy, as ranges of 1-based lines and their last commit
+///
+/// Lines changed since the last commit have no commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileBlame {
+    hunks: Vec<(usize, usize, Option<LineCommit>)>,
+}
+
+impl FileBlame {
+    /// The most recent commit among lines `start..=end`
+    pub fn latest(&self, start: usize, end: usize) -> Option<&LineCommit> {
+        self.hunks
+            .iter()
+            .filter(|(first, last, _)| *first <= end && start <= *last)
+            .filter_map(|(_, _, commit)| commit.as_ref())
+            .max_by_key(|commit| commit.time)
+    }
+}
+
+/// Blames the files of a git repository for `index.blame`
+pub struct Blamer {
+    repo: Repository,
+    workdir: PathBuf,
+    /// Blamers of the checked out submodules, whose files the repository can't blame
+    submodules: Vec<Blamer>,
+}
+
+impl Blamer {
+    /// Blamer for the repository containing `path`, if there is one
+    pub fn open(path: &Path) -> Option<Self> {
+        Self::for_repo(Repository::discover(path).ok()?)
+    }
+
+    fn for_repo(repo: Repository) -> Option<Self> {
+        let workdir = repo.workdir()?.canonicalize().ok()?;
+        let submodules = repo
+            .submodules()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|submodule| submodule.open().ok())
+            .filter_map(Self::for_repo)
+            .collect();
+        Some(Self {
+            repo,
+            workdir,
+            submodules,
+        })
+    }
+
+    /// The blamer of the repository holding `path`
+    fn for_path(&self, path: &Path) -> &Self {
+        self.submodules
+            .iter()
+            .find(|submodule| path.starts_with(&submodule.workdir))
+            .map_or(self, |submodule| submodule.for_path(path))
+    }
+
+    /// Blame `path`, whose current content is `content`
+    ///
+    /// Untracked files and files git cannot blame have no blame; this is logged rather than
+    /// failing the indexing.
+    pub fn blame(&self, path: &Path, content: &str) -> Option<FileBlame> {
+        match self.try_blame(path, content) {
+            Ok(blame) => Some(blame),
+            Err(e) => {
+                debug!("No blame for {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn try_blame(&self, path: &Path, content: &str) -> Result<FileBlame> {
+        let path = path.canonicalize()?;
+        let blamer = self.for_path(&path);
+        let relative = path.strip_prefix(&blamer.workdir)?;
+
+        // Blame the committed file, then apply the uncommitted changes on top
+        let committed = blamer
+            .repo
+            .blame_file(relative, Some(BlameOptions::new().use_mailmap(true)))?;
+        let blame = committed.blame_buffer(content.as_bytes())?;
+
+        let hunks = blame
+            .iter()
+            .map(|hunk| {
+                let start = hunk.final_start_line();
+                let end = start + hunk.lines_in_hunk().max(1) - 1;
+                let commit = (!hunk.final_commit_id().is_zero()).then(|| {
+                    let signature = hunk.final_signature();
+                    LineCommit {
+                        author: format!(
+                            "{} <{}>",
+                            String::from_utf8_lossy(signature.name_bytes()),
+                            String::from_utf8_lossy(signature.email_bytes())
+                        ),
+                        time: signature.when().seconds(),
+                    }
+                });
+                (start, end, commit)
+            })
+            .collect();
+        Ok(FileBlame { hunks })
+    }
+}
+
+/// Seconds since the Unix epoch at the start (UTC) of a `YYYY-MM-DD` date
+pub fn parse_date(date: &str) -> Result<i64> {
+    let parts: Vec<&str> = date.trim().split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        anyhow::bail!("Invalid date '{}', expected YYYY-MM-DD", date);
+    };
+    let (year, month, day): (i64, i64, i64) = match (year.parse(), month.parse(), day.parse()) {
+        (Ok(year), Ok(month), Ok(day))
+            if (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day) =>
+        {
+            (year, month, day)
+        }
+        _ => anyhow::bail!("Invalid date '{}', expected YYYY-MM-DD", date),
+    };
+
+    // Days from civil (Howard Hinnant's algorithm), with March as the first month
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days * 86_400)
+}
+
+/// Number of days in `month` (1-12) of `year`
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// `YYYY-MM-DD` (UTC) of a time in seconds since the Unix epoch
+pub fn format_date(time: i64) -> String {
+    // Civil from days, the inverse of `parse_date`
+    let days = time.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Seconds since the Unix epoch of a `YYYY-MM-DD` date or a time relative to now, such as
+/// "2 days ago", "3 hours ago", "1w" or "yesterday"
+pub fn parse_time(text: &str) -> Result<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    parse_time_at(text, now)
+}
+
+fn parse_time_at(text: &str, now: i64) -> Result<i64> {
+    let text = text.trim().to_lowercase();
+    if text == "yesterday" {
+        return Ok(now - 86_400);
+    }
+    if let Ok(date) = parse_date(&text) {
+        return Ok(date);
+    }
+    let relative = text.strip_suffix("ago").unwrap_or(&text).trim();
+    let split = relative
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(relative.len());
+    let (count, unit) = relative.split_at(split);
+    let Ok(count) = count.parse::<i64>() else {
+        anyhow::bail!(
+            "Invalid time '{}', expected YYYY-MM-DD or e.g. \"2 days ago\"",
+            text
+        );
+    };
+    let seconds = match unit.trim().trim_end_matches('s') {
+        "m" | "min" | "minute" => 60,
+        "h" | "hour" => 3_600,
+        "d" | "day" => 86_400,
+        "w" | "week" => 7 * 86_400,
+        "mo" | "month" => 30 * 86_400,
+        "y" | "year" => 365 * 86_400,
+        unit => anyhow::bail!(
+            "Invalid time unit '{}', expected minutes, hours, days, weeks, months or years",
+            unit
+        ),
+    };
+    Ok(now - count * seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_commit_of_a_range() {
+        let commit = |author: &str, time| {
+            Some(LineCommit {
+                author: author.to_string(),
+                time,
+            })
+        };
+        let blame = FileBlame {
+            hunks: vec![
+                (1, 4, commit("Ana <ana@example.com>", 100)),
+                (5, 5, None),
+                (6, 9, commit("Bob <bob@example.com>", 200)),
+            ],
+        };
+
+        assert_eq!(blame.latest(2, 3).unwrap().time, 100);
+        assert_eq!(blame.latest(4, 7).unwrap().author, "Bob <bob@example.com>");
+        assert!(blame.latest(5, 5).is_none());
+        assert!(blame.latest(10, 12).is_none());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2024-01-01").unwrap(), 1_704_067_200);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 951_868_800);
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-02-31").is_err());
+        assert!(parse_date("2024-04-31").is_err());
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("1900-02-29").is_err());
+        assert!(parse_date("last week").is_err());
+
+        for date in ["1970-01-01", "2000-02-29", "2024-12-31"] {
+            assert_eq!(format_date(parse_date(date).unwrap()), date);
+        }
+        assert_eq!(format_date(1_704_067_200 + 3_600), "2024-01-01");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = 1_704_067_200;
+        assert_eq!(parse_time_at("2 days ago", now).unwrap(), now - 2 * 86_400);
+        assert_eq!(parse_time_at("1 Week ago", now).unwrap(), now - 7 * 86_400);
+        assert_eq!(parse_time_at("3h", now).unwrap(), now - 3 * 3_600);
+        assert_eq!(parse_time_at("90 minutes", now).unwrap(), now - 90 * 60);
+        assert_eq!(parse_time_at("yesterday", now).unwrap(), now - 86_400);
+        assert_eq!(parse_time_at("2023-12-31", now).unwrap(), now - 86_400);
+        assert!(parse_time_at("2 fortnights ago", now).is_err());
+        assert!(parse_time_at("last week", now).is_err());
+    }
+}
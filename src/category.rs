@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Component, Path};
+
+use crate::config::CategoriesConfig;
+
+/// Category of files matching the `categories.tests` patterns
+pub const TEST: &str = "test";
+
+/// Category of files matching the `categories.vendor` patterns
+pub const VENDOR: &str = "vendor";
+
+/// Category of every other file
+pub const SOURCE: &str = "src";
+
+/// Assigns files to the built-in path categories using gitignore-style patterns
+pub struct PathCategories {
+    tests: Gitignore,
+    vendor: Gitignore,
+}
+
+impl PathCategories {
+    pub fn new(config: &CategoriesConfig) -> Result<Self> {
+        Ok(Self {
+            tests: build_matcher(&config.tests).context("Invalid categories.tests pattern")?,
+            vendor: build_matcher(&config.vendor).context("Invalid categories.vendor pattern")?,
+        })
+    }
+
+    /// Category of a file path as stored in the index (usually relative to the project root)
+    ///
+    /// Vendored code wins over tests, so a vendored library's test suite counts as vendor.
+    pub fn categorize(&self, path: &Path) -> &'static str {
+        // Patterns are matched against the path below the filesystem root
+        let path: &Path = path
+            .strip_prefix(Component::RootDir.as_os_str())
+            .unwrap_or(path);
+
+        if matches(&self.vendor, path) {
+            VENDOR
+        } else if matches(&self.tests, path) {
+            TEST
+        } else {
+            SOURCE
+        }
+    }
+}
+
+impl Default for PathCategories {
+    fn default() -> Self {
+        Self::new(&CategoriesConfig::default()).expect("default category patterns are valid")
+    }
+}
+
+fn build_matcher(patterns: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder.add_line(None, pattern)?;
+    }
+    Ok(builder.build()?)
+}
+
+fn matches(matcher: &Gitignore, path: &Path) -> bool {
+    matcher.matched_path_or_any_parents(path, false).is_ignore()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_categories() {
+        let categories = PathCategories::default();
+        let category = |path: &str| categories.categorize(Path::new(path));
+
+        assert_eq!(category("src/main.rs"), SOURCE);
+        assert_eq!(category("tests/integration.rs"), TEST);
+        assert_eq!(category("web/src/__tests__/app.js"), TEST);
+        assert_eq!(category("pkg/server_test.go"), TEST);
+        assert_eq!(category("app/test_models.py"), TEST);
+        assert_eq!(category("vendor/lib/tests/a.rs"), VENDOR);
+        assert_eq!(category("/elsewhere/third_party/zlib/inflate.c"), VENDOR);
+        // Only whole path components match
+        assert_eq!(category("src/contests/mod.rs"), SOURCE);
+    }
+
+    #[test]
+    fn test_custom_patterns() {
+        let categories = PathCategories::new(&CategoriesConfig {
+            tests: vec!["spec/".to_string()],
+            vendor: vec![],
+        })
+        .unwrap();
+
+        assert_eq!(categories.categorize(Path::new("spec/a_spec.rb")), TEST);
+        assert_eq!(categories.categorize(Path::new("tests/a.rs")), SOURCE);
+        assert_eq!(categories.categorize(Path::new("vendor/a.rs")), SOURCE);
+    }
+}
@@ -1,17 +1,35 @@
 use anyhow::{Context, Result};
-use log::debug;
+use fnv::FnvHasher;
+use log::{debug, info, warn};
 use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::path::Path;
 use streaming_iterator::StreamingIterator;
-use tree_sitter::{Language, Parser, Query, QueryCursor};
+use tree_sitter::{Language, Node, Parser, Query, QueryCursor, Tree};
+use tree_sitter_bash::LANGUAGE as BASH_LANGUAGE;
+use tree_sitter_c_sharp::LANGUAGE as CSHARP_LANGUAGE;
 use tree_sitter_javascript::LANGUAGE as JS_LANGUAGE;
+use tree_sitter_objc::LANGUAGE as OBJC_LANGUAGE;
+use tree_sitter_php::LANGUAGE_PHP as PHP_LANGUAGE;
 use tree_sitter_python::LANGUAGE as PYTHON_LANGUAGE;
+use tree_sitter_ruby::LANGUAGE as RUBY_LANGUAGE;
 use tree_sitter_rust::LANGUAGE as RUST_LANGUAGE;
+use tree_sitter_swift::LANGUAGE as SWIFT_LANGUAGE;
 use tree_sitter_typescript::LANGUAGE_TYPESCRIPT as TS_LANGUAGE;
 
+use crate::config::{ChunkerPluginConfig, ChunkingConfig};
+use crate::config_files;
+use crate::injection::{self, Injection};
+use crate::normalize;
+use crate::notebook;
+use crate::objc;
+use crate::plugins::{self, ChunkerPlugins, PluginChunk};
+use crate::secrets::SecretScanner;
+
 #[derive(Debug, Serialize)]
 pub struct CodeChunk {
     pub content: String,
@@ -19,116 +37,804 @@ pub struct CodeChunk {
     pub end_byte: usize,
     pub start_line: usize,
     pub end_line: usize,
-    pub kind: String, // "function", "class", "method", etc.
+    pub kind: String,         // "function", "class", "method", etc.
+    pub name: Option<String>, // Symbol name (function/type name) if the node has one
     pub leading_comments: String,
     pub parent_name: Option<String>, // Name of original function/class if this is a sub-chunk
+    pub continuation: bool, // A later part of a split chunk, not the start of its definition
+    pub language: Option<String>, // Language of embedded code, if not the file's own
+    /// Lines of surrounding code embedded with the chunk but not part of its range
+    /// (`neighbor_context_lines`)
+    pub context_before: String,
+    pub context_after: String,
 }
 
 impl CodeChunk {
+    /// Name stored with the chunk: the split part or notebook cell it is (`parent_name`),
+    /// otherwise the name of what it defines
+    pub fn label(&self) -> Option<&str> {
+        self.parent_name.as_deref().or(self.name.as_deref())
+    }
+
     pub fn hash(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.content.hash(&mut hasher);
         self.kind.hash(&mut hasher);
+        self.leading_comments.hash(&mut hasher);
+        // Only when set, so chunks without context keep the hashes they always had
+        if !self.context_before.is_empty() || !self.context_after.is_empty() {
+            self.context_before.hash(&mut hasher);
+            self.context_after.hash(&mut hasher);
+        }
         hasher.finish()
     }
+
+    /// Whether the chunk was extracted from within or around other code (an SQL string, a
+    /// comment) rather than being a definition or block of its own
+    pub fn is_extracted(&self) -> bool {
+        matches!(self.kind.as_str(), EMBEDDED | COMMENT | TODO)
+    }
+
+    /// Text sent to the embedder: leading doc comments followed by the code itself, between
+    /// the lines of context around it if any
+    pub fn embedding_text(&self) -> String {
+        let mut text = format!(
+            "{}{}{}",
+            self.context_before, self.leading_comments, self.content
+        );
+        if !self.context_after.is_empty() {
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&self.context_after);
+        }
+        text
+    }
+}
+
+/// Kind of the synthesized per-file summary chunk
+pub const FILE_SUMMARY: &str = "file_summary";
+
+/// Kind of a chunk of embedded code without definitions of its own: an SQL string, a
+/// markdown fence or an HTML script
+pub const EMBEDDED: &str = "embedded";
+
+/// Kind of a free-standing comment, i.e. one that neither documents a definition nor sits
+/// inside one
+pub const COMMENT: &str = "comment";
+
+/// Kind of a comment containing one of [`TODO_MARKERS`], wherever it is
+pub const TODO: &str = "todo";
+
+/// Words that make a comment a `todo` chunk, named after the first one it contains
+pub const TODO_MARKERS: &[&str] = &["TODO", "FIXME", "HACK", "XXX"];
+
+/// Comments with fewer words are not worth a chunk of their own, unless they are todos
+const MIN_COMMENT_WORDS: usize = 3;
+
+/// The first of [`TODO_MARKERS`] appearing as a whole word in `text`
+pub fn todo_marker(text: &str) -> Option<&'static str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .find_map(|word| TODO_MARKERS.iter().find(|marker| **marker == word))
+        .copied()
+}
+
+/// Number of words in `text`, ignoring punctuation such as comment markers
+fn words(text: &str) -> usize {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .count()
+}
+
+/// Byte ranges of the top-level statements of a shell script outside of its functions, in
+/// blocks separated by blank lines
+///
+/// Comments ending a block are left out: they document what follows or stand on their own.
+fn script_blocks(tree: &Tree, content: &str) -> Vec<Range<usize>> {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let mut blocks: Vec<Vec<Node>> = Vec::new();
+    let mut open = false;
+    for node in root.named_children(&mut cursor) {
+        if node.kind() == "function_definition" {
+            open = false;
+            continue;
+        }
+        match blocks.last_mut() {
+            Some(block)
+                if open
+                    && content[block[block.len() - 1].end_byte()..node.start_byte()]
+                        .matches('\n')
+                        .count()
+                        < 2 =>
+            {
+                block.push(node)
+            }
+            _ => {
+                blocks.push(vec![node]);
+                open = true;
+            }
+        }
+    }
+
+    blocks
+        .into_iter()
+        .filter_map(|mut block| {
+            while block.last().is_some_and(|node| node.kind() == "comment") {
+                block.pop();
+            }
+            Some(block.first()?.start_byte()..block.last()?.end_byte())
+        })
+        .collect()
+}
+
+/// The innermost definition among `chunks` containing the bytes `start..end`
+fn enclosing_definition(chunks: &[CodeChunk], start: usize, end: usize) -> Option<&CodeChunk> {
+    chunks
+        .iter()
+        .filter(|chunk| !chunk.is_extracted())
+        .filter(|chunk| chunk.start_byte <= start && end <= chunk.end_byte)
+        .min_by_key(|chunk| chunk.end_byte - chunk.start_byte)
+}
+
+/// Remove the license header at `range` of `content` from a file's chunks: chunks of the
+/// header alone are dropped, and it is cut from leading comments and the file summary
+fn strip_license_header(chunks: &mut Vec<CodeChunk>, content: &str, range: Range<usize>) {
+    let header = content[range.clone()].trim();
+    let first_line = header.lines().next().unwrap_or(header);
+    let strip = |text: &mut String| {
+        if let Some(position) = text.find(header) {
+            let rest = text[position + header.len()..].trim_start().to_string();
+            text.truncate(position);
+            text.push_str(&rest);
+        } else if let Some(position) = text.find(first_line) {
+            // A file summary cut short inside the header
+            if header.starts_with(text[position..].trim_end()) {
+                text.truncate(position);
+            }
+        }
+    };
+
+    chunks.retain(|chunk| chunk.start_byte < range.start || chunk.end_byte > range.end);
+    for chunk in chunks {
+        strip(&mut chunk.leading_comments);
+        if chunk.kind == FILE_SUMMARY {
+            strip(&mut chunk.content);
+        }
+    }
+}
+
+/// Give each chunk of code up to `lines` lines of the code before and after it, starting
+/// no earlier than the 1-based `first_line`
+///
+/// File summaries and chunks extracted from within other code are left as they are.
+fn add_neighbor_context(chunks: &mut [CodeChunk], content: &str, lines: usize, first_line: usize) {
+    let file_lines: Vec<&str> = content.split_inclusive('\n').collect();
+    for chunk in chunks {
+        if chunk.kind == FILE_SUMMARY || chunk.is_extracted() {
+            continue;
+        }
+        // Leading comments sit right above the chunk's range
+        let before_end = chunk
+            .start_line
+            .saturating_sub(1 + chunk.leading_comments.trim().lines().count())
+            .min(file_lines.len());
+        let before_start = before_end.saturating_sub(lines).max(first_line - 1);
+        let after_start = chunk.end_line.min(file_lines.len());
+        let after_end = (chunk.end_line + lines).min(file_lines.len());
+        if before_start < before_end {
+            chunk.context_before = file_lines[before_start..before_end].concat();
+        }
+        chunk.context_after = file_lines[after_start..after_end].concat();
+    }
+}
+
+/// A named definition, as listed by `ragrep defs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub line: usize,
+}
+
+/// Definitions among a file's chunks: every named tree-sitter capture, once per definition
+pub fn symbols(chunks: &[CodeChunk]) -> Vec<Symbol> {
+    chunks
+        .iter()
+        .filter(|chunk| {
+            !chunk.continuation
+                && !matches!(
+                    chunk.kind.as_str(),
+                    "file"
+                        | "cell"
+                        | "script"
+                        | "template"
+                        | FILE_SUMMARY
+                        | EMBEDDED
+                        | COMMENT
+                        | TODO
+                )
+        })
+        .filter_map(|chunk| {
+            Some(Symbol {
+                name: chunk.name.clone()?,
+                kind: chunk.kind.clone(),
+                line: chunk.start_line,
+            })
+        })
+        .collect()
+}
+
+/// Algorithm behind `content_hash`, recorded in the index so hashes stored by a build
+/// using another one are never compared to its own
+pub const CONTENT_HASH_ALGORITHM: &str = "fnv1a-64";
+
+/// Hash of a whole file's content, used to skip reindexing files that didn't change
+///
+/// The hashes are stored in the index, so this uses a fixed algorithm rather than
+/// `DefaultHasher`, whose output may change between Rust releases.
+pub fn content_hash(content: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(content.as_bytes());
+    hasher.finish()
+}
+
+/// Estimate how many tokens the embedding model's WordPiece tokenizer produces for `text`.
+///
+/// Every punctuation character becomes its own token and identifiers are broken into
+/// sub-words, so runs of alphanumerics are counted as one token per four characters.
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut run: usize = 0;
+
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            run += 1;
+            continue;
+        }
+        tokens += run.div_ceil(4);
+        run = 0;
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+
+    tokens + run.div_ceil(4)
+}
+
+/// Grammar for a file extension; extensions without one are chunked as plain text
+fn grammar_for(ext: &str) -> Option<&'static str> {
+    match ext {
+        "rs" => Some("rs"),
+        "py" | "pyi" => Some("py"),
+        "js" | "mjs" | "cjs" | "jsx" => Some("js"),
+        "ts" | "mts" | "cts" => Some("ts"),
+        "rb" => Some("rb"),
+        "php" => Some("php"),
+        "cs" => Some("cs"),
+        "swift" => Some("swift"),
+        "m" | "mm" => Some("objc"),
+        "sh" | "bash" | "zsh" => Some("sh"),
+        _ => None,
+    }
+}
+
+/// Language name recorded for chunks of a file extension, also accepted by `--lang`
+///
+/// Extensions without a grammar use the extension itself, so `--lang sql` works too.
+pub fn language_for_extension(ext: &str) -> String {
+    let language = match grammar_for(ext) {
+        Some("rs") => "rust",
+        Some("py") => "python",
+        Some("js") => "javascript",
+        Some("ts") => "typescript",
+        Some("rb") => "ruby",
+        Some("php") => "php",
+        Some("cs") => "csharp",
+        Some("swift") => "swift",
+        Some("sh") => "shell",
+        Some("objc") => "objc",
+        _ if ext == "ipynb" => "python",
+        _ if ext == "yml" => "yaml",
+        _ => ext,
+    };
+    language.to_string()
+}
+
+pub fn language_for_path(path: &Path) -> String {
+    match config_files::format_for(path) {
+        Some(config_files::Format::Dockerfile) => return "dockerfile".to_string(),
+        Some(config_files::Format::Makefile) => return "make".to_string(),
+        _ => {}
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => language_for_extension(&ext.to_lowercase()),
+        None => "text".to_string(),
+    }
+}
+
+/// Whether a comment followed by `gap` documents the definition after the gap: only blank
+/// lines and attributes or decorators (`#[test]`, `@cached`) may separate them
+fn documents(gap: &str) -> bool {
+    gap.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with("#[") || line.starts_with('@')
+    })
+}
+
+/// 1-based first and last line of a node
+fn line_span(node: &Node) -> (usize, usize) {
+    // Tree-sitter rows are 0-based; a node ending at column 0 stops on the line before
+    let start_line = node.start_position().row + 1;
+    let end_position = node.end_position();
+    let end_line = if end_position.column == 0 && end_position.row > start_line - 1 {
+        end_position.row
+    } else {
+        end_position.row + 1
+    };
+    (start_line, end_line)
 }
 
 pub struct Chunker {
     parser: Parser,
-    // max_chunk_size: usize,
-    // overlap_percentage: usize,
+    max_tokens: usize,
+    overlap_percentage: usize,
+    file_summaries: bool,
+    injections: bool,
+    comments: bool,
+    strip_license_headers: bool,
+    collapse_whitespace: bool,
+    skip_generated: bool,
+    neighbor_context_lines: usize,
+    /// Masks secrets in files before they are chunked (`redact_secrets`)
+    secrets: Option<SecretScanner>,
+    /// Secrets masked in the files chunked so far
+    secrets_redacted: usize,
+    /// External chunkers by file pattern (`[chunkers]`)
+    plugins: ChunkerPlugins,
 }
 
 impl Chunker {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: &ChunkingConfig) -> Result<Self> {
         let parser = Parser::new();
 
         Ok(Self {
             parser,
-            // max_chunk_size: 1000,   // Maximum tokens per chunk
-            // overlap_percentage: 15, // 15% overlap between chunks
+            max_tokens: config.max_tokens.max(1),
+            overlap_percentage: config.overlap_percentage.min(90),
+            file_summaries: config.file_summaries,
+            injections: config.injections,
+            comments: config.comments,
+            strip_license_headers: config.strip_license_headers,
+            collapse_whitespace: config.collapse_whitespace,
+            skip_generated: config.skip_generated,
+            neighbor_context_lines: config.neighbor_context_lines,
+            secrets: config
+                .redact_secrets
+                .then(|| SecretScanner::new(&config.secret_patterns))
+                .transpose()?,
+            secrets_redacted: 0,
+            plugins: ChunkerPlugins::default(),
         })
     }
 
-    // fn split_large_chunk(&self, chunk: CodeChunk) -> Vec<CodeChunk> {
-    //     let content = chunk.content.as_str();
-    //     let tokens: Vec<&str> = content.split_whitespace().collect();
-
-    //     if tokens.len() <= self.max_chunk_size {
-    //         return vec![chunk];
-    //     }
-
-    //     let overlap_size = (self.max_chunk_size * self.overlap_percentage) / 100;
-    //     let step_size = self.max_chunk_size - overlap_size;
-    //     let mut chunks = Vec::new();
-    //     let mut start_token = 0;
-
-    //     // Extract any inline comments from the content
-    //     let mut inline_comments = String::new();
-    //     if let Some(comment_start) = content.find("//") {
-    //         inline_comments = content[comment_start..]
-    //             .lines()
-    //             .next()
-    //             .unwrap_or("")
-    //             .to_string();
-    //     }
-
-    //     while start_token < tokens.len() {
-    //         let end_token = (start_token + self.max_chunk_size).min(tokens.len());
-    //         let sub_content = tokens[start_token..end_token].join(" ");
-
-    //         // Calculate byte offsets for the sub-chunk
-    //         let start_byte =
-    //             chunk.start_byte + content[..content.find(tokens[start_token]).unwrap_or(0)].len();
-    //         let end_byte = if end_token < tokens.len() {
-    //             chunk.start_byte
-    //                 + content[..content.find(tokens[end_token - 1]).unwrap_or(0)].len()
-    //                 + tokens[end_token - 1].len()
-    //         } else {
-    //             chunk.end_byte
-    //         };
-
-    //         // Combine leading comments with any inline comments
-    //         let mut combined_comments = chunk.leading_comments.clone();
-    //         if !inline_comments.is_empty() {
-    //             if !combined_comments.is_empty() {
-    //                 combined_comments.push('\n');
-    //             }
-    //             combined_comments.push_str(&inline_comments);
-    //         }
-
-    //         chunks.push(CodeChunk {
-    //             content: sub_content,
-    //             start_byte,
-    //             end_byte,
-    //             start_line: 0,
-    //             end_line: 0,
-    //             kind: chunk.kind.clone(),
-    //             leading_comments: combined_comments, // Include comments in all chunks
-    //             parent_name: Some(format!("{} (part {})", chunk.kind, chunks.len() + 1)),
-    //         });
-
-    //         if end_token >= tokens.len() {
-    //             break;
-    //         }
-    //         start_token += step_size;
-    //     }
-
-    //     chunks
-    // }
+    /// Chunk the files matching the patterns of `chunkers` (relative to `root`, the
+    /// indexed directory) with their commands
+    pub fn with_plugins(
+        mut self,
+        chunkers: &BTreeMap<String, ChunkerPluginConfig>,
+        root: &Path,
+    ) -> Result<Self> {
+        self.plugins = ChunkerPlugins::new(chunkers, root)?;
+        Ok(self)
+    }
+
+    /// Number of secrets masked in the files chunked so far
+    pub fn secrets_redacted(&self) -> usize {
+        self.secrets_redacted
+    }
+
+    /// Text embedded for `chunk`: its leading comments and code, with runs of whitespace
+    /// collapsed if `collapse_whitespace` is set
+    pub fn embedding_text(&self, chunk: &CodeChunk) -> String {
+        let text = chunk.embedding_text();
+        if self.collapse_whitespace {
+            normalize::collapse_whitespace(&text)
+        } else {
+            text
+        }
+    }
+
+    /// Split a chunk that exceeds `max_tokens` into line-aligned parts with overlap.
+    fn split_large_chunk(&self, chunk: CodeChunk) -> Vec<CodeChunk> {
+        if estimate_tokens(&chunk.content) <= self.max_tokens {
+            return vec![chunk];
+        }
+
+        let lines: Vec<&str> = chunk.content.split_inclusive('\n').collect();
+        let line_tokens: Vec<usize> = lines.iter().map(|line| estimate_tokens(line)).collect();
+        let overlap_tokens = self.max_tokens * self.overlap_percentage / 100;
+        let owner = match &chunk.name {
+            Some(name) => format!("{} {}", chunk.kind, name),
+            None => chunk.kind.clone(),
+        };
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+
+        while start < lines.len() {
+            // Take lines until the budget is spent (always at least one line)
+            let mut end = start;
+            let mut budget = 0;
+            while end < lines.len()
+                && (end == start || budget + line_tokens[end] <= self.max_tokens)
+            {
+                budget += line_tokens[end];
+                end += 1;
+            }
+
+            let offset: usize = lines[..start].iter().map(|line| line.len()).sum();
+            let content: String = lines[start..end].concat();
+
+            parts.push(CodeChunk {
+                start_byte: chunk.start_byte + offset,
+                end_byte: chunk.start_byte + offset + content.len(),
+                start_line: chunk.start_line + start,
+                end_line: chunk.start_line + end - 1,
+                content,
+                kind: chunk.kind.clone(),
+                name: chunk.name.clone(),
+                leading_comments: chunk.leading_comments.clone(),
+                parent_name: Some(format!("part {} of {}", parts.len() + 1, owner)),
+                continuation: chunk.continuation || !parts.is_empty(),
+                language: chunk.language.clone(),
+                context_before: String::new(),
+                context_after: String::new(),
+            });
+
+            if end >= lines.len() {
+                break;
+            }
+
+            // Step back over trailing lines to repeat them in the next part
+            let mut next = end;
+            let mut overlap = 0;
+            while next > start + 1 && overlap + line_tokens[next - 1] <= overlap_tokens {
+                overlap += line_tokens[next - 1];
+                next -= 1;
+            }
+            start = next;
+        }
+
+        parts
+    }
+
+    /// Chunk a file that has no tree-sitter grammar as a single block split into line-aligned parts
+    fn chunk_text(&self, path: &Path, content: &str) -> Vec<CodeChunk> {
+        if content.trim().is_empty() {
+            return vec![];
+        }
+
+        let chunk = CodeChunk {
+            content: content.to_string(),
+            start_byte: 0,
+            end_byte: content.len(),
+            start_line: 1,
+            end_line: content.lines().count().max(1),
+            kind: "file".to_string(),
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            leading_comments: String::new(),
+            parent_name: None,
+            continuation: false,
+            language: None,
+            context_before: String::new(),
+            context_after: String::new(),
+        };
+        self.split_large_chunk(chunk)
+    }
+
+    /// Chunks of `content` at the line ranges an external chunker printed
+    fn plugin_chunks(&self, content: &str, chunks: Vec<PluginChunk>) -> Vec<CodeChunk> {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| {
+                let start_byte = line_starts[chunk.start_line - 1];
+                let end_byte = line_starts
+                    .get(chunk.end_line)
+                    .copied()
+                    .unwrap_or(content.len());
+                self.split_large_chunk(CodeChunk {
+                    content: content[start_byte..end_byte].to_string(),
+                    start_byte,
+                    end_byte,
+                    start_line: chunk.start_line,
+                    end_line: chunk.end_line,
+                    kind: chunk.kind.unwrap_or_else(|| "block".to_string()),
+                    name: chunk.name,
+                    leading_comments: String::new(),
+                    parent_name: None,
+                    continuation: false,
+                    language: chunk.language,
+                    context_before: String::new(),
+                    context_after: String::new(),
+                })
+            })
+            .collect()
+    }
 
+    /// Chunk a file; generated and minified files have no chunks if `skip_generated` is set
     pub fn chunk_file(&mut self, path: &Path, content: &str) -> Result<Vec<CodeChunk>> {
-        let ext = path
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("");
+        if self.skip_generated {
+            if normalize::is_generated(content) {
+                debug!("Skipping generated file {}", path.display());
+                return Ok(Vec::new());
+            }
+            if normalize::is_minified(content) {
+                debug!("Skipping minified file {}", path.display());
+                return Ok(Vec::new());
+            }
+        }
+
+        let content = match &self.secrets {
+            Some(secrets) => {
+                let (redacted, count) = secrets.redact(content);
+                if count > 0 {
+                    info!("Masked {} secrets in {}", count, path.display());
+                    self.secrets_redacted += count;
+                }
+                redacted
+            }
+            None => Cow::Borrowed(content),
+        };
+        let content = content.as_ref();
+
+        let mut chunks = self.chunk_content(path, content)?;
+        // Context never reaches back into a stripped license header
+        let mut first_line = 1;
+        if self.strip_license_headers {
+            if let Some(header) = normalize::license_header(content) {
+                first_line = content[..header.end].lines().count() + 1;
+                strip_license_header(&mut chunks, content, header);
+            }
+        }
+        // Notebook chunks are numbered by cell, not by line of the file
+        let is_notebook = path.extension().is_some_and(|ext| ext == "ipynb");
+        if self.neighbor_context_lines > 0 && !is_notebook {
+            add_neighbor_context(
+                &mut chunks,
+                content,
+                self.neighbor_context_lines,
+                first_line,
+            );
+        }
+        Ok(chunks)
+    }
+
+    fn chunk_content(&mut self, path: &Path, content: &str) -> Result<Vec<CodeChunk>> {
+        if let Some(command) = self.plugins.command_for(path) {
+            // A broken chunker costs its files their structure, not the whole index
+            match plugins::run(command, path, content) {
+                Ok(chunks) => return Ok(self.plugin_chunks(content, chunks)),
+                Err(e) => warn!("{:#}; chunking {} without it", e, path.display()),
+            }
+        }
+
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+        if ext == "ipynb" {
+            return self.chunk_notebook(path, content);
+        }
+        if ext == "vue" || ext == "svelte" {
+            return self.chunk_component(path, content);
+        }
+        if let Some(format) = config_files::format_for(path) {
+            return Ok(self.chunk_config(format, content));
+        }
+
+        let injections = match ext {
+            "md" | "markdown" if self.injections => Some(injection::markdown_fences(content)),
+            "html" | "htm" if self.injections => Some(injection::script_blocks(content)),
+            _ => None,
+        };
+        if let Some(injections) = injections {
+            let mut chunks = self.chunk_text(path, content);
+            chunks.extend(self.chunk_injections(path, content, &injections)?);
+            return Ok(chunks);
+        }
+
+        // Headers are C unless they use Objective-C
+        let grammar = match ext {
+            "h" if objc::is_objc(path, content) => Some("objc"),
+            _ => grammar_for(ext),
+        };
+        let Some(grammar) = grammar else {
+            return Ok(self.chunk_text(path, content));
+        };
+        let mut chunks = self.chunk_with_grammar(path, grammar, content)?;
+        if self.file_summaries {
+            if let Some(summary) = self.file_summary(path, content, &chunks) {
+                chunks.insert(0, summary);
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Chunk a configuration file by its top-level sections
+    fn chunk_config(&self, format: config_files::Format, content: &str) -> Vec<CodeChunk> {
+        config_files::sections(format, content)
+            .into_iter()
+            .flat_map(|section| {
+                let text = &content[section.start_byte..section.end_byte];
+                let chunk = CodeChunk {
+                    content: text.to_string(),
+                    start_byte: section.start_byte,
+                    end_byte: section.end_byte,
+                    start_line: section.start_line,
+                    end_line: section.start_line + text.lines().count().max(1) - 1,
+                    kind: section.kind.to_string(),
+                    name: section.name,
+                    leading_comments: section.comments,
+                    parent_name: None,
+                    continuation: false,
+                    language: None,
+                    context_before: String::new(),
+                    context_after: String::new(),
+                };
+                self.split_large_chunk(chunk)
+            })
+            .collect()
+    }
+
+    /// Synthesize a chunk spanning the whole file for coarse navigation queries
+    ///
+    /// Its text is the file's header (doc comments, imports and whatever else precedes the
+    /// first definition); the file's location and the names it defines are prepended for
+    /// embedding. Both are cut to fit in `max_tokens`.
+    fn file_summary(&self, path: &Path, content: &str, chunks: &[CodeChunk]) -> Option<CodeChunk> {
+        if content.trim().is_empty() {
+            return None;
+        }
+
+        // The last two path components, e.g. "billing/invoice.rs", say the most about a file
+        let components: Vec<_> = path.components().collect();
+        let location = components[components.len().saturating_sub(2)..]
+            .iter()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut summary = format!("File {}\n", location);
+        let mut budget = self.max_tokens.saturating_sub(estimate_tokens(&summary));
+
+        let definitions: Vec<String> = symbols(chunks)
+            .into_iter()
+            .map(|symbol| format!("{} {}", symbol.kind, symbol.name))
+            .collect();
+        if !definitions.is_empty() {
+            let mut line = "Defines:".to_string();
+            for (i, definition) in definitions.iter().enumerate() {
+                let tokens = estimate_tokens(definition) + 1;
+                if tokens > budget {
+                    break;
+                }
+                budget -= tokens;
+                line.push_str(if i == 0 { " " } else { ", " });
+                line.push_str(definition);
+            }
+            summary.push_str(&line);
+            summary.push('\n');
+        }
+
+        let header_end = chunks
+            .iter()
+            .filter(|chunk| !chunk.is_extracted())
+            .map(|chunk| chunk.start_byte)
+            .min()
+            .unwrap_or(content.len());
+        let mut header = String::new();
+        for line in content[..header_end].split_inclusive('\n') {
+            let tokens = estimate_tokens(line);
+            if tokens > budget {
+                break;
+            }
+            budget -= tokens;
+            header.push_str(line);
+        }
+
+        Some(CodeChunk {
+            content: header.trim_end().to_string(),
+            start_byte: 0,
+            end_byte: content.len(),
+            start_line: 1,
+            end_line: content.lines().count().max(1),
+            kind: FILE_SUMMARY.to_string(),
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            leading_comments: summary,
+            parent_name: None,
+            continuation: false,
+            language: None,
+            context_before: String::new(),
+            context_after: String::new(),
+        })
+    }
 
-        let language: Language = match ext {
+    /// Chunk the code cells of a Jupyter notebook with the Python grammar
+    ///
+    /// Line numbers are relative to the cell, which is recorded in `parent_name`. Cells
+    /// without any function become a single "cell" chunk so top-level code stays searchable.
+    fn chunk_notebook(&mut self, path: &Path, content: &str) -> Result<Vec<CodeChunk>> {
+        let notebook = notebook::extract_code(content)
+            .with_context(|| format!("Failed to read notebook {}", path.display()))?;
+
+        let mut chunks = Vec::new();
+        let mut covered_cells = HashSet::new();
+        for mut chunk in self.chunk_with_grammar(path, "py", &notebook.source)? {
+            let Some((cell, line)) = notebook.locate(chunk.start_line) else {
+                continue;
+            };
+            // SQL strings and comments alone don't make a cell's other code searchable
+            if !chunk.is_extracted() {
+                covered_cells.insert(cell.index);
+            }
+            chunk.end_line = line + (chunk.end_line - chunk.start_line);
+            chunk.start_line = line;
+            chunk.parent_name = Some(match chunk.parent_name {
+                Some(parent) => format!("cell {}, {}", cell.index, parent),
+                None => format!("cell {}", cell.index),
+            });
+            chunks.push(chunk);
+        }
+
+        for cell in &notebook.cells {
+            if covered_cells.contains(&cell.index) {
+                continue;
+            }
+            let chunk = CodeChunk {
+                content: notebook.cell_source(cell).to_string(),
+                start_byte: cell.start_byte,
+                end_byte: cell.end_byte,
+                start_line: 1,
+                end_line: cell.line_count,
+                kind: "cell".to_string(),
+                name: Some(format!("cell {}", cell.index)),
+                leading_comments: String::new(),
+                parent_name: None,
+                continuation: false,
+                language: None,
+                context_before: String::new(),
+                context_after: String::new(),
+            };
+            chunks.extend(self.split_large_chunk(chunk));
+        }
+
+        chunks.sort_by_key(|chunk| chunk.start_byte);
+        Ok(chunks)
+    }
+
+    fn chunk_with_grammar(
+        &mut self,
+        path: &Path,
+        grammar: &str,
+        content: &str,
+    ) -> Result<Vec<CodeChunk>> {
+        let language: Language = match grammar {
             "rs" => RUST_LANGUAGE.into(),
             "py" => PYTHON_LANGUAGE.into(),
             "ts" => TS_LANGUAGE.into(),
-            "js" => JS_LANGUAGE.into(),
-            _ => return Err(anyhow::anyhow!("Unsupported file extension: {}", ext)),
+            "rb" => RUBY_LANGUAGE.into(),
+            "php" => PHP_LANGUAGE.into(),
+            "cs" => CSHARP_LANGUAGE.into(),
+            "swift" => SWIFT_LANGUAGE.into(),
+            "objc" => OBJC_LANGUAGE.into(),
+            "sh" => BASH_LANGUAGE.into(),
+            _ => JS_LANGUAGE.into(),
         };
 
         self.parser.set_language(&language)?;
@@ -137,7 +843,7 @@ impl Chunker {
             .parse(content, None)
             .with_context(|| "Failed to parse file")?;
 
-        let query_str = match ext {
+        let query_str = match grammar {
             "rs" => {
                 r#"
                 ([(line_comment)* (block_comment)*] @comment
@@ -159,64 +865,156 @@ impl Chunker {
                   (method_definition) @function])
                 "#
             }
-            _ => return Ok(vec![]),
+            "rb" => {
+                r#"
+                ((comment)* @comment
+                 [(method) @function
+                  (singleton_method) @function
+                  (class) @class
+                  (module) @module])
+                "#
+            }
+            "php" => {
+                r#"
+                ((comment)* @comment
+                 [(function_definition) @function
+                  (method_declaration) @function
+                  (class_declaration) @class
+                  (interface_declaration) @interface
+                  (trait_declaration) @trait])
+                "#
+            }
+            "cs" => {
+                r#"
+                ((comment)* @comment
+                 [(method_declaration) @function
+                  (constructor_declaration) @function
+                  (class_declaration) @class
+                  (struct_declaration) @struct
+                  (interface_declaration) @interface
+                  (namespace_declaration) @namespace])
+                "#
+            }
+            // Classes, structs, enums, actors and extensions are all class declarations
+            "swift" => {
+                r#"
+                ([(comment)* (multiline_comment)*] @comment
+                 [(function_declaration) @function
+                  (init_declaration) @function
+                  (class_declaration declaration_kind: "class") @class
+                  (class_declaration declaration_kind: "actor") @class
+                  (class_declaration declaration_kind: "struct") @struct
+                  (class_declaration declaration_kind: "enum") @enum
+                  (class_declaration declaration_kind: "extension") @extension
+                  (protocol_declaration) @protocol])
+                "#
+            }
+            // Categories are chunked like the class they extend; methods only where they
+            // have a body
+            "objc" => {
+                r#"
+                ((comment)* @comment
+                 [(class_interface) @interface
+                  (category_interface) @interface
+                  (class_implementation) @implementation
+                  (category_implementation) @implementation
+                  (protocol_declaration) @protocol
+                  (method_definition) @function])
+                "#
+            }
+            "sh" => {
+                r#"
+                ((comment)* @comment
+                 (function_definition) @function)
+                "#
+            }
+            _ => unreachable!("grammar_for only returns known grammars"),
         };
 
         let query = Query::new(&language, query_str)?;
         let mut cursor = QueryCursor::new();
         let mut chunks = Vec::new();
         let mut seen_hashes = HashSet::new();
+        let mut leading_comments = HashSet::new();
 
-        // Pre-calculate line starts for efficient line number lookup
-        let line_starts: Vec<_> = content
-            .match_indices('\n')
-            .map(|(i, _)| i)
-            .chain(std::iter::once(content.len()))
-            .collect();
-
+        // A definition can match once per subset of the comments before it, so keep the
+        // match with the most comments directly above it (see `documents`)
+        let mut definitions: BTreeMap<usize, (Node, &str, Vec<Node>)> = BTreeMap::new();
         let mut query_matches = cursor.matches(&query, tree.root_node(), content.as_bytes());
         while let Some(match_) = query_matches.next() {
-            let mut comments = String::new();
-            let mut chunk_content = String::new();
+            let mut comment_nodes = Vec::new();
+            let mut code_capture = None;
 
             for capture in match_.captures {
-                let capture_text = &content[capture.node.byte_range()];
-
                 if query.capture_names()[capture.index as usize] == "comment" {
-                    comments.push_str(capture_text);
-                    comments.push('\n');
+                    comment_nodes.push(capture.node);
                 } else {
-                    chunk_content = capture_text.to_string();
+                    code_capture = Some(capture);
+                }
+            }
+
+            let Some(capture) = code_capture else {
+                continue;
+            };
+            let node = capture.node;
+            let mut attached = Vec::new();
+            let mut start = node.start_byte();
+            for comment in comment_nodes.iter().rev() {
+                if comment.end_byte() > start || !documents(&content[comment.end_byte()..start]) {
+                    break;
                 }
+                start = comment.start_byte();
+                attached.insert(0, *comment);
             }
 
-            if !chunk_content.is_empty() {
-                let start_byte = match_.captures[0].node.start_byte();
-                let end_byte = match_.captures[0].node.end_byte();
+            let kind = query.capture_names()[capture.index as usize];
+            let definition =
+                definitions
+                    .entry(node.start_byte())
+                    .or_insert((node, kind, Vec::new()));
+            if attached.len() > definition.2.len() {
+                definition.2 = attached;
+            }
+        }
 
-                // Convert byte offsets to line numbers
-                let start_line = line_starts
-                    .iter()
-                    .position(|&pos| pos >= start_byte)
-                    .unwrap_or(0)
-                    + 1;
-                let end_line = line_starts
-                    .iter()
-                    .position(|&pos| pos >= end_byte)
-                    .unwrap_or(line_starts.len())
-                    + 1;
+        for (node, kind, comment_nodes) in definitions.into_values() {
+            let mut comments = String::new();
+            for comment in comment_nodes {
+                comments.push_str(&content[comment.byte_range()]);
+                comments.push('\n');
+                leading_comments.insert(comment.start_byte());
+            }
 
-                let chunk = CodeChunk {
-                    content: chunk_content,
-                    start_byte,
-                    end_byte,
-                    start_line,
-                    end_line,
-                    kind: query.capture_names()[match_.captures[0].index as usize].to_string(),
-                    leading_comments: comments,
-                    parent_name: None,
-                };
+            let start_byte = node.start_byte();
+            let end_byte = node.end_byte();
+            let (start_line, end_line) = line_span(&node);
+
+            // impl blocks have no name field, so fall back to the implemented type
+            let name = if grammar == "objc" {
+                objc::definition_name(kind, &content[node.byte_range()])
+            } else {
+                node.child_by_field_name("name")
+                    .or_else(|| node.child_by_field_name("type"))
+                    .map(|name_node| content[name_node.byte_range()].to_string())
+            };
 
+            let chunk = CodeChunk {
+                content: content[start_byte..end_byte].to_string(),
+                start_byte,
+                end_byte,
+                start_line,
+                end_line,
+                kind: kind.to_string(),
+                name,
+                leading_comments: comments,
+                parent_name: None,
+                continuation: false,
+                language: None,
+                context_before: String::new(),
+                context_after: String::new(),
+            };
+
+            for chunk in self.split_large_chunk(chunk) {
                 let hash = chunk.hash();
                 if seen_hashes.insert(hash) {
                     chunks.push(chunk);
@@ -224,14 +1022,965 @@ impl Chunker {
                     debug!(
                         "Duplicate chunk detected for file {} at lines {}-{}",
                         path.display(),
-                        start_line,
-                        end_line
+                        chunk.start_line,
+                        chunk.end_line
                     );
                 }
             }
         }
+        // Most of what a shell script does happens outside of its functions
+        if grammar == "sh" {
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string());
+            for range in script_blocks(&tree, content) {
+                for chunk in self.whole_block(content, range, "script", file_name.clone()) {
+                    if seen_hashes.insert(chunk.hash()) {
+                        chunks.push(chunk);
+                    }
+                }
+            }
+        }
+        let mut extracted = Vec::new();
+        if self.injections {
+            extracted.extend(self.sql_strings(&tree, &language, grammar, content, &chunks)?);
+        }
+        if self.comments {
+            extracted.extend(self.comment_chunks(
+                &tree,
+                &language,
+                grammar,
+                content,
+                &chunks,
+                &leading_comments,
+            )?);
+        }
+        for chunk in extracted {
+            if seen_hashes.insert(chunk.hash()) {
+                chunks.push(chunk);
+            }
+        }
+
+        chunks.sort_by_key(|chunk| chunk.start_byte);
+        Ok(chunks)
+    }
+
+    /// Chunks for string literals holding SQL, tagged with the `sql` language and named
+    /// after the innermost of `definitions` they appear in
+    fn sql_strings(
+        &self,
+        tree: &Tree,
+        language: &Language,
+        grammar: &str,
+        content: &str,
+        definitions: &[CodeChunk],
+    ) -> Result<Vec<CodeChunk>> {
+        let query_str = match grammar {
+            "rs" => "[(string_literal) (raw_string_literal)] @string",
+            "py" | "rb" => "(string) @string",
+            "php" => "[(string) (encapsed_string)] @string",
+            "cs" => "[(string_literal) (verbatim_string_literal)] @string",
+            "swift" => "[(line_string_literal) (multi_line_string_literal)] @string",
+            "objc" => "(string_literal) @string",
+            "sh" => "[(string) (raw_string)] @string",
+            _ => "[(string) (template_string)] @string",
+        };
+
+        let query = Query::new(language, query_str)?;
+        let mut cursor = QueryCursor::new();
+        let mut chunks = Vec::new();
+
+        let mut captures = cursor.captures(&query, tree.root_node(), content.as_bytes());
+        while let Some((match_, index)) = captures.next() {
+            let node = match_.captures[*index].node;
+            let literal = &content[node.byte_range()];
+            let range = injection::string_contents(literal);
+            if !injection::looks_like_sql(&literal[range.clone()]) {
+                continue;
+            }
+
+            let start_byte = node.start_byte() + range.start;
+            let end_byte = node.start_byte() + range.end;
+            let (start_line, end_line) = line_span(&node);
+            let owner = enclosing_definition(definitions, start_byte, end_byte)
+                .and_then(|chunk| Some(format!("sql in {} {}", chunk.kind, chunk.name.as_ref()?)));
+
+            let chunk = CodeChunk {
+                content: literal[range].to_string(),
+                start_byte,
+                end_byte,
+                start_line,
+                end_line,
+                kind: EMBEDDED.to_string(),
+                name: Some("sql".to_string()),
+                leading_comments: String::new(),
+                parent_name: owner,
+                continuation: false,
+                language: Some("sql".to_string()),
+                context_before: String::new(),
+                context_after: String::new(),
+            };
+            chunks.extend(self.split_large_chunk(chunk));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Chunks for runs of comments on consecutive lines: `todo` chunks for those with a
+    /// todo marker, `comment` chunks for other runs outside of `definitions` that don't
+    /// document one (their `leading_comments` start at a byte in `leading_comments`)
+    fn comment_chunks(
+        &self,
+        tree: &Tree,
+        language: &Language,
+        grammar: &str,
+        content: &str,
+        definitions: &[CodeChunk],
+        leading_comments: &HashSet<usize>,
+    ) -> Result<Vec<CodeChunk>> {
+        let query_str = match grammar {
+            "rs" => "[(line_comment) (block_comment)] @comment",
+            "swift" => "[(comment) (multiline_comment)] @comment",
+            _ => "(comment) @comment",
+        };
+
+        let query = Query::new(language, query_str)?;
+        let mut cursor = QueryCursor::new();
+
+        // Group the comments into runs: (start byte, end byte, start line, end line, leading)
+        let mut runs: Vec<(usize, usize, usize, usize, bool)> = Vec::new();
+        let mut captures = cursor.captures(&query, tree.root_node(), content.as_bytes());
+        while let Some((match_, index)) = captures.next() {
+            let node = match_.captures[*index].node;
+            let (start_line, end_line) = line_span(&node);
+            let leading = leading_comments.contains(&node.start_byte());
+            match runs.last_mut() {
+                Some(run) if run.3 + 1 >= start_line => {
+                    run.1 = node.end_byte();
+                    run.3 = end_line;
+                    run.4 |= leading;
+                }
+                _ => runs.push((
+                    node.start_byte(),
+                    node.end_byte(),
+                    start_line,
+                    end_line,
+                    leading,
+                )),
+            }
+        }
+
+        let mut chunks = Vec::new();
+        for (start_byte, end_byte, start_line, end_line, leading) in runs {
+            let text = content[start_byte..end_byte].trim_end();
+            let owner = enclosing_definition(definitions, start_byte, end_byte);
+            // Comments right above a definition document it even where the grammar wraps
+            // the definition in another node (`export function` in TypeScript)
+            let leading = leading
+                || definitions
+                    .iter()
+                    .any(|chunk| chunk.start_line == end_line + 1);
+
+            let (kind, name) = match todo_marker(text) {
+                Some(marker) => (TODO, Some(marker.to_string())),
+                None if leading || owner.is_some() => continue,
+                None if words(text) < MIN_COMMENT_WORDS => continue,
+                None => (COMMENT, None),
+            };
+
+            // e.g. "TODO in function send"
+            let parent_name = owner.and_then(|chunk| {
+                Some(format!(
+                    "{} in {} {}",
+                    name.as_deref().unwrap_or(kind),
+                    chunk.kind,
+                    chunk.name.as_ref()?
+                ))
+            });
+            let chunk = CodeChunk {
+                content: text.to_string(),
+                start_byte,
+                end_byte: start_byte + text.len(),
+                start_line,
+                end_line,
+                kind: kind.to_string(),
+                name,
+                leading_comments: String::new(),
+                parent_name,
+                continuation: false,
+                language: None,
+                context_before: String::new(),
+                context_after: String::new(),
+            };
+            chunks.extend(self.split_large_chunk(chunk));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Chunk blocks of embedded code, with positions in the host file
+    ///
+    /// Blocks in a language with a grammar are chunked like a file of it; blocks without a
+    /// grammar or without any definition become `embedded` chunks of the whole block.
+    fn chunk_injections(
+        &mut self,
+        path: &Path,
+        content: &str,
+        injections: &[Injection],
+    ) -> Result<Vec<CodeChunk>> {
+        let mut chunks = Vec::new();
+
+        for block in injections {
+            let language = language_for_extension(&block.extension);
+            let mut block_chunks = self.chunk_block(path, content, block)?;
+            if block_chunks.iter().all(CodeChunk::is_extracted) {
+                block_chunks.extend(self.whole_block(
+                    content,
+                    block.start_byte..block.end_byte,
+                    EMBEDDED,
+                    Some(language.clone()),
+                ));
+            }
+
+            for mut chunk in block_chunks {
+                chunk.language.get_or_insert_with(|| language.clone());
+                chunks.push(chunk);
+            }
+        }
+
+        chunks.sort_by_key(|chunk| chunk.start_byte);
+        Ok(chunks)
+    }
+
+    /// Chunk a Vue or Svelte single-file component
+    ///
+    /// Its `<script>` blocks are chunked with the JavaScript or TypeScript grammar (a block
+    /// without definitions becomes one "script" chunk) and the markup outside of scripts and
+    /// styles becomes "template" chunks, all with lines of the component file.
+    fn chunk_component(&mut self, path: &Path, content: &str) -> Result<Vec<CodeChunk>> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+        let mut chunks = Vec::new();
+
+        for block in injection::script_blocks(content) {
+            let mut block_chunks = self.chunk_block(path, content, &block)?;
+            if block_chunks.iter().all(CodeChunk::is_extracted) {
+                block_chunks.extend(self.whole_block(
+                    content,
+                    block.start_byte..block.end_byte,
+                    "script",
+                    file_name.clone(),
+                ));
+            }
+            chunks.extend(block_chunks);
+        }
+
+        for markup in injection::component_markup(content) {
+            chunks.extend(self.whole_block(content, markup, "template", file_name.clone()));
+        }
 
         chunks.sort_by_key(|chunk| chunk.start_byte);
         Ok(chunks)
     }
+
+    /// Chunk a block of embedded code with its grammar, with positions in the host file
+    ///
+    /// Returns no chunks for languages without a grammar.
+    fn chunk_block(
+        &mut self,
+        path: &Path,
+        content: &str,
+        block: &Injection,
+    ) -> Result<Vec<CodeChunk>> {
+        let Some(grammar) = grammar_for(&block.extension) else {
+            return Ok(Vec::new());
+        };
+
+        let mut chunks =
+            self.chunk_with_grammar(path, grammar, &content[block.start_byte..block.end_byte])?;
+        for chunk in &mut chunks {
+            chunk.start_byte += block.start_byte;
+            chunk.end_byte += block.start_byte;
+            chunk.start_line += block.start_line - 1;
+            chunk.end_line += block.start_line - 1;
+        }
+        Ok(chunks)
+    }
+
+    /// A `kind` chunk of the text in `range` without its surrounding blank lines, split
+    /// into line-aligned parts if needed
+    fn whole_block(
+        &self,
+        content: &str,
+        range: Range<usize>,
+        kind: &str,
+        name: Option<String>,
+    ) -> Vec<CodeChunk> {
+        let text = &content[range.clone()];
+        let blank = &text[..text.len() - text.trim_start().len()];
+        let start = range.start + blank.rfind('\n').map_or(0, |newline| newline + 1);
+        let range = start..(range.start + text.trim_end().len()).max(start);
+
+        let text = &content[range.clone()];
+        let start_line = injection::line_at(content, range.start);
+        let chunk = CodeChunk {
+            content: text.to_string(),
+            start_byte: range.start,
+            end_byte: range.end,
+            start_line,
+            end_line: start_line + text.lines().count().max(1) - 1,
+            kind: kind.to_string(),
+            name,
+            leading_comments: String::new(),
+            parent_name: None,
+            continuation: false,
+            language: None,
+            context_before: String::new(),
+            context_after: String::new(),
+        };
+        self.split_large_chunk(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker(max_tokens: usize) -> Chunker {
+        Chunker::new(&ChunkingConfig {
+            max_tokens,
+            overlap_percentage: 15,
+            file_summaries: false,
+            injections: false,
+            comments: false,
+            strip_license_headers: false,
+            collapse_whitespace: false,
+            skip_generated: false,
+            neighbor_context_lines: 0,
+            redact_secrets: false,
+            secret_patterns: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("fn foo()"), 4);
+        assert_eq!(estimate_tokens("split_large_chunk"), 8);
+    }
+
+    #[test]
+    fn test_content_hash_is_stable() {
+        // Stored in the index, so pinned to the FNV-1a test vectors
+        assert_eq!(content_hash(""), 0xcbf29ce484222325);
+        assert_eq!(content_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(content_hash("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_small_chunks_are_not_split() {
+        let content = "fn foo() {\n    bar();\n}\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("lib.rs"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].name.as_deref(), Some("foo"));
+        assert!(chunks[0].parent_name.is_none());
+    }
+
+    #[test]
+    fn test_leading_comments_are_embedded() {
+        let content = "/// Retries the request with backoff\nfn fetch() {}\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("lib.rs"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].leading_comments.contains("Retries the request"));
+        assert!(chunks[0]
+            .embedding_text()
+            .starts_with("/// Retries the request with backoff"));
+        assert!(chunks[0].embedding_text().ends_with("fn fetch() {}"));
+    }
+
+    #[test]
+    fn test_neighbor_context() {
+        let content = "// Copyright 2024 Example Corp.\n// Licensed under the MIT License\n\nconst LIMIT: u32 = 3;\n\n/// Clamp to the limit\nfn clamp(n: u32) -> u32 {\n    n.min(LIMIT)\n}\n\nfn double(n: u32) -> u32 {\n    clamp(n * 2)\n}\n";
+        let chunk = |lines: usize, name: &str| {
+            Chunker::new(&ChunkingConfig {
+                file_summaries: false,
+                neighbor_context_lines: lines,
+                ..ChunkingConfig::default()
+            })
+            .unwrap()
+            .chunk_file(Path::new("lib.rs"), content)
+            .unwrap()
+            .into_iter()
+            .find(|chunk| chunk.name.as_deref() == Some(name))
+            .unwrap()
+        };
+
+        let clamp = chunk(2, "clamp");
+        assert_eq!((clamp.start_line, clamp.end_line), (7, 9));
+        assert_eq!(clamp.context_before, "const LIMIT: u32 = 3;\n\n");
+        assert_eq!(clamp.context_after, "\nfn double(n: u32) -> u32 {\n");
+        assert!(clamp
+            .embedding_text()
+            .starts_with("const LIMIT: u32 = 3;\n\n/// Clamp to the limit\n"));
+        assert!(!clamp.content.contains("LIMIT: u32"));
+        assert_ne!(clamp.hash(), chunk(0, "clamp").hash());
+
+        // The license header is never context, and the last lines have nothing after them
+        assert_eq!(
+            chunk(10, "clamp").context_before,
+            "\nconst LIMIT: u32 = 3;\n\n"
+        );
+        assert_eq!(chunk(10, "double").context_after, "");
+    }
+
+    #[test]
+    fn test_normalization() {
+        let mut chunker = Chunker::new(&ChunkingConfig::default()).unwrap();
+        let content = "// Copyright 2024 Example Corp.\n// Licensed under the MIT License\n\nuse std::io;\n\n/// Reads    the input\nfn read() {\n\n\n    io::stdin();\n}\n";
+        let chunks = chunker.chunk_file(Path::new("lib.rs"), content).unwrap();
+
+        assert!(chunks
+            .iter()
+            .all(|chunk| !chunk.content.contains("Copyright") && chunk.kind != COMMENT));
+        let read = chunks
+            .iter()
+            .find(|chunk| chunk.kind == "function")
+            .unwrap();
+        assert_eq!(
+            chunker.embedding_text(read),
+            "/// Reads the input\n\nfn read() {\n\nio::stdin();\n}"
+        );
+
+        let generated = "// Code generated by protoc-gen-go. DO NOT EDIT.\nfn a() {}\n";
+        assert!(chunker
+            .chunk_file(Path::new("api.rs"), generated)
+            .unwrap()
+            .is_empty());
+        let minified = "function a(b){return b+1}".repeat(100);
+        assert!(chunker
+            .chunk_file(Path::new("app.js"), &minified)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_only_adjacent_comments_document_definitions() {
+        let content = "// Section about parsing\nuse std::io;\n\nfn a() {}\n\n/// Parses b\n#[inline]\nfn b() {}\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("lib.rs"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].leading_comments, "");
+        assert_eq!(chunks[1].leading_comments, "/// Parses b\n\n");
+    }
+
+    #[test]
+    fn test_symbols_skip_continuation_parts() {
+        let body: String = (0..40)
+            .map(|i| format!("    let v{} = {};\n", i, i))
+            .collect();
+        let content = format!("fn big() {{\n{}}}\n\nfn small() {{}}\n", body);
+        let chunks = chunker(64)
+            .chunk_file(Path::new("lib.rs"), &content)
+            .unwrap();
+        assert!(chunks.len() > 2);
+
+        let symbol = |name: &str, line| Symbol {
+            name: name.to_string(),
+            kind: "function".to_string(),
+            line,
+        };
+        assert_eq!(
+            symbols(&chunks),
+            vec![symbol("big", 1), symbol("small", 44)]
+        );
+    }
+
+    #[test]
+    fn test_file_summary() {
+        let content = "//! Invoice billing\nuse crate::db;\n\nfn charge() {}\n\nfn refund() {}\n";
+        let mut chunker = Chunker::new(&ChunkingConfig::default()).unwrap();
+        let chunks = chunker
+            .chunk_file(Path::new("/repo/src/billing/invoice.rs"), content)
+            .unwrap();
+
+        let summary = &chunks[0];
+        assert_eq!(summary.kind, FILE_SUMMARY);
+        assert_eq!((summary.start_line, summary.end_line), (1, 6));
+        assert_eq!(summary.content, "//! Invoice billing\nuse crate::db;");
+        assert_eq!(
+            summary.leading_comments,
+            "File billing/invoice.rs\nDefines: function charge, function refund\n"
+        );
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(symbols(&chunks).len(), 2);
+    }
+
+    fn line_ranges(file: &str, content: &str) -> Vec<(usize, usize)> {
+        chunker(512)
+            .chunk_file(Path::new(file), content)
+            .unwrap()
+            .iter()
+            .map(|chunk| (chunk.start_line, chunk.end_line))
+            .collect()
+    }
+
+    #[test]
+    fn test_line_numbers_rust() {
+        let content = "use std::fmt;\n\n/// Adds\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nfn one() -> i32 { 1 }\n";
+        assert_eq!(line_ranges("lib.rs", content), vec![(4, 6), (8, 8)]);
+    }
+
+    #[test]
+    fn test_line_numbers_python() {
+        let content =
+            "import os\n\n# Adds\ndef add(a, b):\n    return a + b\n\ndef one():\n    return 1\n";
+        assert_eq!(line_ranges("mod.py", content), vec![(4, 5), (7, 8)]);
+    }
+
+    #[test]
+    fn test_line_numbers_javascript() {
+        let content = "const x = 1;\n\n// Adds\nfunction add(a, b) {\n  return a + b;\n}\n";
+        assert_eq!(line_ranges("app.js", content), vec![(4, 6)]);
+    }
+
+    #[test]
+    fn test_line_numbers_typescript() {
+        let content = "class Calc {\n  add(a: number, b: number): number {\n    return a + b;\n  }\n}\n\nfunction one(): number {\n  return 1;\n}\n";
+        assert_eq!(line_ranges("app.ts", content), vec![(2, 4), (7, 9)]);
+    }
+
+    fn definitions(path: &str, content: &str) -> Vec<(String, String, usize)> {
+        chunker(1000)
+            .chunk_file(Path::new(path), content)
+            .unwrap()
+            .into_iter()
+            .map(|chunk| (chunk.kind, chunk.name.unwrap_or_default(), chunk.start_line))
+            .collect()
+    }
+
+    #[test]
+    fn test_ruby_definitions() {
+        let content = "require 'json'\n\nmodule Billing\n  # Sends invoices\n  class Invoice\n    def total(items)\n      items.sum\n    end\n\n    def self.build\n      new\n    end\n  end\nend\n";
+        let expected = [
+            ("module", "Billing", 3),
+            ("class", "Invoice", 5),
+            ("function", "total", 6),
+            ("function", "build", 10),
+        ];
+        let chunks = definitions("billing.rb", content);
+        assert_eq!(chunks, expected.map(|(k, n, l)| (k.into(), n.into(), l)));
+        assert_eq!(language_for_path(Path::new("billing.rb")), "ruby");
+    }
+
+    #[test]
+    fn test_php_definitions() {
+        let content = "<?php\n\ninterface Payable {}\n\nclass Invoice implements Payable\n{\n    public function total(array $items): int\n    {\n        return array_sum($items);\n    }\n}\n\nfunction format_total($total) {\n    return \"$total\";\n}\n";
+        let expected = [
+            ("interface", "Payable", 3),
+            ("class", "Invoice", 5),
+            ("function", "total", 7),
+            ("function", "format_total", 13),
+        ];
+        let chunks = definitions("invoice.php", content);
+        assert_eq!(chunks, expected.map(|(k, n, l)| (k.into(), n.into(), l)));
+        assert_eq!(language_for_path(Path::new("invoice.php")), "php");
+    }
+
+    #[test]
+    fn test_csharp_definitions() {
+        let content = "using System;\n\nnamespace Billing\n{\n    /// <summary>An invoice</summary>\n    public class Invoice\n    {\n        public Invoice() {}\n\n        public int Total(int[] items)\n        {\n            return items.Length;\n        }\n    }\n}\n";
+        let expected = [
+            ("namespace", "Billing", 3),
+            ("class", "Invoice", 6),
+            ("function", "Invoice", 8),
+            ("function", "Total", 10),
+        ];
+        let chunks = definitions("Invoice.cs", content);
+        assert_eq!(chunks, expected.map(|(k, n, l)| (k.into(), n.into(), l)));
+        assert_eq!(language_for_path(Path::new("Foo.cs")), "csharp");
+    }
+
+    #[test]
+    fn test_swift_definitions() {
+        let content = "import UIKit\n\n/// A line item\nstruct Item {\n    let price: Int\n}\n\nprotocol Payable {}\n\nclass Invoice {\n    init() {}\n\n    func total(of items: [Item]) -> Int {\n        items.map(\\.price).reduce(0, +)\n    }\n}\n\nextension Invoice: Payable {}\n";
+        let expected = [
+            ("struct", "Item", 4),
+            ("protocol", "Payable", 8),
+            ("class", "Invoice", 10),
+            ("function", "init", 11),
+            ("function", "total", 13),
+            ("extension", "Invoice", 18),
+        ];
+        let chunks = definitions("Invoice.swift", content);
+        assert_eq!(chunks, expected.map(|(k, n, l)| (k.into(), n.into(), l)));
+    }
+
+    #[test]
+    fn test_shell_scripts() {
+        let content = "#!/usr/bin/env bash\nset -euo pipefail\n\n# Push an image\npush() {\n  docker push \"$1\"\n}\n\n# Build the release image\ndocker build -t app:release .\npush app:release\n# done\n";
+        let expected = [
+            ("script", "release.sh", 1),
+            ("function", "push", 5),
+            ("script", "release.sh", 9),
+        ];
+        let chunks = chunker(1000)
+            .chunk_file(Path::new("release.sh"), content)
+            .unwrap();
+        let outline: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.kind.as_str(),
+                    chunk.name.as_deref().unwrap(),
+                    chunk.start_line,
+                )
+            })
+            .collect();
+        assert_eq!(outline, expected);
+        assert_eq!(chunks[1].leading_comments, "# Push an image\n");
+        assert_eq!(
+            chunks[2].content,
+            "# Build the release image\ndocker build -t app:release .\npush app:release"
+        );
+        assert_eq!(language_for_path(Path::new("release.zsh")), "shell");
+        assert_eq!(language_for_path(Path::new("Makefile")), "make");
+    }
+
+    #[test]
+    fn test_objc_files() {
+        let content = "#import \"Invoice.h\"\n\n@protocol Payable\n- (int)total;\n@end\n\n// An invoice\n@interface Invoice : NSObject <Payable>\n- (int)total;\n@end\n\n@implementation Invoice (Totals)\n\n/** Sum of the items */\n- (int)total {\n    dispatch_sync(queue, ^{ NSLog(@\"@end }\"); });\n    return [self sum];\n}\n\n- (void)addItem:(Item *)item quantity:(int)quantity {\n}\n@end\n";
+        let expected = [
+            ("protocol", "Payable", 3),
+            ("interface", "Invoice", 8),
+            ("implementation", "Invoice (Totals)", 12),
+            ("function", "total", 15),
+            ("function", "addItem:quantity:", 20),
+        ];
+        let chunks = definitions("Invoice.m", content);
+        assert_eq!(chunks, expected.map(|(k, n, l)| (k.into(), n.into(), l)));
+        assert_eq!(language_for_path(Path::new("Invoice.m")), "objc");
+
+        // Headers without Objective-C are C, chunked as text
+        let header = "int total(int *items);\n";
+        assert_eq!(
+            definitions("total.h", header),
+            [("file".into(), "total.h".into(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_large_chunks_are_split_with_overlap() {
+        let body: String = (0..40)
+            .map(|i| format!("    let value_{} = compute({});\n", i, i))
+            .collect();
+        let content = format!("fn foo() {{\n{}}}\n", body);
+        let chunks = chunker(128)
+            .chunk_file(Path::new("lib.rs"), &content)
+            .unwrap();
+
+        assert!(chunks.len() > 1);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert!(estimate_tokens(&chunk.content) <= 128);
+            assert_eq!(
+                chunk.parent_name.as_deref(),
+                Some(format!("part {} of function foo", i + 1).as_str())
+            );
+        }
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start_line <= pair[0].end_line);
+            assert!(pair[1].start_line > pair[0].start_line);
+        }
+        assert_eq!(chunks.last().unwrap().end_line, chunks[0].start_line + 41);
+    }
+
+    #[test]
+    fn test_files_without_grammar_are_chunked_as_text() {
+        let content = "SELECT id, name\nFROM users\nWHERE active = 1;\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("queries.sql"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].kind, "file");
+        assert_eq!(chunks[0].name.as_deref(), Some("queries.sql"));
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 3));
+
+        let chunks = chunker(512)
+            .chunk_file(Path::new("empty.tf"), "\n")
+            .unwrap();
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn test_notebook_cells() {
+        let content = r##"{"cells": [
+            {"cell_type": "code", "source": ["import numpy as np\n", "x = np.zeros(3)\n"]},
+            {"cell_type": "markdown", "source": ["Helpers\n"]},
+            {"cell_type": "code", "source": ["y = 1\n", "\n", "def scale(v):\n", "    return v * 2\n"]}
+        ]}"##;
+        let chunks = chunker(512)
+            .chunk_file(Path::new("analysis.ipynb"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].kind, "cell");
+        assert_eq!(chunks[0].name.as_deref(), Some("cell 1"));
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 2));
+
+        assert_eq!(chunks[1].kind, "function");
+        assert_eq!(chunks[1].name.as_deref(), Some("scale"));
+        assert_eq!(chunks[1].parent_name.as_deref(), Some("cell 3"));
+        assert_eq!((chunks[1].start_line, chunks[1].end_line), (3, 4));
+    }
+
+    fn injecting_chunker() -> Chunker {
+        Chunker::new(&ChunkingConfig {
+            file_summaries: false,
+            ..ChunkingConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sql_strings_are_embedded_chunks() {
+        let content = "fn load(db: &Db) {\n    db.query(\n        \"SELECT id, name FROM users WHERE active = 1\",\n    );\n    log(\"select a file from the list\");\n}\n";
+        let chunks = injecting_chunker()
+            .chunk_file(Path::new("lib.rs"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].name.as_deref(), Some("load"));
+        assert!(chunks[0].language.is_none());
+
+        let sql = &chunks[1];
+        assert_eq!(sql.kind, EMBEDDED);
+        assert_eq!(sql.content, "SELECT id, name FROM users WHERE active = 1");
+        assert_eq!(&content[sql.start_byte..sql.end_byte], sql.content);
+        assert_eq!((sql.start_line, sql.end_line), (3, 3));
+        assert_eq!(sql.language.as_deref(), Some("sql"));
+        assert_eq!(sql.label(), Some("sql in function load"));
+        assert_eq!(symbols(&chunks).len(), 1);
+
+        // Off unless enabled
+        assert_eq!(line_ranges("lib.rs", content), vec![(1, 6)]);
+    }
+
+    #[test]
+    fn test_markdown_fences_are_chunked_in_their_language() {
+        let content = "# Setup\n\n```python\ndef connect(url):\n    return url\n```\n\n```bash\nmake install\n```\n";
+        let chunks = injecting_chunker()
+            .chunk_file(Path::new("README.md"), content)
+            .unwrap();
+
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.kind.as_str(),
+                    chunk.name.as_deref(),
+                    chunk.language.as_deref(),
+                    chunk.start_line,
+                    chunk.end_line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("file", Some("README.md"), None, 1, 10),
+                ("function", Some("connect"), Some("python"), 4, 5),
+                ("script", Some("README.md"), Some("shell"), 9, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_scripts_are_chunked_as_javascript() {
+        let content = "<body>\n<script>\nconst n = 1;\n</script>\n</body>\n";
+        let chunks = injecting_chunker()
+            .chunk_file(Path::new("index.html"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].kind, EMBEDDED);
+        assert_eq!(chunks[1].content.trim(), "const n = 1;");
+        assert_eq!(chunks[1].language.as_deref(), Some("javascript"));
+        assert_eq!(chunks[1].start_line, 3);
+    }
+
+    #[test]
+    fn test_vue_component() {
+        let content = "<template>\n  <button @click=\"save\">Save</button>\n</template>\n\n<script lang=\"ts\">\nexport default {\n  methods: {\n    save(): void {\n      this.$emit('save');\n    },\n  },\n};\n</script>\n\n<style>\nbutton { color: red; }\n</style>\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("src/SaveButton.vue"), content)
+            .unwrap();
+
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.kind.as_str(),
+                    chunk.name.as_deref(),
+                    chunk.start_line,
+                    chunk.end_line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("template", Some("SaveButton.vue"), 1, 3),
+                ("function", Some("save"), 8, 10),
+            ]
+        );
+        assert_eq!(language_for_path(Path::new("src/SaveButton.vue")), "vue");
+    }
+
+    #[test]
+    fn test_svelte_script_without_definitions() {
+        let content = "<script>\n  let count = 0;\n</script>\n\n<button on:click={() => count++}>{count}</button>\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("Counter.svelte"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].kind, "script");
+        assert_eq!(chunks[0].content.trim(), "let count = 0;");
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (2, 2));
+        assert_eq!(chunks[1].kind, "template");
+        assert_eq!((chunks[1].start_line, chunks[1].end_line), (5, 5));
+        assert!(symbols(&chunks).is_empty());
+    }
+
+    #[test]
+    fn test_config_files_are_chunked_by_section() {
+        let content = "services:\n  cache:\n    image: redis:7\n\n# Connection used by the API\nredis_url: redis://cache:6379\n";
+        let chunks = chunker(512)
+            .chunk_file(Path::new("deploy/compose.yaml"), content)
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].kind, "key");
+        assert_eq!(chunks[0].name.as_deref(), Some("services"));
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 3));
+        assert_eq!(chunks[1].name.as_deref(), Some("redis_url"));
+        assert_eq!(chunks[1].leading_comments, "# Connection used by the API\n");
+        assert_eq!((chunks[1].start_line, chunks[1].end_line), (6, 6));
+    }
+
+    #[test]
+    fn test_comment_chunks() {
+        let content = "// Retry policy shared by every client\n// of the billing service\nuse crate::http;\n\n/// Sends the invoice\nfn send() {\n    // TODO: back off between attempts\n    // Short note\n    post();\n}\n\n// done\n";
+        let chunks = Chunker::new(&ChunkingConfig {
+            file_summaries: false,
+            injections: false,
+            ..ChunkingConfig::default()
+        })
+        .unwrap()
+        .chunk_file(Path::new("billing.rs"), content)
+        .unwrap();
+
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.kind.as_str(),
+                    chunk.label(),
+                    chunk.start_line,
+                    chunk.end_line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (COMMENT, None, 1, 2),
+                ("function", Some("send"), 6, 10),
+                (TODO, Some("TODO in function send"), 7, 8),
+            ]
+        );
+        assert_eq!(
+            chunks[2].content,
+            "// TODO: back off between attempts\n    // Short note"
+        );
+        assert_eq!(symbols(&chunks).len(), 1);
+        assert_eq!(todo_marker("# FIXME(ana): flaky"), Some("FIXME"));
+        assert_eq!(todo_marker("// todos are listed"), None);
+    }
+
+    #[test]
+    fn test_language_names() {
+        assert_eq!(language_for_path(Path::new("src/main.rs")), "rust");
+        assert_eq!(language_for_path(Path::new("app.MJS")), "javascript");
+        assert_eq!(language_for_path(Path::new("analysis.ipynb")), "python");
+        assert_eq!(language_for_path(Path::new("schema.sql")), "sql");
+        assert_eq!(language_for_path(Path::new("LICENSE")), "text");
+        assert_eq!(
+            language_for_path(Path::new("docker/Dockerfile.dev")),
+            "dockerfile"
+        );
+        assert_eq!(language_for_path(Path::new("ci.yml")), "yaml");
+        assert_eq!(language_for_extension("python"), "python");
+        assert_eq!(language_for_extension("ts"), "typescript");
+    }
+
+    #[test]
+    fn test_extension_aliases_use_grammar() {
+        let content = "function add(a, b) {\n  return a + b;\n}\n";
+        assert_eq!(line_ranges("app.mjs", content), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn test_plugin_chunker() {
+        let content = "syntax = \"proto3\";\nmessage User {\n  string name = 1;\n}\n";
+        let command =
+            r#"echo '[{"start_line": 2, "end_line": 4, "kind": "message", "name": "User"}]'"#;
+        let chunkers = BTreeMap::from([(
+            "*.proto".to_string(),
+            ChunkerPluginConfig {
+                command: command.to_string(),
+            },
+        )]);
+        let mut chunker = Chunker::new(&ChunkingConfig::default())
+            .unwrap()
+            .with_plugins(&chunkers, Path::new("/repo"))
+            .unwrap();
+        let chunks = chunker
+            .chunk_file(Path::new("api/user.proto"), content)
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (2, 4));
+        assert_eq!(chunks[0].kind, "message");
+        assert_eq!(chunks[0].name.as_deref(), Some("User"));
+        assert_eq!(
+            &content[chunks[0].start_byte..chunks[0].end_byte],
+            chunks[0].content
+        );
+        assert!(chunks[0].content.starts_with("message User {"));
+
+        // A failing chunker falls back to the usual chunking
+        let chunkers = BTreeMap::from([(
+            "*.proto".to_string(),
+            ChunkerPluginConfig {
+                command: "exit 1".to_string(),
+            },
+        )]);
+        let mut chunker = Chunker::new(&ChunkingConfig::default())
+            .unwrap()
+            .with_plugins(&chunkers, Path::new("/repo"))
+            .unwrap();
+        assert!(chunker
+            .chunk_file(Path::new("api/user.proto"), content)
+            .is_ok());
+    }
 }
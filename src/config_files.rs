@@ -0,0 +1,350 @@
+use std::path::Path;
+
+/// Configuration and infrastructure formats chunked by their top-level structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Dockerfile,
+    Hcl,
+    Makefile,
+}
+
+/// Format of a configuration file, from its extension or (for Dockerfiles and Makefiles)
+/// its name
+///
+/// Generated lockfiles are not configuration and have no format.
+pub fn format_for(path: &Path) -> Option<Format> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name == "pnpm-lock.yaml" {
+        return None;
+    }
+    if name == "dockerfile"
+        || name == "containerfile"
+        || name.starts_with("dockerfile.")
+        || name.ends_with(".dockerfile")
+    {
+        return Some(Format::Dockerfile);
+    }
+    if name == "makefile" || name == "gnumakefile" {
+        return Some(Format::Makefile);
+    }
+
+    match path.extension()?.to_str()?.to_lowercase().as_str() {
+        "yaml" | "yml" => Some(Format::Yaml),
+        "toml" => Some(Format::Toml),
+        "tf" | "tfvars" | "hcl" => Some(Format::Hcl),
+        "mk" => Some(Format::Makefile),
+        _ => None,
+    }
+}
+
+/// Extension `index.extensions` lists a file under: its own, or `mk` for Makefiles, which
+/// have none
+pub fn listed_extension(path: &Path) -> Option<&str> {
+    match format_for(path) {
+        Some(Format::Makefile) => Some("mk"),
+        _ => path.extension()?.to_str(),
+    }
+}
+
+/// A top-level unit of a configuration file: a YAML key, TOML table, Dockerfile stage, HCL
+/// block or Makefile rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub kind: &'static str,
+    pub name: Option<String>,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// 1-based line of the section's header
+    pub start_line: usize,
+    /// Comment lines directly above the header
+    pub comments: String,
+}
+
+/// Kind and name of the section a line starts, if it starts one
+fn header(format: Format, line: &str) -> Option<(&'static str, Option<String>)> {
+    let unquote = |text: &str| text.trim().trim_matches(['"', '\'']).to_string();
+
+    match format {
+        Format::Yaml => {
+            if line.starts_with(char::is_whitespace) || line.starts_with(['#', '%']) {
+                return None;
+            }
+            if let Some(item) = line.strip_prefix("- ") {
+                return Some(("item", Some(item.trim().to_string())));
+            }
+            let (key, _) = line
+                .split_once(": ")
+                .or_else(|| line.trim_end().strip_suffix(':').map(|key| (key, "")))?;
+            Some(("key", Some(unquote(key))))
+        }
+        Format::Toml => {
+            let line = line.trim();
+            // Only a comment may follow a header, unlike arrays in multi-line values
+            let header_end = |rest: &str| rest.trim().is_empty() || rest.trim().starts_with('#');
+            if let Some(table) = line.strip_prefix("[[") {
+                let (table, rest) = table.split_once("]]")?;
+                return header_end(rest).then(|| ("array", Some(table.trim().to_string())));
+            }
+            let (table, rest) = line.strip_prefix('[')?.split_once(']')?;
+            header_end(rest).then(|| ("table", Some(table.trim().to_string())))
+        }
+        Format::Dockerfile => {
+            // Indented lines continue the instruction above
+            if line.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let mut words = line.split_whitespace();
+            if !words.next()?.eq_ignore_ascii_case("from") {
+                return None;
+            }
+            let words: Vec<&str> = words.filter(|word| !word.starts_with("--")).collect();
+            let name = match words.as_slice() {
+                [_, alias, stage, ..] if alias.eq_ignore_ascii_case("as") => stage,
+                [image, ..] => image,
+                [] => return None,
+            };
+            Some(("stage", Some(name.to_string())))
+        }
+        Format::Hcl => {
+            if !line.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+                return None;
+            }
+            let line = line.trim_end();
+            if let Some(block) = line.strip_suffix('{') {
+                // `resource "aws_instance" "web" {` is named by its labels
+                let mut words = block.split_whitespace();
+                let kind = words.next()?;
+                let labels: Vec<String> = words.map(unquote).collect();
+                let name = (!labels.is_empty()).then(|| labels.join("."));
+                return Some((hcl_kind(kind), name));
+            }
+            let (attribute, _) = line.split_once('=')?;
+            Some(("attribute", Some(attribute.trim().to_string())))
+        }
+        Format::Makefile => {
+            // Recipes are indented; special targets such as `.PHONY` declare rather than build
+            if line.starts_with(char::is_whitespace) || line.starts_with('.') {
+                return None;
+            }
+            let (targets, rest) = line.split_once(':')?;
+            // `CC := gcc` and `URL = http://` are variables, and so are target-specific ones
+            // (`release: VERSION = 1`); a recipe may follow the prerequisites after `;`
+            let prerequisites = rest.split(';').next().unwrap_or_default();
+            if targets.contains('=') || prerequisites.contains('=') {
+                return None;
+            }
+            Some(("target", Some(targets.trim().to_string())))
+        }
+    }
+}
+
+/// Kind recorded for an HCL block type; unknown block types become "block"
+fn hcl_kind(block: &str) -> &'static str {
+    match block {
+        "resource" => "resource",
+        "data" => "data",
+        "module" => "module",
+        "variable" => "variable",
+        "output" => "output",
+        "locals" => "locals",
+        "provider" => "provider",
+        "terraform" => "terraform",
+        _ => "block",
+    }
+}
+
+fn is_comment(format: Format, line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with('#') || (format == Format::Hcl && line.starts_with("//"))
+}
+
+/// Split a configuration file into its top-level sections
+///
+/// Each section runs from its header to the next one. Anything before the first header
+/// other than comments (root keys of a TOML file, `ARG`s before a Dockerfile's first stage)
+/// becomes a "preamble" section.
+pub fn sections(format: Format, content: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = Vec::new();
+    // Comment lines right above the current line, and where they start
+    let mut comments = String::new();
+    let mut comments_start = None;
+    let mut offset = 0;
+
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+
+        if is_comment(format, line) {
+            comments_start.get_or_insert(line_start);
+            comments.push_str(line);
+            continue;
+        }
+
+        match header(format, line) {
+            Some((kind, name)) => {
+                let start = comments_start.unwrap_or(line_start);
+                match sections.last_mut() {
+                    Some(previous) => previous.end_byte = start,
+                    None if !is_blank(format, &content[..start]) => sections.push(Section {
+                        kind: "preamble",
+                        name: None,
+                        start_byte: 0,
+                        end_byte: start,
+                        start_line: 1,
+                        comments: String::new(),
+                    }),
+                    None => {}
+                }
+                sections.push(Section {
+                    kind,
+                    name,
+                    start_byte: line_start,
+                    end_byte: content.len(),
+                    start_line: index + 1,
+                    comments: std::mem::take(&mut comments),
+                });
+            }
+            None => comments.clear(),
+        }
+        comments_start = None;
+    }
+
+    if sections.is_empty() && !is_blank(format, content) {
+        sections.push(Section {
+            kind: "preamble",
+            name: None,
+            start_byte: 0,
+            end_byte: content.len(),
+            start_line: 1,
+            comments: String::new(),
+        });
+    }
+
+    // Blank lines between sections belong to neither
+    for section in &mut sections {
+        section.end_byte = section.start_byte
+            + content[section.start_byte..section.end_byte]
+                .trim_end()
+                .len();
+    }
+    sections
+}
+
+/// Whether `text` holds nothing but whitespace and comments
+fn is_blank(format: Format, text: &str) -> bool {
+    text.lines()
+        .all(|line| line.trim().is_empty() || is_comment(format, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outline(format: Format, content: &str) -> Vec<(&'static str, Option<String>, usize)> {
+        sections(format, content)
+            .into_iter()
+            .map(|section| (section.kind, section.name, section.start_line))
+            .collect()
+    }
+
+    fn named(kind: &'static str, name: &str, line: usize) -> (&'static str, Option<String>, usize) {
+        (kind, Some(name.to_string()), line)
+    }
+
+    #[test]
+    fn test_format_for() {
+        assert_eq!(format_for(Path::new("ci/deploy.yml")), Some(Format::Yaml));
+        assert_eq!(format_for(Path::new("Cargo.toml")), Some(Format::Toml));
+        assert_eq!(
+            format_for(Path::new("docker/Dockerfile")),
+            Some(Format::Dockerfile)
+        );
+        assert_eq!(
+            format_for(Path::new("api.dockerfile")),
+            Some(Format::Dockerfile)
+        );
+        assert_eq!(format_for(Path::new("main.tf")), Some(Format::Hcl));
+        assert_eq!(format_for(Path::new("Makefile")), Some(Format::Makefile));
+        assert_eq!(format_for(Path::new("rules.mk")), Some(Format::Makefile));
+        assert_eq!(format_for(Path::new("main.rs")), None);
+    }
+
+    #[test]
+    fn test_yaml_top_level_keys() {
+        let content = "# Cache settings\nredis:\n  url: redis://localhost:6379\n\nworkers: 4\n\"log level\": debug\n";
+        let sections = sections(Format::Yaml, content);
+
+        assert_eq!(
+            outline(Format::Yaml, content),
+            vec![
+                named("key", "redis", 2),
+                named("key", "workers", 5),
+                named("key", "log level", 6)
+            ]
+        );
+        assert_eq!(sections[0].comments, "# Cache settings\n");
+        assert_eq!(
+            &content[sections[0].start_byte..sections[0].end_byte],
+            "redis:\n  url: redis://localhost:6379"
+        );
+    }
+
+    #[test]
+    fn test_toml_tables() {
+        let content = "name = \"app\"\n\n[database]\nurl = \"postgres://\"\nports = [\n  [80, 443],\n]\n\n[[bin]] # binaries\nname = \"cli\"\n";
+        assert_eq!(
+            outline(Format::Toml, content),
+            vec![
+                ("preamble", None, 1),
+                named("table", "database", 3),
+                named("array", "bin", 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dockerfile_stages() {
+        let content = "ARG RUST_VERSION=1.80\nFROM rust:${RUST_VERSION} AS build\nRUN cargo build\n\nFROM --platform=linux/amd64 debian:bookworm-slim\nCOPY --from=build /app /app\n";
+        assert_eq!(
+            outline(Format::Dockerfile, content),
+            vec![
+                ("preamble", None, 1),
+                named("stage", "build", 2),
+                named("stage", "debian:bookworm-slim", 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hcl_blocks() {
+        let content = "terraform {\n  required_version = \">= 1.5\"\n}\n\n// Primary cache\nresource \"aws_elasticache_cluster\" \"redis\" {\n  engine = \"redis\"\n}\n\nregion = \"us-east-1\"\n";
+        let sections = sections(Format::Hcl, content);
+        assert_eq!(
+            outline(Format::Hcl, content),
+            vec![
+                ("terraform", None, 1),
+                named("resource", "aws_elasticache_cluster.redis", 6),
+                named("attribute", "region", 10),
+            ]
+        );
+        assert_eq!(sections[1].comments, "// Primary cache\n");
+    }
+
+    #[test]
+    fn test_makefile_targets() {
+        let content = "IMAGE := app\nVERSION = $(shell git describe)\n\n.PHONY: build release\nrelease: VERSION = 1\n\nbuild:\n\tcargo build --release\n\n# Build and push the release image\nrelease: build\n\tdocker build -t $(IMAGE):$(VERSION) .\n\tdocker push $(IMAGE):$(VERSION)\n\nsrc/%.o: src/%.c\n\t$(CC) -c $<\n";
+        let sections = sections(Format::Makefile, content);
+        assert_eq!(
+            outline(Format::Makefile, content),
+            vec![
+                ("preamble", None, 1),
+                named("target", "build", 7),
+                named("target", "release", 11),
+                named("target", "src/%.o", 15),
+            ]
+        );
+        assert_eq!(sections[2].comments, "# Build and push the release image\n");
+    }
+}
@@ -9,12 +9,21 @@ pub mod constants {
     /// Database filename
     pub const DATABASE_FILENAME: &str = "ragrep.db";
 
+    /// Summary of the default index for build tooling, rewritten after every (re)index
+    pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+    /// Directory under `.ragrep` holding the databases of named index profiles
+    pub const INDEXES_DIR_NAME: &str = "indexes";
+
     /// Unix socket filename for server communication
     pub const SOCKET_FILENAME: &str = "ragrep.sock";
 
     /// PID file filename for server process tracking
     pub const PID_FILENAME: &str = "server.pid";
 
+    /// Server metrics kept across restarts, next to the PID file
+    pub const METRICS_FILENAME: &str = "metrics.json";
+
     /// Configuration filename
     pub const CONFIG_FILENAME: &str = "config.toml";
 
@@ -24,8 +33,17 @@ pub mod constants {
     /// Models subdirectory name
     pub const MODELS_DIR_NAME: &str = "models";
 
+    /// Embedding model used for indexing and queries
+    pub const EMBEDDING_MODEL: &str = "mixedbread-ai/mxbai-embed-large-v1";
+
+    /// Dimension of the vectors produced by the embedding model
+    pub const EMBEDDING_DIMENSIONS: usize = 1024;
+
     /// Default file extensions to index
-    pub const DEFAULT_FILE_EXTENSIONS: &[&str] = &["rs", "py", "js", "ts"];
+    pub const DEFAULT_FILE_EXTENSIONS: &[&str] = &[
+        "rs", "py", "js", "ts", "rb", "php", "cs", "swift", "m", "h", "sh", "bash", "zsh", "mk",
+        "ipynb", "vue", "svelte",
+    ];
 
     /// Common build/cache directories to ignore
     pub const IGNORED_DIRECTORIES: &[&str] = &[
@@ -37,4 +55,29 @@ pub mod constants {
         "dist",
         "build",
     ];
+
+    /// Default gitignore-style patterns of test files (`--no-tests`)
+    pub const DEFAULT_TEST_PATTERNS: &[&str] = &[
+        "tests/",
+        "test/",
+        "__tests__/",
+        "*_test.*",
+        "test_*.py",
+        "*.test.*",
+        "*.spec.*",
+    ];
+
+    /// Default gitignore-style patterns of vendored third-party code (`--no-vendor`)
+    pub const DEFAULT_VENDOR_PATTERNS: &[&str] = &["vendor/", "third_party/", "node_modules/"];
+
+    /// File name endings of minified bundles and protobuf/gRPC generated code, not indexed
+    pub const GENERATED_FILE_SUFFIXES: &[&str] = &[
+        ".min.js",
+        "-min.js",
+        "_pb2.py",
+        "_pb2_grpc.py",
+        "_pb.js",
+        "_grpc_pb.js",
+        "_pb.d.ts",
+    ];
 }
@@ -1,99 +1,737 @@
-use crate::chunker::Chunker;
-use crate::config::ConfigManager;
+use crate::ann::AnnIndex;
+use crate::blame::Blamer;
+use crate::chunker::{self, Chunker, CodeChunk};
+use crate::config::{self, Config, ConfigManager, IndexConfig};
 use crate::constants::constants;
-use crate::db::Database;
+use crate::db::{self, Database};
 use crate::embedder::Embedder;
-use crate::indexer::{FileInfo, Indexer};
+use crate::git_watcher;
+use crate::indexer::{FileInfo, IgnoreCache, IgnoreRules, Indexer};
+use crate::lock::IndexLock;
+use crate::manifest::Manifest;
 use crate::reranker::Reranker;
-use anyhow::{Context as AnyhowContext, Result};
-use log::{debug, info};
+use crate::worker::{WorkerEmbedder, WorkerModel, WorkerProcess, WorkerReranker};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
 
-pub struct AppContext {
-    pub embedder: Embedder,
-    pub reranker: Reranker,
-    pub db: Database,
-    pub ragrep_dir: PathBuf,
-    pub config_manager: ConfigManager,
+/// Chunks a reindex embeds between checks for interactive queries to yield to
+const REINDEX_BATCH_CHUNKS: usize = 8;
+
+/// Database file of an index profile; without a profile, the default `.ragrep/ragrep.db`
+pub fn database_path(ragrep_dir: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        Some(profile) => ragrep_dir
+            .join(constants::INDEXES_DIR_NAME)
+            .join(format!("{}.db", profile)),
+        None => ragrep_dir.join(constants::DATABASE_FILENAME),
+    }
 }
 
-impl AppContext {
-    pub async fn new(base_path: &Path) -> Result<Self> {
-        let start_time = Instant::now();
+/// Index profile for the project at `base_path`: `explicit` when given, otherwise the
+/// current git branch if `index.per_branch` is enabled
+///
+/// Names are made safe to use as file names (`feature/login` becomes `feature_login`).
+pub fn resolve_profile(
+    base_path: &Path,
+    config: &IndexConfig,
+    explicit: Option<&str>,
+) -> Option<String> {
+    let name = match explicit {
+        Some(name) => name.to_string(),
+        None if config.per_branch => git_watcher::current_branch(base_path)?,
+        None => return None,
+    };
 
-        let config_manager = ConfigManager::new(Some(base_path))?;
+    Some(
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Open (creating if needed) the database of an index profile under `.ragrep` without
+/// loading any models
+///
+/// A `read_only` index must already exist and is never written to.
+pub fn open_database(base_path: &Path, profile: Option<&str>, read_only: bool) -> Result<Database> {
+    let db_path = database_path(&config::project_data_dir(base_path), profile);
+
+    // Paths are stored relative to the project root
+    let root = base_path
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {}", base_path.display()))?;
+
+    if read_only {
+        return Database::open_read_only(&db_path, &root);
+    }
 
-        // Create .ragrep directory if it doesn't exist
-        let ragrep_dir = base_path.join(constants::RAGREP_DIR_NAME);
-        fs::create_dir_all(&ragrep_dir)?;
+    if let Some(dir) = db_path.parent() {
+        config::create_private_dir(dir)?;
+    }
+    let db = Database::new(&db_path, &root)
+        .with_context(|| format!("Failed to initialize database at {}", db_path.display()))?;
+
+    if db.get_metadata(db::META_EMBEDDING_MODEL)?.is_none() {
+        db.set_metadata(db::META_EMBEDDING_MODEL, constants::EMBEDDING_MODEL)?;
+    }
+
+    Ok(db)
+}
+
+/// Embeddings a reindex of changed files reused from their previous versions or computed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReindexStats {
+    pub reused_embeddings: usize,
+    pub new_embeddings: usize,
+}
 
-        // Initialize database
-        let db_path = ragrep_dir.join(constants::DATABASE_FILENAME);
-        let db = Database::new(&db_path)
-            .with_context(|| format!("Failed to initialize database at {}", db_path.display()))?;
+/// Embedding and reranking models, shared by every project context a server holds
+#[derive(Clone)]
+pub struct Models {
+    pub embedder: Arc<Embedder>,
+    pub reranker: Arc<Reranker>,
+}
 
+/// The settings [`Models::load`] loads by: model cache directory, quantization and
+/// embedding dimensions; configs that agree on them can share one set of models
+pub type ModelsKey = (PathBuf, bool, Option<usize>);
+
+impl Models {
+    /// What the models `config_manager` loads are told apart by
+    pub fn key(config_manager: &ConfigManager) -> Result<ModelsKey> {
+        let embedding = &config_manager.config().embedding;
+        Ok((
+            config_manager.get_model_cache_dir()?,
+            embedding.quantized,
+            embedding.dimensions,
+        ))
+    }
+
+    /// Load both models from the configured model cache directory
+    pub fn load(config_manager: &ConfigManager) -> Result<Self> {
         // Initialize embedder with configured model cache directory
         let model_cache_dir = config_manager.get_model_cache_dir()?;
         fs::create_dir_all(&model_cache_dir)?;
         debug!("Using model cache directory: {}", model_cache_dir.display());
 
         let embedder_start = Instant::now();
-        let embedder = Embedder::new(&model_cache_dir)?;
+        let config = config_manager.config();
+        let embedding = &config.embedding;
+        let embedder = Embedder::new(
+            &model_cache_dir,
+            embedding.quantized,
+            embedding_threads(config),
+        )?
+        .with_dimensions(embedding.dimensions)?;
         debug!(
             "[TIMING] Embedder initialization: {:.3}s",
             embedder_start.elapsed().as_secs_f64()
         );
 
+        Ok(Self {
+            embedder: Arc::new(embedder),
+            reranker: Arc::new(Self::load_reranker(config_manager)?),
+        })
+    }
+
+    /// Load the reranker alone, from the configured model cache directory
+    pub fn load_reranker(config_manager: &ConfigManager) -> Result<Reranker> {
+        let model_cache_dir = config_manager.get_model_cache_dir()?;
+        fs::create_dir_all(&model_cache_dir)?;
+
         // Initialize reranker with BGE model
         debug!("Initializing local BGE reranker");
         let reranker_start = Instant::now();
-        let reranker = Reranker::new(&model_cache_dir)?;
+        let reranker = Reranker::new(&model_cache_dir, reranker_threads(config_manager.config()))?;
         debug!(
             "[TIMING] Reranker initialization: {:.3}s",
             reranker_start.elapsed().as_secs_f64()
         );
+        Ok(reranker)
+    }
+
+    /// Models for a server: in worker processes with `server.model_worker`, so a model crash
+    /// can't take the server down, otherwise loaded in-process
+    pub fn load_for_server(config_manager: &ConfigManager) -> Result<Self> {
+        let config = config_manager.config();
+        if !config.server.model_worker {
+            return Self::load(config_manager);
+        }
+
+        let model_cache_dir = config_manager.get_model_cache_dir()?;
+        fs::create_dir_all(&model_cache_dir)?;
+        info!("Running the models in worker processes");
+        let embedding = &config.embedding;
+        let embedder = WorkerEmbedder::new(
+            WorkerProcess::for_model(
+                WorkerModel::Embed,
+                &model_cache_dir,
+                embedding.quantized,
+                embedding_threads(config),
+            )?,
+            embedding.quantized,
+        );
+        let reranker = WorkerReranker::new(WorkerProcess::for_model(
+            WorkerModel::Rerank,
+            &model_cache_dir,
+            false,
+            reranker_threads(config),
+        )?);
+
+        Ok(Self {
+            embedder: Arc::new(
+                Embedder::with_model(Box::new(embedder)).with_dimensions(embedding.dimensions)?,
+            ),
+            reranker: Arc::new(Reranker::with_model(Box::new(reranker))),
+        })
+    }
+
+    /// Run both models once on a dummy query, so ONNX Runtime's lazy initialization is done
+    /// before the first real query rather than during it
+    pub async fn warm_up(&self) -> Result<()> {
+        let start = Instant::now();
+        let text = "warm up";
+        self.embedder.embed_queries(&[text], "").await?;
+        self.reranker.rerank(text, &[text.to_string()])?;
+        debug!(
+            "[TIMING] Model warm-up: {:.3}s",
+            start.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+
+    /// Whether both models are currently held in memory
+    pub fn is_loaded(&self) -> bool {
+        self.embedder.is_loaded() && self.reranker.is_loaded()
+    }
+
+    /// Drop both models; each reloads on its next use
+    pub fn unload(&self) {
+        self.embedder.unload();
+        self.reranker.unload();
+    }
+}
+
+/// Embed `chunk` of `file_path` the way indexing does, from the text `chunker` made of it
+pub async fn embed_chunk(
+    embedder: &Embedder,
+    chunker: &Chunker,
+    chunk: &CodeChunk,
+    file_path: &str,
+    document_prefix: &str,
+) -> Result<Vec<f32>> {
+    let text = chunker.embedding_text(chunk);
+    Ok(embedder
+        .embed_text(&text, file_path, document_prefix)
+        .await?
+        .0)
+}
+
+/// Embedding of `chunk`: that of identical content already in the index (a vendored copy,
+/// a pasted helper) if there is one, so copies share it, otherwise a new one
+pub async fn chunk_embedding(
+    db: &Database,
+    embedder: &Embedder,
+    chunker: &Chunker,
+    chunk: &CodeChunk,
+    file_path: &str,
+    document_prefix: &str,
+) -> Result<Vec<f32>> {
+    match db.get_embedding(chunk.hash())? {
+        Some(embedding) => Ok(embedding),
+        None => embed_chunk(embedder, chunker, chunk, file_path, document_prefix).await,
+    }
+}
+
+/// Threads the embedding model runs on (`embedding.threads`)
+fn embedding_threads(config: &Config) -> usize {
+    config
+        .embedding
+        .threads
+        .unwrap_or_else(config::default_inference_threads)
+}
+
+/// Threads the reranking model runs on (`reranker.threads`)
+fn reranker_threads(config: &Config) -> usize {
+    config
+        .reranker
+        .as_ref()
+        .and_then(|reranker| reranker.threads)
+        .unwrap_or_else(config::default_inference_threads)
+}
+
+pub struct AppContext {
+    pub embedder: Arc<Embedder>,
+    pub reranker: Arc<Reranker>,
+    pub db: Database,
+    pub ragrep_dir: PathBuf,
+    pub config_manager: ConfigManager,
+    /// In-memory ANN index, built by servers when `search.ann` is enabled
+    pub ann: Option<AnnIndex>,
+    /// Named index profile in use; `None` for the default index
+    pub profile: Option<String>,
+    /// The index is shared and must not be modified (no reindexing or metadata updates)
+    pub read_only: bool,
+    /// Which files of search results ignore files exclude by now
+    pub ignored_files: IgnoreCache,
+}
+
+impl AppContext {
+    /// Load the models and open the index selected by `profile` (see [`resolve_profile`])
+    pub async fn new(base_path: &Path, profile: Option<&str>, read_only: bool) -> Result<Self> {
+        let start_time = Instant::now();
+
+        let config_manager = ConfigManager::new(Some(base_path))?;
+        let models = Models::load(&config_manager)?;
+        let profile = resolve_profile(base_path, &config_manager.config().index, profile);
+        let context = Self::with_models(base_path, models, profile.as_deref(), read_only)?;
 
         debug!(
             "[TIMING] Total AppContext initialization: {:.3}s",
             start_time.elapsed().as_secs_f64()
         );
 
-        Ok(Self {
-            embedder,
-            reranker,
+        Ok(context)
+    }
+
+    /// Create a context for an already resolved index profile of the project at `base_path`,
+    /// reusing already loaded models
+    pub fn with_models(
+        base_path: &Path,
+        models: Models,
+        profile: Option<&str>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let config_manager = ConfigManager::new(Some(base_path))?;
+        let ragrep_dir = config::project_data_dir(base_path);
+        let mut db = open_database(base_path, profile, read_only)?;
+        db.set_categories(&config_manager.config().categories)?;
+        if let Some(profile) = profile {
+            info!("Using index profile '{}'", profile);
+        }
+
+        let mut context = Self {
+            ignored_files: IgnoreCache::new(db.root()),
+            embedder: models.embedder,
+            reranker: models.reranker,
             db,
             ragrep_dir,
             config_manager,
-        })
+            ann: None,
+            profile: profile.map(str::to_string),
+            read_only,
+        };
+        context.check_dimensions()?;
+        context.check_quantization()?;
+        context.check_document_prefix()?;
+        if !read_only {
+            context.configure_vectors()?;
+        }
+
+        Ok(context)
     }
 
-    /// Incrementally reindex specific files with embedding reuse
-    pub async fn reindex_files(&mut self, file_paths: Vec<PathBuf>) -> Result<()> {
-        info!("Incrementally reindexing {} files", file_paths.len());
+    /// Lay out an empty vector table as configured (`index.vector_storage` and the embedder's
+    /// dimension) and record the dimension in index metadata
+    fn configure_vectors(&mut self) -> Result<()> {
+        let storage = self.config_manager.config().index.vector_storage;
+        self.db
+            .set_vector_layout(storage, self.embedder.dimensions())?;
+        if let Some(dimensions) = self.db.dimensions() {
+            self.db
+                .set_metadata(db::META_EMBEDDING_DIMENSIONS, &dimensions.to_string())?;
+        }
+        Ok(())
+    }
 
-        let indexer = Indexer::new();
-        let mut chunker = Chunker::new()?;
+    /// Refuse a project whose `embedding.dimensions` differs from the embedder's, as when a
+    /// global server's models (truncated per the server's config) serve a project configured
+    /// otherwise: its index and queries would not fit together
+    fn check_dimensions(&self) -> Result<()> {
+        let configured = self
+            .config_manager
+            .config()
+            .embedding
+            .dimensions
+            .unwrap_or(constants::EMBEDDING_DIMENSIONS);
+        let loaded = self.embedder.dimensions();
+        if configured != loaded {
+            bail!(
+                "{} is configured for {}-dimensional embeddings, but the loaded embedding model \
+                 produces {}; set the same embedding.dimensions for the server, or run a server \
+                 of its own for this project",
+                self.db.root().display(),
+                configured,
+                loaded
+            );
+        }
+        Ok(())
+    }
 
-        // Separate existing files from deleted ones
+    /// Record the embedding model's quantization for a new index, or warn if an existing
+    /// index was built with a different one (its vectors are not directly comparable)
+    fn check_quantization(&self) -> Result<()> {
+        let current = self.embedder.quantization();
+        let stored = match self.db.get_metadata(db::META_EMBEDDING_QUANTIZATION)? {
+            Some(stored) => Some(stored),
+            // Indexes built before quantization was recorded used the full model
+            None if self.db.count_chunks()? > 0 => Some("none".to_string()),
+            None => None,
+        };
+
+        match stored {
+            Some(stored) if stored != current => warn!(
+                "Index was built with quantization '{}' but the embedding model uses '{}'; \
+                 run `ragrep index --full` to rebuild it",
+                stored, current
+            ),
+            Some(_) => {}
+            None if self.read_only => {}
+            None => self
+                .db
+                .set_metadata(db::META_EMBEDDING_QUANTIZATION, current)?,
+        }
+
+        Ok(())
+    }
+
+    /// Record the document prefix for a new index, or warn if an existing index was embedded
+    /// with a different one (its vectors no longer match what queries are compared to)
+    fn check_document_prefix(&self) -> Result<()> {
+        let current = &self.config_manager.config().embedding.document_prefix;
+        let stored = match self.db.get_metadata(db::META_EMBEDDING_DOCUMENT_PREFIX)? {
+            Some(stored) => Some(stored),
+            // Indexes built before prefixes existed were embedded without one
+            None if self.db.count_chunks()? > 0 => Some(String::new()),
+            None => None,
+        };
+
+        match stored {
+            Some(stored) if &stored != current => warn!(
+                "Index was embedded with document prefix '{}' but embedding.document_prefix is \
+                 '{}'; run `ragrep index --full` to rebuild it",
+                stored, current
+            ),
+            Some(_) => {}
+            None if self.read_only => {}
+            None => self
+                .db
+                .set_metadata(db::META_EMBEDDING_DOCUMENT_PREFIX, current)?,
+        }
+
+        Ok(())
+    }
+
+    /// Build the in-memory ANN index from the stored embeddings if `search.ann` is enabled
+    pub fn build_ann_index(&mut self) -> Result<()> {
+        if !self.config_manager.config().search.ann {
+            return Ok(());
+        }
+
+        let embeddings = self.db.get_embeddings(None)?;
+        info!("Building ANN index over {} chunks", embeddings.len());
+        self.ann = Some(AnnIndex::build(embeddings));
+        Ok(())
+    }
+
+    /// Re-read the project's config and apply it, returning the keys that changed
+    ///
+    /// Settings read per query or per reindex take effect right away; the path categories
+    /// and the ANN index are refreshed here. The loaded models are left alone.
+    pub fn reload_config(&mut self) -> Result<Vec<String>> {
+        let config_manager = self.config_manager.reload()?;
+        let changed = self.config_manager.changed_keys(&config_manager);
+        if changed.is_empty() {
+            return Ok(changed);
+        }
+
+        self.db
+            .set_categories(&config_manager.config().categories)?;
+        let ann_changed =
+            config_manager.config().search.ann != self.config_manager.config().search.ann;
+        self.config_manager = config_manager;
+        if ann_changed {
+            self.ann = None;
+            self.build_ann_index()?;
+        }
+        if changed.iter().any(|key| key == "embedding.document_prefix") {
+            self.check_document_prefix()?;
+        }
+        Ok(changed)
+    }
+
+    /// Empty the index and record the current embedding model's quantization and document
+    /// prefix for the rebuild
+    ///
+    /// The emptied vector table takes on the configured vector storage and dimension.
+    pub fn clear_index(&mut self) -> Result<()> {
+        self.ensure_writable()?;
+        self.db.clear_all()?;
+        self.configure_vectors()?;
+        self.db.set_metadata(
+            db::META_EMBEDDING_QUANTIZATION,
+            self.embedder.quantization(),
+        )?;
+        self.db.set_metadata(
+            db::META_EMBEDDING_DOCUMENT_PREFIX,
+            &self.config_manager.config().embedding.document_prefix,
+        )
+    }
+
+    /// Fail with a clear error when the index was opened read-only
+    pub fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!(
+                "Index {} is opened read-only and cannot be modified",
+                self.database_path().display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Repair what [`Database::verify`] found: re-embed chunks that lost their vector and
+    /// delete vectors whose chunk is gone
+    ///
+    /// The files of those chunks are chunked again, so each is embedded from the same text
+    /// as at indexing time, neighbor context included; chunks whose file has changed since
+    /// are left for the next reindex.
+    pub async fn repair_index(&mut self, report: &db::IntegrityReport) -> Result<()> {
+        self.ensure_writable()?;
+
+        let mut missing: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+        for &id in &report.chunks_without_vectors {
+            if let Some(chunk) = self.db.get_chunk_by_id(id)? {
+                missing
+                    .entry(chunk.file_path)
+                    .or_default()
+                    .push((id, chunk.hash));
+            }
+        }
+
+        let config = self.config_manager.config();
+        let mut chunker =
+            Chunker::new(&config.chunking)?.with_plugins(&config.chunkers, self.db.root())?;
+        let document_prefix = config.embedding.document_prefix.clone();
+        let mut vectors = Vec::with_capacity(report.chunks_without_vectors.len());
+        let mut stale = 0;
+        for (file_path, chunks) in missing {
+            let path = self.db.resolve_path(&file_path);
+            let current = match fs::read_to_string(&path) {
+                Ok(content) => chunker.chunk_file(&path, &content)?,
+                Err(_) => Vec::new(),
+            };
+            for (id, hash) in chunks {
+                let Some(chunk) = current.iter().find(|chunk| chunk.hash() as i64 == hash) else {
+                    stale += 1;
+                    continue;
+                };
+                let embedding = embed_chunk(
+                    &self.embedder,
+                    &chunker,
+                    chunk,
+                    &path.to_string_lossy(),
+                    &document_prefix,
+                )
+                .await?;
+                vectors.push((id, embedding));
+            }
+        }
+        if stale > 0 {
+            warn!(
+                "{} chunks without vectors are from files changed since they were indexed; \
+                 run `ragrep index` to reindex them",
+                stale
+            );
+        }
+        self.db.save_vectors(&vectors)?;
+        self.db.delete_vectors(&report.vectors_without_chunks)?;
+
+        if self.ann.is_some() {
+            self.ann = None;
+            self.build_ann_index()?;
+        }
+        info!(
+            "Repaired index: re-embedded {} chunks, deleted {} orphaned vectors",
+            vectors.len(),
+            report.vectors_without_chunks.len()
+        );
+        Ok(())
+    }
+
+    /// Check the index for orphaned chunks and vectors when `server.verify_index` is set,
+    /// repairing them unless the index is read-only
+    pub async fn verify_on_open(&mut self) -> Result<()> {
+        if !self.config_manager.config().server.verify_index {
+            return Ok(());
+        }
+
+        let report = self.db.verify()?;
+        if report.is_clean() {
+            debug!("Index {} is consistent", self.database_path().display());
+            return Ok(());
+        }
+        warn!(
+            "Index {} has {} chunks without vectors and {} vectors without chunks",
+            self.database_path().display(),
+            report.chunks_without_vectors.len(),
+            report.vectors_without_chunks.len()
+        );
+        if self.read_only {
+            warn!("Read-only index left as is; run `ragrep doctor --fix` on a writable copy");
+            return Ok(());
+        }
+        self.repair_index(&report).await
+    }
+
+    /// Database file of the index profile in use
+    pub fn database_path(&self) -> PathBuf {
+        database_path(&self.ragrep_dir, self.profile.as_deref())
+    }
+
+    /// Take the index's advisory lock, waiting for another `ragrep index` to finish first
+    pub async fn lock_index(&self) -> Result<IndexLock> {
+        IndexLock::acquire(&self.database_path().with_extension("lock")).await
+    }
+
+    /// The models this context uses, for sharing with other project contexts
+    pub fn models(&self) -> Models {
+        Models {
+            embedder: Arc::clone(&self.embedder),
+            reranker: Arc::clone(&self.reranker),
+        }
+    }
+
+    /// The files to reindex for the watcher's `changes`
+    ///
+    /// A moved file or directory expands to its indexed files at the old path, which no
+    /// longer exist and are removed, and the files found at the new path; reindexing both
+    /// lets the moved files reuse their embeddings. A changed ignore file expands to every
+    /// file in its directory, indexed or not, so newly ignored files are removed and newly
+    /// revealed ones indexed.
+    pub fn changed_files(&self, changes: Vec<git_watcher::FileChange>) -> Result<Vec<PathBuf>> {
+        let mut indexed: Option<Vec<String>> = None;
+        let mut files = Vec::new();
+        for change in changes {
+            let (from, to) = match change {
+                git_watcher::FileChange::Changed(path) => {
+                    files.push(path);
+                    continue;
+                }
+                git_watcher::FileChange::Renamed { from, to } => (from, Some(to)),
+                git_watcher::FileChange::Removed(from) => (from, None),
+                git_watcher::FileChange::IgnoreFile(file) => match file.parent() {
+                    Some(dir) => (dir.to_path_buf(), Some(dir.to_path_buf())),
+                    None => continue,
+                },
+                git_watcher::FileChange::Submodule(dir) => (dir.clone(), Some(dir)),
+            };
+
+            // The index holds canonical paths, and only the old path's parent still exists
+            let from = match (from.parent(), from.file_name()) {
+                (Some(parent), Some(name)) => parent
+                    .canonicalize()
+                    .map_or_else(|_| from.clone(), |parent| parent.join(name)),
+                _ => from,
+            };
+            let indexed = match &mut indexed {
+                Some(indexed) => indexed,
+                None => indexed.insert(self.db.get_indexed_files()?),
+            };
+            files.extend(
+                indexed
+                    .iter()
+                    .map(PathBuf::from)
+                    .filter(|file| file.starts_with(&from)),
+            );
+
+            match to {
+                Some(to) if to.is_dir() => {
+                    let indexer = Indexer::new(&self.config_manager.config().index);
+                    files.extend(
+                        indexer
+                            .index_directory(&to)?
+                            .into_iter()
+                            .map(|file| file.path),
+                    );
+                }
+                Some(to) => files.push(to),
+                None => {}
+            }
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        files.retain(|file| seen.insert(file.clone()));
+        Ok(files)
+    }
+
+    /// Incrementally reindex specific files of a shared context with embedding reuse
+    ///
+    /// The context is only locked to read what can be reused and to swap in each file's new
+    /// chunks; chunking and embedding run unlocked, so searches keep being answered. Before
+    /// each file and each small batch of chunks it embeds, the reindex yields to queries in
+    /// flight (see [`Embedder::prioritize_query`]), so they don't wait for a big reindex.
+    ///
+    /// `progress` is called with the number of files reindexed so far and the total, once
+    /// with 0 before the first file and then after every file.
+    pub async fn reindex_files(
+        context: &Mutex<AppContext>,
+        file_paths: Vec<PathBuf>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<ReindexStats> {
+        let (indexer, mut chunker, embedder, document_prefix, blamer) = {
+            let this = context.lock().await;
+            this.ensure_writable()?;
+            let config = this.config_manager.config();
+            (
+                Indexer::new(&config.index),
+                Chunker::new(&config.chunking)?.with_plugins(&config.chunkers, this.db.root())?,
+                Arc::clone(&this.embedder),
+                config.embedding.document_prefix.clone(),
+                config
+                    .index
+                    .blame
+                    .then(|| Blamer::open(this.db.root()))
+                    .flatten(),
+            )
+        };
+        info!("Incrementally reindexing {} files", file_paths.len());
+
+        // Separate existing files from deleted ones, and ones ignore files exclude by now
+        let ignore_rules = IgnoreRules::new(context.lock().await.db.root());
         let (existing_files, deleted_files): (Vec<_>, Vec<_>) = file_paths
             .into_iter()
-            .partition(|path| path.exists());
-
-        // Delete chunks for files that no longer exist
-        for deleted_path in &deleted_files {
-            let file_path_str = deleted_path
-                .canonicalize()
-                .unwrap_or_else(|_| deleted_path.clone())
-                .to_string_lossy()
-                .to_string();
-            debug!("Removing deleted file from index: {}", file_path_str);
-            self.db.delete_file(&file_path_str)?;
-        }
+            .partition(|path| path.exists() && !ignore_rules.is_ignored(path));
 
-        if !deleted_files.is_empty() {
-            info!("Removed {} deleted files from index", deleted_files.len());
+        // Deleted files by content hash: a new file with the same content was most likely
+        // moved there and reuses their embeddings
+        let mut rename_sources: HashMap<u64, Vec<String>> = HashMap::new();
+        {
+            let this = context.lock().await;
+            for deleted_path in &deleted_files {
+                let file_path_str = deleted_path
+                    .canonicalize()
+                    .unwrap_or_else(|_| deleted_path.clone())
+                    .to_string_lossy()
+                    .to_string();
+                let content_hash = this.db.get_file_hash(&file_path_str)?.unwrap_or_default();
+                rename_sources
+                    .entry(content_hash)
+                    .or_default()
+                    .push(file_path_str);
+            }
         }
 
         // Filter to only valid files (exist, correct extensions)
@@ -101,73 +739,372 @@ impl AppContext {
 
         if files.is_empty() {
             debug!("No valid files to reindex");
-            return Ok(());
+            context.lock().await.remove_deleted_files(rename_sources)?;
+            return Ok(ReindexStats::default());
         }
 
         let start = std::time::Instant::now();
         let mut total_chunks = 0;
         let mut reused_embeddings = 0;
         let mut new_embeddings = 0;
+        let mut unchanged_files = 0;
+        let mut renamed_files = 0;
 
-        for file in &files {
+        progress(0, files.len());
+        for (done, file) in files.iter().enumerate() {
+            embedder.yield_to_queries().await;
             let file_path_str = file.path.to_string_lossy().to_string();
 
+            let content = std::fs::read_to_string(&file.path)
+                .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+            let content_hash = chunker::content_hash(&content);
+
             // OPTIMIZATION: Load old embeddings BEFORE deleting
-            let embedding_cache = self.db.get_chunks_with_embeddings(&file_path_str)?;
+            let (mut embedding_cache, renamed_from) = {
+                let this = context.lock().await;
 
-            // Delete old chunks for this file (clean slate)
-            self.db.delete_file(&file_path_str)?;
+                // Editors and formatters often rewrite files without changing them
+                if this.db.get_file_hash(&file_path_str)? == Some(content_hash) {
+                    debug!("Skipping unchanged file: {}", file_path_str);
+                    unchanged_files += 1;
+                    progress(done + 1, files.len());
+                    continue;
+                }
 
-            // Read and chunk the file
-            let content = std::fs::read_to_string(&file.path)
-                .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+                let mut embedding_cache = this.db.get_chunks_with_embeddings(&file_path_str)?;
+
+                // Only the file summary, which names the file, needs a new embedding after
+                // a move
+                let renamed_from = rename_sources.get_mut(&content_hash).and_then(Vec::pop);
+                if let Some(old_path) = &renamed_from {
+                    debug!("{} was moved to {}", old_path, file_path_str);
+                    embedding_cache.extend(this.db.get_chunks_with_embeddings(old_path)?);
+                }
+                (embedding_cache, renamed_from)
+            };
 
+            // Chunk the file
             let chunks = chunker.chunk_file(&file.path, &content)?;
+            let blame = blamer
+                .as_ref()
+                .and_then(|blamer| blamer.blame(&file.path, &content));
             total_chunks += chunks.len();
 
-            // Embed and save chunks, REUSING embeddings where possible
-            for (idx, chunk) in chunks.iter().enumerate() {
-                let hash = chunk.hash() as i64;
+            // Content already indexed elsewhere (a vendored copy, a pasted helper) shares its
+            // embedding
+            {
+                let this = context.lock().await;
+                for chunk in &chunks {
+                    let hash = chunk.hash();
+                    if embedding_cache.contains_key(&(hash as i64)) {
+                        continue;
+                    }
+                    if let Some(embedding) = this.db.get_embedding(hash)? {
+                        embedding_cache.insert(hash as i64, embedding);
+                    }
+                }
+            }
 
-                // Try to reuse embedding if content unchanged
-                let embedding = if let Some(cached) = embedding_cache.get(&hash) {
-                    // Content unchanged! Reuse old embedding (FAST!)
-                    reused_embeddings += 1;
-                    cached.clone()
-                } else {
-                    // Content changed, need to re-embed (SLOW)
-                    new_embeddings += 1;
-                    let result = self
-                        .embedder
-                        .embed_text(&chunk.content, &file_path_str)
-                        .await?;
-                    result.0 // Extract Vec<f32> from Embedding wrapper
-                };
+            // Embed chunks in small batches, REUSING embeddings where possible. Embedding
+            // keeps the thread busy, so the runtime hands searches to another thread meanwhile.
+            let mut records = Vec::with_capacity(chunks.len());
+            for batch in chunks.chunks(REINDEX_BATCH_CHUNKS) {
+                embedder.yield_to_queries().await;
+                tokio::task::block_in_place(|| {
+                    Handle::current().block_on(async {
+                        for chunk in batch {
+                            let hash = chunk.hash() as i64;
+
+                            // Try to reuse embedding if content unchanged
+                            let embedding = if let Some(cached) = embedding_cache.get(&hash) {
+                                // Content unchanged! Reuse old embedding (FAST!)
+                                reused_embeddings += 1;
+                                cached.clone()
+                            } else {
+                                // Content changed, need to re-embed (SLOW)
+                                new_embeddings += 1;
+                                embed_chunk(
+                                    &embedder,
+                                    &chunker,
+                                    chunk,
+                                    &file_path_str,
+                                    &document_prefix,
+                                )
+                                .await?
+                            };
+
+                            let idx = records.len();
+                            records.push(
+                                db::ChunkRecord::new(&file_path_str, idx, chunk, embedding)
+                                    .with_blame(blame.as_ref()),
+                            );
+                        }
+                        anyhow::Ok(())
+                    })
+                })?;
+            }
+
+            // Replace the old chunks (clean slate) with the new ones in one go, so searches
+            // never see the file half indexed
+            let mut this = context.lock().await;
+            if let Some(old_path) = &renamed_from {
+                this.remove_from_ann(old_path)?;
+                this.db.delete_file(old_path)?;
+                renamed_files += 1;
+            }
+            // Chunks the file still has keep the time they were first indexed
+            let updated_times = this.db.updated_times(&file_path_str)?;
+            for record in &mut records {
+                record.updated_at = updated_times.get(&record.hash).copied();
+            }
+            this.remove_from_ann(&file_path_str)?;
+            this.db.delete_file(&file_path_str)?;
+            this.db.save_chunks_batch(&records)?;
 
-                self.db.save_chunk(
-                    &file_path_str,
-                    idx as i32,
-                    &chunk.kind,
-                    chunk.parent_name.as_deref(),
-                    chunk.start_line,
-                    chunk.end_line,
-                    &chunk.content,
-                    hash as u64,
-                    &embedding,
-                )?;
+            this.db
+                .save_symbols(&file_path_str, &chunker::symbols(&chunks))?;
+            this.db.set_file_hash(&file_path_str, content_hash)?;
+
+            let this = &mut *this;
+            if let Some(ann) = &mut this.ann {
+                for (id, embedding) in this.db.get_embeddings(Some(&file_path_str))? {
+                    ann.insert(id, embedding);
+                }
             }
+            progress(done + 1, files.len());
         }
 
         let elapsed = start.elapsed();
         info!(
-            "Reindexed {} files ({} chunks) in {:.2}s - reused {} embeddings, computed {} new",
-            files.len(),
+            "Reindexed {} files ({} chunks) in {:.2}s - reused {} embeddings, computed {} new, \
+             skipped {} unchanged files",
+            files.len() - unchanged_files,
             total_chunks,
             elapsed.as_secs_f64(),
             reused_embeddings,
-            new_embeddings
+            new_embeddings,
+            unchanged_files
         );
+        if renamed_files > 0 {
+            info!(
+                "{} files were moved, keeping their embeddings",
+                renamed_files
+            );
+        }
+        if chunker.secrets_redacted() > 0 {
+            warn!(
+                "Masked {} secrets (API keys, tokens, passwords) before indexing",
+                chunker.secrets_redacted()
+            );
+        }
+
+        let mut this = context.lock().await;
+        this.remove_deleted_files(rename_sources)?;
+        this.finish_indexing()?;
+        Ok(ReindexStats {
+            reused_embeddings,
+            new_embeddings,
+        })
+    }
+
+    /// Bring the index within its size budget and rewrite its manifest; run after every
+    /// (re)index
+    pub fn finish_indexing(&mut self) -> Result<()> {
+        self.enforce_size_budget()?;
+        Manifest::build(&self.db)?.write(&self.manifest_path())
+    }
+
+    /// Manifest of this context's index: `.ragrep/manifest.json`, or next to the database
+    /// of a named profile
+    pub fn manifest_path(&self) -> PathBuf {
+        match &self.profile {
+            Some(profile) => self
+                .ragrep_dir
+                .join(constants::INDEXES_DIR_NAME)
+                .join(format!("{}.{}", profile, constants::MANIFEST_FILENAME)),
+            None => self.ragrep_dir.join(constants::MANIFEST_FILENAME),
+        }
+    }
+
+    /// Evict files, in the order of `index.eviction`, until the index fits in
+    /// `index.max_size_mb`
+    pub fn enforce_size_budget(&mut self) -> Result<()> {
+        let Some(max_size_mb) = self.config_manager.config().index.max_size_mb else {
+            return Ok(());
+        };
+        self.evict_to_fit(max_size_mb * 1024 * 1024)
+    }
+
+    /// Evict files, in the order of `index.eviction`, until the indexed data takes at most
+    /// `budget` bytes
+    ///
+    /// The size is that of the data ([`Database::data_bytes`]), not of the database file,
+    /// whose pages are only partly freed by deleting rows.
+    fn evict_to_fit(&mut self, budget: u64) -> Result<()> {
+        let policy = self.config_manager.config().index.eviction;
+        let budget_mb = budget as f64 / (1024.0 * 1024.0);
+
+        let mut evicted = 0;
+        loop {
+            let used = self.db.data_bytes()?;
+            let files = files_to_evict(
+                self.db.eviction_order(policy)?,
+                used,
+                budget,
+                self.db.count_chunks()?,
+            );
+            if files.is_empty() {
+                break;
+            }
+
+            for file_path in &files {
+                info!(
+                    "Evicting {} (index over its {:.1} MB budget)",
+                    file_path, budget_mb
+                );
+                self.remove_from_ann(file_path)?;
+            }
+            self.db.evict_files(&files)?;
+            evicted += files.len();
+        }
 
+        if evicted > 0 {
+            info!(
+                "Evicted {} files ({}) to keep the index within {:.1} MB",
+                evicted,
+                policy.name(),
+                budget_mb
+            );
+        }
         Ok(())
     }
+
+    /// Delete deleted files that were not moved, all at once (a branch switch can remove
+    /// thousands)
+    fn remove_deleted_files(&mut self, rename_sources: HashMap<u64, Vec<String>>) -> Result<()> {
+        let deleted_paths: Vec<String> = rename_sources.into_values().flatten().collect();
+        if deleted_paths.is_empty() {
+            return Ok(());
+        }
+
+        for file_path_str in &deleted_paths {
+            debug!("Removing deleted file from index: {}", file_path_str);
+            self.remove_from_ann(file_path_str)?;
+        }
+        self.db.delete_files(&deleted_paths)?;
+        info!("Removed {} deleted files from index", deleted_paths.len());
+        Ok(())
+    }
+
+    /// Drop a file's chunks from the ANN index before they are deleted from the database
+    fn remove_from_ann(&mut self, file_path: &str) -> Result<()> {
+        if let Some(ann) = &mut self.ann {
+            for (id, _) in self.db.get_embeddings(Some(file_path))? {
+                ann.remove(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first of `candidates` (paths and chunk counts, in eviction order) whose chunks
+/// free the bytes an index of `used` bytes and `total_chunks` chunks has beyond `budget`,
+/// taking every chunk to be the same size
+fn files_to_evict(
+    candidates: Vec<(String, usize)>,
+    used: u64,
+    budget: u64,
+    total_chunks: usize,
+) -> Vec<String> {
+    if used <= budget || total_chunks == 0 {
+        return Vec::new();
+    }
+
+    let bytes_per_chunk = used as f64 / total_chunks as f64;
+    let mut excess = (used - budget) as f64;
+    let mut files = Vec::new();
+    for (file_path, chunks) in candidates {
+        if excess <= 0.0 {
+            break;
+        }
+        excess -= chunks as f64 * bytes_per_chunk;
+        files.push(file_path);
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_database_paths() {
+        let ragrep_dir = Path::new("/repo/.ragrep");
+        assert_eq!(
+            database_path(ragrep_dir, None),
+            Path::new("/repo/.ragrep/ragrep.db")
+        );
+        assert_eq!(
+            database_path(ragrep_dir, Some("main")),
+            Path::new("/repo/.ragrep/indexes/main.db")
+        );
+    }
+
+    #[test]
+    fn test_files_to_evict() {
+        let candidates = || {
+            vec![
+                ("a.rs".to_string(), 10),
+                ("b.rs".to_string(), 30),
+                ("c.rs".to_string(), 60),
+            ]
+        };
+        // 100 chunks of 10 bytes
+        assert!(files_to_evict(candidates(), 1000, 1000, 100).is_empty());
+        assert_eq!(files_to_evict(candidates(), 1000, 950, 100), ["a.rs"]);
+        assert_eq!(
+            files_to_evict(candidates(), 1000, 850, 100),
+            ["a.rs", "b.rs"]
+        );
+        assert_eq!(files_to_evict(candidates(), 1000, 0, 100).len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_evict_to_fit() {
+        let fixture = crate::testing::Fixture::indexed("evict").await;
+        let mut context = fixture.context.lock().await;
+        let count_files = |db: &Database| {
+            db.eviction_order(crate::config::EvictionPolicy::default())
+                .unwrap()
+                .len()
+        };
+        let files = count_files(&context.db);
+        let budget = context.db.data_bytes().unwrap() / 2;
+
+        context.evict_to_fit(budget).unwrap();
+        // Evicting stops once the data fits, however much of the file's pages stay in use
+        assert!(context.db.data_bytes().unwrap() <= budget);
+        let evicted = context.db.evicted_files().unwrap().len();
+        assert!(evicted > 0);
+        assert!(evicted < files, "every file was evicted");
+        assert_eq!(count_files(&context.db), files - evicted);
+    }
+
+    #[test]
+    fn test_resolve_profile() {
+        let config = IndexConfig::default();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(resolve_profile(&cwd, &config, None), None);
+        assert_eq!(
+            resolve_profile(&cwd, &config, Some("feature/login")).as_deref(),
+            Some("feature_login")
+        );
+
+        // Outside a git repository there is no branch to select a profile by
+        let per_branch = IndexConfig {
+            per_branch: true,
+            ..IndexConfig::default()
+        };
+        assert_eq!(resolve_profile(Path::new("/"), &per_branch, None), None);
+    }
 }
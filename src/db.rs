@@ -1,25 +1,308 @@
-use anyhow::Result;
-use log::debug;
-use rusqlite::{params, Connection};
+use anyhow::{bail, Result};
+use fnv::FnvHasher;
+use log::{debug, info, warn};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension, ToSql};
+use serde::{Deserialize, Serialize};
 use sqlite_vec::sqlite3_vec_init;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::RuntimeFlavor;
 use zerocopy::IntoBytes;
 
+use crate::category::PathCategories;
+use crate::chunker::{self, CodeChunk, Symbol};
+use crate::config::{CategoriesConfig, EvictionPolicy, VectorStorage};
+use crate::constants::constants;
+
+/// Schema version recorded in `PRAGMA user_version`
+pub const SCHEMA_VERSION: i32 = 6;
+
+/// How long SQLite waits for another connection's lock before reporting SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Attempts made for a write that still finds the database locked after `BUSY_TIMEOUT`
+const WRITE_ATTEMPTS: u32 = 5;
+
+/// Backoff before the first retry of a locked write; doubled on every further attempt
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// Metadata key holding the embedding model the index was built with
+pub const META_EMBEDDING_MODEL: &str = "embedding_model";
+
+/// Metadata key holding the quantization of the embedding model ("none" or "int8")
+pub const META_EMBEDDING_QUANTIZATION: &str = "embedding_quantization";
+
+/// Metadata key holding the `embedding.document_prefix` the chunks were embedded with
+pub const META_EMBEDDING_DOCUMENT_PREFIX: &str = "embedding_document_prefix";
+
+/// Metadata key holding the length of the stored vectors (below the model's own when
+/// `embedding.dimensions` truncates them)
+pub const META_EMBEDDING_DIMENSIONS: &str = "embedding_dimensions";
+
+/// Metadata key holding the algorithm of the stored file content hashes
+/// (`chunker::CONTENT_HASH_ALGORITHM`)
+pub const META_CONTENT_HASH: &str = "content_hash";
+
+/// A chunk and its embedding, ready to be saved
+#[derive(Debug, Clone)]
+pub struct ChunkRecord<'a> {
+    pub file_path: &'a str,
+    pub chunk_index: i32,
+    pub node_type: &'a str,
+    pub node_name: Option<&'a str>,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: &'a str,
+    pub comments: &'a str,
+    /// Language of embedded code; the file's language when `None`
+    pub language: Option<&'a str>,
+    /// Author of the last commit to touch the chunk's lines, when `index.blame` is on
+    pub author: Option<&'a str>,
+    /// Time of that commit, in seconds since the Unix epoch
+    pub committed_at: Option<i64>,
+    pub hash: u64,
+    pub embedding: Vec<f32>,
+    /// When the chunk was first indexed with this content, in seconds since the Unix epoch;
+    /// now when `None`
+    pub updated_at: Option<i64>,
+}
+
+impl<'a> ChunkRecord<'a> {
+    /// Record of the `chunk_index`th chunk of a file
+    pub fn new(
+        file_path: &'a str,
+        chunk_index: usize,
+        chunk: &'a CodeChunk,
+        embedding: Vec<f32>,
+    ) -> Self {
+        Self {
+            file_path,
+            chunk_index: chunk_index as i32,
+            node_type: &chunk.kind,
+            node_name: chunk.label(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            text: &chunk.content,
+            comments: &chunk.leading_comments,
+            language: chunk.language.as_deref(),
+            author: None,
+            committed_at: None,
+            hash: chunk.hash(),
+            embedding,
+            updated_at: None,
+        }
+    }
+
+    /// The same record, attributed to the last commit that touched its lines
+    pub fn with_blame(mut self, blame: Option<&'a crate::blame::FileBlame>) -> Self {
+        if let Some(commit) = blame.and_then(|blame| blame.latest(self.start_line, self.end_line)) {
+            self.author = Some(&commit.author);
+            self.committed_at = Some(commit.time);
+        }
+        self
+    }
+}
+
+/// A chunk found by a vector search
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkMatch {
+    /// Row id of the chunk; stays the same until its file is reindexed
+    pub id: i64,
+    pub text: String,
+    pub file_path: String,
+    pub start_line: i32,
+    pub end_line: i32,
+    pub node_type: String,
+    pub node_name: Option<String>,
+    /// Hash of the chunk's content, keying its vector
+    pub hash: i64,
+    /// Distance between the chunk's embedding and the query's
+    pub distance: f32,
+}
+
+impl ChunkMatch {
+    /// Read a row of `id, text, file_path, start_line, end_line, node_type, node_name,
+    /// hash` followed by the distance, unless the distance is already known
+    fn from_row(row: &rusqlite::Row, distance: Option<f32>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            text: row.get(1)?,
+            file_path: row.get(2)?,
+            start_line: row.get(3)?,
+            end_line: row.get(4)?,
+            node_type: row.get(5)?,
+            node_name: row.get(6)?,
+            hash: row.get(7)?,
+            distance: match distance {
+                Some(distance) => distance,
+                None => row.get(8)?,
+            },
+        })
+    }
+}
+
+/// A `defs` hit: symbol name, kind, file path and line
+pub type SymbolMatch = (String, String, String, i32);
+
+/// A chunk row together with its embedding, as stored in the database
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredChunk {
+    pub file_path: String,
+    pub chunk_index: i32,
+    pub node_type: String,
+    pub node_name: Option<String>,
+    pub start_line: i32,
+    pub end_line: i32,
+    pub text: String,
+    pub comments: String,
+    /// Language of the chunk; the file's language when `None`
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Path category; recomputed from the path when `None`
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub committed_at: Option<i64>,
+    pub hash: i64,
+    pub embedding: Vec<f32>,
+    /// When the chunk was indexed; the time of import when `None`
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+}
+
+/// A row of the files table (what incremental indexing compares files by), as stored
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredFile {
+    pub file_path: String,
+    pub content_hash: i64,
+    pub modified_at: Option<i64>,
+}
+
+/// A row of the symbols table, as stored
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredSymbol {
+    pub file_path: String,
+    pub name: String,
+    pub kind: String,
+    pub line: i64,
+}
+
+/// Everything stored about a chunk except its embedding, for `ragrep show`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkDetails {
+    pub id: i64,
+    pub file_path: String,
+    pub chunk_index: i32,
+    pub node_type: String,
+    pub node_name: Option<String>,
+    pub start_line: i32,
+    pub end_line: i32,
+    pub text: String,
+    pub comments: String,
+    pub language: Option<String>,
+    pub category: Option<String>,
+    pub author: Option<String>,
+    pub committed_at: Option<i64>,
+    pub hash: i64,
+    pub created_at: String,
+}
+
+/// Rows of `chunks` and `chunks_vec` without a counterpart in the other table, as left by
+/// a crash between writing one and the other
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// Chunks that have no vector, so searches never find them
+    pub chunks_without_vectors: Vec<i64>,
+    /// Vectors (by content hash) no chunk has anymore; searches that hit them return fewer
+    /// results
+    pub vectors_without_chunks: Vec<i64>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.chunks_without_vectors.is_empty() && self.vectors_without_chunks.is_empty()
+    }
+}
+
+/// Restricts which chunks a search considers; the default matches every chunk
+#[derive(Debug, Clone, Default)]
+pub struct ChunkFilter {
+    /// Only chunks of these languages (see [`chunker::language_for_path`])
+    pub languages: Vec<String>,
+    /// Only chunks of these path categories (see [`crate::category`])
+    pub categories: Vec<String>,
+    /// No chunks of these path categories
+    pub exclude_categories: Vec<String>,
+    /// Only chunks of these kinds (`node_type`, e.g. "todo")
+    pub kinds: Vec<String>,
+    /// Only chunks of files under these directories or matching these globs (paths as
+    /// stored)
+    pub paths: Vec<String>,
+    /// Only chunks of comment text: comment and todo chunks, and definitions with leading
+    /// comments
+    pub comments: bool,
+    /// Only chunks last changed by an author containing this text, case-insensitively
+    pub author: Option<String>,
+    /// Only chunks last changed at or after this time, in seconds since the Unix epoch
+    pub since: Option<i64>,
+    /// Only chunks (re)indexed at or after this time, in seconds since the Unix epoch
+    pub changed_since: Option<i64>,
+    /// Only chunks of these files (paths as stored)
+    pub files: Vec<String>,
+    /// With `files`, also chunks of files that have no file summary, which a search of
+    /// summaries cannot pick
+    pub unsummarized_files: bool,
+}
+
+impl ChunkFilter {
+    pub fn is_empty(&self) -> bool {
+        self.languages.is_empty()
+            && self.categories.is_empty()
+            && self.exclude_categories.is_empty()
+            && self.kinds.is_empty()
+            && self.paths.is_empty()
+            && !self.comments
+            && self.author.is_none()
+            && self.since.is_none()
+            && self.changed_since.is_none()
+            && self.files.is_empty()
+    }
+}
+
+/// Chunks and their embeddings
+///
+/// Embeddings are stored once per chunk content: `chunks_vec` is keyed by the content hash
+/// that chunk rows carry, so vendored copies and copy-pasted code share one vector.
 pub struct Database {
     conn: Connection,
+    /// Index root; file paths inside it are stored relative to it
+    root: PathBuf,
+    /// Tags saved chunks with the category of their path
+    categories: PathCategories,
+    /// Dimension the vector table was created with
+    dimensions: Option<usize>,
+    /// Precision of the vectors in the vector table
+    storage: VectorStorage,
+    /// When `record_matches` last wrote each file's match time
+    recorded_matches: Mutex<HashMap<String, Instant>>,
 }
 
 impl Database {
-    pub fn new(path: &Path) -> Result<Self> {
-        // Initialize sqlite-vec extension
-        unsafe {
-            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
-                sqlite3_vec_init as *const (),
-            )));
-        }
+    /// Project root the stored file paths are relative to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
 
+    pub fn new(path: &Path, root: &Path) -> Result<Self> {
+        register_vec_extension();
         let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
 
         // Use query_row for PRAGMA that returns results.
         let _journal_mode: String =
@@ -38,28 +321,239 @@ impl Database {
                 start_line INTEGER NOT NULL,
                 end_line INTEGER NOT NULL,
                 text TEXT NOT NULL,
+                comments TEXT,
+                language TEXT,
+                category TEXT,
+                author TEXT,
+                committed_at INTEGER,
                 hash INTEGER NOT NULL,
                 created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at INTEGER,
+                handle TEXT,
                 UNIQUE(file_path, start_line, end_line, hash)
             );
 
             CREATE INDEX IF NOT EXISTS idx_file_path ON chunks(file_path);
             CREATE INDEX IF NOT EXISTS idx_chunk_index ON chunks(chunk_index);
+            CREATE INDEX IF NOT EXISTS idx_hash ON chunks(hash);
+
+            CREATE TABLE IF NOT EXISTS files (
+                file_path TEXT PRIMARY KEY,
+                content_hash INTEGER NOT NULL,
+                modified_at INTEGER,
+                indexed_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS symbols (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file_path TEXT NOT NULL,
+                name TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                line INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_symbols_name ON symbols(name);
+            CREATE INDEX IF NOT EXISTS idx_symbols_file_path ON symbols(file_path);
+
+            CREATE TABLE IF NOT EXISTS metadata (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS evicted_files (
+                file_path TEXT PRIMARY KEY,
+                evicted_at INTEGER NOT NULL
+            );
             "#,
         )?;
 
-        // Create vector table with dimensions (1024 is the dimension of our embeddings)
-        conn.execute(
-            "CREATE VIRTUAL TABLE IF NOT EXISTS chunks_vec USING vec0(
-            rowid INTEGER PRIMARY KEY,
-            embedding FLOAT[1024]
-            )",
-            [],
+        // Databases created before a column existed need it added in place
+        add_column_if_missing(&conn, "chunks", "comments", "TEXT")?;
+        add_column_if_missing(&conn, "chunks", "language", "TEXT")?;
+        add_column_if_missing(&conn, "chunks", "category", "TEXT")?;
+        add_column_if_missing(&conn, "chunks", "author", "TEXT")?;
+        add_column_if_missing(&conn, "chunks", "committed_at", "INTEGER")?;
+        add_column_if_missing(&conn, "chunks", "updated_at", "INTEGER")?;
+        add_column_if_missing(&conn, "chunks", "handle", "TEXT")?;
+        add_column_if_missing(&conn, "files", "modified_at", "INTEGER")?;
+        add_column_if_missing(&conn, "files", "last_matched_at", "INTEGER")?;
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_language ON chunks(language);
+             CREATE INDEX IF NOT EXISTS idx_category ON chunks(category);
+             CREATE INDEX IF NOT EXISTS idx_handle ON chunks(handle);",
+        )?;
+        migrate(&conn, root)?;
+        reset_foreign_content_hashes(&conn)?;
+
+        create_vector_table(
+            &conn,
+            VectorStorage::default(),
+            constants::EMBEDDING_DIMENSIONS,
         )?;
+        let (storage, dimensions) = vector_declaration(&conn)?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            root: root.to_path_buf(),
+            categories: PathCategories::default(),
+            dimensions,
+            storage,
+            recorded_matches: Mutex::new(HashMap::new()),
+        })
     }
 
+    /// Open an existing index without ever writing to it (no schema setup or migrations)
+    ///
+    /// Reading a WAL database still needs its `-shm` file, which can't be created on a
+    /// read-only volume. If the index directory isn't writable, the database is opened as
+    /// immutable instead: SQLite then skips locking and the WAL, which is safe as nothing
+    /// can write there either.
+    pub fn open_read_only(path: &Path, root: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!("No index at {} (opened read-only)", path.display());
+        }
+
+        register_vec_extension();
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = if path.parent().is_some_and(is_writable) {
+            Connection::open_with_flags(path, flags)?
+        } else {
+            debug!(
+                "{} is on a read-only directory, opening it immutable",
+                path.display()
+            );
+            Connection::open_with_flags(immutable_uri(path), flags | OpenFlags::SQLITE_OPEN_URI)?
+        };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < SCHEMA_VERSION {
+            bail!(
+                "Index at {} has schema version {} but {} is required; \
+                 open it once without --read-only to migrate it",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            );
+        }
+
+        let (storage, dimensions) = vector_declaration(&conn)?;
+        Ok(Self {
+            conn,
+            root: root.to_path_buf(),
+            categories: PathCategories::default(),
+            dimensions,
+            storage,
+            recorded_matches: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fail with an actionable error if the vector table holds vectors of another length
+    /// than `dimensions`, e.g. after switching embedding models
+    fn check_dimensions(&self, dimensions: usize) -> Result<()> {
+        match self.dimensions {
+            Some(stored) if stored != dimensions => bail!(dimension_mismatch(stored, dimensions)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Empty the index and recreate the vector table for `dimensions`-dimensional embeddings
+    pub fn rebuild_vector_table(
+        &mut self,
+        storage: VectorStorage,
+        dimensions: usize,
+    ) -> Result<()> {
+        self.clear_all()?;
+        self.recreate_vector_table(storage, dimensions)?;
+        info!(
+            "Recreated the vector table for {}-dimensional embeddings",
+            dimensions
+        );
+        Ok(())
+    }
+
+    /// Store `dimensions`-dimensional vectors with `storage` from now on
+    ///
+    /// Only an empty index is converted; a filled one keeps its vectors until it is rebuilt,
+    /// with a warning.
+    pub fn set_vector_layout(&mut self, storage: VectorStorage, dimensions: usize) -> Result<()> {
+        if storage == self.storage && Some(dimensions) == self.dimensions {
+            return Ok(());
+        }
+        if self.count_chunks()? > 0 {
+            match self.dimensions {
+                Some(stored) if stored != dimensions => {
+                    warn!("{}", dimension_mismatch(stored, dimensions))
+                }
+                _ => warn!(
+                    "The index stores {} vectors but index.vector_storage is '{}'; \
+                     run `ragrep index --full` to convert it",
+                    self.storage.name(),
+                    storage.name()
+                ),
+            }
+            return Ok(());
+        }
+        self.recreate_vector_table(storage, dimensions)?;
+        debug!(
+            "Storing {}-dimensional {} vectors",
+            dimensions,
+            storage.name()
+        );
+        Ok(())
+    }
+
+    /// Precision of the stored vectors
+    pub fn vector_storage(&self) -> VectorStorage {
+        self.storage
+    }
+
+    /// Length of the stored vectors, once the vector table exists
+    pub fn dimensions(&self) -> Option<usize> {
+        self.dimensions
+    }
+
+    fn recreate_vector_table(&mut self, storage: VectorStorage, dimensions: usize) -> Result<()> {
+        // Cached statements refer to the old table
+        self.conn.flush_prepared_statement_cache();
+        self.conn.execute("DROP TABLE IF EXISTS chunks_vec", [])?;
+        create_vector_table(&self.conn, storage, dimensions)?;
+        self.dimensions = Some(dimensions);
+        self.storage = storage;
+        Ok(())
+    }
+
+    /// Use the configured category patterns for chunks saved from now on
+    pub fn set_categories(&mut self, config: &CategoriesConfig) -> Result<()> {
+        self.categories = PathCategories::new(config)?;
+        Ok(())
+    }
+
+    /// Path as stored in the database: relative to the index root when inside it
+    fn stored_path(&self, file_path: &str) -> String {
+        Path::new(file_path)
+            .strip_prefix(&self.root)
+            .map(|relative| relative.to_string_lossy().to_string())
+            .unwrap_or_else(|_| file_path.to_string())
+    }
+
+    /// Absolute path of a file path read back from the database
+    pub fn resolve_path(&self, stored_path: &str) -> PathBuf {
+        self.root.join(stored_path)
+    }
+
+    /// Pretend the chunks of `file_path` were indexed at `updated_at`
+    #[cfg(test)]
+    pub fn backdate_chunks(&self, file_path: &str, updated_at: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE chunks SET updated_at = ?1 WHERE file_path = ?2",
+            params![updated_at, self.stored_path(file_path)],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(test)]
     pub fn save_chunk(
         &mut self,
         file_path: &str,
@@ -69,107 +563,368 @@ impl Database {
         start_line: usize,
         end_line: usize,
         text: &str,
+        comments: &str,
         chunk_hash: u64,
         embedding: &[f32],
     ) -> Result<()> {
-        // Start a transaction to ensure both inserts succeed or fail together.
-        let tx = self.conn.transaction()?;
-
-        // Insert metadata into the chunks table.
-        let rows = tx.execute(
-            r#"
-            INSERT OR IGNORE INTO chunks (
-                file_path, chunk_index, node_type, node_name,
-                start_line, end_line, text, hash
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
-            "#,
-            (
-                file_path,
-                chunk_index,
-                node_type,
-                node_name,
-                start_line as i32,
-                end_line as i32,
-                text,
-                chunk_hash as i64,
-            ),
-        )?;
+        self.save_chunks_batch(&[ChunkRecord {
+            file_path,
+            chunk_index,
+            node_type,
+            node_name,
+            start_line,
+            end_line,
+            text,
+            comments,
+            language: None,
+            author: None,
+            committed_at: None,
+            hash: chunk_hash,
+            embedding: embedding.to_vec(),
+            updated_at: None,
+        }])
+    }
 
-        // Insert into chunks_vec only if a new row was added.
-        if rows > 0 {
-            let last_row_id = tx.last_insert_rowid();
-            tx.execute(
-                r#"
-                INSERT OR IGNORE INTO chunks_vec (rowid, embedding) 
-                VALUES (?1, ?2)
-                "#,
-                (last_row_id, embedding.as_bytes()),
-            )?;
+    /// Save chunks and their embeddings in a single transaction
+    ///
+    /// Much faster than saving chunk by chunk, as SQLite syncs once per transaction. A chunk
+    /// whose content is already stored shares the existing vector.
+    pub fn save_chunks_batch(&mut self, records: &[ChunkRecord]) -> Result<()> {
+        for record in records {
+            self.check_dimensions(record.embedding.len())?;
         }
 
-        tx.commit()?;
-        Ok(())
+        let rows: Vec<(String, String, &'static str)> = records
+            .iter()
+            .map(|record| {
+                let language = match record.language {
+                    Some(language) => language.to_string(),
+                    None => chunker::language_for_path(Path::new(record.file_path)),
+                };
+                let file_path = self.stored_path(record.file_path);
+                let category = self.categories.categorize(Path::new(&file_path));
+                (file_path, language, category)
+            })
+            .collect();
+
+        retry_busy(|| {
+            // Chunks and vectors are written together or not at all
+            let tx = self.conn.transaction()?;
+            {
+                let mut insert_chunk = tx.prepare_cached(
+                    r#"
+                    INSERT OR IGNORE INTO chunks (
+                        file_path, chunk_index, node_type, node_name,
+                        start_line, end_line, text, comments, language, category,
+                        author, committed_at, hash, handle, updated_at
+                    ) VALUES (
+                        ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                        COALESCE(?15, CAST(strftime('%s', 'now') AS INTEGER))
+                    )
+                    "#,
+                )?;
+                let mut has_vector =
+                    tx.prepare_cached("SELECT 1 FROM chunks_vec WHERE rowid = ?1")?;
+                let mut insert_vector = tx.prepare_cached(&format!(
+                    "INSERT INTO chunks_vec (rowid, embedding) VALUES (?1, {})",
+                    vector_param(self.storage, 2)
+                ))?;
+
+                for (record, (file_path, language, category)) in records.iter().zip(&rows) {
+                    insert_chunk.execute((
+                        file_path,
+                        record.chunk_index,
+                        record.node_type,
+                        record.node_name,
+                        record.start_line as i32,
+                        record.end_line as i32,
+                        record.text,
+                        record.comments,
+                        language,
+                        category,
+                        record.author,
+                        record.committed_at,
+                        record.hash as i64,
+                        chunk_handle(file_path, record.hash as i64),
+                        record.updated_at,
+                    ))?;
+
+                    let hash = record.hash as i64;
+                    if !has_vector.exists([hash])? {
+                        let vector = encode_vector(self.storage, &record.embedding);
+                        insert_vector.execute((hash, vector))?;
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
     }
 
+    /// Find the `limit` chunks nearest to `query_embedding`
+    ///
+    /// Only chunks matching `filter` are considered; the filter is applied before ranking so
+    /// it never eats into `limit`.
+    ///
+    /// With quantized vectors, more candidates are found by their quantized distance and
+    /// then reordered by the distance of their decoded vectors to the unquantized query.
+    /// Only the query is exact there: the stored vectors stay lossy.
     pub fn find_similar_chunks(
         &self,
         query_embedding: &[f32],
         limit: usize,
-    ) -> Result<Vec<(String, String, i32, i32, String, f32)>> {
-        let mut stmt = self.conn.prepare(
+        filter: &ChunkFilter,
+    ) -> Result<Vec<ChunkMatch>> {
+        self.check_dimensions(query_embedding.len())?;
+        let candidates = match self.storage {
+            VectorStorage::Float32 => limit,
+            VectorStorage::Int8 | VectorStorage::Binary => limit * RESCORE_OVERSAMPLING,
+        };
+        let query_vector = encode_vector(self.storage, query_embedding);
+        let chunks = if filter.is_empty() {
+            self.find_nearest_chunks(&query_vector, candidates)?
+        } else {
+            self.find_similar_chunks_filtered(&query_vector, candidates, filter)?
+        };
+
+        match self.storage {
+            VectorStorage::Float32 => Ok(chunks),
+            VectorStorage::Int8 | VectorStorage::Binary => {
+                self.rescore(query_embedding, chunks, limit)
+            }
+        }
+    }
+
+    /// K-nearest-neighbor query over the whole vector table
+    ///
+    /// A vector shared by several chunks yields each of them, up to `limit` chunks in all.
+    fn find_nearest_chunks(&self, query_vector: &[u8], limit: usize) -> Result<Vec<ChunkMatch>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
             r#"
-            SELECT c.text, c.file_path, c.start_line, c.end_line, c.node_type, distance
+            SELECT c.id, c.text, c.file_path, c.start_line, c.end_line, c.node_type,
+                   c.node_name, c.hash, distance
             FROM chunks_vec
-            JOIN chunks c ON c.id = chunks_vec.rowid
-            WHERE embedding MATCH ?1 AND k = ?
-            ORDER BY distance
+            JOIN chunks c ON c.hash = chunks_vec.rowid
+            WHERE embedding MATCH {} AND k = ?
+            ORDER BY distance, c.id
             "#,
+            vector_param(self.storage, 1)
+        ))?;
+
+        let mut chunks = stmt
+            .query_map(params![query_vector, limit], |row| {
+                ChunkMatch::from_row(row, None)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        chunks.truncate(limit);
+
+        Ok(chunks)
+    }
+
+    /// Rank quantized candidates by the distance of their decoded vectors to the unquantized
+    /// query, keeping the `limit` closest
+    ///
+    /// This refines the ranking of binary vectors most, whose search compares bits only;
+    /// it recovers none of the precision lost when the vectors were quantized.
+    fn rescore(
+        &self,
+        query_embedding: &[f32],
+        mut chunks: Vec<ChunkMatch>,
+        limit: usize,
+    ) -> Result<Vec<ChunkMatch>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT v.embedding FROM chunks c JOIN chunks_vec v ON v.rowid = c.hash
+             WHERE c.id = ?1",
         )?;
+        for chunk in &mut chunks {
+            let bytes: Vec<u8> = stmt.query_row([chunk.id], |row| row.get(0))?;
+            let vector = decode_vector(self.storage, &bytes);
+            chunk.distance = query_embedding
+                .iter()
+                .zip(&vector)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt();
+        }
+        chunks.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        chunks.truncate(limit);
+        Ok(chunks)
+    }
+
+    /// Exact nearest-neighbor scan restricted to the chunks matching `filter`
+    fn find_similar_chunks_filtered(
+        &self,
+        query_vector: &[u8],
+        limit: usize,
+        filter: &ChunkFilter,
+    ) -> Result<Vec<ChunkMatch>> {
+        let mut values: Vec<&dyn ToSql> = vec![&query_vector, &limit];
+        let conditions = filter_conditions(filter, &mut values);
+
+        // Binary vectors are compared bit by bit
+        let distance = match self.storage {
+            VectorStorage::Float32 | VectorStorage::Int8 => "vec_distance_l2",
+            VectorStorage::Binary => "vec_distance_hamming",
+        };
+        let mut stmt = self.conn.prepare_cached(&format!(
+            r#"
+            SELECT c.id, c.text, c.file_path, c.start_line, c.end_line, c.node_type,
+                   c.node_name, c.hash, {}(v.embedding, {}) AS distance
+            FROM chunks c
+            JOIN chunks_vec v ON v.rowid = c.hash
+            WHERE {}
+            ORDER BY distance
+            LIMIT ?2
+            "#,
+            distance,
+            vector_param(self.storage, 1),
+            conditions.join(" AND ")
+        ))?;
 
         let chunks = stmt
-            .query_map(params![query_embedding.as_bytes(), limit], |row| {
-                Ok((
-                    row.get(0)?, // text
-                    row.get(1)?, // file_path
-                    row.get(2)?, // start_line
-                    row.get(3)?, // end_line
-                    row.get(4)?, // node_type
-                    row.get(5)?, // distance
-                ))
+            .query_map(values.as_slice(), |row| ChunkMatch::from_row(row, None))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(chunks)
+    }
+
+    /// The first `limit` chunks matching `filter` whose text contains any of `needles` (all
+    /// of them with `match_all`) exactly, case-sensitively, in file and line order
+    pub fn find_chunks_containing(
+        &self,
+        needles: &[&str],
+        match_all: bool,
+        limit: usize,
+        filter: &ChunkFilter,
+    ) -> Result<Vec<ChunkMatch>> {
+        let limit = limit as i64;
+        let mut values: Vec<&dyn ToSql> = vec![&limit];
+        let contains: Vec<String> = needles
+            .iter()
+            .enumerate()
+            .map(|(i, _)| format!("instr(c.text, ?{}) > 0", i + 2))
+            .collect();
+        values.extend(needles.iter().map(|needle| needle as &dyn ToSql));
+        let mut conditions = vec![format!(
+            "({})",
+            contains.join(if match_all { " AND " } else { " OR " })
+        )];
+        conditions.extend(filter_conditions(filter, &mut values));
+
+        let mut stmt = self.conn.prepare_cached(&format!(
+            r#"
+            SELECT c.id, c.text, c.file_path, c.start_line, c.end_line, c.node_type,
+                   c.node_name, c.hash
+            FROM chunks c
+            WHERE {}
+            ORDER BY c.file_path, c.start_line
+            LIMIT ?1
+            "#,
+            conditions.join(" AND ")
+        ))?;
+
+        let chunks = stmt
+            .query_map(values.as_slice(), |row| {
+                ChunkMatch::from_row(row, Some(0.0))
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(chunks)
     }
 
+    /// Look up the chunks for `(chunk id, distance)` hits from an external vector index,
+    /// keeping the order of `hits`
+    pub fn get_chunks_by_ids(&self, hits: &[(i64, f32)]) -> Result<Vec<ChunkMatch>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, text, file_path, start_line, end_line, node_type, node_name, hash
+             FROM chunks WHERE id = ?1",
+        )?;
+
+        let mut chunks = Vec::with_capacity(hits.len());
+        for (id, distance) in hits {
+            let chunk = stmt
+                .query_row([id], |row| ChunkMatch::from_row(row, Some(*distance)))
+                .optional()?;
+            // Skip ids deleted since the vector index was last updated
+            chunks.extend(chunk);
+        }
+
+        Ok(chunks)
+    }
+
+    /// The first `limit` chunks of kind `kind` (`node_type`), in file and line order
+    pub fn find_chunks_of_kind(&self, kind: &str, limit: usize) -> Result<Vec<ChunkMatch>> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            SELECT id, text, file_path, start_line, end_line, node_type, node_name, hash
+            FROM chunks
+            WHERE node_type = ?1
+            ORDER BY file_path, start_line
+            LIMIT ?2
+            "#,
+        )?;
+
+        let chunks = stmt
+            .query_map(params![kind, limit as i64], |row| {
+                ChunkMatch::from_row(row, Some(0.0))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chunks)
+    }
+
+    /// Get every `(chunk id, embedding)` pair, or only those of `file_path` when given
+    ///
+    /// Quantized vectors come back decoded, so only approximately as they were embedded.
+    pub fn get_embeddings(&self, file_path: Option<&str>) -> Result<Vec<(i64, Vec<f32>)>> {
+        let decode = |row: &rusqlite::Row| -> rusqlite::Result<(i64, Vec<f32>)> {
+            let embedding_bytes: Vec<u8> = row.get(1)?;
+            Ok((row.get(0)?, decode_vector(self.storage, &embedding_bytes)))
+        };
+
+        let embeddings = match file_path {
+            Some(file_path) => self
+                .conn
+                .prepare_cached(
+                    r#"
+                    SELECT c.id, v.embedding
+                    FROM chunks c
+                    JOIN chunks_vec v ON v.rowid = c.hash
+                    WHERE c.file_path = ?1
+                    "#,
+                )?
+                .query_map([self.stored_path(file_path)], decode)?
+                .collect::<std::result::Result<Vec<_>, _>>()?,
+            None => self
+                .conn
+                .prepare_cached(
+                    "SELECT c.id, v.embedding FROM chunks c JOIN chunks_vec v ON v.rowid = c.hash",
+                )?
+                .query_map([], decode)?
+                .collect::<std::result::Result<Vec<_>, _>>()?,
+        };
+
+        Ok(embeddings)
+    }
+
     /// Get all chunks for a file with their hashes and embeddings (for reuse)
     pub fn get_chunks_with_embeddings(&self, file_path: &str) -> Result<HashMap<i64, Vec<f32>>> {
-        let mut stmt = self.conn.prepare(
+        let file_path = self.stored_path(file_path);
+        let mut stmt = self.conn.prepare_cached(
             r#"
-            SELECT c.hash, v.embedding
+            SELECT DISTINCT c.hash, v.embedding
             FROM chunks c
-            JOIN chunks_vec v ON v.rowid = c.id
+            JOIN chunks_vec v ON v.rowid = c.hash
             WHERE c.file_path = ?1
             "#,
         )?;
 
         let rows: Vec<(i64, Vec<u8>)> = stmt
-            .query_map([file_path], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .query_map([&file_path], |row| Ok((row.get(0)?, row.get(1)?)))?
             .collect::<std::result::Result<Vec<_>, _>>()?;
 
         let mut cache = HashMap::new();
         for (hash, embedding_bytes) in rows {
-            // Convert bytes back to f32 array
-            // Each f32 is 4 bytes, so we need to convert in chunks of 4
-            let embedding: Vec<f32> = embedding_bytes
-                .chunks_exact(4)
-                .map(|chunk| {
-                    let bytes: [u8; 4] = [chunk[0], chunk[1], chunk[2], chunk[3]];
-                    f32::from_le_bytes(bytes)
-                })
-                .collect();
-
-            cache.insert(hash, embedding);
+            cache.insert(hash, decode_vector(self.storage, &embedding_bytes));
         }
 
         debug!(
@@ -180,63 +935,2260 @@ impl Database {
         Ok(cache)
     }
 
+    /// The stored embedding of chunks with content hash `hash`, wherever they are, so a
+    /// copy of indexed content needs no embedding of its own
+    ///
+    /// A quantized vector comes back decoded; saving it again quantizes it to the same bytes.
+    pub fn get_embedding(&self, hash: u64) -> Result<Option<Vec<f32>>> {
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached("SELECT embedding FROM chunks_vec WHERE rowid = ?1")?
+            .query_row([hash as i64], |row| row.get(0))
+            .optional()?;
+        Ok(bytes.map(|bytes| decode_vector(self.storage, &bytes)))
+    }
+
     /// Delete all chunks for a specific file
     pub fn delete_file(&mut self, file_path: &str) -> Result<()> {
-        // Get all row IDs for this file first
-        let row_ids: Vec<i64> = {
-            let mut stmt = self
-                .conn
-                .prepare("SELECT id FROM chunks WHERE file_path = ?1")?;
-            let result = stmt
-                .query_map([file_path], |row| row.get(0))?
-                .collect::<std::result::Result<Vec<_>, _>>()?;
-            drop(stmt);
-            result
-        };
+        self.delete_files(&[file_path])
+    }
 
-        // Now perform deletions in a transaction
-        {
-            let tx = self.conn.transaction()?;
+    /// Delete the chunks, vectors, symbols and file records of many files in one
+    /// transaction, e.g. the thousands of files a branch switch can remove
+    pub fn delete_files<S: AsRef<str>>(&mut self, file_paths: &[S]) -> Result<()> {
+        let file_paths: Vec<String> = file_paths
+            .iter()
+            .map(|file_path| self.stored_path(file_path.as_ref()))
+            .collect();
 
-            // Delete from vector table using prepared statement
+        let deleted = retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                "CREATE TEMP TABLE IF NOT EXISTS deleted_paths (file_path TEXT PRIMARY KEY)",
+                [],
+            )?;
+            tx.execute("DELETE FROM deleted_paths", [])?;
             {
-                let mut delete_vec_stmt = tx.prepare("DELETE FROM chunks_vec WHERE rowid = ?1")?;
-                for row_id in &row_ids {
-                    delete_vec_stmt.execute([row_id])?;
+                let mut insert = tx.prepare_cached(
+                    "INSERT OR IGNORE INTO deleted_paths (file_path) VALUES (?1)",
+                )?;
+                for file_path in &file_paths {
+                    insert.execute([file_path])?;
                 }
             }
 
-            // Delete from chunks table
-            {
-                let mut delete_chunks_stmt =
-                    tx.prepare("DELETE FROM chunks WHERE file_path = ?1")?;
-                delete_chunks_stmt.execute([file_path])?;
+            tx.execute(
+                "CREATE TEMP TABLE IF NOT EXISTS orphaned_hashes (hash INTEGER PRIMARY KEY)",
+                [],
+            )?;
+            tx.execute("DELETE FROM orphaned_hashes", [])?;
+            tx.execute(
+                "INSERT OR IGNORE INTO orphaned_hashes (hash)
+                 SELECT hash FROM chunks WHERE file_path IN (SELECT file_path FROM deleted_paths)",
+                [],
+            )?;
+
+            let deleted = tx.execute(
+                "DELETE FROM chunks WHERE file_path IN (SELECT file_path FROM deleted_paths)",
+                [],
+            )?;
+
+            // Vectors still shared with chunks of other files stay
+            tx.execute(
+                "DELETE FROM orphaned_hashes WHERE hash IN (SELECT hash FROM chunks)",
+                [],
+            )?;
+            // vec0 answers `rowid IN (subquery)` by scanning the whole vector table: worth it
+            // for many files, while a few are faster deleted one rowid at a time
+            if file_paths.len() >= VECTOR_SCAN_MIN_FILES {
+                tx.execute(
+                    "DELETE FROM chunks_vec WHERE rowid IN (SELECT hash FROM orphaned_hashes)",
+                    [],
+                )?;
+            } else {
+                let hashes = tx
+                    .prepare_cached("SELECT hash FROM orphaned_hashes")?
+                    .query_map([], |row| row.get::<_, i64>(0))?
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let mut delete_vector =
+                    tx.prepare_cached("DELETE FROM chunks_vec WHERE rowid = ?1")?;
+                for hash in hashes {
+                    delete_vector.execute([hash])?;
+                }
             }
 
+            tx.execute(
+                "DELETE FROM files WHERE file_path IN (SELECT file_path FROM deleted_paths)",
+                [],
+            )?;
+            tx.execute(
+                "DELETE FROM symbols WHERE file_path IN (SELECT file_path FROM deleted_paths)",
+                [],
+            )?;
             tx.commit()?;
-        }
-
-        debug!("Deleted {} chunks for file: {}", row_ids.len(), file_path);
+            Ok(deleted)
+        })?;
 
+        match file_paths.as_slice() {
+            [file_path] => debug!("Deleted {} chunks for file: {}", deleted, file_path),
+            _ => debug!("Deleted {} chunks of {} files", deleted, file_paths.len()),
+        }
         Ok(())
     }
 
-    /// Get all indexed file paths
+    /// Get all indexed file paths (resolved to absolute paths)
     pub fn get_indexed_files(&self) -> Result<Vec<String>> {
-        let mut stmt = self.conn.prepare("SELECT DISTINCT file_path FROM chunks")?;
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT DISTINCT file_path FROM chunks")?;
         let files: Vec<String> = stmt
-            .query_map([], |row| row.get(0))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|row| row.map(|path| self.resolve_path(&path).to_string_lossy().to_string()))
             .collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(files)
     }
 
-    /// Clear all chunks from the database
-    pub fn clear_all(&mut self) -> Result<()> {
-        let tx = self.conn.transaction()?;
-        tx.execute("DELETE FROM chunks_vec", [])?;
-        tx.execute("DELETE FROM chunks", [])?;
-        tx.commit()?;
-        debug!("Cleared all chunks from database");
-        Ok(())
+    /// Every indexed file (path as stored) with its content hash and number of chunks,
+    /// sorted by path
+    pub fn file_chunk_counts(&self) -> Result<Vec<(String, u64, usize)>> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            SELECT f.file_path, f.content_hash,
+                   (SELECT COUNT(*) FROM chunks c WHERE c.file_path = f.file_path)
+            FROM files f
+            ORDER BY f.file_path
+            "#,
+        )?;
+        let files = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, i64>(1)? as u64,
+                    row.get::<_, i64>(2)? as usize,
+                ))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(files)
+    }
+
+    /// Number of chunks stored in the index
+    pub fn count_chunks(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Number of indexed files, i.e. rows of the files table
+    pub fn count_files(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Number of chunks that have a vector, i.e. those [`Database::for_each_chunk`] visits
+    pub fn count_chunks_with_vectors(&self) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM chunks c JOIN chunks_vec v ON v.rowid = c.hash",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Number of distinct vectors stored, at most one per chunk content
+    pub fn count_vectors(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM chunks_vec", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Visit every stored chunk with its embedding, in insertion order
+    pub fn for_each_chunk(&self, mut f: impl FnMut(StoredChunk) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            SELECT c.file_path, c.chunk_index, c.node_type, c.node_name, c.start_line,
+                   c.end_line, c.text, c.comments, c.hash, v.embedding, c.language,
+                   c.category, c.author, c.committed_at, c.updated_at
+            FROM chunks c
+            JOIN chunks_vec v ON v.rowid = c.hash
+            ORDER BY c.id
+            "#,
+        )?;
+
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let embedding_bytes: Vec<u8> = row.get(9)?;
+            f(StoredChunk {
+                file_path: row.get(0)?,
+                chunk_index: row.get(1)?,
+                node_type: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                node_name: row.get(3)?,
+                start_line: row.get(4)?,
+                end_line: row.get(5)?,
+                text: row.get(6)?,
+                comments: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                language: row.get(10)?,
+                category: row.get(11)?,
+                author: row.get(12)?,
+                committed_at: row.get(13)?,
+                hash: row.get(8)?,
+                embedding: decode_vector(self.storage, &embedding_bytes),
+                updated_at: row.get(14)?,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Call `f` with every row of the files table
+    pub fn for_each_file(&self, mut f: impl FnMut(StoredFile) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file_path, content_hash, modified_at FROM files ORDER BY file_path",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(StoredFile {
+                file_path: row.get(0)?,
+                content_hash: row.get(1)?,
+                modified_at: row.get(2)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` with every recorded definition
+    pub fn for_each_symbol(&self, mut f: impl FnMut(StoredSymbol) -> Result<()>) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT file_path, name, kind, line FROM symbols ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(StoredSymbol {
+                file_path: row.get(0)?,
+                name: row.get(1)?,
+                kind: row.get(2)?,
+                line: row.get(3)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Replace everything in the index with the rows `fill` writes, in one transaction
+    ///
+    /// Either the new contents are committed as a whole, or (when `fill` or a write fails)
+    /// the index is left as it was. `fill` runs again if the database is locked, so it must
+    /// be able to start over.
+    pub fn replace_contents(
+        &mut self,
+        mut fill: impl FnMut(&mut ContentsWriter) -> Result<()>,
+    ) -> Result<()> {
+        let Self {
+            conn,
+            categories,
+            storage,
+            ..
+        } = self;
+        retry_busy(|| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM chunks_vec", [])?;
+            tx.execute("DELETE FROM chunks", [])?;
+            tx.execute("DELETE FROM files", [])?;
+            tx.execute("DELETE FROM symbols", [])?;
+            tx.execute("DELETE FROM evicted_files", [])?;
+            fill(&mut ContentsWriter {
+                tx: &tx,
+                categories,
+                storage: *storage,
+            })?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// The chunk with `handle` (see [`chunk_handle`]), if one still exists
+    ///
+    /// When a file holds the same chunk twice, the first of them is returned.
+    pub fn get_chunk(&self, handle: &str) -> Result<Option<ChunkDetails>> {
+        let id: Option<i64> = self
+            .conn
+            .prepare_cached(
+                "SELECT id FROM chunks WHERE handle = ?1 ORDER BY file_path, start_line LIMIT 1",
+            )?
+            .query_row([handle], |row| row.get(0))
+            .optional()?;
+        match id {
+            Some(id) => self.get_chunk_by_id(id),
+            None => Ok(None),
+        }
+    }
+
+    /// The chunk with row id `id`, if it still exists
+    pub fn get_chunk_by_id(&self, id: i64) -> Result<Option<ChunkDetails>> {
+        let chunk = self
+            .conn
+            .query_row(
+                "SELECT id, file_path, chunk_index, node_type, node_name, start_line, end_line,
+                        text, comments, language, category, author, committed_at, hash,
+                        created_at
+                 FROM chunks WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(ChunkDetails {
+                        id: row.get(0)?,
+                        file_path: row.get(1)?,
+                        chunk_index: row.get(2)?,
+                        node_type: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                        node_name: row.get(4)?,
+                        start_line: row.get(5)?,
+                        end_line: row.get(6)?,
+                        text: row.get(7)?,
+                        comments: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                        language: row.get(9)?,
+                        category: row.get(10)?,
+                        author: row.get(11)?,
+                        committed_at: row.get(12)?,
+                        hash: row.get(13)?,
+                        created_at: row.get(14)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(chunk)
+    }
+
+    /// Find chunks without a vector and vectors without a chunk
+    pub fn verify(&self) -> Result<IntegrityReport> {
+        let chunks: Vec<(i64, i64)> = self
+            .conn
+            .prepare("SELECT id, hash FROM chunks")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let vector_hashes: HashSet<i64> = self
+            .conn
+            .prepare("SELECT rowid FROM chunks_vec")?
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<HashSet<_>, _>>()?;
+        let chunk_hashes: HashSet<i64> = chunks.iter().map(|(_, hash)| *hash).collect();
+
+        let mut report = IntegrityReport {
+            chunks_without_vectors: chunks
+                .iter()
+                .filter(|(_, hash)| !vector_hashes.contains(hash))
+                .map(|(id, _)| *id)
+                .collect(),
+            vectors_without_chunks: vector_hashes.difference(&chunk_hashes).copied().collect(),
+        };
+        report.chunks_without_vectors.sort_unstable();
+        report.vectors_without_chunks.sort_unstable();
+        Ok(report)
+    }
+
+    /// Store the embeddings of existing chunks that have none, by chunk id
+    pub fn save_vectors(&mut self, vectors: &[(i64, Vec<f32>)]) -> Result<()> {
+        for (_, embedding) in vectors {
+            self.check_dimensions(embedding.len())?;
+        }
+
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            {
+                let mut chunk_hash = tx.prepare_cached(
+                    "SELECT hash FROM chunks c
+                     WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM chunks_vec WHERE rowid = c.hash)",
+                )?;
+                let mut insert_vector = tx.prepare_cached(&format!(
+                    "INSERT INTO chunks_vec (rowid, embedding) VALUES (?1, {})",
+                    vector_param(self.storage, 2)
+                ))?;
+                for (id, embedding) in vectors {
+                    let hash: Option<i64> =
+                        chunk_hash.query_row([id], |row| row.get(0)).optional()?;
+                    if let Some(hash) = hash {
+                        insert_vector.execute((hash, encode_vector(self.storage, embedding)))?;
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Delete vectors by rowid (content hash), e.g. those no chunk has anymore
+    pub fn delete_vectors(&mut self, ids: &[i64]) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            {
+                let mut delete_vector =
+                    tx.prepare_cached("DELETE FROM chunks_vec WHERE rowid = ?1")?;
+                for id in ids {
+                    delete_vector.execute([id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// When each chunk of `file_path` was indexed, by content hash
+    ///
+    /// Reindexing a file keeps these times for the chunks it still has, so only the chunks
+    /// that actually changed count as changed.
+    pub fn updated_times(&self, file_path: &str) -> Result<HashMap<u64, i64>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT hash, updated_at FROM chunks WHERE file_path = ?1 AND updated_at IS NOT NULL",
+        )?;
+        let times = stmt
+            .query_map([self.stored_path(file_path)], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(times)
+    }
+
+    /// Content hash recorded when `file_path` was last indexed
+    pub fn get_file_hash(&self, file_path: &str) -> Result<Option<u64>> {
+        let hash: Option<i64> = self
+            .conn
+            .prepare_cached("SELECT content_hash FROM files WHERE file_path = ?1")?
+            .query_row([self.stored_path(file_path)], |row| row.get(0))
+            .optional()?;
+        Ok(hash.map(|hash| hash as u64))
+    }
+
+    /// Record the content hash of a file whose chunks were just saved, along with its
+    /// modification time (for recency ranking)
+    ///
+    /// An evicted file that is indexed again is no longer evicted.
+    pub fn set_file_hash(&self, file_path: &str, content_hash: u64) -> Result<()> {
+        let modified_at = std::fs::metadata(self.resolve_path(file_path))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs() as i64);
+        let file_path = self.stored_path(file_path);
+        retry_busy(|| {
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO files (file_path, content_hash, modified_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(file_path) DO UPDATE SET content_hash = excluded.content_hash,
+                         modified_at = excluded.modified_at, indexed_at = CURRENT_TIMESTAMP",
+                )?
+                .execute(params![file_path, content_hash as i64, modified_at])?;
+            self.conn
+                .prepare_cached("DELETE FROM evicted_files WHERE file_path = ?1")?
+                .execute([&file_path])?;
+            Ok(())
+        })
+    }
+
+    /// Modification time (seconds since the Unix epoch) `file_path` had when it was indexed
+    pub fn get_file_modified(&self, file_path: &str) -> Result<Option<i64>> {
+        let modified: Option<Option<i64>> = self
+            .conn
+            .prepare_cached("SELECT modified_at FROM files WHERE file_path = ?1")?
+            .query_row([self.stored_path(file_path)], |row| row.get(0))
+            .optional()?;
+        Ok(modified.flatten())
+    }
+
+    /// Record that `file_paths` just had search results, for `least_recently_matched`
+    /// eviction
+    ///
+    /// Files recorded within the last [`MATCH_RECORD_INTERVAL`] are skipped and the rest are
+    /// written in one transaction, so searches rarely write at all. A busy index is not
+    /// waited for: a lost match time only makes eviction a little less accurate.
+    pub fn record_matches<S: AsRef<str>>(&self, file_paths: &[S]) -> Result<()> {
+        let now = Instant::now();
+        let mut recorded = self.recorded_matches.lock().unwrap();
+        let due: Vec<String> = file_paths
+            .iter()
+            .map(|file_path| self.stored_path(file_path.as_ref()))
+            .filter(|file_path| {
+                recorded
+                    .get(file_path)
+                    .is_none_or(|at| now.duration_since(*at) >= MATCH_RECORD_INTERVAL)
+            })
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut update = tx.prepare_cached(
+                "UPDATE files SET last_matched_at = CAST(strftime('%s', 'now') AS INTEGER)
+                 WHERE file_path = ?1",
+            )?;
+            for file_path in &due {
+                update.execute([file_path])?;
+            }
+        }
+        tx.commit()?;
+        for file_path in due {
+            recorded.insert(file_path, now);
+        }
+        Ok(())
+    }
+
+    /// Bytes of the database file in use, not counting pages freed by deletions
+    pub fn size_bytes(&self) -> Result<u64> {
+        let used_pages: i64 = self.conn.query_row(
+            "SELECT (SELECT page_count FROM pragma_page_count())
+                  - (SELECT freelist_count FROM pragma_freelist_count())",
+            [],
+            |row| row.get(0),
+        )?;
+        let page_size: i64 = self
+            .conn
+            .query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok((used_pages * page_size) as u64)
+    }
+
+    /// Bytes of the indexed data: the chunk, file and symbol rows and the stored vectors
+    ///
+    /// Unlike [`Database::size_bytes`], this drops as soon as rows are deleted; vec0 only
+    /// frees the pages of its vector chunks once they are empty, so the file's pages barely
+    /// shrink after an eviction.
+    pub fn data_bytes(&self) -> Result<u64> {
+        let rows: i64 = self.conn.query_row(
+            &format!(
+                "SELECT
+                   (SELECT COALESCE(SUM(length(file_path) + length(text)
+                        + COALESCE(length(comments), 0) + COALESCE(length(node_name), 0)
+                        + COALESCE(length(node_type), 0) + COALESCE(length(language), 0)
+                        + COALESCE(length(category), 0) + COALESCE(length(author), 0)
+                        + {0}), 0) FROM chunks)
+                 + (SELECT COALESCE(SUM(length(file_path) + {0}), 0) FROM files)
+                 + (SELECT COALESCE(SUM(length(file_path) + length(name) + length(kind)
+                        + {0}), 0) FROM symbols)",
+                ROW_OVERHEAD_BYTES
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        let dimensions = self.dimensions.unwrap_or(0);
+        let vector_bytes = match self.storage {
+            VectorStorage::Float32 => dimensions * 4,
+            VectorStorage::Int8 => dimensions,
+            VectorStorage::Binary => dimensions.div_ceil(8),
+        };
+        Ok(rows as u64 + (self.count_vectors()? * vector_bytes) as u64)
+    }
+
+    /// Indexed files (absolute paths) with their number of chunks, in the order `policy`
+    /// evicts them
+    pub fn eviction_order(&self, policy: EvictionPolicy) -> Result<Vec<(String, usize)>> {
+        // Files never matched (or indexed before matches were recorded) go first
+        let order = match policy {
+            EvictionPolicy::LeastRecentlyMatched => {
+                "COALESCE(f.last_matched_at, 0), COALESCE(f.modified_at, 0)"
+            }
+            EvictionPolicy::OldestModified => {
+                "COALESCE(f.modified_at, 0), COALESCE(f.last_matched_at, 0)"
+            }
+        };
+        let sql = format!(
+            "SELECT c.file_path, COUNT(*) FROM chunks c
+             LEFT JOIN files f ON f.file_path = c.file_path
+             GROUP BY c.file_path
+             ORDER BY {}, c.file_path",
+            order
+        );
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let files = stmt
+            .query_map([], |row| {
+                let path: String = row.get(0)?;
+                let chunks: i64 = row.get(1)?;
+                Ok((
+                    self.resolve_path(&path).to_string_lossy().to_string(),
+                    chunks as usize,
+                ))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(files)
+    }
+
+    /// Delete files to keep the index within its size budget, remembering them so
+    /// incremental indexing doesn't add them back until they change
+    pub fn evict_files<S: AsRef<str>>(&mut self, file_paths: &[S]) -> Result<()> {
+        self.delete_files(file_paths)?;
+        let file_paths: Vec<String> = file_paths
+            .iter()
+            .map(|file_path| self.stored_path(file_path.as_ref()))
+            .collect();
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT OR REPLACE INTO evicted_files (file_path, evicted_at)
+                     VALUES (?1, CAST(strftime('%s', 'now') AS INTEGER))",
+                )?;
+                for file_path in &file_paths {
+                    insert.execute([file_path])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Files evicted to keep the index within its size budget (absolute paths)
+    pub fn evicted_files(&self) -> Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT file_path FROM evicted_files")?;
+        let files = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|row| row.map(|path| self.resolve_path(&path).to_string_lossy().to_string()))
+            .collect::<std::result::Result<HashSet<_>, _>>()?;
+        Ok(files)
+    }
+
+    /// Replace the definitions recorded for `file_path`
+    pub fn save_symbols(&mut self, file_path: &str, symbols: &[Symbol]) -> Result<()> {
+        let file_path = self.stored_path(file_path);
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.prepare_cached("DELETE FROM symbols WHERE file_path = ?1")?
+                .execute([&file_path])?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT INTO symbols (file_path, name, kind, line) VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for symbol in symbols {
+                    insert.execute(params![
+                        file_path,
+                        symbol.name,
+                        symbol.kind,
+                        symbol.line as i64
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Look up definitions named `name`
+    ///
+    /// With `fuzzy`, any name containing `name` case-insensitively matches; exact and shorter
+    /// names come first.
+    pub fn find_symbols(&self, name: &str, fuzzy: bool, limit: usize) -> Result<Vec<SymbolMatch>> {
+        let sql = if fuzzy {
+            r#"
+            SELECT name, kind, file_path, line FROM symbols
+            WHERE instr(lower(name), lower(?1)) > 0
+            ORDER BY name = ?1 DESC, lower(name) = lower(?1) DESC, length(name), file_path, line
+            LIMIT ?2
+            "#
+        } else {
+            r#"
+            SELECT name, kind, file_path, line FROM symbols
+            WHERE name = ?1
+            ORDER BY file_path, line
+            LIMIT ?2
+            "#
+        };
+
+        let mut stmt = self.conn.prepare_cached(sql)?;
+        let symbols = stmt
+            .query_map(params![name, limit as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(symbols)
+    }
+
+    /// Definitions recorded for `file_path`, by line
+    pub fn file_symbols(&self, file_path: &str) -> Result<Vec<Symbol>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT name, kind, line FROM symbols WHERE file_path = ?1 ORDER BY line",
+        )?;
+        let symbols = stmt
+            .query_map([self.stored_path(file_path)], |row| {
+                Ok(Symbol {
+                    name: row.get(0)?,
+                    kind: row.get(1)?,
+                    line: row.get::<_, i64>(2)? as usize,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(symbols)
+    }
+
+    /// Number of definitions recorded in the index
+    pub fn count_symbols(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM symbols", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Read a value from the index metadata table
+    pub fn get_metadata(&self, key: &str) -> Result<Option<String>> {
+        let value = self
+            .conn
+            .query_row("SELECT value FROM metadata WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Write a value to the index metadata table
+    pub fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        retry_busy(|| {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+                [key, value],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Clear all chunks from the database
+    pub fn clear_all(&mut self) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.execute("DELETE FROM chunks_vec", [])?;
+            tx.execute("DELETE FROM chunks", [])?;
+            tx.execute("DELETE FROM files", [])?;
+            tx.execute("DELETE FROM symbols", [])?;
+            tx.execute("DELETE FROM evicted_files", [])?;
+            tx.commit()?;
+            Ok(())
+        })?;
+        debug!("Cleared all chunks from database");
+        Ok(())
+    }
+}
+
+/// Writes the rows of [`Database::replace_contents`], file paths as stored
+pub struct ContentsWriter<'a> {
+    tx: &'a rusqlite::Transaction<'a>,
+    categories: &'a PathCategories,
+    storage: VectorStorage,
+}
+
+impl ContentsWriter<'_> {
+    /// Add a chunk, and its vector unless a chunk of the same content brought it already
+    pub fn chunk(&mut self, chunk: &StoredChunk) -> Result<()> {
+        let language = match &chunk.language {
+            Some(language) => language.clone(),
+            None => chunker::language_for_path(Path::new(&chunk.file_path)),
+        };
+        let category = match &chunk.category {
+            Some(category) => category.as_str(),
+            None => self.categories.categorize(Path::new(&chunk.file_path)),
+        };
+        self.tx
+            .prepare_cached(
+                r#"
+                INSERT OR IGNORE INTO chunks (
+                    file_path, chunk_index, node_type, node_name,
+                    start_line, end_line, text, comments, language, category,
+                    author, committed_at, hash, handle, updated_at
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                    COALESCE(?15, CAST(strftime('%s', 'now') AS INTEGER))
+                )
+                "#,
+            )?
+            .execute(params![
+                chunk.file_path,
+                chunk.chunk_index,
+                chunk.node_type,
+                chunk.node_name,
+                chunk.start_line,
+                chunk.end_line,
+                chunk.text,
+                chunk.comments,
+                language,
+                category,
+                chunk.author,
+                chunk.committed_at,
+                chunk.hash,
+                chunk_handle(&chunk.file_path, chunk.hash),
+                chunk.updated_at,
+            ])?;
+        if !self
+            .tx
+            .prepare_cached("SELECT 1 FROM chunks_vec WHERE rowid = ?1")?
+            .exists([chunk.hash])?
+        {
+            self.tx
+                .prepare_cached(&format!(
+                    "INSERT INTO chunks_vec (rowid, embedding) VALUES (?1, {})",
+                    vector_param(self.storage, 2)
+                ))?
+                .execute((chunk.hash, encode_vector(self.storage, &chunk.embedding)))?;
+        }
+        Ok(())
+    }
+
+    /// Add a row of the files table
+    pub fn file(&mut self, file: &StoredFile) -> Result<()> {
+        self.tx
+            .prepare_cached(
+                "INSERT OR REPLACE INTO files (file_path, content_hash, modified_at)
+                 VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![file.file_path, file.content_hash, file.modified_at])?;
+        Ok(())
+    }
+
+    /// Add a definition
+    pub fn symbol(&mut self, symbol: &StoredSymbol) -> Result<()> {
+        self.tx
+            .prepare_cached(
+                "INSERT INTO symbols (file_path, name, kind, line) VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![
+                symbol.file_path,
+                symbol.name,
+                symbol.kind,
+                symbol.line
+            ])?;
+        Ok(())
+    }
+}
+
+/// Run a write, retrying with exponential backoff while another connection holds the lock
+///
+/// SQLite already waits up to `BUSY_TIMEOUT` for a lock; this covers writers that hold it
+/// longer, such as a full index running next to a server reindexing changed files.
+fn retry_busy<T>(mut write: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match write() {
+            Err(e) if attempt < WRITE_ATTEMPTS && is_busy(&e) => {
+                warn!(
+                    "Database is locked by another writer, retrying in {:?}",
+                    delay
+                );
+                sleep_blocking(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Handle of a chunk for `ragrep show`: 12 hex digits derived from its file path and
+/// content hash
+///
+/// Unlike the row id, it survives its file being reindexed as long as the chunk itself
+/// didn't change.
+pub fn chunk_handle(file_path: &str, hash: i64) -> String {
+    let mut hasher = FnvHasher::default();
+    hasher.write(file_path.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(&hash.to_le_bytes());
+    format!("{:012x}", hasher.finish() >> 16)
+}
+
+/// Whether new files (such as SQLite's `-shm` and `-wal`) can be created in `dir`
+///
+/// A directory without any write permission bits counts as read-only even for root, who
+/// could still write to it.
+fn is_writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let Ok(c_dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let read_only = std::fs::metadata(dir).is_ok_and(|metadata| metadata.permissions().readonly());
+    // SAFETY: `c_dir` is a valid NUL-terminated string that outlives the call
+    !read_only && unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } == 0
+}
+
+/// SQLite URI filename opening `path` as immutable
+fn immutable_uri(path: &Path) -> String {
+    let path = path
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    format!("file:{}?immutable=1", path)
+}
+
+/// Sleep for `delay`, letting the runtime move other tasks off this thread when called
+/// from an async worker
+fn sleep_blocking(delay: Duration) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| std::thread::sleep(delay))
+        }
+        _ => std::thread::sleep(delay),
+    }
+}
+
+/// Whether `error`, or any error it was given as context to, is SQLite reporting the
+/// database as locked
+pub fn is_busy(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(failure, _))
+                if matches!(failure.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        )
+    })
+}
+
+/// Deleting at least this many files at once removes their vectors with one scan of the
+/// vector table instead of one delete per vector
+const VECTOR_SCAN_MIN_FILES: usize = 100;
+
+/// How long a file's recorded match time is kept before another match rewrites it
+const MATCH_RECORD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bytes a row takes beyond its text, roughly: integer columns, record header and its
+/// index entries (counted by [`Database::data_bytes`])
+const ROW_OVERHEAD_BYTES: usize = 64;
+
+/// Candidates fetched per wanted result when quantized vectors are rescored against the query
+const RESCORE_OVERSAMPLING: usize = 4;
+
+/// Prepared statements kept per connection; enough for every statement of the hot paths
+/// plus a few filtered searches
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Load sqlite-vec into every connection opened from now on
+fn register_vec_extension() {
+    unsafe {
+        rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
+            sqlite3_vec_init as *const (),
+        )));
+    }
+}
+
+/// Vector as stored in a `chunks_vec` table of `storage`: little-endian f32s, signed bytes
+/// scaled from [-1, 1], or sign bits packed eight per byte, lowest bit first
+fn encode_vector(storage: VectorStorage, embedding: &[f32]) -> Vec<u8> {
+    match storage {
+        VectorStorage::Float32 => embedding.as_bytes().to_vec(),
+        VectorStorage::Int8 => embedding
+            .iter()
+            .map(|value| (value * 127.0).round().clamp(-127.0, 127.0) as i8 as u8)
+            .collect(),
+        VectorStorage::Binary => embedding
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .enumerate()
+                    .filter(|(_, value)| **value > 0.0)
+                    .fold(0u8, |byte, (i, _)| byte | (1 << i))
+            })
+            .collect(),
+    }
+}
+
+/// Convert a vector from a `chunks_vec` table of `storage` back into floats
+///
+/// Binary vectors come back with unit length, so their distances are on the same scale as
+/// those of the model's (normalized) embeddings.
+fn decode_vector(storage: VectorStorage, bytes: &[u8]) -> Vec<f32> {
+    match storage {
+        VectorStorage::Float32 => bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect(),
+        VectorStorage::Int8 => bytes
+            .iter()
+            .map(|byte| *byte as i8 as f32 / 127.0)
+            .collect(),
+        VectorStorage::Binary => {
+            let magnitude = 1.0 / ((bytes.len() * 8) as f32).sqrt();
+            bytes
+                .iter()
+                .flat_map(|byte| (0..8).map(move |i| byte >> i & 1))
+                .map(|bit| if bit == 1 { magnitude } else { -magnitude })
+                .collect()
+        }
+    }
+}
+
+/// SQL for vector parameter `?index`, typed for a `chunks_vec` table of `storage`
+fn vector_param(storage: VectorStorage, index: usize) -> String {
+    match storage {
+        VectorStorage::Float32 => format!("?{}", index),
+        VectorStorage::Int8 => format!("vec_int8(?{})", index),
+        VectorStorage::Binary => format!("vec_bit(?{})", index),
+    }
+}
+
+/// Bring databases written by older versions up to `SCHEMA_VERSION`
+fn migrate(conn: &Connection, root: &Path) -> Result<()> {
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version < 1 {
+        // Version 1: file paths are stored relative to the index root
+        let prefix = format!("{}/", root.to_string_lossy());
+        let migrated = conn.execute(
+            "UPDATE chunks SET file_path = substr(file_path, ?2)
+             WHERE substr(file_path, 1, ?2 - 1) = ?1",
+            params![prefix, prefix.chars().count() as i64 + 1],
+        )?;
+        debug!(
+            "Migrated {} chunk paths to be relative to the index root",
+            migrated
+        );
+    }
+
+    if version < 2 {
+        // Version 2: chunks record the language of their file
+        let mut stmt =
+            conn.prepare("SELECT DISTINCT file_path FROM chunks WHERE language IS NULL")?;
+        let paths: Vec<String> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for path in &paths {
+            conn.execute(
+                "UPDATE chunks SET language = ?1 WHERE file_path = ?2",
+                [chunker::language_for_path(Path::new(path)), path.clone()],
+            )?;
+        }
+        debug!("Tagged chunks of {} files with their language", paths.len());
+    }
+
+    if version < 3 {
+        // Version 3: chunks record the category of their path (default patterns)
+        let categories = PathCategories::default();
+        let mut stmt =
+            conn.prepare("SELECT DISTINCT file_path FROM chunks WHERE category IS NULL")?;
+        let paths: Vec<String> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for path in &paths {
+            conn.execute(
+                "UPDATE chunks SET category = ?1 WHERE file_path = ?2",
+                [categories.categorize(Path::new(path)), path.as_str()],
+            )?;
+        }
+        debug!("Tagged chunks of {} files with their category", paths.len());
+    }
+
+    if version < 4 {
+        // Version 4: vectors are keyed by chunk content hash instead of chunk id, so copies
+        // of the same content share one
+        rekey_vectors(conn)?;
+    }
+
+    if version < 5 {
+        // Version 5: chunks record when they were last (re)indexed; until then, that was
+        // when their row was created
+        let migrated = conn.execute(
+            "UPDATE chunks SET updated_at = CAST(strftime('%s', created_at) AS INTEGER)
+             WHERE updated_at IS NULL",
+            [],
+        )?;
+        debug!("Recorded the indexing time of {} chunks", migrated);
+    }
+
+    if version < 6 {
+        // Version 6: chunks store their handle, so `ragrep show` looks it up by index
+        let mut stmt =
+            conn.prepare("SELECT id, file_path, hash FROM chunks WHERE handle IS NULL")?;
+        let rows: Vec<(i64, String, i64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut update = conn.prepare("UPDATE chunks SET handle = ?1 WHERE id = ?2")?;
+        for (id, file_path, hash) in &rows {
+            update.execute(params![chunk_handle(file_path, *hash), id])?;
+        }
+        debug!("Recorded the handle of {} chunks", rows.len());
+    }
+
+    if version < SCHEMA_VERSION {
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    }
+
+    Ok(())
+}
+
+/// Forget the file content hashes stored with another algorithm than the current one, so
+/// the next index compares every file by content again instead of by a meaningless hash
+fn reset_foreign_content_hashes(conn: &Connection) -> Result<()> {
+    let algorithm: Option<String> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = ?1",
+            [META_CONTENT_HASH],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if algorithm.as_deref() == Some(chunker::CONTENT_HASH_ALGORITHM) {
+        return Ok(());
+    }
+
+    let reset = conn.execute("UPDATE files SET content_hash = 0", [])?;
+    if reset > 0 {
+        info!(
+            "File hashes were computed with another algorithm; {} files will be checked again",
+            reset
+        );
+    }
+    conn.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+        params![META_CONTENT_HASH, chunker::CONTENT_HASH_ALGORITHM],
+    )?;
+    Ok(())
+}
+
+/// Move each vector from its chunk's id to its chunk's content hash, dropping the copies of
+/// a vector another chunk with the same content already moved
+fn rekey_vectors(conn: &Connection) -> Result<()> {
+    let exists: bool = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE name = 'chunks_vec'")?
+        .exists([])?;
+    if !exists {
+        return Ok(());
+    }
+    let (storage, _) = vector_declaration(conn)?;
+
+    let tx = conn.unchecked_transaction()?;
+    let (mut moved, mut shared) = (0, 0);
+    {
+        let mut select = tx.prepare(
+            "SELECT c.id, c.hash, v.embedding FROM chunks c
+             JOIN chunks_vec v ON v.rowid = c.id
+             LIMIT 1000",
+        )?;
+        let mut has_vector = tx.prepare("SELECT 1 FROM chunks_vec WHERE rowid = ?1")?;
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO chunks_vec (rowid, embedding) VALUES (?1, {})",
+            vector_param(storage, 2)
+        ))?;
+        let mut delete = tx.prepare("DELETE FROM chunks_vec WHERE rowid = ?1")?;
+        loop {
+            let rows: Vec<(i64, i64, Vec<u8>)> = select
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            if rows.is_empty() {
+                break;
+            }
+            for (id, hash, embedding) in rows {
+                delete.execute([id])?;
+                if has_vector.exists([hash])? {
+                    shared += 1;
+                } else {
+                    insert.execute((hash, embedding))?;
+                    moved += 1;
+                }
+            }
+        }
+    }
+    tx.commit()?;
+    debug!(
+        "Keyed {} vectors by content hash, dropped {} duplicates",
+        moved, shared
+    );
+    Ok(())
+}
+
+/// Create the vector table for `dimensions`-dimensional vectors unless it exists
+fn create_vector_table(conn: &Connection, storage: VectorStorage, dimensions: usize) -> Result<()> {
+    let element_type = match storage {
+        VectorStorage::Float32 => "FLOAT",
+        VectorStorage::Int8 => "INT8",
+        VectorStorage::Binary => "BIT",
+    };
+    conn.execute(
+        &format!(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS chunks_vec USING vec0(
+            rowid INTEGER PRIMARY KEY,
+            embedding {}[{}]
+            )",
+            element_type, dimensions
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+/// Storage and dimension of the vector table, read from its declaration
+/// (`embedding FLOAT[1024]`)
+fn vector_declaration(conn: &Connection) -> Result<(VectorStorage, Option<usize>)> {
+    let sql: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE name = 'chunks_vec'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some((element_type, rest)) = sql.as_deref().and_then(|sql| {
+        let (_, declaration) = sql.split_once("embedding")?;
+        declaration.trim_start().split_once('[')
+    }) else {
+        return Ok((VectorStorage::default(), None));
+    };
+
+    let storage = match element_type.trim().to_uppercase().as_str() {
+        "INT8" => VectorStorage::Int8,
+        "BIT" => VectorStorage::Binary,
+        _ => VectorStorage::Float32,
+    };
+    let dimensions = rest
+        .split_once(']')
+        .and_then(|(dimensions, _)| dimensions.trim().parse().ok());
+    Ok((storage, dimensions))
+}
+
+fn dimension_mismatch(stored: usize, configured: usize) -> String {
+    format!(
+        "The index stores {}-dimensional embeddings but the embedding model produces {}; \
+         run `ragrep index --rebuild` to recreate the vector table and reindex",
+        stored, configured
+    )
+}
+
+/// SQL conditions on chunks (as `c`) restricting them to `filter`, whose parameters are
+/// appended to `values`
+fn filter_conditions<'a>(filter: &'a ChunkFilter, values: &mut Vec<&'a dyn ToSql>) -> Vec<String> {
+    let mut conditions = Vec::new();
+
+    // Chunks saved before categories were recorded count as source
+    let category = format!("COALESCE(c.category, '{}')", crate::category::SOURCE);
+    let lists = [
+        ("c.language", "IN", &filter.languages),
+        (category.as_str(), "IN", &filter.categories),
+        (category.as_str(), "NOT IN", &filter.exclude_categories),
+        ("c.node_type", "IN", &filter.kinds),
+    ];
+    for (column, operator, list) in lists {
+        if list.is_empty() {
+            continue;
+        }
+        let placeholders: Vec<String> = (0..list.len())
+            .map(|i| format!("?{}", values.len() + i + 1))
+            .collect();
+        conditions.push(format!(
+            "{} {} ({})",
+            column,
+            operator,
+            placeholders.join(", ")
+        ));
+        values.extend(list.iter().map(|value| value as &dyn ToSql));
+    }
+    if !filter.files.is_empty() {
+        let placeholders: Vec<String> = (0..filter.files.len())
+            .map(|i| format!("?{}", values.len() + i + 1))
+            .collect();
+        let files = format!("c.file_path IN ({})", placeholders.join(", "));
+        conditions.push(if filter.unsummarized_files {
+            format!(
+                "({} OR NOT EXISTS (SELECT 1 FROM chunks s \
+                 WHERE s.file_path = c.file_path AND s.node_type = '{}'))",
+                files,
+                chunker::FILE_SUMMARY
+            )
+        } else {
+            files
+        });
+        values.extend(filter.files.iter().map(|file| file as &dyn ToSql));
+    }
+    if !filter.paths.is_empty() {
+        // A directory matches the files under it
+        let patterns: Vec<String> = (0..filter.paths.len())
+            .map(|i| {
+                let index = values.len() + i + 1;
+                format!(
+                    "c.file_path GLOB ?{0} OR c.file_path GLOB ?{0} || '/*'",
+                    index
+                )
+            })
+            .collect();
+        conditions.push(format!("({})", patterns.join(" OR ")));
+        values.extend(filter.paths.iter().map(|path| path as &dyn ToSql));
+    }
+    if filter.comments {
+        conditions.push(format!(
+            "(c.node_type IN ('{}', '{}') OR c.comments <> '')",
+            chunker::COMMENT,
+            chunker::TODO
+        ));
+    }
+    if let Some(author) = &filter.author {
+        conditions.push(format!(
+            "instr(lower(c.author), lower(?{})) > 0",
+            values.len() + 1
+        ));
+        values.push(author);
+    }
+    if let Some(since) = &filter.since {
+        conditions.push(format!("c.committed_at >= ?{}", values.len() + 1));
+        values.push(since);
+    }
+    if let Some(changed_since) = &filter.changed_since {
+        conditions.push(format!("c.updated_at >= ?{}", values.len() + 1));
+        values.push(changed_since);
+    }
+    conditions
+}
+
+/// Add a column to an existing table unless it is already present
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Vec<String> = stmt
+        .query_map([], |row| row.get(1))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    if !columns.iter().any(|name| name == column) {
+        debug!("Migrating {}: adding column {}", table, column);
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ragrep-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn stored_paths(db: &Database) -> Vec<String> {
+        let mut stmt = db.conn.prepare("SELECT file_path FROM chunks").unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_save_chunks_batch() {
+        let root = temp_dir("batch");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/lib.rs").to_string_lossy().to_string();
+        let record = |chunk_index: i32, text: &'static str, hash: u64| ChunkRecord {
+            file_path: &file,
+            chunk_index,
+            node_type: "function",
+            node_name: None,
+            start_line: 1,
+            end_line: 1,
+            text,
+            comments: "",
+            language: None,
+            author: None,
+            committed_at: None,
+            hash,
+            embedding: vec![0.0; constants::EMBEDDING_DIMENSIONS],
+            updated_at: None,
+        };
+
+        // The duplicate of the first chunk is skipped, vector included
+        db.save_chunks_batch(&[
+            record(0, "fn a() {}", 1),
+            record(1, "fn b() {}", 2),
+            record(0, "fn a() {}", 1),
+        ])
+        .unwrap();
+        assert_eq!(db.count_chunks().unwrap(), 2);
+        assert_eq!(db.get_embeddings(None).unwrap().len(), 2);
+        assert_eq!(stored_paths(&db), vec!["src/lib.rs", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let root = temp_dir("eviction");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let files: Vec<String> = ["a.rs", "b.rs", "c.rs"]
+            .iter()
+            .map(|name| root.join(name).to_string_lossy().to_string())
+            .collect();
+        for (i, file) in files.iter().enumerate() {
+            db.save_chunk(
+                file,
+                0,
+                "function",
+                None,
+                1,
+                1,
+                "x",
+                "",
+                i as u64,
+                &[0.0; 1024],
+            )
+            .unwrap();
+            db.set_file_hash(file, 1).unwrap();
+        }
+        assert!(db.size_bytes().unwrap() > 0);
+
+        // Files that never had results go first, then the least recently matched
+        db.record_matches(&[&files[0]]).unwrap();
+        let matched_at = |db: &Database| -> Option<i64> {
+            db.conn
+                .query_row(
+                    "SELECT last_matched_at FROM files WHERE file_path = 'a.rs'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert!(matched_at(&db).is_some());
+        // Another match right after isn't written again
+        db.conn
+            .execute(
+                "UPDATE files SET last_matched_at = 5 WHERE file_path = 'a.rs'",
+                [],
+            )
+            .unwrap();
+        db.record_matches(&[&files[0]]).unwrap();
+        assert_eq!(matched_at(&db), Some(5));
+        db.conn
+            .execute(
+                "UPDATE files SET last_matched_at = NULL WHERE file_path = 'a.rs'",
+                [],
+            )
+            .unwrap();
+        db.recorded_matches.lock().unwrap().clear();
+        db.record_matches(&[&files[0]]).unwrap();
+        db.conn
+            .execute(
+                "UPDATE files SET last_matched_at = 1 WHERE file_path = 'c.rs'",
+                [],
+            )
+            .unwrap();
+        let order = |db: &Database, policy| -> Vec<String> {
+            db.eviction_order(policy)
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect()
+        };
+        assert_eq!(
+            order(&db, EvictionPolicy::LeastRecentlyMatched),
+            [files[1].clone(), files[2].clone(), files[0].clone()]
+        );
+
+        db.evict_files(&[&files[1]]).unwrap();
+        assert_eq!(
+            db.evicted_files().unwrap(),
+            HashSet::from([files[1].clone()])
+        );
+        assert_eq!(order(&db, EvictionPolicy::OldestModified).len(), 2);
+
+        // Indexing it again brings it back
+        db.set_file_hash(&files[1], 2).unwrap();
+        assert!(db.evicted_files().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_delete_files() {
+        let root = temp_dir("delete-files");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let files: Vec<String> = (0..120)
+            .map(|i| {
+                root.join(format!("src/{}.rs", i))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        for (i, file) in files.iter().enumerate() {
+            db.save_chunk(
+                file,
+                0,
+                "function",
+                None,
+                1,
+                1,
+                "x",
+                "",
+                i as u64,
+                &[0.0; 1024],
+            )
+            .unwrap();
+            db.set_file_hash(file, 1).unwrap();
+        }
+
+        // Enough files to delete their vectors with a scan, then few enough for point deletes
+        db.delete_files(&files[..110]).unwrap();
+        assert_eq!(db.count_chunks().unwrap(), 10);
+        db.delete_files(&files[110..112]).unwrap();
+        assert_eq!(db.count_chunks().unwrap(), 8);
+        assert_eq!(db.get_embeddings(None).unwrap().len(), 8);
+        assert_eq!(db.get_file_hash(&files[0]).unwrap(), None);
+        assert_eq!(db.get_file_hash(&files[119]).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        let root = temp_dir("dimensions");
+        let path = root.join("ragrep.db");
+        register_vec_extension();
+        Connection::open(&path)
+            .unwrap()
+            .execute(
+                "CREATE VIRTUAL TABLE chunks_vec USING vec0(rowid INTEGER PRIMARY KEY, embedding FLOAT[384])",
+                [],
+            )
+            .unwrap();
+
+        let mut db = Database::new(&path, &root).unwrap();
+        let save = |db: &mut Database| {
+            let embedding = [0.0; constants::EMBEDDING_DIMENSIONS];
+            db.save_chunk(
+                "a.rs",
+                0,
+                "function",
+                None,
+                1,
+                1,
+                "fn a() {}",
+                "",
+                1,
+                &embedding,
+            )
+        };
+        let error = save(&mut db).unwrap_err().to_string();
+        assert!(
+            error.contains("stores 384-dimensional embeddings"),
+            "{}",
+            error
+        );
+        assert!(error.contains("index --rebuild"), "{}", error);
+
+        db.rebuild_vector_table(VectorStorage::default(), constants::EMBEDDING_DIMENSIONS)
+            .unwrap();
+        save(&mut db).unwrap();
+        assert_eq!(db.count_chunks().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_quantized_storage() {
+        let dimensions = constants::EMBEDDING_DIMENSIONS;
+        let scale = 1.0 / (dimensions as f32).sqrt();
+        // All positive, half positive, all negative: increasingly far from the query
+        let embeddings: Vec<Vec<f32>> = [dimensions, dimensions / 2, 0]
+            .iter()
+            .map(|positive| {
+                (0..dimensions)
+                    .map(|i| if i < *positive { scale } else { -scale })
+                    .collect()
+            })
+            .collect();
+        let mut query = embeddings[0].clone();
+        query[0] = 0.0;
+
+        for storage in [VectorStorage::Int8, VectorStorage::Binary] {
+            let root = temp_dir(storage.name());
+            let path = root.join("ragrep.db");
+            let mut db = Database::new(&path, &root).unwrap();
+            db.set_vector_layout(storage, dimensions).unwrap();
+            for (i, embedding) in embeddings.iter().enumerate() {
+                let file = format!("{}.rs", i);
+                db.save_chunk(
+                    &file, 0, "function", None, 1, 1, "x", "", i as u64, embedding,
+                )
+                .unwrap();
+            }
+
+            let found = db
+                .find_similar_chunks(&query, 2, &ChunkFilter::default())
+                .unwrap();
+            let files: Vec<&str> = found.iter().map(|chunk| chunk.file_path.as_str()).collect();
+            assert_eq!(files, ["0.rs", "1.rs"], "{}", storage.name());
+            assert!(found[0].distance < 0.1, "{}", found[0].distance);
+
+            // Filtered searches scan the quantized vectors themselves
+            let filter = ChunkFilter {
+                files: vec!["1.rs".to_string(), "2.rs".to_string()],
+                ..ChunkFilter::default()
+            };
+            let found = db.find_similar_chunks(&query, 1, &filter).unwrap();
+            assert_eq!(found[0].file_path, "1.rs", "{}", storage.name());
+
+            // Stored vectors come back close to the originals
+            let stored = db.get_embeddings(None).unwrap();
+            let error: f32 = stored[0]
+                .1
+                .iter()
+                .zip(&embeddings[0])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 0.01, "{} {}", storage.name(), error);
+
+            // The storage is kept on reopen, and a filled index is not converted
+            drop(db);
+            let mut db = Database::new(&path, &root).unwrap();
+            assert_eq!(db.vector_storage(), storage);
+            db.set_vector_layout(VectorStorage::Float32, dimensions)
+                .unwrap();
+            assert_eq!(db.vector_storage(), storage);
+        }
+    }
+
+    #[test]
+    fn test_paths_are_stored_relative_to_root() {
+        let root = temp_dir("relative");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/lib.rs").to_string_lossy().to_string();
+
+        db.save_chunk(
+            &file,
+            0,
+            "function",
+            None,
+            1,
+            3,
+            "fn a() {}",
+            "",
+            1,
+            &[0.0; 1024],
+        )
+        .unwrap();
+
+        assert_eq!(stored_paths(&db), vec!["src/lib.rs".to_string()]);
+        assert_eq!(db.get_indexed_files().unwrap(), vec![file.clone()]);
+
+        db.delete_file(&file).unwrap();
+        assert!(stored_paths(&db).is_empty());
+    }
+
+    #[test]
+    fn test_file_hashes() {
+        let root = temp_dir("file-hash");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/lib.rs").to_string_lossy().to_string();
+
+        assert_eq!(db.get_file_hash(&file).unwrap(), None);
+        db.set_file_hash(&file, u64::MAX).unwrap();
+        assert_eq!(db.get_file_hash(&file).unwrap(), Some(u64::MAX));
+        // The file doesn't exist, so there is no modification time to record
+        assert_eq!(db.get_file_modified(&file).unwrap(), None);
+
+        let lib = root.join("lib.rs");
+        std::fs::write(&lib, "fn a() {}").unwrap();
+        db.set_file_hash(&lib.to_string_lossy(), 1).unwrap();
+        assert!(db.get_file_modified("lib.rs").unwrap().unwrap() > 0);
+
+        db.delete_file(&file).unwrap();
+        assert_eq!(db.get_file_hash(&file).unwrap(), None);
+    }
+
+    #[test]
+    fn test_absolute_paths_are_migrated() {
+        let root = temp_dir("migrate");
+        let db_path = root.join("ragrep.db");
+        {
+            let db = Database::new(&db_path, &root).unwrap();
+            db.conn
+                .execute(
+                    "INSERT INTO chunks (file_path, chunk_index, start_line, end_line, text, hash)
+                     VALUES (?1, 0, 1, 1, 'x', 1)",
+                    [root.join("a.rs").to_string_lossy().to_string()],
+                )
+                .unwrap();
+            db.conn.execute_batch("PRAGMA user_version = 0").unwrap();
+        }
+
+        let db = Database::new(&db_path, &root).unwrap();
+        assert_eq!(stored_paths(&db), vec!["a.rs".to_string()]);
+
+        let language: String = db
+            .conn
+            .query_row("SELECT language FROM chunks", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(language, "rust");
+        assert!(db.get_chunk(&chunk_handle("a.rs", 1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_content_hashes_of_another_algorithm_are_reset() {
+        let root = temp_dir("hash-algorithm");
+        let db_path = root.join("ragrep.db");
+        let db = Database::new(&db_path, &root).unwrap();
+        db.set_file_hash("a.rs", 42).unwrap();
+        drop(db);
+
+        // Reopening with the same algorithm keeps the hashes
+        let db = Database::new(&db_path, &root).unwrap();
+        assert_eq!(db.get_file_hash("a.rs").unwrap(), Some(42));
+        db.set_metadata(META_CONTENT_HASH, "siphash-1-3").unwrap();
+        drop(db);
+
+        let db = Database::new(&db_path, &root).unwrap();
+        assert_eq!(db.get_file_hash("a.rs").unwrap(), Some(0));
+        assert_eq!(
+            db.get_metadata(META_CONTENT_HASH).unwrap().as_deref(),
+            Some(chunker::CONTENT_HASH_ALGORITHM)
+        );
+    }
+
+    #[test]
+    fn test_vectors_are_rekeyed_by_content_hash() {
+        let root = temp_dir("rekey");
+        let db_path = root.join("ragrep.db");
+        let embedding = vec![0.25; constants::EMBEDDING_DIMENSIONS];
+        {
+            // A version 3 index: one vector per chunk, keyed by chunk id
+            let mut db = Database::new(&db_path, &root).unwrap();
+            for file in ["a.rs", "b.rs"] {
+                let file = root.join(file).to_string_lossy().to_string();
+                db.save_chunk(&file, 0, "function", None, 1, 1, "x", "", 9, &embedding)
+                    .unwrap();
+            }
+            db.conn.execute("DELETE FROM chunks_vec", []).unwrap();
+            let ids: Vec<i64> = db
+                .conn
+                .prepare("SELECT id FROM chunks")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<std::result::Result<_, _>>()
+                .unwrap();
+            for id in ids {
+                db.conn
+                    .execute(
+                        "INSERT INTO chunks_vec (rowid, embedding) VALUES (?1, ?2)",
+                        (id, embedding.as_bytes()),
+                    )
+                    .unwrap();
+            }
+            db.conn.execute_batch("PRAGMA user_version = 3").unwrap();
+        }
+
+        let db = Database::new(&db_path, &root).unwrap();
+        assert_eq!(db.count_vectors().unwrap(), 1);
+        assert_eq!(db.get_embedding(9).unwrap(), Some(embedding));
+        assert!(db.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let root = temp_dir("read-only");
+        let db_path = root.join("ragrep.db");
+        assert!(Database::open_read_only(&db_path, &root).is_err());
+
+        let file = root.join("a.rs").to_string_lossy().to_string();
+        Database::new(&db_path, &root)
+            .unwrap()
+            .save_chunk(&file, 0, "function", None, 1, 1, "x", "", 1, &[0.0; 1024])
+            .unwrap();
+
+        let mut db = Database::open_read_only(&db_path, &root).unwrap();
+        assert_eq!(db.count_chunks().unwrap(), 1);
+        assert_eq!(
+            db.find_similar_chunks(&[0.0; 1024], 5, &ChunkFilter::default())
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .save_chunk(&file, 1, "function", None, 2, 2, "y", "", 2, &[0.0; 1024])
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_only_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_dir("read-only-dir#1");
+        let db_path = root.join("ragrep.db");
+        let file = root.join("a.rs").to_string_lossy().to_string();
+        Database::new(&db_path, &root)
+            .unwrap()
+            .save_chunk(&file, 0, "function", None, 1, 1, "x", "", 1, &[0.0; 1024])
+            .unwrap();
+
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o555)).unwrap();
+        let opened = Database::open_read_only(&db_path, &root).and_then(|db| db.count_chunks());
+        let shm_created = root.join("ragrep.db-shm").exists();
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(opened.unwrap(), 1);
+        assert!(!shm_created);
+    }
+
+    #[test]
+    fn test_changed_since_filter() {
+        let root = temp_dir("changed-since");
+        let db_path = root.join("ragrep.db");
+        let file = root.join("lib.rs").to_string_lossy().to_string();
+        {
+            let mut db = Database::new(&db_path, &root).unwrap();
+            db.save_chunk(
+                &file,
+                0,
+                "function",
+                None,
+                1,
+                1,
+                "fn old() {}",
+                "",
+                1,
+                &[0.0; 1024],
+            )
+            .unwrap();
+            db.save_chunk(
+                &file,
+                1,
+                "function",
+                None,
+                2,
+                2,
+                "fn new() {}",
+                "",
+                2,
+                &[0.0; 1024],
+            )
+            .unwrap();
+            // As written by an older version, with the first chunk indexed a week ago
+            db.conn
+                .execute_batch(
+                    "UPDATE chunks SET updated_at = NULL;
+                     UPDATE chunks SET created_at = datetime('now', '-7 days') WHERE hash = 1;
+                     PRAGMA user_version = 4;",
+                )
+                .unwrap();
+        }
+
+        let db = Database::new(&db_path, &root).unwrap();
+        let day_ago = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            - 86_400;
+        let texts: Vec<String> = db
+            .find_similar_chunks(
+                &[0.0; 1024],
+                5,
+                &ChunkFilter {
+                    changed_since: Some(day_ago),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .into_iter()
+            .map(|chunk| chunk.text)
+            .collect();
+        assert_eq!(texts, vec!["fn new() {}"]);
+    }
+
+    #[test]
+    fn test_retry_busy() {
+        let busy = || {
+            anyhow::Error::from(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            ))
+        };
+
+        let mut calls = 0;
+        let result = retry_busy(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(busy())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        // Also when given context on the way up
+        assert!(is_busy(&busy().context("Failed to save chunk")));
+
+        // Other errors are returned immediately
+        let mut calls = 0;
+        let result: Result<()> = retry_busy(|| {
+            calls += 1;
+            bail!("disk full")
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_language_filter() {
+        let root = temp_dir("language");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        for (i, file) in ["a.rs", "b.py", "c.ipynb"].iter().enumerate() {
+            let mut embedding = [0.0; 1024];
+            embedding[0] = i as f32;
+            let file = root.join(file).to_string_lossy().to_string();
+            db.save_chunk(
+                &file, 0, "function", None, 1, 1, "x", "", i as u64, &embedding,
+            )
+            .unwrap();
+        }
+
+        let files = |languages: &[&str]| -> Vec<String> {
+            let filter = ChunkFilter {
+                languages: languages.iter().map(|l| l.to_string()).collect(),
+                ..Default::default()
+            };
+            db.find_similar_chunks(&[0.0; 1024], 10, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|chunk| chunk.file_path)
+                .collect()
+        };
+        assert_eq!(files(&[]).len(), 3);
+        assert_eq!(files(&["python"]), vec!["b.py", "c.ipynb"]);
+        assert_eq!(files(&["rust", "python"]), vec!["a.rs", "b.py", "c.ipynb"]);
+        assert!(files(&["go"]).is_empty());
+    }
+
+    #[test]
+    fn test_comment_filters() {
+        let root = temp_dir("comments");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("lib.rs").to_string_lossy().to_string();
+        let chunks = [
+            ("function", "fn a() {}", "/// Documented\n", 3),
+            ("function", "fn b() {}", "", 1),
+            ("todo", "// TODO: retry", "", 7),
+            ("comment", "// Free-standing note about a", "", 5),
+        ];
+        for (i, (kind, text, comments, line)) in chunks.into_iter().enumerate() {
+            db.save_chunk(
+                &file,
+                i as i32,
+                kind,
+                None,
+                line,
+                line,
+                text,
+                comments,
+                i as u64,
+                &[0.0; 1024],
+            )
+            .unwrap();
+        }
+
+        let texts = |filter: ChunkFilter| -> Vec<String> {
+            let mut texts: Vec<String> = db
+                .find_similar_chunks(&[0.0; 1024], 10, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|chunk| chunk.text)
+                .collect();
+            texts.sort();
+            texts
+        };
+        assert_eq!(
+            texts(ChunkFilter {
+                comments: true,
+                ..Default::default()
+            }),
+            vec![
+                "// Free-standing note about a",
+                "// TODO: retry",
+                "fn a() {}"
+            ]
+        );
+        assert_eq!(
+            texts(ChunkFilter {
+                kinds: vec!["todo".to_string()],
+                ..Default::default()
+            }),
+            vec!["// TODO: retry"]
+        );
+
+        let listed: Vec<i32> = db
+            .find_chunks_of_kind("comment", 10)
+            .unwrap()
+            .into_iter()
+            .map(|chunk| chunk.start_line)
+            .collect();
+        assert_eq!(listed, vec![5]);
+    }
+
+    #[test]
+    fn test_blame_filters() {
+        let root = temp_dir("blame");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("lib.rs").to_string_lossy().to_string();
+        let blamed = [
+            (
+                "fn a() {}",
+                Some("Alice Liddell <alice@example.com>"),
+                Some(1_700_000_000),
+            ),
+            (
+                "fn b() {}",
+                Some("Bob <bob@example.com>"),
+                Some(1_720_000_000),
+            ),
+            ("fn c() {}", None, None),
+        ];
+        let records: Vec<ChunkRecord> = blamed
+            .iter()
+            .enumerate()
+            .map(|(i, (text, author, committed_at))| ChunkRecord {
+                file_path: &file,
+                chunk_index: i as i32,
+                node_type: "function",
+                node_name: None,
+                start_line: i + 1,
+                end_line: i + 1,
+                text,
+                comments: "",
+                language: None,
+                author: *author,
+                committed_at: *committed_at,
+                hash: i as u64,
+                embedding: vec![0.0; constants::EMBEDDING_DIMENSIONS],
+                updated_at: None,
+            })
+            .collect();
+        db.save_chunks_batch(&records).unwrap();
+
+        let texts = |filter: ChunkFilter| -> Vec<String> {
+            let mut texts: Vec<String> = db
+                .find_similar_chunks(&[0.0; 1024], 10, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|chunk| chunk.text)
+                .collect();
+            texts.sort();
+            texts
+        };
+        assert_eq!(
+            texts(ChunkFilter {
+                author: Some("alice".to_string()),
+                ..Default::default()
+            }),
+            vec!["fn a() {}"]
+        );
+        assert_eq!(
+            texts(ChunkFilter {
+                author: Some("example.com".to_string()),
+                since: Some(1_710_000_000),
+                ..Default::default()
+            }),
+            vec!["fn b() {}"]
+        );
+
+        let id = db
+            .find_similar_chunks(
+                &[0.0; 1024],
+                1,
+                &ChunkFilter {
+                    author: Some("bob".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap()[0]
+            .id;
+        let chunk = db.get_chunk_by_id(id).unwrap().unwrap();
+        assert_eq!(chunk.author.as_deref(), Some("Bob <bob@example.com>"));
+        assert_eq!(chunk.committed_at, Some(1_720_000_000));
+    }
+
+    #[test]
+    fn test_get_chunk() {
+        let root = temp_dir("get-chunk");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/a.rs").to_string_lossy().to_string();
+        db.save_chunk(
+            &file,
+            0,
+            "function",
+            Some("open"),
+            3,
+            5,
+            "fn open() {}",
+            "/// Opens it",
+            7,
+            &[0.0; 1024],
+        )
+        .unwrap();
+
+        let found = db
+            .find_similar_chunks(&[0.0; 1024], 1, &ChunkFilter::default())
+            .unwrap();
+        let handle = chunk_handle(&found[0].file_path, found[0].hash);
+        let chunk = db.get_chunk(&handle).unwrap().unwrap();
+        assert_eq!(chunk.file_path, "src/a.rs");
+        assert_eq!(chunk.node_name.as_deref(), Some("open"));
+        assert_eq!((chunk.start_line, chunk.end_line), (3, 5));
+        assert_eq!(chunk.comments, "/// Opens it");
+        assert_eq!(chunk.category.as_deref(), Some("src"));
+        assert!(db.get_chunk("000000000000").unwrap().is_none());
+
+        // Reindexing the file gives the chunk a new row but keeps its handle
+        db.delete_file(&file).unwrap();
+        db.save_chunk(
+            &file,
+            1,
+            "function",
+            Some("open"),
+            8,
+            10,
+            "fn open() {}",
+            "/// Opens it",
+            7,
+            &[0.0; 1024],
+        )
+        .unwrap();
+        assert!(db.get_chunk_by_id(found[0].id).unwrap().is_none());
+        let chunk = db.get_chunk(&handle).unwrap().unwrap();
+        assert_eq!((chunk.start_line, chunk.end_line), (8, 10));
+    }
+
+    #[test]
+    fn test_shared_vectors() {
+        let root = temp_dir("shared");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let embedding = vec![0.5; constants::EMBEDDING_DIMENSIONS];
+        // The same helper in a source file and a vendored copy, and one of its own
+        for (file, text, hash) in [
+            ("src/util.rs", "fn clamp() {}", 7),
+            ("vendor/util.rs", "fn clamp() {}", 7),
+            ("src/main.rs", "fn main() {}", 8),
+        ] {
+            let file = root.join(file).to_string_lossy().to_string();
+            db.save_chunk(&file, 0, "function", None, 1, 1, text, "", hash, &embedding)
+                .unwrap();
+        }
+        assert_eq!(
+            (db.count_chunks().unwrap(), db.count_vectors().unwrap()),
+            (3, 2)
+        );
+        assert_eq!(db.get_embedding(7).unwrap(), Some(embedding.clone()));
+        assert_eq!(db.get_embeddings(None).unwrap().len(), 3);
+        assert_eq!(
+            db.find_similar_chunks(&embedding, 10, &ChunkFilter::default())
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // The vector stays while a copy still uses it
+        db.delete_file(&root.join("src/util.rs").to_string_lossy())
+            .unwrap();
+        assert_eq!(db.count_vectors().unwrap(), 2);
+        assert!(db.verify().unwrap().is_clean());
+        db.delete_file(&root.join("vendor/util.rs").to_string_lossy())
+            .unwrap();
+        assert_eq!(db.count_vectors().unwrap(), 1);
+        assert_eq!(db.get_embedding(7).unwrap(), None);
+        assert!(db.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_verify() {
+        let root = temp_dir("verify");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/a.rs").to_string_lossy().to_string();
+        let embedding = vec![0.0; constants::EMBEDDING_DIMENSIONS];
+        for (i, text) in ["fn a() {}", "fn b() {}"].iter().enumerate() {
+            db.save_chunk(
+                &file, i as i32, "function", None, 1, 1, text, "", i as u64, &embedding,
+            )
+            .unwrap();
+        }
+        assert!(db.verify().unwrap().is_clean());
+
+        // As if a crash had separated the tables; vectors are keyed by content hash
+        let ids = db.get_embeddings(None).unwrap();
+        db.conn
+            .execute("DELETE FROM chunks WHERE id = ?1", [ids[0].0])
+            .unwrap();
+        db.delete_vectors(&[1]).unwrap();
+        let report = db.verify().unwrap();
+        assert_eq!(report.chunks_without_vectors, vec![ids[1].0]);
+        assert_eq!(report.vectors_without_chunks, vec![0]);
+
+        db.save_vectors(&[(ids[1].0, embedding)]).unwrap();
+        db.delete_vectors(&report.vectors_without_chunks).unwrap();
+        assert!(db.verify().unwrap().is_clean());
+        assert_eq!(db.get_embeddings(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_category_filter() {
+        let root = temp_dir("category");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        for (i, file) in ["src/a.rs", "tests/b.rs", "vendor/c.rs"].iter().enumerate() {
+            let mut embedding = [0.0; 1024];
+            embedding[0] = i as f32;
+            let file = root.join(file).to_string_lossy().to_string();
+            db.save_chunk(
+                &file, 0, "function", None, 1, 1, "x", "", i as u64, &embedding,
+            )
+            .unwrap();
+        }
+
+        let files = |categories: &[&str], exclude_categories: &[&str]| -> Vec<String> {
+            let filter = ChunkFilter {
+                categories: categories.iter().map(|c| c.to_string()).collect(),
+                exclude_categories: exclude_categories.iter().map(|c| c.to_string()).collect(),
+                ..Default::default()
+            };
+            db.find_similar_chunks(&[0.0; 1024], 10, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|chunk| chunk.file_path)
+                .collect()
+        };
+        assert_eq!(files(&["src"], &[]), vec!["src/a.rs"]);
+        assert_eq!(files(&[], &["test"]), vec!["src/a.rs", "vendor/c.rs"]);
+        assert_eq!(files(&[], &["test", "vendor"]), vec!["src/a.rs"]);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let root = temp_dir("symbols");
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let file = root.join("src/db.rs").to_string_lossy().to_string();
+        let symbol = |name: &str, line| Symbol {
+            name: name.to_string(),
+            kind: "function".to_string(),
+            line,
+        };
+
+        db.save_symbols(&file, &[symbol("open", 3), symbol("open_read_only", 9)])
+            .unwrap();
+        // Saving again replaces the file's previous symbols
+        db.save_symbols(&file, &[symbol("open", 4), symbol("open_read_only", 10)])
+            .unwrap();
+        assert_eq!(db.count_symbols().unwrap(), 2);
+
+        let names = |matches: Vec<SymbolMatch>| -> Vec<(String, i32)> {
+            matches
+                .into_iter()
+                .map(|(name, _, _, line)| (name, line))
+                .collect()
+        };
+        assert_eq!(
+            names(db.find_symbols("open", false, 10).unwrap()),
+            vec![("open".to_string(), 4)]
+        );
+        assert_eq!(
+            names(db.find_symbols("OPEN", true, 10).unwrap()),
+            vec![("open".to_string(), 4), ("open_read_only".to_string(), 10)]
+        );
+        assert_eq!(
+            db.find_symbols("open", false, 10).unwrap()[0].2,
+            "src/db.rs"
+        );
+        assert_eq!(
+            db.file_symbols("src/db.rs").unwrap(),
+            vec![symbol("open", 4), symbol("open_read_only", 10)]
+        );
+
+        db.delete_file(&file).unwrap();
+        assert_eq!(db.count_symbols().unwrap(), 0);
     }
 }
@@ -0,0 +1,74 @@
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::debug;
+use std::path::Path;
+use std::process::Command;
+
+/// Invocation used with `$VISUAL`/`$EDITOR`; understood by vim, nano, emacs and most others
+const DEFAULT_TEMPLATE: &str = "+{line} {file}";
+
+/// Open `file` at `line` with the configured editor command, `$VISUAL` or `$EDITOR`
+///
+/// `template` is split on whitespace and `{file}` and `{line}` are substituted in each
+/// argument, e.g. `code -g {file}:{line}`. Waits for the editor to exit so terminal
+/// editors get the terminal.
+pub fn open(file: &Path, line: i32, template: Option<&str>) -> Result<()> {
+    let template = match template {
+        Some(template) => template.to_string(),
+        None => {
+            let editor = std::env::var("VISUAL")
+                .or_else(|_| std::env::var("EDITOR"))
+                .unwrap_or_else(|_| "vi".to_string());
+            format!("{} {}", editor, DEFAULT_TEMPLATE)
+        }
+    };
+
+    let args = build_command(&template, &file.to_string_lossy(), line);
+    let Some((program, args)) = args.split_first() else {
+        bail!("Editor command is empty");
+    };
+
+    debug!("Opening editor: {} {:?}", program, args);
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to launch editor '{}'", program))?;
+    if !status.success() {
+        bail!("Editor '{}' exited with {}", program, status);
+    }
+    Ok(())
+}
+
+/// Expand an editor command template into program and arguments
+fn build_command(template: &str, file: &str, line: i32) -> Vec<String> {
+    let mut args: Vec<String> = template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{file}", file)
+                .replace("{line}", &line.to_string())
+        })
+        .collect();
+
+    // A template without a file placeholder gets the file appended
+    if !template.contains("{file}") {
+        args.push(file.to_string());
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_command() {
+        assert_eq!(
+            build_command("code -g {file}:{line}", "/src/my lib.rs", 12),
+            vec!["code", "-g", "/src/my lib.rs:12"]
+        );
+        assert_eq!(
+            build_command("vim +{line} {file}", "a.rs", 3),
+            vec!["vim", "+3", "a.rs"]
+        );
+        assert_eq!(build_command("subl", "a.rs", 3), vec!["subl", "a.rs"]);
+    }
+}
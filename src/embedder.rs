@@ -1,70 +1,342 @@
-use anyhow::{Error, Result};
+use crate::constants::constants;
+use anyhow::{bail, Error, Result};
 use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
 use ignore::Walk;
-use log::debug;
+use log::{debug, info};
 use promkit::preset::confirm::Confirm;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use streaming_iterator::StreamingIterator;
 use tree_sitter::{Language, Parser, Query, QueryCursor};
+use tree_sitter_bash::LANGUAGE as BASH_LANGUAGE;
+use tree_sitter_c_sharp::LANGUAGE as CSHARP_LANGUAGE;
 use tree_sitter_javascript::LANGUAGE as JS_LANGUAGE;
+use tree_sitter_php::LANGUAGE_PHP as PHP_LANGUAGE;
 use tree_sitter_python::LANGUAGE as PYTHON_LANGUAGE;
+use tree_sitter_ruby::LANGUAGE as RUBY_LANGUAGE;
 use tree_sitter_rust::LANGUAGE as RUST_LANGUAGE;
+use tree_sitter_swift::LANGUAGE as SWIFT_LANGUAGE;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Embedding(pub Vec<f32>);
 
-pub struct Embedder {
-    model: Mutex<TextEmbedding>,
-    cache: Mutex<HashMap<u64, Embedding>>,
+/// A model turning texts into embeddings
+///
+/// Implemented by mxbai-embed-large and, in tests, by deterministic doubles that need no
+/// model (see `crate::testing`).
+pub trait Embed: Send + Sync {
+    /// Embedding of each of `texts`, in order
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Embedding>>;
+
+    /// Number of tokens the model's tokenizer produces for `text`, even past the model's
+    /// input length
+    fn count_tokens(&self, text: &str) -> Result<usize>;
+
+    /// Quantization of the model's weights, as recorded in index metadata
+    fn quantization(&self) -> &'static str {
+        "none"
+    }
+
+    /// Whether the model is currently held in memory
+    fn is_loaded(&self) -> bool {
+        true
+    }
+
+    /// Drop the model to free memory until the next request
+    fn unload(&self) {}
 }
 
-impl Embedder {
+/// mixedbread-ai/mxbai-embed-large-v1, run locally by fastembed
+pub struct MxbaiEmbedder {
+    model_cache_dir: PathBuf,
+    /// Use the int8-quantized ONNX export of the model
+    quantized: bool,
+    /// ONNX Runtime intra-op threads
+    threads: usize,
+    /// `None` while unloaded; reloaded on the next embedding request
+    model: Mutex<Option<TextEmbedding>>,
+}
+
+impl MxbaiEmbedder {
     fn model_exists(model_cache_dir: &Path) -> bool {
         Walk::new(model_cache_dir)
             .filter_map(|entry| entry.ok())
             .any(|entry| entry.path().extension().map_or(false, |ext| ext == "onnx"))
     }
 
-    pub fn new(model_cache_dir: &Path) -> Result<Self, Error> {
-        let start_time = Instant::now();
-        
-        let mut options = InitOptions::default().with_cache_dir(model_cache_dir.to_path_buf());
+    fn load_model(
+        model_cache_dir: &Path,
+        quantized: bool,
+        threads: usize,
+    ) -> Result<TextEmbedding> {
+        let mut options = InitOptions::default()
+            .with_cache_dir(model_cache_dir.to_path_buf())
+            .with_intra_threads(threads);
         // Using mixedbread-ai/mxbai-embed-large-v1 - 1024 dimensions, MTEB score 64.68
-        options.model_name = EmbeddingModel::MxbaiEmbedLargeV1;
+        // The quantized export is ~4x smaller and faster on CPU at a small cost in accuracy
+        options.model_name = if quantized {
+            EmbeddingModel::MxbaiEmbedLargeV1Q
+        } else {
+            EmbeddingModel::MxbaiEmbedLargeV1
+        };
+
+        TextEmbedding::try_new(options)
+    }
+
+    pub fn new(model_cache_dir: &Path, quantized: bool, threads: usize) -> Result<Self, Error> {
+        let start_time = Instant::now();
 
         if !Self::model_exists(model_cache_dir) {
-            let size_mb = 600; // Approximate size of the model
+            let size_mb = if quantized { 340 } else { 600 }; // Approximate size of the model
             let message = format!(
                 "The embedding model (~{}MB) needs to be downloaded. This is a one-time operation.",
                 size_mb
             );
 
-            let mut prompt = Confirm::new(&message).prompt()?;
-            let response = prompt.run()?;
+            // Services and containers have nobody to answer the prompt
+            if std::io::stdin().is_terminal() {
+                let mut prompt = Confirm::new(&message).prompt()?;
+                let response = prompt.run()?;
 
-            if response == "n" || response == "N" || response == "no" || response == "No" {
-                return Err(Error::msg("Model download cancelled by user"));
+                if response == "n" || response == "N" || response == "no" || response == "No" {
+                    return Err(Error::msg("Model download cancelled by user"));
+                }
+            } else {
+                info!("{} Downloading it now.", message);
             }
         }
 
-        let model = TextEmbedding::try_new(options)?;
-        
-        debug!("[TIMING] Embedder model loading: {:.3}s", start_time.elapsed().as_secs_f64());
-        
+        let model = Self::load_model(model_cache_dir, quantized, threads)?;
+
+        debug!(
+            "[TIMING] Embedder model loading: {:.3}s",
+            start_time.elapsed().as_secs_f64()
+        );
+
         Ok(Self {
-            model: Mutex::new(model),
-            cache: Mutex::new(HashMap::new()),
+            model_cache_dir: model_cache_dir.to_path_buf(),
+            quantized,
+            threads,
+            model: Mutex::new(Some(model)),
+        })
+    }
+
+    /// Run `f` with the model, reloading it first if it was unloaded
+    fn with_model<T>(&self, f: impl FnOnce(&mut TextEmbedding) -> Result<T>) -> Result<T> {
+        let mut model = self.model.lock().unwrap();
+        if model.is_none() {
+            let start_time = Instant::now();
+            info!("Reloading embedding model...");
+            *model = Some(Self::load_model(
+                &self.model_cache_dir,
+                self.quantized,
+                self.threads,
+            )?);
+            debug!(
+                "[TIMING] Embedder model reloading: {:.3}s",
+                start_time.elapsed().as_secs_f64()
+            );
+        }
+
+        f(model.as_mut().expect("embedding model was just loaded"))
+    }
+}
+
+impl Embed for MxbaiEmbedder {
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        let embeddings = self.with_model(|model| model.embed(texts, None))?;
+        Ok(embeddings.into_iter().map(Embedding).collect())
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.with_model(|model| {
+            // The tokenizer truncates to the model's 512 tokens; counting must not, or token
+            // budgets would take long chunks for short ones
+            let truncation = model.tokenizer.get_truncation().cloned();
+            model
+                .tokenizer
+                .with_truncation(None)
+                .map_err(|e| Error::msg(format!("Failed to disable truncation: {}", e)))?;
+            let encoding = model.tokenizer.encode(text, false);
+            model
+                .tokenizer
+                .with_truncation(truncation)
+                .map_err(|e| Error::msg(format!("Failed to restore truncation: {}", e)))?;
+            let encoding =
+                encoding.map_err(|e| Error::msg(format!("Failed to tokenize text: {}", e)))?;
+            Ok(encoding.get_ids().len())
         })
     }
 
-    pub async fn embed_text(&self, text: &str, file_path: &str) -> Result<Embedding> {
+    fn quantization(&self) -> &'static str {
+        if self.quantized {
+            "int8"
+        } else {
+            "none"
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.model.lock().unwrap().is_some()
+    }
+
+    fn unload(&self) {
+        *self.model.lock().unwrap() = None;
+    }
+}
+
+/// How often background embedding checks whether the queries it yields to are done
+const QUERY_YIELD_POLL: Duration = Duration::from_millis(10);
+
+/// Longest background embedding waits for queries, so a steady stream of them can't stall
+/// indexing forever
+const MAX_QUERY_YIELD: Duration = Duration::from_secs(2);
+
+/// `text` after an embedding instruction, separated by a space unless the instruction
+/// ends in whitespace already
+pub fn with_prefix(prefix: &str, text: &str) -> String {
+    if prefix.is_empty() {
+        text.to_string()
+    } else if prefix.ends_with(char::is_whitespace) {
+        format!("{}{}", prefix, text)
+    } else {
+        format!("{} {}", prefix, text)
+    }
+}
+
+/// An interactive query in flight; background embedding yields to it until it's dropped
+pub struct QueryPriority(Arc<AtomicUsize>);
+
+impl Drop for QueryPriority {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Embeds code and queries with an [`Embed`] model, caching embeddings of repeated texts
+pub struct Embedder {
+    model: Box<dyn Embed>,
+    cache: Mutex<HashMap<u64, Embedding>>,
+    /// Length embeddings are truncated to; `None` keeps the model's full length
+    dimensions: Option<usize>,
+    /// Interactive queries in flight (see [`Embedder::prioritize_query`])
+    pending_queries: Arc<AtomicUsize>,
+}
+
+impl Embedder {
+    /// Embedder with the local mxbai model from `model_cache_dir`, run on `threads` threads
+    pub fn new(model_cache_dir: &Path, quantized: bool, threads: usize) -> Result<Self, Error> {
+        Ok(Self::with_model(Box::new(MxbaiEmbedder::new(
+            model_cache_dir,
+            quantized,
+            threads,
+        )?)))
+    }
+
+    /// Embedder embedding with `model`
+    pub fn with_model(model: Box<dyn Embed>) -> Self {
+        Self {
+            model,
+            cache: Mutex::new(HashMap::new()),
+            dimensions: None,
+            pending_queries: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Truncate embeddings to their first `dimensions` and renormalize them
+    ///
+    /// mxbai-embed-large is trained with Matryoshka representation learning, so its leading
+    /// dimensions carry most of the meaning on their own.
+    pub fn with_dimensions(mut self, dimensions: Option<usize>) -> Result<Self> {
+        if let Some(dimensions) = dimensions {
+            if dimensions == 0
+                || dimensions % 8 != 0
+                || dimensions > constants::EMBEDDING_DIMENSIONS
+            {
+                bail!(
+                    "embedding.dimensions must be a multiple of 8 up to {}, got {}",
+                    constants::EMBEDDING_DIMENSIONS,
+                    dimensions
+                );
+            }
+        }
+        self.dimensions = dimensions.filter(|d| *d < constants::EMBEDDING_DIMENSIONS);
+        Ok(self)
+    }
+
+    /// Length of the embeddings this embedder returns
+    pub fn dimensions(&self) -> usize {
+        self.dimensions.unwrap_or(constants::EMBEDDING_DIMENSIONS)
+    }
+
+    /// `embedding` cut down to the configured dimensions
+    fn truncate(&self, embedding: Embedding) -> Embedding {
+        let Some(dimensions) = self.dimensions else {
+            return embedding;
+        };
+        let mut values = embedding.0;
+        values.truncate(dimensions);
+        let norm = values.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            values.iter_mut().for_each(|value| *value /= norm);
+        }
+        Embedding(values)
+    }
+
+    /// Quantization of the model's weights, as recorded in index metadata
+    pub fn quantization(&self) -> &'static str {
+        self.model.quantization()
+    }
+
+    /// Whether the model is currently held in memory
+    pub fn is_loaded(&self) -> bool {
+        self.model.is_loaded()
+    }
+
+    /// Drop the model and the embedding cache to free memory until the next request
+    pub fn unload(&self) {
+        self.model.unload();
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Number of tokens the model's tokenizer produces for `text`, even past the model's
+    /// input length
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.model.count_tokens(text)
+    }
+
+    /// Mark an interactive query as in flight until the returned guard is dropped, so
+    /// background indexing pauses at its next [`Embedder::yield_to_queries`]
+    pub fn prioritize_query(&self) -> QueryPriority {
+        self.pending_queries.fetch_add(1, Ordering::SeqCst);
+        QueryPriority(Arc::clone(&self.pending_queries))
+    }
+
+    /// Wait while interactive queries are in flight, for at most [`MAX_QUERY_YIELD`]
+    ///
+    /// Background indexing calls this between small batches of work, so queries don't
+    /// compete with it for the model, the CPU and the index lock.
+    pub async fn yield_to_queries(&self) {
+        let start = Instant::now();
+        while self.pending_queries.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= MAX_QUERY_YIELD {
+                debug!("Queries kept coming, resuming indexing anyway");
+                return;
+            }
+            tokio::time::sleep(QUERY_YIELD_POLL).await;
+        }
+    }
+
+    /// Embed the code `text` of `file_path`, after `prefix` (`embedding.document_prefix`)
+    pub async fn embed_text(&self, text: &str, file_path: &str, prefix: &str) -> Result<Embedding> {
         use std::hash::{Hash, Hasher};
 
-        let processed = self.preprocess_code(text, file_path);
+        let processed = with_prefix(prefix, &self.preprocess_code(text, file_path));
 
         let mut hasher = std::collections::hash_map::DefaultHasher::new();
         processed.hash(&mut hasher);
@@ -77,9 +349,7 @@ impl Embedder {
             }
         }
 
-        let mut model = self.model.lock().unwrap();
-        let embeddings = model.embed(vec![&processed], None)?;
-        let embedding_result = Embedding(embeddings[0].clone());
+        let embedding_result = self.truncate(self.model.embed(&[&processed])?.remove(0));
 
         {
             let mut cache = self.cache.lock().unwrap();
@@ -89,15 +359,30 @@ impl Embedder {
         Ok(embedding_result)
     }
 
-    pub async fn embed_query(&self, query: &str) -> Result<Embedding> {
+    /// Embed queries (and query expansions) with a single model call, each after `prefix`
+    /// (`embedding.query_prefix`)
+    pub async fn embed_queries(&self, queries: &[&str], prefix: &str) -> Result<Vec<Embedding>> {
         let start_time = Instant::now();
-        
-        let mut model = self.model.lock().unwrap();
-        let embeddings = model.embed(vec![query], None)?;
-        
-        debug!("[TIMING] Query embedding: {:.3}s", start_time.elapsed().as_secs_f64());
-        
-        Ok(Embedding(embeddings[0].clone()))
+
+        let prefixed: Vec<String> = queries
+            .iter()
+            .map(|query| with_prefix(prefix, query))
+            .collect();
+        let prefixed: Vec<&str> = prefixed.iter().map(String::as_str).collect();
+        let embeddings: Vec<Embedding> = self
+            .model
+            .embed(&prefixed)?
+            .into_iter()
+            .map(|embedding| self.truncate(embedding))
+            .collect();
+
+        debug!(
+            "[TIMING] Embedding {} queries: {:.3}s",
+            queries.len(),
+            start_time.elapsed().as_secs_f64()
+        );
+
+        Ok(embeddings)
     }
 
     fn preprocess_code(&self, text: &str, file_path: &str) -> String {
@@ -110,8 +395,13 @@ impl Embedder {
 
         let language: Language = match ext {
             Some("rs") => RUST_LANGUAGE.into(),
-            Some("py") => PYTHON_LANGUAGE.into(),
+            Some("py" | "ipynb") => PYTHON_LANGUAGE.into(),
             Some("js" | "ts") => JS_LANGUAGE.into(),
+            Some("rb") => RUBY_LANGUAGE.into(),
+            Some("php") => PHP_LANGUAGE.into(),
+            Some("cs") => CSHARP_LANGUAGE.into(),
+            Some("swift") => SWIFT_LANGUAGE.into(),
+            Some("sh" | "bash" | "zsh") => BASH_LANGUAGE.into(),
             _ => JS_LANGUAGE.into(), // default
         };
 
@@ -140,7 +430,7 @@ impl Embedder {
                 name: (identifier) @trait_name
             ) @trait
             "#
-        } else if ext == Some("py") {
+        } else if matches!(ext, Some("py" | "ipynb")) {
             r#"
             (function_definition
                 name: (identifier) @name
@@ -153,6 +443,64 @@ impl Embedder {
                 body: (block) @body
             ) @class
             "#
+        } else if ext == Some("rb") {
+            r#"
+            (method
+                name: (_) @name
+                parameters: (method_parameters)? @params
+            ) @function
+
+            (class
+                name: (_) @name
+            ) @class
+
+            (module
+                name: (_) @name
+            ) @class
+            "#
+        } else if ext == Some("php") {
+            r#"
+            (function_definition
+                name: (name) @name
+                parameters: (formal_parameters) @params
+            ) @function
+
+            (method_declaration
+                name: (name) @name
+                parameters: (formal_parameters) @params
+            ) @method
+
+            (class_declaration
+                name: (name) @name
+            ) @class
+            "#
+        } else if ext == Some("cs") {
+            r#"
+            (method_declaration
+                name: (identifier) @name
+                parameters: (parameter_list) @params
+            ) @method
+
+            (class_declaration
+                name: (identifier) @name
+            ) @class
+            "#
+        } else if matches!(ext, Some("sh" | "bash" | "zsh")) {
+            r#"
+            (function_definition
+                name: (word) @name
+            ) @function
+            "#
+        } else if ext == Some("swift") {
+            r#"
+            (function_declaration
+                name: (simple_identifier) @name
+            ) @function
+
+            (class_declaration
+                name: (_) @name
+            ) @class
+            "#
         } else {
             r#"
             (function_declaration
@@ -0,0 +1,560 @@
+//! `ragrep eval`: search quality on a labeled query set (recall@k, MRR and latency), for
+//! comparing chunking, model and reranker settings on the same index
+//!
+//! `ragrep eval --generate` bootstraps a query set from git history: the summary of each
+//! recent commit is a query for the definitions it changed.
+
+use anyhow::{bail, Context as AnyhowContext, Result};
+use git2::{DiffOptions, Repository, Sort};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::bench::{self, Percentiles};
+use crate::chunker::{self, Chunker};
+use crate::context::AppContext;
+use crate::db::Database;
+use crate::protocol::{SearchRequest, SearchResult};
+use crate::server;
+
+/// Most cases generated from one commit, so a large refactoring doesn't dominate the set
+const MAX_CASES_PER_COMMIT: usize = 3;
+
+/// Fewest words of a commit summary used as a query; shorter ones ("Fix typo") say too
+/// little about the code
+const MIN_QUERY_WORDS: usize = 3;
+
+/// A query and the code it should find
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct EvalCase {
+    pub query: String,
+    /// File the answer is in, relative to the project root
+    pub file: String,
+    /// Line of the answer; any result from `file` counts when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<i32>,
+}
+
+impl EvalCase {
+    /// Whether `result` is (or contains) the expected answer
+    fn matches(&self, result: &SearchResult) -> bool {
+        let file = self.file.strip_prefix("./").unwrap_or(&self.file);
+        result.file_path == file
+            && self
+                .line
+                .is_none_or(|line| (result.start_line..=result.end_line).contains(&line))
+    }
+
+    /// Rank (from 1) of the first of `results` that is the expected answer
+    fn first_hit(&self, results: &[SearchResult]) -> Option<usize> {
+        results
+            .iter()
+            .position(|result| self.matches(result))
+            .map(|index| index + 1)
+    }
+}
+
+/// A query set file: `[[queries]]` tables in TOML, or `{"queries": [...]}` in JSON
+#[derive(Deserialize, Serialize)]
+struct EvalSet {
+    queries: Vec<EvalCase>,
+}
+
+/// Read the cases of a `.toml` or `.json` query set
+pub fn load_cases(path: &Path) -> Result<Vec<EvalCase>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read query set: {}", path.display()))?;
+    let set: EvalSet = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&content)
+            .with_context(|| format!("Invalid query set {}", path.display()))?,
+        Some("toml") => toml::from_str(&content)
+            .with_context(|| format!("Invalid query set {}", path.display()))?,
+        _ => bail!("Query set {} must be a .toml or .json file", path.display()),
+    };
+    info!(
+        "Loaded {} queries from {}",
+        set.queries.len(),
+        path.display()
+    );
+    Ok(set.queries)
+}
+
+/// Write `cases` as a query set to `path` (`.toml` or `.json`), which must not exist yet
+pub fn write_cases(path: &Path, cases: &[EvalCase]) -> Result<()> {
+    if path.exists() {
+        bail!(
+            "{} already exists; remove it or choose another path",
+            path.display()
+        );
+    }
+    let set = EvalSet {
+        queries: cases.to_vec(),
+    };
+    let content = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::to_string_pretty(&set)? + "\n",
+        Some("toml") => format!(
+            "# Generated from git history by `ragrep eval --generate`; review the queries and\n\
+             # expected answers before relying on the numbers\n\n{}",
+            toml::to_string(&set)?
+        ),
+        _ => bail!("Query set {} must be a .toml or .json file", path.display()),
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write query set: {}", path.display()))
+}
+
+/// Query made of a commit summary, without a leading `[tag]` or `type(scope):` prefix;
+/// `None` for reverts and summaries too short to describe code
+fn commit_query(summary: &str) -> Option<String> {
+    let mut query = summary.trim();
+    if let Some(rest) = query.strip_prefix('[') {
+        query = rest
+            .split_once(']')
+            .map_or(query, |(_, rest)| rest.trim_start());
+    }
+    if let Some((prefix, rest)) = query.split_once(": ") {
+        let kind = prefix
+            .split('(')
+            .next()
+            .unwrap_or(prefix)
+            .trim_end_matches('!');
+        if !kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            query = rest.trim_start();
+        }
+    }
+
+    let is_revert = query.starts_with("Revert ") || query.starts_with("revert ");
+    (!is_revert && query.split_whitespace().count() >= MIN_QUERY_WORDS).then(|| query.to_string())
+}
+
+/// Cases from the last `commits` commits of the git repository holding the index of `db`
+///
+/// Each commit's summary becomes a query for the definitions its diff touched (innermost
+/// first), found in the file as it was committed and located where they are now through
+/// the symbol table, so later edits that moved them don't matter. Merges, the root commit
+/// (which adds everything), definitions since removed and files outside the index are
+/// skipped.
+pub fn generate_cases(
+    db: &Database,
+    chunker: &mut Chunker,
+    commits: usize,
+) -> Result<Vec<EvalCase>> {
+    let root = db.root();
+    let repo = Repository::discover(root)
+        .with_context(|| format!("{} is not in a git repository", root.display()))?;
+    let workdir = repo
+        .workdir()
+        .context("Can't generate queries from a bare repository")?
+        .canonicalize()?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.simplify_first_parent()?;
+
+    let mut cases = Vec::new();
+    for oid in revwalk.take(commits) {
+        let commit = repo.find_commit(oid?)?;
+        if commit.parent_count() != 1 {
+            continue;
+        }
+        let Some(query) = commit.summary().and_then(commit_query) else {
+            continue;
+        };
+
+        // New-side line ranges of the changes, by file
+        let tree = commit.tree()?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&commit.parent(0)?.tree()?),
+            Some(&tree),
+            Some(DiffOptions::new().context_lines(0)),
+        )?;
+        let mut changes: BTreeMap<PathBuf, Vec<(usize, usize)>> = BTreeMap::new();
+        diff.foreach(
+            &mut |_, _| true,
+            None,
+            Some(&mut |delta, hunk| {
+                if let Some(path) = delta.new_file().path() {
+                    // A deletion has no new lines; it changed the code around its position
+                    let start = hunk.new_start().max(1) as usize;
+                    let end = start + hunk.new_lines().max(1) as usize - 1;
+                    changes
+                        .entry(path.to_path_buf())
+                        .or_default()
+                        .push((start, end));
+                }
+                true
+            }),
+            None,
+        )?;
+
+        let mut commit_cases: Vec<EvalCase> = Vec::new();
+        for (path, ranges) in changes {
+            let Ok(relative) = workdir
+                .join(&path)
+                .strip_prefix(root)
+                .map(Path::to_path_buf)
+            else {
+                continue;
+            };
+            let Ok(blob) = tree
+                .get_path(&path)
+                .and_then(|entry| entry.to_object(&repo))
+            else {
+                continue;
+            };
+            let Some(content) = blob
+                .as_blob()
+                .and_then(|blob| std::str::from_utf8(blob.content()).ok())
+            else {
+                continue;
+            };
+            let chunks = match chunker.chunk_file(&root.join(&relative), content) {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    warn!(
+                        "Skipping {} of {}: {}",
+                        path.display(),
+                        oid_short(&commit),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let mut touched: Vec<_> = chunks
+                .into_iter()
+                .filter(|chunk| {
+                    ranges
+                        .iter()
+                        .any(|&(start, end)| chunk.start_line <= end && start <= chunk.end_line)
+                })
+                .collect();
+            touched.sort_by_key(|chunk| chunk.end_line - chunk.start_line);
+
+            let file = relative.to_string_lossy().to_string();
+            let current = db.file_symbols(&file)?;
+            for symbol in chunker::symbols(&touched) {
+                // The definition of that name nearest to where it was, should there be several
+                let Some(line) = current
+                    .iter()
+                    .filter(|current| current.name == symbol.name && current.kind == symbol.kind)
+                    .map(|current| current.line as i32)
+                    .min_by_key(|line| line.abs_diff(symbol.line as i32))
+                else {
+                    continue;
+                };
+                if !commit_cases
+                    .iter()
+                    .any(|case| case.file == file && case.line == Some(line))
+                {
+                    commit_cases.push(EvalCase {
+                        query: query.clone(),
+                        file: file.clone(),
+                        line: Some(line),
+                    });
+                }
+            }
+        }
+
+        debug!(
+            "{} {:?}: {} expected answers",
+            oid_short(&commit),
+            query,
+            commit_cases.len()
+        );
+        commit_cases.truncate(MAX_CASES_PER_COMMIT);
+        cases.extend(commit_cases);
+    }
+    Ok(cases)
+}
+
+/// Abbreviated id of `commit`, for logs
+fn oid_short(commit: &git2::Commit) -> String {
+    commit.id().to_string()[..8].to_string()
+}
+
+/// How each query of a set fared
+pub struct EvalReport {
+    /// Rank of each case's answer among its results, if it was found
+    pub ranks: Vec<Option<usize>>,
+    /// Results searched per query
+    pub k: usize,
+    pub latency: Percentiles,
+}
+
+impl EvalReport {
+    /// Share of the queries whose answer is among their first `k` results
+    pub fn recall_at(&self, k: usize) -> f64 {
+        let hits = self
+            .ranks
+            .iter()
+            .filter(|rank| rank.is_some_and(|rank| rank <= k))
+            .count();
+        hits as f64 / self.ranks.len().max(1) as f64
+    }
+
+    /// Mean reciprocal rank of the answers, counting answers not found as 0
+    pub fn mrr(&self) -> f64 {
+        let sum: f64 = self
+            .ranks
+            .iter()
+            .map(|rank| rank.map_or(0.0, |rank| 1.0 / rank as f64))
+            .sum();
+        sum / self.ranks.len().max(1) as f64
+    }
+}
+
+/// Search for every case with `request(query)` and see where its answer ranks
+pub async fn run_eval(
+    ctx: &mut AppContext,
+    cases: &[EvalCase],
+    request: impl Fn(&str) -> SearchRequest,
+) -> Result<EvalReport> {
+    // Warm up once so model initialization doesn't skew the first latency
+    if let Some(case) = cases.first() {
+        server::execute_search(ctx, request(&case.query)).await?;
+    }
+
+    let mut ranks = Vec::with_capacity(cases.len());
+    let mut latencies = Vec::with_capacity(cases.len());
+    let mut k = 0;
+    for case in cases {
+        let search = request(&case.query);
+        k = search.top_n;
+        let (response, timings) = server::execute_search_timed(ctx, search).await?;
+        let rank = case.first_hit(&response.results);
+        debug!("{:?}: answer at rank {:?}", case.query, rank);
+        ranks.push(rank);
+        latencies.push(timings.total);
+    }
+
+    Ok(EvalReport {
+        ranks,
+        k,
+        latency: bench::percentiles(latencies),
+    })
+}
+
+/// Print the metrics of `report`, then the queries whose answer wasn't found
+pub fn print_report(cases: &[EvalCase], report: &EvalReport) {
+    println!("Evaluated {} queries", cases.len());
+    let mut ks = vec![1, 5, report.k];
+    ks.retain(|&k| k <= report.k);
+    ks.dedup();
+    for k in ks {
+        println!(
+            "  {:<10} {:>6.3}",
+            format!("recall@{}", k),
+            report.recall_at(k)
+        );
+    }
+    println!("  {:<10} {:>6.3}", "MRR", report.mrr());
+    println!(
+        "  {:<10} p50 {:.1}ms, p95 {:.1}ms",
+        "latency",
+        report.latency.p50.as_secs_f64() * 1000.0,
+        report.latency.p95.as_secs_f64() * 1000.0
+    );
+
+    let misses: Vec<&EvalCase> = cases
+        .iter()
+        .zip(&report.ranks)
+        .filter(|(_, rank)| rank.is_none())
+        .map(|(case, _)| case)
+        .collect();
+    if !misses.is_empty() {
+        println!();
+        println!("Not found in the top {}:", report.k);
+        for case in misses {
+            match case.line {
+                Some(line) => println!("  {:?} (expected {}:{})", case.query, case.file, line),
+                None => println!("  {:?} (expected {})", case.query, case.file),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn result(file_path: &str, start_line: i32, end_line: i32) -> SearchResult {
+        SearchResult {
+            id: None,
+            file_path: file_path.to_string(),
+            start_line,
+            end_line,
+            text: String::new(),
+            score: 0.5,
+            kind: "function".to_string(),
+            name: None,
+            modified_at: None,
+            explanation: None,
+        }
+    }
+
+    #[test]
+    fn test_load_cases() {
+        let dir = std::env::temp_dir().join(format!("ragrep-eval-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected = vec![
+            EvalCase {
+                query: "retry with backoff".to_string(),
+                file: "src/retry.rs".to_string(),
+                line: Some(9),
+            },
+            EvalCase {
+                query: "open the database".to_string(),
+                file: "src/db.rs".to_string(),
+                line: None,
+            },
+        ];
+
+        let toml = dir.join("queries.toml");
+        std::fs::write(
+            &toml,
+            "[[queries]]\nquery = \"retry with backoff\"\nfile = \"src/retry.rs\"\nline = 9\n\n\
+             [[queries]]\nquery = \"open the database\"\nfile = \"src/db.rs\"\n",
+        )
+        .unwrap();
+        assert_eq!(load_cases(&toml).unwrap(), expected);
+
+        let json = dir.join("queries.json");
+        std::fs::write(
+            &json,
+            r#"{"queries": [
+                {"query": "retry with backoff", "file": "src/retry.rs", "line": 9},
+                {"query": "open the database", "file": "src/db.rs"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(load_cases(&json).unwrap(), expected);
+
+        assert!(load_cases(&dir.join("queries.txt")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_hit() {
+        let results = [
+            result("src/a.rs", 1, 5),
+            result("src/retry.rs", 20, 30),
+            result("src/retry.rs", 5, 12),
+        ];
+        let case = |file: &str, line| EvalCase {
+            query: String::new(),
+            file: file.to_string(),
+            line,
+        };
+        assert_eq!(case("src/retry.rs", Some(9)).first_hit(&results), Some(3));
+        assert_eq!(case("./src/retry.rs", None).first_hit(&results), Some(2));
+        assert_eq!(case("src/retry.rs", Some(15)).first_hit(&results), None);
+    }
+
+    #[test]
+    fn test_commit_query() {
+        assert_eq!(
+            commit_query("Retry failed uploads with backoff").as_deref(),
+            Some("Retry failed uploads with backoff")
+        );
+        assert_eq!(
+            commit_query("fix(upload)!: retry failed uploads").as_deref(),
+            Some("retry failed uploads")
+        );
+        assert_eq!(
+            commit_query("[PROJ-12] Cache parsed config files").as_deref(),
+            Some("Cache parsed config files")
+        );
+        assert_eq!(commit_query("Fix typo"), None);
+        assert_eq!(commit_query("Revert \"Cache parsed config files\""), None);
+    }
+
+    #[test]
+    fn test_generate_cases() {
+        let root = std::env::temp_dir()
+            .join(format!("ragrep-eval-git-{}", std::process::id()))
+            .join("repo");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let repo = Repository::init(&root).unwrap();
+        let signature = git2::Signature::now("Ana", "ana@example.com").unwrap();
+        let commit = |content: &str, message: &str| {
+            std::fs::write(root.join("src/upload.rs"), content).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("src/upload.rs")).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+            let parents: Vec<_> = parent.iter().collect();
+            repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents,
+            )
+            .unwrap();
+        };
+
+        let original = "fn connect() {\n    open();\n}\n\nfn upload() {\n    send();\n}\n";
+        let retrying = original.replace("    send();", "    send();\n    retry();");
+        let lazy = retrying.replace("open()", "open_lazily()");
+        commit(original, "Add the upload client");
+        commit(&retrying, "Retry failed uploads");
+        commit(&lazy, "Open connections lazily");
+        commit(&lazy.replace("retry", "retry_later"), "Fix typo");
+        // Both functions have since moved down
+        let current = format!("fn ping() {{}}\n\n{}", lazy);
+        std::fs::write(root.join("src/upload.rs"), &current).unwrap();
+
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let mut chunker = Chunker::new(&crate::config::ChunkingConfig {
+            file_summaries: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let file = root.join("src/upload.rs");
+        let chunks = chunker.chunk_file(&file, &current).unwrap();
+        db.save_symbols(&file.to_string_lossy(), &chunker::symbols(&chunks))
+            .unwrap();
+
+        let cases = generate_cases(&db, &mut chunker, 10).unwrap();
+        let case = |query: &str, line| EvalCase {
+            query: query.to_string(),
+            file: "src/upload.rs".to_string(),
+            line: Some(line),
+        };
+        // "Fix typo" is too short a query, and the root commit added everything
+        assert_eq!(
+            cases,
+            vec![
+                case("Open connections lazily", 3),
+                case("Retry failed uploads", 7)
+            ]
+        );
+        assert_eq!(generate_cases(&db, &mut chunker, 1).unwrap(), vec![]);
+
+        let set = root.join("queries.toml");
+        write_cases(&set, &cases).unwrap();
+        assert_eq!(load_cases(&set).unwrap(), cases);
+        assert!(write_cases(&set, &cases).is_err());
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let report = EvalReport {
+            ranks: vec![Some(1), Some(2), None, Some(10)],
+            k: 10,
+            latency: bench::percentiles(vec![Duration::from_millis(5)]),
+        };
+        assert_eq!(report.recall_at(1), 0.25);
+        assert_eq!(report.recall_at(5), 0.5);
+        assert_eq!(report.recall_at(10), 0.75);
+        assert!((report.mrr() - (1.0 + 0.5 + 0.1) / 4.0).abs() < 1e-9);
+    }
+}
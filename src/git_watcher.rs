@@ -1,22 +1,25 @@
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use git2::Repository;
-use ignore::gitignore::GitignoreBuilder;
 use log::{debug, warn};
-use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashSet;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{
+    Config, Event, EventHandler, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::{
-    mpsc::{channel, Receiver},
+    mpsc::{channel, Receiver, Sender},
     Arc, Mutex as StdMutex,
 };
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
 
+use crate::config::{GitWatchConfig, IndexConfig, WatchBackend};
 use crate::constants::constants;
+use crate::indexer::{IgnoreRules, NestedRepositories};
 
-/// Get the git working directory for a path
-fn get_git_workdir(path: &Path) -> Result<PathBuf> {
-    let repo = Repository::discover(path).context("Failed to find git repository")?;
-
+/// Get the git working directory of a repository
+fn get_git_workdir(repo: &Repository) -> Result<PathBuf> {
     let workdir = repo
         .workdir()
         .ok_or_else(|| anyhow!("Repository has no working directory"))?
@@ -27,14 +30,213 @@ fn get_git_workdir(path: &Path) -> Result<PathBuf> {
     Ok(workdir)
 }
 
+/// The files that move with the HEAD of each submodule checked out in `repo` (and in its
+/// submodules), each with the submodule's working tree
+///
+/// A checkout rewrites `HEAD`, while a commit on a branch only appends to its reflog,
+/// `logs/HEAD`.
+fn submodule_heads(repo: &Repository) -> HashMap<PathBuf, PathBuf> {
+    let mut heads = HashMap::new();
+    for submodule in repo.submodules().unwrap_or_default() {
+        let Ok(submodule) = submodule.open() else {
+            continue;
+        };
+        let Some(workdir) = submodule.workdir() else {
+            continue;
+        };
+        for head in ["HEAD", "logs/HEAD"] {
+            heads.insert(submodule.path().join(head), workdir.to_path_buf());
+        }
+        heads.extend(submodule_heads(&submodule));
+    }
+    heads
+}
+
+/// Name of the branch checked out at `path`; `None` outside a repository or on a detached HEAD
+pub fn current_branch(path: &Path) -> Option<String> {
+    let repo = Repository::discover(path).ok()?;
+    let head = repo.head().ok()?;
+    if !head.is_branch() {
+        return None;
+    }
+    head.shorthand().map(str::to_string)
+}
+
 /// Check if the given path is in a git repository
 fn is_git_repo(path: &Path) -> bool {
     Repository::discover(path).is_ok()
 }
 
+/// A change the watcher reports
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FileChange {
+    /// A source file was created, modified or removed
+    Changed(PathBuf),
+    /// A file or directory was moved within the watched tree
+    Renamed { from: PathBuf, to: PathBuf },
+    /// A file or directory was moved where it isn't indexed (an ignored directory, say)
+    Removed(PathBuf),
+    /// An ignore file (`.gitignore`, `.ignore` or `.ragrepignore`) changed, which may hide
+    /// or reveal any file in its directory
+    IgnoreFile(PathBuf),
+    /// The HEAD of the submodule checked out in this directory moved (a commit, a checkout
+    /// or `git submodule update`), which may change any file in it
+    Submodule(PathBuf),
+}
+
+/// Keeps a started watch running; stopping or dropping it ends the watch
+///
+/// Once stopped, the receivers returned with the handle disconnect.
+pub struct WatchHandle {
+    watcher: Option<Box<dyn Watcher + Send>>,
+    /// Task flushing debounced changes (`watch_debounced` only)
+    debounce: Option<JoinHandle<()>>,
+}
+
+impl WatchHandle {
+    /// Stop watching; does nothing if already stopped
+    pub fn stop(&mut self) {
+        if self.watcher.take().is_some() {
+            debug!("File watcher stopped");
+        }
+        if let Some(debounce) = self.debounce.take() {
+            debounce.abort();
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Whether `path` names an ignore file the indexer's walk honors
+fn is_ignore_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        name == ".gitignore" || name == ".ignore" || name == constants::RAGREP_IGNORE_FILENAME
+    })
+}
+
+/// Turns watcher events into the changes to reindex
+#[derive(Clone)]
+struct ChangeHandler {
+    /// Extensions to report and directories to skip
+    config: IndexConfig,
+    ignore_rules: Arc<IgnoreRules>,
+    nested_repositories: Arc<NestedRepositories>,
+    /// Working trees of the submodules, by the files that move with their HEAD
+    submodule_heads: Arc<HashMap<PathBuf, PathBuf>>,
+    tx: Sender<FileChange>,
+}
+
+impl EventHandler for ChangeHandler {
+    fn handle_event(&mut self, res: notify::Result<Event>) {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Watch error: {:?}", e);
+                return;
+            }
+        };
+
+        // Paths outside build directories and ignore files, where files are indexed. The
+        // `.ragrep` directory is never watched: its manifest and metrics change with every
+        // reindex, and a move into place there would trigger the next one.
+        let watched = |path: &Path| {
+            let excluded = path.components().any(|component| {
+                component.as_os_str().to_str().is_some_and(|name| {
+                    name == constants::RAGREP_DIR_NAME || self.config.is_excluded_dir(name)
+                })
+            });
+            if excluded {
+                return false;
+            }
+            if !self.config.submodules && self.nested_repositories.contains(path) {
+                return false;
+            }
+            if self.ignore_rules.is_ignored(path) {
+                debug!("Ignoring file (ignore files): {}", path.display());
+                return false;
+            }
+            true
+        };
+
+        // Git writes HEAD by moving a lock file over it, so this comes before moves
+        if let Some(workdir) = event
+            .paths
+            .iter()
+            .find_map(|path| self.submodule_heads.get(path))
+        {
+            debug!("Submodule HEAD moved: {}", workdir.display());
+            let _ = self.tx.send(FileChange::Submodule(workdir.clone()));
+            return;
+        }
+
+        // A move within the tree, reported with both paths; this covers directories,
+        // whose files get no events of their own
+        if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) =
+            (event.kind, event.paths.as_slice())
+        {
+            if watched(to) {
+                debug!("Renamed: {} -> {}", from.display(), to.display());
+                let _ = self.tx.send(FileChange::Renamed {
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            } else if watched(from) {
+                debug!("Moved out of view: {}", from.display());
+                let _ = self.tx.send(FileChange::Removed(from.clone()));
+            }
+            return;
+        }
+
+        // Handle modify, remove, and create events
+        let should_process = matches!(
+            event.kind,
+            EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Create(_)
+        );
+        if !should_process {
+            return;
+        }
+
+        for path in event.paths {
+            if is_ignore_file(&path) && watched(&path) {
+                debug!("Ignore file changed: {}", path.display());
+                let _ = self.tx.send(FileChange::IgnoreFile(path));
+                continue;
+            }
+
+            // Only process source files
+            if watched(&path) && self.config.includes_file(&path) {
+                match event.kind {
+                    EventKind::Modify(_) => {
+                        debug!("File modified: {}", path.display());
+                    }
+                    EventKind::Remove(_) => {
+                        debug!("File removed: {}", path.display());
+                    }
+                    EventKind::Create(_) => {
+                        debug!("File created: {}", path.display());
+                    }
+                    _ => {}
+                }
+                let _ = self.tx.send(FileChange::Changed(path));
+            }
+        }
+    }
+}
+
 /// Watches source files in working directory for changes
 pub struct GitFileWatcher {
     watch_path: PathBuf,
+    /// Extensions to report and directories to skip
+    config: IndexConfig,
+    backend: WatchBackend,
+    poll_interval_ms: u64,
+    /// Working trees of the submodules (when indexed), by the files that move with their
+    /// HEAD; submodules checked out later are watched after a restart
+    submodule_heads: HashMap<PathBuf, PathBuf>,
 }
 
 impl GitFileWatcher {
@@ -44,8 +246,18 @@ impl GitFileWatcher {
     }
 
     /// Create a new file watcher for git-tracked files
-    pub fn new(base_path: &Path) -> Result<Self> {
-        let watch_path = get_git_workdir(base_path)?;
+    pub fn new(
+        base_path: &Path,
+        config: &IndexConfig,
+        watch_config: &GitWatchConfig,
+    ) -> Result<Self> {
+        let repo = Repository::discover(base_path).context("Failed to find git repository")?;
+        let watch_path = get_git_workdir(&repo)?;
+        let submodule_heads = if config.submodules {
+            submodule_heads(&repo)
+        } else {
+            HashMap::new()
+        };
 
         debug!("Watching source files at: {:?}", watch_path);
         debug!(
@@ -53,146 +265,112 @@ impl GitFileWatcher {
             constants::RAGREP_IGNORE_FILENAME
         );
 
-        Ok(Self { watch_path })
+        Ok(Self {
+            watch_path,
+            config: config.clone(),
+            backend: watch_config.backend,
+            poll_interval_ms: watch_config.poll_interval_ms,
+            submodule_heads,
+        })
     }
 
-    /// Start watching for changes, returns a channel that receives changed file paths
-    pub fn watch(&self) -> Result<Receiver<PathBuf>> {
+    /// Start watching for changes, returns a channel that receives the changes
+    ///
+    /// The watch runs until the returned handle is stopped or dropped.
+    pub fn watch(&self) -> Result<(WatchHandle, Receiver<FileChange>)> {
         let (tx, rx) = channel();
-        let watch_path = self.watch_path.clone();
-
-        // Rebuild gitignore matcher in closure (since Gitignore isn't easily cloneable)
-        let mut builder = GitignoreBuilder::new(&watch_path);
-
-        // Add .gitignore from repo root
-        let gitignore_path = watch_path.join(".gitignore");
-        if gitignore_path.exists() {
-            if let Ok(content) = std::fs::read_to_string(&gitignore_path) {
-                let _ = builder.add_line(None, &content);
-            }
-        }
-
-        // Add .ragrepignore if exists
-        let ragrepignore_path = watch_path.join(constants::RAGREP_IGNORE_FILENAME);
-        if ragrepignore_path.exists() {
-            if let Ok(content) = std::fs::read_to_string(&ragrepignore_path) {
-                let _ = builder.add_line(None, &content);
+        let handler = ChangeHandler {
+            config: self.config.clone(),
+            // Ignore files at every level, as the indexer's walk honors them
+            ignore_rules: Arc::new(IgnoreRules::new(&self.watch_path)),
+            nested_repositories: Arc::new(NestedRepositories::new(&self.watch_path)),
+            submodule_heads: Arc::new(self.submodule_heads.clone()),
+            tx,
+        };
+
+        let mut watcher: Box<dyn Watcher + Send> = match self.backend {
+            WatchBackend::Poll => Box::new(self.poll_watcher(handler)?),
+            WatchBackend::Native => match self.native_watcher(handler.clone()) {
+                Ok(watcher) => Box::new(watcher),
+                Err(e) => {
+                    // Out of inotify watches, or a filesystem without notifications
+                    warn!(
+                        "Native file watching failed ({}), polling every {}ms instead",
+                        e, self.poll_interval_ms
+                    );
+                    Box::new(self.poll_watcher(handler)?)
+                }
+            },
+        };
+
+        // Submodule git directories usually live in the parent's `.git`, watched with the
+        // rest of the tree; only ones kept elsewhere need watches of their own
+        let head_dirs: HashSet<&Path> = self
+            .submodule_heads
+            .keys()
+            .filter_map(|head| head.parent())
+            .filter(|dir| !dir.starts_with(&self.watch_path) && dir.is_dir())
+            .collect();
+        for dir in head_dirs {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch submodule HEAD in {}: {}", dir.display(), e);
             }
         }
 
-        let gitignore = builder
-            .build()
-            .unwrap_or_else(|_| GitignoreBuilder::new(&watch_path).build().unwrap());
-
-        let mut watcher = RecommendedWatcher::new(
-            move |res: Result<Event, notify::Error>| {
-                match res {
-                    Ok(event) => {
-                        // Handle modify, remove, and create events
-                        let should_process = matches!(
-                            event.kind,
-                            EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Create(_)
-                        );
-
-                        if should_process {
-                            for path in event.paths {
-                                // Check if path should be ignored (gitignore, ragrepignore, build dirs, etc.)
-                                let relative_path = path.strip_prefix(&watch_path).unwrap_or(&path);
-                                if gitignore.matched(relative_path, path.is_dir()).is_ignore() {
-                                    debug!(
-                                        "Ignoring file (gitignore/ragrepignore): {}",
-                                        path.display()
-                                    );
-                                    continue;
-                                }
-
-                                // Check common build directories
-                                let components: Vec<_> = path.components().collect();
-                                let mut should_skip = false;
-                                for component in &components {
-                                    if let Some(name) = component.as_os_str().to_str() {
-                                        if constants::IGNORED_DIRECTORIES.contains(&name) {
-                                            should_skip = true;
-                                            break;
-                                        }
-                                    }
-                                }
-                                if should_skip {
-                                    continue;
-                                }
-
-                                // Only process source files
-                                if let Some(ext) = path.extension() {
-                                    if ext
-                                        .to_str()
-                                        .map(|e| constants::DEFAULT_FILE_EXTENSIONS.contains(&e))
-                                        .unwrap_or(false)
-                                    {
-                                        match event.kind {
-                                            EventKind::Modify(_) => {
-                                                debug!("File modified: {}", path.display());
-                                            }
-                                            EventKind::Remove(_) => {
-                                                debug!("File removed: {}", path.display());
-                                            }
-                                            EventKind::Create(_) => {
-                                                debug!("File created: {}", path.display());
-                                            }
-                                            _ => {}
-                                        }
-                                        let _ = tx.send(path);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => warn!("Watch error: {:?}", e),
-                }
-            },
-            Config::default(),
-        )?;
+        let handle = WatchHandle {
+            watcher: Some(watcher),
+            debounce: None,
+        };
+        Ok((handle, rx))
+    }
 
-        // Watch the entire working directory recursively
+    /// Watch the entire working directory recursively with the platform's notifications
+    fn native_watcher(&self, handler: ChangeHandler) -> Result<RecommendedWatcher> {
+        let mut watcher = RecommendedWatcher::new(handler, Config::default())?;
         watcher.watch(&self.watch_path, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    }
 
-        // Keep watcher alive
-        std::mem::forget(watcher);
-
-        Ok(rx)
+    /// Watch the entire working directory recursively by rescanning it
+    fn poll_watcher(&self, handler: ChangeHandler) -> Result<PollWatcher> {
+        let config =
+            Config::default().with_poll_interval(Duration::from_millis(self.poll_interval_ms));
+        let mut watcher = PollWatcher::new(handler, config)?;
+        watcher.watch(&self.watch_path, RecursiveMode::Recursive)?;
+        Ok(watcher)
     }
 
     /// Start watching with debouncing (collects changed files and waits for quiet period)
-    pub fn watch_debounced(&self, debounce_ms: u64) -> Result<Receiver<Vec<PathBuf>>> {
+    pub fn watch_debounced(
+        &self,
+        debounce_ms: u64,
+    ) -> Result<(WatchHandle, Receiver<Vec<FileChange>>)> {
         let (tx, rx) = channel();
-        let (file_tx, file_rx) = channel::<PathBuf>();
+        let (file_tx, file_rx) = channel::<FileChange>();
 
-        // Shared set of changed files
+        // Shared set of changes
         let changed_files = Arc::new(StdMutex::new(HashSet::new()));
         let changed_files_clone = Arc::clone(&changed_files);
 
         // Spawn debounce task
-        tokio::spawn(async move {
+        let debounce = tokio::spawn(async move {
             loop {
                 sleep(Duration::from_millis(debounce_ms)).await;
 
-                // Check if we have any changed files
-                let files_to_reindex: Vec<PathBuf> = {
+                // Check if we have any changes
+                let changes: Vec<FileChange> = {
                     let mut guard = changed_files_clone.lock().unwrap();
-                    if guard.is_empty() {
-                        Vec::new()
-                    } else {
-                        let files: Vec<PathBuf> = guard.iter().cloned().collect();
-                        guard.clear();
-                        files
-                    }
+                    guard.drain().collect()
                 };
 
-                if !files_to_reindex.is_empty() {
+                if !changes.is_empty() {
                     debug!(
-                        "Debounce period elapsed, reindexing {} files",
-                        files_to_reindex.len()
+                        "Debounce period elapsed, reindexing {} changes",
+                        changes.len()
                     );
-                    let _ = tx.send(files_to_reindex);
+                    if tx.send(changes).is_err() {
+                        break; // Receiver dropped
+                    }
                 }
             }
         });
@@ -200,22 +378,22 @@ impl GitFileWatcher {
         // Spawn file collector task
         let changed_files_for_collector = Arc::clone(&changed_files);
         std::thread::spawn(move || {
-            while let Ok(path) = file_rx.recv() {
-                let mut guard = changed_files_for_collector.lock().unwrap();
-                guard.insert(path.clone());
-                debug!("File queued for reindex: {}", path.display());
+            while let Ok(change) = file_rx.recv() {
+                debug!("Change queued for reindex: {:?}", change);
+                changed_files_for_collector.lock().unwrap().insert(change);
             }
         });
 
         // Start the file watcher
-        let watch_rx = self.watch()?;
+        let (mut handle, watch_rx) = self.watch()?;
+        handle.debounce = Some(debounce);
         std::thread::spawn(move || {
-            while let Ok(path) = watch_rx.recv() {
-                let _ = file_tx.send(path);
+            while let Ok(change) = watch_rx.recv() {
+                let _ = file_tx.send(change);
             }
         });
 
-        Ok(rx)
+        Ok((handle, rx))
     }
 }
 
@@ -229,4 +407,70 @@ mod tests {
         let current_dir = std::env::current_dir().unwrap();
         assert!(is_git_repo(&current_dir));
     }
+
+    #[test]
+    fn test_stop_disconnects() {
+        let root = std::env::temp_dir().join(format!("ragrep-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        Repository::init(&root).unwrap();
+
+        for backend in [WatchBackend::Native, WatchBackend::Poll] {
+            let watch_config = GitWatchConfig {
+                backend,
+                ..GitWatchConfig::default()
+            };
+            let watcher =
+                GitFileWatcher::new(&root, &IndexConfig::default(), &watch_config).unwrap();
+            let (mut handle, rx) = watcher.watch().unwrap();
+            handle.stop();
+            handle.stop();
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(5)),
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_submodule_heads() {
+        let root = std::env::temp_dir().join(format!("ragrep-watch-sub-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let repo = Repository::init(&root).unwrap();
+        repo.submodule("https://example.com/lib.git", Path::new("vendor/lib"), true)
+            .unwrap();
+
+        let watch_config = GitWatchConfig::default();
+        let watcher = GitFileWatcher::new(&root, &IndexConfig::default(), &watch_config).unwrap();
+        let lib = root.join("vendor/lib");
+        let head = root.join(".git/modules/vendor/lib/HEAD");
+        assert_eq!(watcher.submodule_heads.len(), 2);
+        assert_eq!(watcher.submodule_heads.get(&head), Some(&lib));
+        let config = IndexConfig {
+            submodules: false,
+            ..IndexConfig::default()
+        };
+        let watcher = GitFileWatcher::new(&root, &config, &watch_config).unwrap();
+        assert!(watcher.submodule_heads.is_empty());
+
+        // Git moves a lock file over HEAD
+        let (tx, rx) = channel();
+        let mut handler = ChangeHandler {
+            config: IndexConfig::default(),
+            ignore_rules: Arc::new(IgnoreRules::new(&root)),
+            nested_repositories: Arc::new(NestedRepositories::new(&root)),
+            submodule_heads: Arc::new(HashMap::from([(head.clone(), lib.clone())])),
+            tx,
+        };
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(head.with_file_name("HEAD.lock"))
+            .add_path(head);
+        handler.handle_event(Ok(event));
+        assert_eq!(rx.try_recv(), Ok(FileChange::Submodule(lib)));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
 }
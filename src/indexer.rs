@@ -1,9 +1,15 @@
 use anyhow::{Context, Result};
-use ignore::WalkBuilder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use log::debug;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::SystemTime;
 
+use crate::config::IndexConfig;
+use crate::config_files;
 use crate::constants::constants;
 
 #[derive(Debug, Serialize)]
@@ -14,16 +20,13 @@ pub struct FileInfo {
 }
 
 pub struct Indexer {
-    include_extensions: Vec<String>,
+    config: IndexConfig,
 }
 
 impl Indexer {
-    pub fn new() -> Self {
+    pub fn new(config: &IndexConfig) -> Self {
         Self {
-            include_extensions: constants::DEFAULT_FILE_EXTENSIONS
-                .iter()
-                .map(|s| s.to_string())
-                .collect(),
+            config: config.clone(),
         }
     }
 
@@ -41,12 +44,23 @@ impl Indexer {
             .git_exclude(true) // Use .git/info/exclude
             .require_git(false) // Don't require git repo
             .follow_links(true)
+            .filter_entry({
+                let config = self.config.clone();
+                move |entry| {
+                    if !entry.file_type().is_some_and(|ft| ft.is_dir()) {
+                        return true;
+                    }
+                    let nested_repo = || entry.depth() > 0 && entry.path().join(".git").exists();
+                    !(config.is_excluded_dir(&entry.file_name().to_string_lossy())
+                        || (!config.submodules && nested_repo()))
+                }
+            })
             .build();
 
         for result in walker {
             let entry = result.with_context(|| "Failed to read directory entry")?;
             if entry.file_type().map_or(false, |ft| ft.is_file())
-                && self.is_valid_extension(entry.path())
+                && self.is_indexed_file(entry.path())
             {
                 let canonical_path = entry.path().canonicalize().with_context(|| {
                     format!("Failed to canonicalize path: {}", entry.path().display())
@@ -72,7 +86,7 @@ impl Indexer {
         let mut files = Vec::new();
 
         for path in paths {
-            if self.is_valid_extension(&path) {
+            if self.is_indexed_file(&path) {
                 let canonical_path = path
                     .canonicalize()
                     .with_context(|| format!("Failed to canonicalize path: {}", path.display()))?;
@@ -90,14 +104,387 @@ impl Indexer {
         Ok(files)
     }
 
-    fn is_valid_extension(&self, path: &Path) -> bool {
-        path.extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| {
-                self.include_extensions
-                    .iter()
-                    .any(|valid_ext| valid_ext == ext)
+    /// Why the walk of `root` skips `path`, or `None` if it indexes it
+    pub fn why_excluded(&self, root: &Path, path: &Path) -> Result<Option<String>> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to canonicalize base path: {}", root.display()))?;
+        let Ok(path) = path.canonicalize() else {
+            return Ok(Some("it does not exist".to_string()));
+        };
+        if !path.is_file() {
+            return Ok(Some("it is not a file".to_string()));
+        }
+        let Ok(relative) = path.strip_prefix(&root) else {
+            return Ok(Some(format!("it is outside {}", root.display())));
+        };
+
+        let parents = relative.parent().into_iter().flat_map(Path::components);
+        for component in parents {
+            let name = component.as_os_str().to_string_lossy();
+            if self.config.is_excluded_dir(&name) {
+                return Ok(Some(format!(
+                    "it is inside `{}`, which is listed in index.exclude_dirs",
+                    name
+                )));
+            }
+        }
+        if !self.config.submodules {
+            if let Some(repo) = nested_repository(&root, &path) {
+                return Ok(Some(format!(
+                    "it is in the repository nested at {} and index.submodules is off",
+                    repo.strip_prefix(&root).unwrap_or(repo).display()
+                )));
+            }
+        }
+        if let Some(pattern) = IgnoreRules::new(&root).ignoring_pattern(&path) {
+            return Ok(Some(format!("it is ignored by {}", pattern)));
+        }
+        if self.config.is_generated_file(&path) {
+            return Ok(Some(
+                "its name marks it as generated (see index.generated_files)".to_string(),
+            ));
+        }
+        if !self.config.includes_file(&path) {
+            let format = config_files::format_for(&path);
+            if format.is_some_and(|format| format != config_files::Format::Makefile) {
+                return Ok(Some(
+                    "it is a configuration file and index.include_config_files is off".to_string(),
+                ));
+            }
+            return Ok(Some(match config_files::listed_extension(&path) {
+                Some(ext) => format!("`.{}` is not listed in index.extensions", ext),
+                None => "it has no extension listed in index.extensions".to_string(),
+            }));
+        }
+        Ok(None)
+    }
+
+    fn is_indexed_file(&self, path: &Path) -> bool {
+        if self.config.is_generated_file(path) {
+            debug!("Skipping generated file {}", path.display());
+            return false;
+        }
+        self.config.includes_file(path)
+    }
+}
+
+/// The working tree of the repository nested in `root` (a git submodule, say) that holds
+/// `path`, or `path` itself if it is one
+pub fn nested_repository<'a>(root: &Path, path: &'a Path) -> Option<&'a Path> {
+    path.ancestors()
+        .take_while(|dir| *dir != root && dir.starts_with(root))
+        .find(|dir| dir.join(".git").exists())
+}
+
+/// [`nested_repository`] for many paths (as the watcher checks changed files), looking for
+/// `.git` once per directory
+pub struct NestedRepositories {
+    root: PathBuf,
+    /// Whether each directory looked at is the working tree of a repository
+    is_repository: Mutex<HashMap<PathBuf, bool>>,
+}
+
+impl NestedRepositories {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            is_repository: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `path` is in (or is) a repository nested in the root
+    pub fn contains(&self, path: &Path) -> bool {
+        let mut is_repository = self.is_repository.lock().unwrap();
+        path.ancestors()
+            .take_while(|dir| *dir != self.root && dir.starts_with(&self.root))
+            .any(|dir| {
+                *is_repository
+                    .entry(dir.to_path_buf())
+                    .or_insert_with(|| dir.join(".git").exists())
             })
-            .unwrap_or(false)
+    }
+}
+
+/// The ignore files the directory walk honors, for checking single paths (as the watcher
+/// does for changed files)
+///
+/// Ignore files inside the tree are read on every check, so edits to them apply at once.
+pub struct IgnoreRules {
+    root: PathBuf,
+    global: Gitignore,
+    exclude: Gitignore,
+}
+
+impl IgnoreRules {
+    pub fn new(root: &Path) -> Self {
+        let mut exclude = GitignoreBuilder::new(root);
+        exclude.add(root.join(".git").join("info").join("exclude"));
+        Self {
+            root: root.to_path_buf(),
+            global: Gitignore::global().0,
+            exclude: exclude.build().unwrap_or_else(|_| Gitignore::empty()),
+        }
+    }
+
+    /// Whether the walk skips `path` because of an ignore file
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.ignoring_pattern(path).is_some()
+    }
+
+    /// The ignore-file pattern excluding `path` from the walk, as "`pattern` in file"
+    ///
+    /// Like the walk, this checks each directory on the way down before the file, as an
+    /// ignored directory is never entered.
+    pub fn ignoring_pattern(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let mut parent = self.root.clone();
+        for component in relative.components() {
+            let entry = parent.join(component);
+            if let Some(pattern) = self.ignoring_pattern_in(&parent, &entry, entry != path) {
+                return Some(pattern);
+            }
+            parent = entry;
+        }
+        None
+    }
+
+    /// The pattern ignoring `entry`, found in `dir`, according to the ignore files of `dir`
+    /// and the directories above it
+    ///
+    /// Mirrors the walk's precedence: `.ragrepignore` over `.ignore` over `.gitignore` over
+    /// `.git/info/exclude` over the global gitignore, and within each, deeper files over
+    /// shallower ones. A negated (`!`) pattern that wins means the entry is not ignored.
+    fn ignoring_pattern_in(&self, dir: &Path, entry: &Path, is_dir: bool) -> Option<String> {
+        let relative = entry.strip_prefix(&self.root).ok()?;
+        let dirs: Vec<&Path> = dir
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&self.root))
+            .collect();
+
+        // Matchers in the tree, from the lowest precedence to the highest
+        let mut matchers = Vec::new();
+        for name in [".gitignore", ".ignore", constants::RAGREP_IGNORE_FILENAME] {
+            for dir in dirs.iter().rev() {
+                let file = dir.join(name);
+                if file.is_file() {
+                    matchers.push(Gitignore::new(&file).0);
+                }
+            }
+        }
+
+        // The global gitignore is rooted at the current directory, so it gets the relative
+        // path
+        let mut decision = match self.global.matched(relative, is_dir) {
+            Match::Ignore(glob) => Some(self.describe(glob)),
+            _ => None,
+        };
+        for matcher in std::iter::once(&self.exclude).chain(&matchers) {
+            match matcher.matched(entry, is_dir) {
+                Match::Ignore(glob) => decision = Some(self.describe(glob)),
+                Match::Whitelist(_) => decision = None,
+                Match::None => {}
+            }
+        }
+        decision
+    }
+
+    fn describe(&self, glob: &ignore::gitignore::Glob) -> String {
+        let file = match glob.from() {
+            Some(file) => file
+                .strip_prefix(&self.root)
+                .unwrap_or(file)
+                .display()
+                .to_string(),
+            None => "the global gitignore".to_string(),
+        };
+        format!("`{}` in {}", glob.original(), file)
+    }
+}
+
+/// [`IgnoreRules`] that remember their decision for each path, for filtering the results of
+/// every search
+///
+/// Ignore files are read once per path until [`IgnoreCache::clear`], which the server calls
+/// when its watcher reports an ignore file change.
+pub struct IgnoreCache {
+    rules: IgnoreRules,
+    ignored: Mutex<HashMap<PathBuf, bool>>,
+}
+
+impl IgnoreCache {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            rules: IgnoreRules::new(root),
+            ignored: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the walk skips `path` because of an ignore file
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if let Some(ignored) = self.ignored.lock().unwrap().get(path) {
+            return *ignored;
+        }
+        let ignored = self.rules.is_ignored(path);
+        self.ignored
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), ignored);
+        ignored
+    }
+
+    /// Forget every decision, so ignore files are read again
+    pub fn clear(&self) {
+        self.ignored.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_why_excluded() {
+        let root = std::env::temp_dir().join(format!("ragrep-why-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["src/gen", "node_modules/lib", "docs"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let files = [
+            "src/main.rs",
+            "src/local.rs",
+            "src/gen/api.rs",
+            "src/gen/keep.rs",
+            "node_modules/lib/index.js",
+            "docs/notes.md",
+            "app.min.js",
+        ];
+        for file in files {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        std::fs::write(root.join(".gitignore"), "src/gen/\nsrc/local.rs\n").unwrap();
+        std::fs::write(root.join("src/.ragrepignore"), "!local.rs\n!gen/keep.rs\n").unwrap();
+
+        let indexer = Indexer::new(&IndexConfig::default());
+        let why = |file: &str| indexer.why_excluded(&root, &root.join(file)).unwrap();
+
+        assert_eq!(why("src/main.rs"), None);
+        assert_eq!(
+            why("src/gen/api.rs").unwrap(),
+            "it is ignored by `src/gen/` in .gitignore"
+        );
+        // A .ragrepignore takes precedence over any .gitignore, but can't reach into an
+        // ignored directory
+        assert_eq!(why("src/local.rs"), None);
+        assert_eq!(
+            why("src/gen/keep.rs").unwrap(),
+            "it is ignored by `src/gen/` in .gitignore"
+        );
+        assert!(why("node_modules/lib/index.js")
+            .unwrap()
+            .contains("index.exclude_dirs"));
+        assert!(why("docs/notes.md").unwrap().contains("`.md`"));
+        assert!(why("app.min.js").unwrap().contains("generated"));
+        assert_eq!(why("missing.rs").unwrap(), "it does not exist");
+
+        // Explanations agree with the walk itself
+        let walked: Vec<PathBuf> = indexer
+            .index_directory(&root)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        let root = root.canonicalize().unwrap();
+        for file in files {
+            assert_eq!(
+                walked.contains(&root.join(file)),
+                why(file).is_none(),
+                "{}",
+                file
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_ignore_rules_nested() {
+        let root = std::env::temp_dir().join(format!("ragrep-ignore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("pkg/fixtures")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("pkg/.gitignore"), "fixtures/\n").unwrap();
+
+        let rules = IgnoreRules::new(&root);
+        assert!(rules.is_ignored(&root.join("pkg/fixtures/data.rs")));
+        assert!(rules.is_ignored(&root.join("pkg/debug.log")));
+        assert!(!rules.is_ignored(&root.join("pkg/lib.rs")));
+
+        // Ignore files are read on each check, so an edit applies without new rules
+        std::fs::write(root.join("pkg/.gitignore"), "lib.rs\n").unwrap();
+        assert!(rules.is_ignored(&root.join("pkg/lib.rs")));
+        assert!(!rules.is_ignored(&root.join("pkg/fixtures/data.rs")));
+
+        // A cache keeps its decisions until cleared
+        let cache = IgnoreCache::new(&root);
+        assert!(cache.is_ignored(&root.join("pkg/lib.rs")));
+        std::fs::write(root.join("pkg/.gitignore"), "").unwrap();
+        assert!(cache.is_ignored(&root.join("pkg/lib.rs")));
+        cache.clear();
+        assert!(!cache.is_ignored(&root.join("pkg/lib.rs")));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_nested_repositories() {
+        let root = std::env::temp_dir().join(format!("ragrep-nested-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("vendor/lib")).unwrap();
+        std::fs::write(root.join("main.rs"), "").unwrap();
+        std::fs::write(root.join("vendor/lib/lib.rs"), "").unwrap();
+        // Submodule working trees have a `.git` file pointing into the parent's `.git`
+        std::fs::write(
+            root.join("vendor/lib/.git"),
+            "gitdir: ../../.git/modules/lib\n",
+        )
+        .unwrap();
+
+        let walked = |config: &IndexConfig| -> Vec<String> {
+            let root = root.canonicalize().unwrap();
+            let mut files: Vec<String> = Indexer::new(config)
+                .index_directory(&root)
+                .unwrap()
+                .iter()
+                .map(|file| file.path.strip_prefix(&root).unwrap().display().to_string())
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            walked(&IndexConfig::default()),
+            ["main.rs", "vendor/lib/lib.rs"]
+        );
+
+        let config = IndexConfig {
+            submodules: false,
+            ..IndexConfig::default()
+        };
+        assert_eq!(walked(&config), ["main.rs"]);
+        assert_eq!(
+            Indexer::new(&config)
+                .why_excluded(&root, &root.join("vendor/lib/lib.rs"))
+                .unwrap()
+                .unwrap(),
+            "it is in the repository nested at vendor/lib and index.submodules is off"
+        );
+
+        let nested = NestedRepositories::new(&root);
+        assert!(nested.contains(&root.join("vendor/lib/lib.rs")));
+        assert!(nested.contains(&root.join("vendor/lib")));
+        assert!(!nested.contains(&root.join("vendor/main.rs")));
+        assert!(!nested.contains(&root.join("main.rs")));
+
+        let _ = std::fs::remove_dir_all(&root);
     }
 }
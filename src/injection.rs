@@ -0,0 +1,385 @@
+use std::ops::Range;
+
+/// A block of one language embedded in a file of another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injection {
+    /// Language of the block as a file extension, e.g. `rs` for a ```rust fence
+    pub extension: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// 1-based line of the block's first line in the host file
+    pub start_line: usize,
+}
+
+/// Statements recognized in string literals, each with a keyword that must follow it
+const SQL_STATEMENTS: &[(&str, &[&str])] = &[
+    ("select", &["from"]),
+    ("insert", &["into"]),
+    ("update", &["set"]),
+    ("delete", &["from"]),
+    ("with", &["select", "as"]),
+    ("create", &["table", "index", "view", "trigger"]),
+    ("alter", &["table"]),
+    ("drop", &["table", "index", "view", "trigger"]),
+];
+
+/// Extension of a markdown fence's language or an alias of it, e.g. `python3` -> `py`
+fn extension_for(language: &str) -> String {
+    let language = language.to_lowercase();
+    let extension = match language.as_str() {
+        "rust" => "rs",
+        "python" | "python3" | "py3" => "py",
+        "javascript" | "node" => "js",
+        "typescript" => "ts",
+        "ruby" => "rb",
+        "shell" => "sh",
+        "csharp" | "c#" => "cs",
+        _ => &language,
+    };
+    extension.to_string()
+}
+
+/// 1-based line of `offset` in `content`
+pub fn line_at(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// Fenced code blocks of a markdown document that name their language
+///
+/// Fences without a language are left to the document's own chunks. A fence that is never
+/// closed runs to the end of the document, as in CommonMark.
+pub fn markdown_fences(content: &str) -> Vec<Injection> {
+    let mut fences = Vec::new();
+    // Fence character and length, language and start offset of the open block
+    let mut open: Option<(char, usize, String, usize)> = None;
+    let mut offset = 0;
+
+    for line in content.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if let Some((fence, length, _, _)) = &open {
+            let closes = trimmed.len() >= *length && trimmed.chars().all(|c| c == *fence);
+            if closes {
+                let (_, _, extension, start_byte) = open.take().unwrap();
+                fences.push((extension, start_byte, line_start));
+            }
+            continue;
+        }
+
+        // Fences may be indented by up to three spaces
+        if line.len() - line.trim_start_matches(' ').len() > 3 {
+            continue;
+        }
+        let Some(fence) = trimmed.chars().next().filter(|c| *c == '`' || *c == '~') else {
+            continue;
+        };
+        let length = trimmed.chars().take_while(|c| *c == fence).count();
+        if length < 3 {
+            continue;
+        }
+        // Info strings like "{python}" or "rust,ignore" start with the language
+        let language = trimmed[length..]
+            .trim_start_matches(['{', '.', ' '])
+            .split(|c: char| c.is_whitespace() || matches!(c, ',' | '}' | '{'))
+            .next()
+            .unwrap_or("");
+        open = Some((fence, length, extension_for(language), offset));
+    }
+
+    if let Some((_, _, extension, start_byte)) = open {
+        fences.push((extension, start_byte, content.len()));
+    }
+
+    fences
+        .into_iter()
+        .filter(|(extension, start_byte, end_byte)| {
+            !extension.is_empty() && !content[*start_byte..*end_byte].trim().is_empty()
+        })
+        .map(|(extension, start_byte, end_byte)| Injection {
+            extension,
+            start_byte,
+            end_byte,
+            start_line: line_at(content, start_byte),
+        })
+        .collect()
+}
+
+/// Value of `name="value"` (quoted or not) in the text of an HTML start tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let at = from + found;
+        from = at + name.len();
+        let preceded = lower[..at].ends_with(|c: char| c.is_whitespace());
+        let rest = lower[from..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or(""),
+        });
+    }
+    None
+}
+
+/// An element of an HTML-like document
+struct Element<'a> {
+    /// Text of the start tag after the element name
+    attributes: &'a str,
+    /// Byte range of the whole element, tags included
+    outer: Range<usize>,
+    /// Byte range between the start and end tags
+    body: Range<usize>,
+}
+
+/// Elements named `name` of an HTML-like document, assumed not to nest
+fn elements<'a>(content: &'a str, name: &str) -> Vec<Element<'a>> {
+    // ASCII lowercasing keeps byte offsets, so positions found here index `content`
+    let lower = content.to_ascii_lowercase();
+    let open = format!("<{}", name);
+    let close = format!("</{}", name);
+    let mut elements = Vec::new();
+    let mut from = 0;
+
+    while let Some(found) = lower[from..].find(&open) {
+        let start = from + found;
+        let Some(tag_length) = lower[start..].find('>') else {
+            break;
+        };
+        let attributes = &content[start + open.len()..start + tag_length];
+        let body_start = start + tag_length + 1;
+        let (body_end, end) = match lower[body_start..].find(&close) {
+            Some(offset) => {
+                let body_end = body_start + offset;
+                let end = lower[body_end..]
+                    .find('>')
+                    .map_or(content.len(), |offset| body_end + offset + 1);
+                (body_end, end)
+            }
+            None => (content.len(), content.len()),
+        };
+        from = end;
+
+        // "<scripts>" or similar custom elements are other elements
+        if !attributes.is_empty() && !attributes.starts_with(char::is_whitespace) {
+            continue;
+        }
+        elements.push(Element {
+            attributes,
+            outer: start..end,
+            body: body_start..body_end,
+        });
+    }
+
+    elements
+}
+
+/// Inline `<script>` blocks of an HTML document or component, as JavaScript or TypeScript
+///
+/// Scripts of other types (JSON data, client-side templates) are skipped.
+pub fn script_blocks(content: &str) -> Vec<Injection> {
+    let mut blocks = Vec::new();
+
+    for element in elements(content, "script") {
+        let lang = attribute(element.attributes, "lang").map(str::to_ascii_lowercase);
+        let kind = attribute(element.attributes, "type").map(str::to_ascii_lowercase);
+        let extension = match (lang.as_deref(), kind.as_deref()) {
+            (Some("ts" | "tsx" | "typescript"), _) => "ts",
+            (_, Some(kind)) if kind.contains("typescript") => "ts",
+            (
+                None | Some("js" | "jsx" | "javascript"),
+                None | Some("module" | "text/javascript" | "application/javascript" | "text/babel"),
+            ) => "js",
+            _ => continue,
+        };
+
+        if content[element.body.clone()].trim().is_empty() {
+            continue;
+        }
+        blocks.push(Injection {
+            extension: extension.to_string(),
+            start_byte: element.body.start,
+            end_byte: element.body.end,
+            start_line: line_at(content, element.body.start),
+        });
+    }
+
+    blocks
+}
+
+/// Markup of a Vue or Svelte component: what is left outside its `<script>` and `<style>`
+/// elements, trimmed of surrounding whitespace
+pub fn component_markup(content: &str) -> Vec<Range<usize>> {
+    let mut blocks: Vec<Range<usize>> = elements(content, "script")
+        .into_iter()
+        .chain(elements(content, "style"))
+        .map(|element| element.outer)
+        .collect();
+    blocks.sort_by_key(|block| block.start);
+
+    let mut markup = Vec::new();
+    let mut from = 0;
+    for block in blocks
+        .into_iter()
+        .chain(std::iter::once(content.len()..content.len()))
+    {
+        if block.start >= from {
+            let gap = &content[from..block.start];
+            let start = from + (gap.len() - gap.trim_start().len());
+            let end = from + gap.trim_end().len();
+            if start < end {
+                markup.push(start..end);
+            }
+        }
+        from = from.max(block.end);
+    }
+
+    markup
+}
+
+/// Whether the text of a string literal reads like an SQL statement
+///
+/// The statement keyword must be followed by its companion (`SELECT ... FROM`), and
+/// lowercase statements must also contain SQL punctuation, so messages such as
+/// "select a file from the list" are not mistaken for queries.
+pub fn looks_like_sql(text: &str) -> bool {
+    let mut words = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty());
+    let Some(first) = words.next() else {
+        return false;
+    };
+    let Some((_, companions)) = SQL_STATEMENTS
+        .iter()
+        .find(|(keyword, _)| first.eq_ignore_ascii_case(keyword))
+    else {
+        return false;
+    };
+    if !words.any(|word| companions.iter().any(|c| word.eq_ignore_ascii_case(c))) {
+        return false;
+    }
+
+    first.chars().all(|c| c.is_ascii_uppercase()) || text.contains(['*', '=', '(', ';', '?'])
+}
+
+/// Byte range of a string literal's text inside its quotes and prefixes
+///
+/// Handles prefixed (`r#"..."#`, `f"..."`), triple-quoted and template literals.
+pub fn string_contents(literal: &str) -> Range<usize> {
+    let Some(open) = literal.find(['"', '\'', '`']) else {
+        return 0..0;
+    };
+    let quote = literal.as_bytes()[open];
+    let quotes = if literal[open..].bytes().take_while(|b| *b == quote).count() >= 3 {
+        3
+    } else {
+        1
+    };
+    let start = (open + quotes).min(literal.len());
+    let end = literal
+        .trim_end_matches('#')
+        .len()
+        .saturating_sub(quotes)
+        .max(start);
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_fences() {
+        let content = "# Usage\n\n```rust,ignore\nfn main() {}\n```\n\n```\nplain\n```\n\n~~~~{python}\nprint(1)\n~~~~\n\n```sql\nSELECT 1\n";
+        let fences = markdown_fences(content);
+
+        let blocks: Vec<_> = fences
+            .iter()
+            .map(|fence| {
+                (
+                    fence.extension.as_str(),
+                    &content[fence.start_byte..fence.end_byte],
+                    fence.start_line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                ("rs", "fn main() {}\n", 4),
+                ("py", "print(1)\n", 12),
+                ("sql", "SELECT 1\n", 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_blocks() {
+        let content = "<html>\n<script src=\"app.js\"></script>\n<SCRIPT type=\"module\">\nimport { a } from './a.js';\n</SCRIPT>\n<script type=\"application/json\">{\"a\": 1}</script>\n<script lang=\"ts\">let n: number = 1;</script>\n";
+        let blocks: Vec<_> = script_blocks(content)
+            .into_iter()
+            .map(|block| {
+                (
+                    block.extension,
+                    content[block.start_byte..block.end_byte].trim().to_string(),
+                    block.start_line,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            blocks,
+            vec![
+                (
+                    "js".to_string(),
+                    "import { a } from './a.js';".to_string(),
+                    3
+                ),
+                ("ts".to_string(), "let n: number = 1;".to_string(), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_component_markup() {
+        let content = "<script setup>\nconst a = 1;\n</script>\n\n<template>\n  <p>{{ a }}</p>\n</template>\n\n<style scoped>\np { color: red; }\n</style>\n";
+        let markup: Vec<_> = component_markup(content)
+            .into_iter()
+            .map(|range| &content[range])
+            .collect();
+
+        assert_eq!(markup, vec!["<template>\n  <p>{{ a }}</p>\n</template>"]);
+    }
+
+    #[test]
+    fn test_looks_like_sql() {
+        assert!(looks_like_sql(
+            "SELECT id, name FROM users WHERE active = 1"
+        ));
+        assert!(looks_like_sql("\n    insert into chunks (id) values (?1)"));
+        assert!(looks_like_sql("CREATE VIRTUAL TABLE chunks_vec USING vec0"));
+        assert!(!looks_like_sql("Select a file from the list"));
+        assert!(!looks_like_sql("Deleted 3 files"));
+        assert!(!looks_like_sql("SELECT"));
+        assert!(!looks_like_sql(""));
+    }
+
+    #[test]
+    fn test_string_contents() {
+        let contents = |literal: &'static str| &literal[string_contents(literal)];
+        assert_eq!(contents("\"SELECT 1\""), "SELECT 1");
+        assert_eq!(contents("r#\"SELECT \"a\"\"#"), "SELECT \"a\"");
+        assert_eq!(contents("f'''SELECT {x}'''"), "SELECT {x}");
+        assert_eq!(contents("`SELECT ${x}`"), "SELECT ${x}");
+        assert_eq!(contents("\"\""), "");
+    }
+}
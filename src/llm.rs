@@ -0,0 +1,191 @@
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::config::{LlmConfig, LlmProvider};
+use crate::protocol::SearchResult;
+
+/// Generating an answer on a local CPU model can take a while
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+const SYSTEM_PROMPT: &str = "You answer questions about a codebase using only the numbered \
+code excerpts provided. Cite the excerpts you rely on by their number in square brackets, \
+e.g. [2]. If the excerpts don't contain the answer, say so instead of guessing.";
+
+const HYDE_PROMPT: &str = "Write a short code snippet that could plausibly be the answer to \
+the user's question about their codebase, as it might appear in that codebase. Reply with \
+the code only, without explanations.";
+
+/// Build the user prompt: every result as a numbered, fenced excerpt with its location,
+/// followed by the question
+pub fn build_prompt(question: &str, results: &[SearchResult]) -> String {
+    let mut prompt = String::new();
+    for (i, result) in results.iter().enumerate() {
+        let _ = writeln!(
+            prompt,
+            "[{}] {}:{}-{}\n```\n{}\n```\n",
+            i + 1,
+            result.file_path,
+            result.start_line,
+            result.end_line,
+            result.text.trim_end()
+        );
+    }
+    let _ = write!(prompt, "Question: {}", question);
+    prompt
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct OpenAiChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    choices: Vec<OpenAiChoice>,
+}
+
+/// Answer `prompt`, built by [`build_prompt`], with the configured chat model
+pub async fn complete(config: &LlmConfig, prompt: &str) -> Result<String> {
+    chat(config, SYSTEM_PROMPT, prompt).await
+}
+
+/// Write a hypothetical code snippet answering `query`, to be embedded alongside it (HyDE)
+pub async fn hypothetical_code(config: &LlmConfig, query: &str) -> Result<String> {
+    let reply = chat(config, HYDE_PROMPT, query).await?;
+    Ok(strip_code_fence(&reply).to_string())
+}
+
+/// The code inside a reply wrapped in a single Markdown code fence
+fn strip_code_fence(reply: &str) -> &str {
+    let Some(fenced) = reply.strip_prefix("```") else {
+        return reply;
+    };
+    // Drop the info string ("```rust") and the closing fence
+    let code = fenced.split_once('\n').map_or("", |(_, code)| code);
+    code.trim_end().strip_suffix("```").unwrap_or(code).trim()
+}
+
+/// Send `prompt` with the `system` instructions to the configured chat model and return
+/// its reply
+async fn chat(config: &LlmConfig, system: &str, prompt: &str) -> Result<String> {
+    let messages = json!([
+        {"role": "system", "content": system},
+        {"role": "user", "content": prompt},
+    ]);
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let request = match config.provider {
+        LlmProvider::Ollama => client
+            .post(format!("{}/api/chat", config.base_url()))
+            .json(&json!({"model": config.model, "messages": messages, "stream": false})),
+        LlmProvider::OpenAi => {
+            let request = client
+                .post(format!("{}/chat/completions", config.base_url()))
+                .json(&json!({"model": config.model, "messages": messages}));
+            match std::env::var(&config.api_key_env) {
+                Ok(key) => request.bearer_auth(key),
+                // Local OpenAI-compatible servers usually don't need a key
+                Err(_) => request,
+            }
+        }
+    };
+
+    debug!("Asking {} ({})", config.model, config.base_url());
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the LLM at {}", config.base_url()))?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        bail!("LLM request failed with {}: {}", status, body.trim());
+    }
+
+    parse_reply(config.provider, &body)
+}
+
+fn parse_reply(provider: LlmProvider, body: &str) -> Result<String> {
+    let reply = match provider {
+        LlmProvider::Ollama => {
+            serde_json::from_str::<OllamaResponse>(body)
+                .context("Unexpected response from Ollama")?
+                .message
+                .content
+        }
+        LlmProvider::OpenAi => serde_json::from_str::<OpenAiResponse>(body)
+            .context("Unexpected response from the OpenAI-compatible API")?
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .context("LLM returned no choices")?,
+    };
+    Ok(reply.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_prompt() {
+        let results = vec![SearchResult {
+            id: Some("3f2a9c01b7d4".to_string()),
+            file_path: "src/db.rs".to_string(),
+            start_line: 10,
+            end_line: 12,
+            text: "fn open() {}\n".to_string(),
+            score: 0.9,
+            kind: "function".to_string(),
+            name: Some("open".to_string()),
+            modified_at: None,
+            explanation: None,
+        }];
+        assert_eq!(
+            build_prompt("How is the database opened?", &results),
+            "[1] src/db.rs:10-12\n```\nfn open() {}\n```\n\nQuestion: How is the database opened?"
+        );
+    }
+
+    #[test]
+    fn test_strip_code_fence() {
+        assert_eq!(
+            strip_code_fence("```rust\nfn retry() {}\n```"),
+            "fn retry() {}"
+        );
+        assert_eq!(strip_code_fence("fn retry() {}"), "fn retry() {}");
+        assert_eq!(strip_code_fence("```\nx = 1\n"), "x = 1");
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let ollama = r#"{"model": "llama3.1", "message": {"role": "assistant", "content": " Via open [1].\n"}, "done": true}"#;
+        assert_eq!(
+            parse_reply(LlmProvider::Ollama, ollama).unwrap(),
+            "Via open [1]."
+        );
+
+        let openai = r#"{"choices": [{"index": 0, "message": {"role": "assistant", "content": "Via open [1]."}}]}"#;
+        assert_eq!(
+            parse_reply(LlmProvider::OpenAi, openai).unwrap(),
+            "Via open [1]."
+        );
+        assert!(parse_reply(LlmProvider::OpenAi, r#"{"choices": []}"#).is_err());
+    }
+}
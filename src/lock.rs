@@ -0,0 +1,121 @@
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+/// How often a waiting index run checks whether the lock was released
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Advisory lock held while an index is being built or replaced
+///
+/// An exclusive `flock` on the lock file next to the database, so a second `ragrep index`
+/// waits for the first instead of both writing at once. The kernel releases it when its
+/// holder exits, crashed or not, so there are no stale locks to detect. The file holds the
+/// holder's PID for messages. Released when dropped.
+pub struct IndexLock {
+    /// Open for as long as the lock is held; closing it releases the lock
+    file: File,
+}
+
+impl IndexLock {
+    /// Take the lock at `path`, waiting while another process holds it
+    ///
+    /// Fails if this process already holds it, since waiting for ourselves would never end.
+    pub async fn acquire(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to create lock {}", path.display()))?;
+        let mut announced = false;
+
+        while !try_lock(&file).with_context(|| format!("Failed to lock {}", path.display()))? {
+            // The holder may not have written its PID yet
+            let holder = fs::read_to_string(path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            if holder == Some(std::process::id()) {
+                bail!(
+                    "Index lock {} is already held by this process",
+                    path.display()
+                )
+            }
+            if !announced {
+                match holder {
+                    Some(pid) => warn!(
+                        "Another index run (PID {}) is writing this index; waiting for it to finish",
+                        pid
+                    ),
+                    None => warn!("Another index run is writing this index; waiting for it to finish"),
+                }
+                announced = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        file.set_len(0)
+            .and_then(|()| write!(file, "{}", std::process::id()))
+            .with_context(|| format!("Failed to write lock {}", path.display()))?;
+        if announced {
+            info!("Index lock acquired, continuing");
+        }
+        Ok(Self { file })
+    }
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        // The file stays: removing it would let a waiter lock the removed file while a
+        // third process creates and locks a new one
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Take an exclusive `flock` on `file` without blocking; `false` if another open file
+/// holds it
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.kind() {
+        ErrorKind::WouldBlock => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_lifecycle() {
+        let dir = std::env::temp_dir().join(format!("ragrep-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ragrep.lock");
+
+        // A lock file left behind by a process that no longer exists is taken over
+        fs::write(&path, "4294967").unwrap();
+        let lock = IndexLock::acquire(&path).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+
+        // Taking it again from the same process fails instead of stealing it
+        assert!(IndexLock::acquire(&path).await.is_err());
+        let other = File::open(&path).unwrap();
+        assert!(!try_lock(&other).unwrap());
+
+        // Dropping it releases it
+        drop(lock);
+        assert!(try_lock(&other).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
@@ -1,44 +1,262 @@
-use anyhow::{Context as AnyhowContext, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use clap::{CommandFactory, Parser, Subcommand};
 use env_logger::Env;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
 use log::{debug, info, warn};
+use std::collections::hash_map::{Entry, HashMap};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use tokio::signal::unix::{signal, SignalKind};
 
+mod ann;
+mod archive;
+mod bench;
+mod blame;
+mod category;
 mod chunker;
 mod client;
 mod config;
+mod config_files;
 mod constants;
 mod context;
 mod db;
+mod editor;
 mod embedder;
+mod eval;
 mod git_watcher;
 mod indexer;
+mod injection;
+mod llm;
+mod lock;
+mod manifest;
+mod metrics;
+mod normalize;
+mod notebook;
+mod objc;
+mod output;
+mod pack;
+mod plugins;
 mod protocol;
 mod reranker;
+mod secrets;
 mod server;
+mod service;
+#[cfg(test)]
+mod testing;
+mod weights;
+mod worker;
 
 use context::AppContext;
-use embedder::Embedding;
-use protocol::{SearchRequest, SearchResponse};
+use protocol::{
+    ErrorCode, QueryCombination, ResultOrder, SearchExplanation, SearchRequest, SearchResponse,
+    SearchResult,
+};
+
+/// Results requested when `--max-tokens` rather than the count limits the output
+const MAX_TOKENS_CANDIDATES: usize = 50;
+
+/// Results shown when neither `-n` nor an alias says how many
+const DEFAULT_TOP_N: usize = 10;
+
+/// Times a query the server refuses as busy is sent again, waiting twice as long each time
+const BUSY_RETRIES: u32 = 3;
+
+/// Wait before the first retry of a query the server refused as busy
+const BUSY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(250);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Search query (default command)
-    query: Option<String>,
+    /// Search queries (default command); several are combined with --any (the default)
+    /// or --all. `@name` runs the saved search `name` from `[aliases]`
+    queries: Vec<String>,
+
+    /// With several queries, return results matching any of them, scored by the best match
+    #[arg(long, conflicts_with = "all")]
+    any: bool,
+
+    /// With several queries, return results matching all of them, scored by the worst match
+    #[arg(long)]
+    all: bool,
+
+    /// Find chunks containing the queries as literal, case-sensitive text, in file order;
+    /// reads the index directly, with no models or server
+    #[arg(short = 'F', long, conflicts_with_all = ["max_tokens", "rerank_depth"])]
+    fixed_strings: bool,
+
+    /// Search these projects instead of the current directory's (repeatable); results are
+    /// ranked together and their paths prefixed with the project's name
+    #[arg(long = "project", value_name = "DIR", conflicts_with_all = ["max_tokens", "open"])]
+    projects: Vec<PathBuf>,
+
+    /// Number of results to show [default: 10]
+    #[arg(short = 'n', long = "top", value_name = "N")]
+    top_n: Option<usize>,
+
+    /// Order of the results: by score, by path and line, or most recently modified file first
+    #[arg(long, value_enum, default_value_t = ResultOrder::Score)]
+    sort: ResultOrder,
+
+    /// Rerank this many vector search candidates (default: `search.rerank_depth`, 50)
+    #[arg(long, value_name = "N")]
+    rerank_depth: Option<usize>,
 
     /// Display only filenames and line numbers without code content
     #[arg(short = 'l', long = "compact")]
     files_only: bool,
 
+    /// Print the number of matching chunks per file, like `grep --count`
+    #[arg(short, long, conflicts_with = "files_with_matches")]
+    count: bool,
+
+    /// Print only the names of files with matches, one per line
+    #[arg(long)]
+    files_with_matches: bool,
+
+    /// Only search chunks of these languages (comma-separated names or extensions, e.g. rust,py)
+    #[arg(long = "lang", value_delimiter = ',')]
+    languages: Vec<String>,
+
+    /// Skip test code (paths matching `categories.tests`)
+    #[arg(long)]
+    no_tests: bool,
+
+    /// Skip vendored third-party code (paths matching `categories.vendor`)
+    #[arg(long)]
+    no_vendor: bool,
+
+    /// Only search source code, i.e. neither tests nor vendored code
+    #[arg(long, conflicts_with_all = ["no_tests", "no_vendor"])]
+    only_src: bool,
+
+    /// Only search comments: free-standing and TODO comments, and the doc comments of
+    /// definitions (shown instead of their code)
+    #[arg(long)]
+    comments: bool,
+
+    /// Only search code last changed by this author (part of the name or email); needs
+    /// `index.blame`
+    #[arg(long, value_name = "NAME")]
+    author: Option<String>,
+
+    /// Only search code last changed on or after this date (YYYY-MM-DD); needs `index.blame`
+    #[arg(long, value_name = "DATE", value_parser = blame::parse_date)]
+    since: Option<i64>,
+
+    /// Only search code (re)indexed since this date (YYYY-MM-DD) or time ago ("2 days ago",
+    /// "3h"), i.e. code that changed since then if the index is kept up to date
+    #[arg(long, value_name = "TIME", value_parser = blame::parse_time)]
+    changed_since: Option<i64>,
+
+    /// Index profile to use instead of the default (or per-branch) index
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Open the index read-only (e.g. a prebuilt index on a shared volume); no reindexing
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Keep the index, server socket and PID file in DIR instead of the project's `.ragrep`
+    #[arg(long, global = true, value_name = "DIR", env = "RAGREP_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Unix socket the server listens on and clients connect to
+    #[arg(long, global = true, value_name = "PATH", env = "RAGREP_SOCKET_PATH")]
+    socket_path: Option<PathBuf>,
+
+    /// Return the best results only while their code fits in N tokens (embedding tokenizer)
+    #[arg(long, value_name = "N")]
+    max_tokens: Option<usize>,
+
+    /// Print the results as one prompt-ready context block for LLM agents
+    #[arg(long, conflicts_with_all = ["files_only", "count", "files_with_matches", "open"])]
+    pack: bool,
+
+    /// Approximate token budget of the --pack output
+    #[arg(long, value_name = "TOKENS", default_value_t = pack::DEFAULT_BUDGET, requires = "pack")]
+    pack_budget: usize,
+
+    /// Write the --pack output to this file instead of stdout
+    #[arg(long, value_name = "FILE", requires = "pack")]
+    pack_output: Option<PathBuf>,
+
+    /// Open the N-th result (default: the top one) in the editor after searching
+    #[arg(long, value_name = "N", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+    open: Option<usize>,
+
+    /// Show how long each search stage (embedding, vector search, rerank) took
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Explain the ranking: each result's vector distance, rerank score and score
+    /// adjustments, the filters applied, and why other candidates were cut
+    #[arg(long, conflicts_with_all = ["fixed_strings", "pack", "count", "files_with_matches"])]
+    explain: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+impl Cli {
+    /// Whether results are printed without their code, so the server can skip sending it
+    fn omits_text(&self) -> bool {
+        self.files_only || self.count || self.files_with_matches
+    }
+
+    /// Search request for `query` with the filters given on the command line
+    fn search_request(&self, query: &str, top_n: usize, files_only: bool) -> SearchRequest {
+        SearchRequest {
+            query: query.to_string(),
+            top_n,
+            files_only,
+            project_root: None,
+            languages: self.languages.clone(),
+            profile: self.profile.clone(),
+            categories: self.categories(),
+            exclude_categories: self.excluded_categories(),
+            max_tokens: self.max_tokens,
+            kinds: Vec::new(),
+            paths: Vec::new(),
+            comments: self.comments,
+            author: self.author.clone(),
+            since: self.since,
+            changed_since: self.changed_since,
+            rerank_depth: self.rerank_depth,
+            queries: Vec::new(),
+            combine: if self.all {
+                QueryCombination::All
+            } else {
+                QueryCombination::Any
+            },
+            explain: self.explain,
+        }
+    }
+
+    /// Path categories to search; empty means all
+    fn categories(&self) -> Vec<String> {
+        if self.only_src {
+            vec![category::SOURCE.to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Path categories excluded from the search
+    fn excluded_categories(&self) -> Vec<String> {
+        let mut excluded = Vec::new();
+        if self.no_tests {
+            excluded.push(category::TEST.to_string());
+        }
+        if self.no_vendor {
+            excluded.push(category::VENDOR.to_string());
+        }
+        excluded
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Index the current directory or specified path
@@ -50,27 +268,325 @@ enum Commands {
         /// Perform full reindex (clear database and reindex all files)
         #[arg(short, long)]
         full: bool,
+
+        /// Continue an interrupted index, skipping files that were already completed
+        #[arg(long, conflicts_with_all = ["full", "rebuild"])]
+        resume: bool,
+
+        /// Recreate the vector table for the current embedding model, then reindex all
+        /// files (needed after switching to a model with another embedding dimension)
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Answer a question about the codebase with an LLM, citing the retrieved code
+    Ask {
+        /// Question to answer
+        question: String,
+
+        /// Number of retrieved chunks given to the model (defaults to `llm.context_chunks`)
+        #[arg(short = 'n', long)]
+        chunks: Option<usize>,
+    },
+    /// List the files the indexer would index, after ignore files, extensions and
+    /// exclusions
+    LsFiles {
+        /// Directory to list (defaults to current directory)
+        path: Option<PathBuf>,
+
+        /// Explain why this file is not indexed instead
+        #[arg(long, value_name = "FILE")]
+        why_excluded: Option<PathBuf>,
+    },
+    /// Find where a symbol is defined, like ctags, without loading any models
+    Defs {
+        /// Name of the function, type or trait
+        name: String,
+
+        /// Match every name containing NAME, ignoring case
+        #[arg(short, long)]
+        fuzzy: bool,
+
+        /// Maximum number of definitions to print
+        #[arg(short = 'n', long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// List TODO, FIXME, HACK and XXX comments, ranked by relevance to QUERY if given
+    Todos {
+        /// What the todos should be about; without it, every todo is listed by file
+        query: Option<String>,
+
+        /// Maximum number of todos to print
+        #[arg(short = 'n', long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Print everything stored about a chunk, by the id shown next to each search result
+    Show {
+        /// Chunk id (`#3f2a9c01b7d4` in search results)
+        #[arg(value_parser = parse_chunk_id)]
+        id: String,
     },
     /// Start the ragrep server
-    Serve {},
+    Serve {
+        /// Serve every project from one process, sharing loaded models
+        #[arg(long)]
+        global: bool,
+
+        /// Talk to a single client over stdin/stdout instead of a socket (for editor
+        /// plugins); exits when stdin closes
+        #[arg(long)]
+        stdio: bool,
+    },
+    /// Run the server at login as a systemd user service (Linux) or launchd agent (macOS)
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Check that every chunk has a vector and every vector a chunk, as a crash while
+    /// saving can break that
+    Doctor {
+        /// Repair the index: re-embed chunks without vectors, delete vectors without chunks
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Show what the index holds and how much of its size budget it uses
+    Status,
+    /// Print the running server's query and indexing counters for Prometheus (queries,
+    /// latency histogram, reindexes, chunks indexed, embedding cache hits)
+    Metrics,
+    /// Print the index manifest (files, chunk counts, content hashes, model), also kept in
+    /// `.ragrep/manifest.json`
+    Manifest,
+    /// Export the index (chunks, vectors and metadata) to a single compressed file
+    Export {
+        /// Archive file to write
+        output: PathBuf,
+    },
+    /// Replace the index with one previously written by `export`
+    Import {
+        /// Archive file to read
+        input: PathBuf,
+    },
+    /// Validate or create configuration files
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Measure search latency per stage and indexing throughput
+    Bench {
+        /// File with one query per line (defaults to a built-in set)
+        #[arg(short, long)]
+        queries: Option<PathBuf>,
+
+        /// Number of times to run each query
+        #[arg(short = 'n', long, default_value_t = 5)]
+        iterations: usize,
+
+        /// Directory to measure indexing throughput on (defaults to the current directory)
+        #[arg(long)]
+        sample: Option<PathBuf>,
+
+        /// Maximum number of files to embed for the throughput measurement (0 skips it)
+        #[arg(long, default_value_t = 50)]
+        sample_files: usize,
+    },
+    /// Measure search quality on a labeled query set: recall@k, MRR and latency
+    Eval {
+        /// Query set (.toml or .json) of queries and the file (and line) each should find
+        queries: PathBuf,
+
+        /// Results to search per query; an answer ranked lower counts as not found
+        #[arg(short, default_value_t = DEFAULT_TOP_N)]
+        k: usize,
+
+        /// Write a query set mined from git history to QUERIES instead: each commit's
+        /// summary, expecting the definitions it changed
+        #[arg(long)]
+        generate: bool,
+
+        /// Recent commits to mine with --generate
+        #[arg(long, default_value_t = 100, requires = "generate")]
+        commits: usize,
+    },
+    /// Print a shell completion script, e.g. `ragrep completions zsh > ~/.zfunc/_ragrep`
+    Completions {
+        /// Shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+    /// Run one model for a server with `server.model_worker`, answering requests on stdin
+    #[command(hide = true)]
+    ModelWorker {
+        model: worker::WorkerModel,
+
+        #[arg(long)]
+        model_cache_dir: PathBuf,
+
+        #[arg(long)]
+        threads: usize,
+
+        #[arg(long)]
+        quantized: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Install, enable and start the service for this project
+    Install {
+        /// Install the global server (`serve --global`) instead
+        #[arg(long)]
+        global: bool,
+    },
+    /// Stop the service and remove it
+    Uninstall {
+        /// Remove the global server's service instead
+        #[arg(long)]
+        global: bool,
+    },
+    /// Show whether the service is installed and running
+    Status {
+        /// Show the global server's service instead
+        #[arg(long)]
+        global: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Parse the global and project config files and show the effective configuration
+    Check,
+    /// Show the effective configuration (files merged with RAGREP_* environment variables)
+    Show {
+        /// Show where each value came from (default, global, local or environment)
+        #[arg(long)]
+        origins: bool,
+    },
+    /// Write a commented config template
+    Init {
+        /// Write the project config (.ragrep/config.toml) instead of the global one
+        #[arg(long)]
+        local: bool,
+
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Open the database of the selected index profile without loading any models
+fn open_selected_database(current_dir: &Path, cli: &Cli) -> Result<db::Database> {
+    let config_manager = config::ConfigManager::new(Some(current_dir))?;
+    let profile = context::resolve_profile(
+        current_dir,
+        &config_manager.config().index,
+        cli.profile.as_deref(),
+    );
+    let mut db = context::open_database(current_dir, profile.as_deref(), cli.read_only)?;
+    db.set_categories(&config_manager.config().categories)?;
+    Ok(db)
+}
+
+/// Report chunks without vectors and vectors without chunks; repair them with `fix`
+async fn doctor(current_dir: &Path, cli: &Cli, fix: bool) -> Result<()> {
+    // Only a repair needs the models
+    let report = open_selected_database(current_dir, cli)?.verify()?;
+    println!(
+        "Chunks: {} without vectors",
+        report.chunks_without_vectors.len()
+    );
+    println!(
+        "Vectors: {} without chunks",
+        report.vectors_without_chunks.len()
+    );
+    if report.is_clean() {
+        println!("Index is consistent");
+        return Ok(());
+    }
+    if !fix {
+        bail!("Index has orphaned rows; run `ragrep doctor --fix` to repair it");
+    }
+
+    let mut context = AppContext::new(current_dir, cli.profile.as_deref(), cli.read_only).await?;
+    let _lock = context.lock_index().await?;
+    // Whatever was saved while waiting for the lock is no longer orphaned
+    let report = context.db.verify()?;
+    context.repair_index(&report).await
+}
+
+/// Report parse errors in the config files and print the merged configuration
+fn check_config(current_dir: &Path) -> Result<()> {
+    let mut errors = 0;
+    for (label, path) in [
+        ("Global", config::global_config_path()?),
+        ("Local", config::local_config_path(current_dir)),
+    ] {
+        if !path.exists() {
+            println!("{} config: {} (not found)", label, path.display());
+            continue;
+        }
+        match config::load_config_file(&path) {
+            Ok(_) => println!("{} config: {} (ok)", label, path.display()),
+            Err(e) => {
+                errors += 1;
+                println!("{} config: {} (error)", label, path.display());
+                println!("{}", e);
+            }
+        }
+    }
+
+    if errors > 0 {
+        return Err(anyhow::anyhow!(
+            "{} config file(s) failed to parse; defaults are used in their place",
+            errors
+        ));
+    }
+
+    println!();
+    println!("Effective configuration:");
+    show_config(current_dir, false)
+}
+
+/// Print the merged configuration, optionally annotating each key with its origin
+fn show_config(current_dir: &Path, origins: bool) -> Result<()> {
+    let config_manager = config::ConfigManager::new(Some(current_dir))?;
+    if !origins {
+        print!("{}", toml::to_string_pretty(config_manager.config())?);
+        return Ok(());
+    }
+
+    for (key, value, origin) in config_manager.origins() {
+        println!("{} = {}  # {}", key, value, origin);
+    }
+    Ok(())
 }
 
 async fn incremental_index(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
     info!("Performing incremental index (only new files)");
-    
-    let indexer = indexer::Indexer::new();
-    let mut chunker = chunker::Chunker::new()?;
-    
+
+    let indexer = indexer::Indexer::new(&ctx.config_manager.config().index);
+    let mut chunker = chunker::Chunker::new(&ctx.config_manager.config().chunking)?
+        .with_plugins(&ctx.config_manager.config().chunkers, ctx.db.root())?;
+    let document_prefix = ctx
+        .config_manager
+        .config()
+        .embedding
+        .document_prefix
+        .clone();
+    let blamer = ctx
+        .config_manager
+        .config()
+        .index
+        .blame
+        .then(|| blame::Blamer::open(&path))
+        .flatten();
+
     // Get all files in directory
     let all_files = indexer.index_directory(&path)?;
-    
-    // Get already indexed files
-    let indexed_files: std::collections::HashSet<String> = ctx
-        .db
-        .get_indexed_files()?
-        .into_iter()
-        .collect();
-    
+
+    // Get already indexed files, and files evicted to stay within the size budget
+    let mut indexed_files: std::collections::HashSet<String> =
+        ctx.db.get_indexed_files()?.into_iter().collect();
+    indexed_files.extend(ctx.db.evicted_files()?);
+
     // Filter to only new files (not yet indexed)
     let new_files: Vec<_> = all_files
         .into_iter()
@@ -79,21 +595,21 @@ async fn incremental_index(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
             !indexed_files.contains(&path_str)
         })
         .collect();
-    
+
     if new_files.is_empty() {
         info!("No new files to index");
-        return Ok(());
+        return ctx.finish_indexing();
     }
-    
+
     info!("Found {} new files to index", new_files.len());
-    
+
     let total_files = new_files.len();
     let mut total_chunks = 0;
     let mut processed_chunks = 0;
-    
+
     // Set up progress bars
     let multi = MultiProgress::new();
-    
+
     let files_pb = multi.add(ProgressBar::new(total_files as u64));
     files_pb.set_style(
         ProgressStyle::default_bar()
@@ -102,7 +618,7 @@ async fn incremental_index(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
             .progress_chars("#>-"),
     );
     files_pb.set_message("Processing new files");
-    
+
     let chunks_pb = multi.add(ProgressBar::new_spinner());
     chunks_pb.set_style(
         ProgressStyle::default_spinner()
@@ -110,15 +626,18 @@ async fn incremental_index(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
             .unwrap(),
     );
     chunks_pb.set_message("Processing chunks");
-    
+
     for file in new_files {
         debug!("Processing: {}", file.path.display());
         files_pb.set_message(format!("Processing {}", file.path.display()));
-        
+
         let content = std::fs::read_to_string(&file.path)
             .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
-        
+
         let chunks = chunker.chunk_file(&file.path, &content)?;
+        let blame = blamer
+            .as_ref()
+            .and_then(|blamer| blamer.blame(&file.path, &content));
         total_chunks += chunks.len();
         chunks_pb.set_length(total_chunks as u64);
         chunks_pb.set_style(
@@ -127,49 +646,67 @@ async fn incremental_index(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
                 .unwrap()
                 .progress_chars("#>-"),
         );
-        
+
         if !chunks.is_empty() {
             let file_path = file.path.to_string_lossy().to_string();
-            
-            // Process chunks and store in database
+
+            // Embed every chunk, then store the file's chunks in one transaction
+            let mut records = Vec::with_capacity(chunks.len());
             for (chunk_index, chunk) in chunks.iter().enumerate() {
-                // Generate embedding for the chunk
-                let Embedding(embedding) =
-                    ctx.embedder.embed_text(&chunk.content, &file_path).await?;
-                
-                // Create longer-lived bindings for the values
-                let chunk_idx = chunk_index as i32;
-                
-                // Store chunk and embedding in database
-                ctx.db.save_chunk(
+                let embedding = context::chunk_embedding(
+                    &ctx.db,
+                    &ctx.embedder,
+                    &chunker,
+                    chunk,
                     &file_path,
-                    chunk_idx,
-                    &chunk.kind,
-                    chunk.parent_name.as_deref(),
-                    chunk.start_line,
-                    chunk.end_line,
-                    &chunk.content,
-                    chunk.hash(),
-                    &embedding,
-                )?;
-                
+                    &document_prefix,
+                )
+                .await?;
+                records.push(
+                    db::ChunkRecord::new(&file_path, chunk_index, chunk, embedding)
+                        .with_blame(blame.as_ref()),
+                );
+
                 processed_chunks += 1;
                 chunks_pb.set_position(processed_chunks as u64);
             }
+            ctx.db.save_chunks_batch(&records)?;
+
+            ctx.db
+                .save_symbols(&file_path, &chunker::symbols(&chunks))?;
+            ctx.db
+                .set_file_hash(&file_path, chunker::content_hash(&content))?;
         }
-        
+
         files_pb.inc(1);
     }
-    
+
     files_pb.finish_with_message("Files processing complete!");
     chunks_pb.finish_with_message("Chunks processing complete!");
-    
-    info!("Incremental indexing complete! {} chunks processed", processed_chunks);
-    
-    Ok(())
+
+    info!(
+        "Incremental indexing complete! {} chunks processed",
+        processed_chunks
+    );
+    report_redacted_secrets(&chunker);
+
+    ctx.finish_indexing()
+}
+
+/// Tell how many secrets indexing masked, so they can be removed from the code too
+fn report_redacted_secrets(chunker: &chunker::Chunker) {
+    let count = chunker.secrets_redacted();
+    if count > 0 {
+        warn!(
+            "Masked {} secrets (API keys, tokens, passwords) before indexing; consider \
+             removing them from the code",
+            count
+        );
+    }
 }
 
-async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
+/// Index every file under `path`; with `resume`, files an earlier run completed are skipped
+async fn index_codebase(ctx: &mut AppContext, path: PathBuf, resume: bool) -> Result<()> {
     info!("Initializing ragrep...");
     debug!(
         "Global config: {}",
@@ -178,23 +715,56 @@ async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
     if let Some(local_path) = &ctx.config_manager.local_config_path {
         debug!("Local config: {}", local_path.display());
     }
-    debug!(
-        "Database: {}",
-        ctx.ragrep_dir
-            .join(constants::constants::DATABASE_FILENAME)
-            .display()
-    );
+    debug!("Database: {}", ctx.database_path().display());
     let model_cache_dir = ctx.config_manager.get_model_cache_dir()?;
     debug!("Model cache: {}", model_cache_dir.display());
     info!("Indexing codebase at: {}", path.display());
 
-    let indexer = indexer::Indexer::new();
-    let mut chunker = chunker::Chunker::new()?;
-    let files = indexer.index_directory(&path)?;
+    let indexer = indexer::Indexer::new(&ctx.config_manager.config().index);
+    let mut chunker = chunker::Chunker::new(&ctx.config_manager.config().chunking)?
+        .with_plugins(&ctx.config_manager.config().chunkers, ctx.db.root())?;
+    let document_prefix = ctx
+        .config_manager
+        .config()
+        .embedding
+        .document_prefix
+        .clone();
+    let blamer = ctx
+        .config_manager
+        .config()
+        .index
+        .blame
+        .then(|| blame::Blamer::open(&path))
+        .flatten();
+    let mut files = indexer.index_directory(&path)?;
     let total_files = files.len();
     let mut total_chunks = 0;
     let mut processed_chunks = 0;
 
+    if resume {
+        // A file is complete once its content hash is recorded, which happens after all of
+        // its chunks were saved
+        let mut pending = Vec::with_capacity(files.len());
+        for file in files {
+            let file_path = file.path.to_string_lossy().to_string();
+            let indexed = match std::fs::read_to_string(&file.path) {
+                Ok(content) => {
+                    ctx.db.get_file_hash(&file_path)? == Some(chunker::content_hash(&content))
+                }
+                Err(_) => false,
+            };
+            if !indexed {
+                pending.push(file);
+            }
+        }
+        files = pending;
+        info!(
+            "Resuming: {} of {} files already indexed",
+            total_files - files.len(),
+            total_files
+        );
+    }
+
     // Set up progress bars
     let multi = MultiProgress::new();
 
@@ -206,6 +776,8 @@ async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
             .progress_chars("#>-"),
     );
     files_pb.set_message("Processing files");
+    files_pb.set_position((total_files - files.len()) as u64);
+    files_pb.reset_eta();
 
     let chunks_pb = multi.add(ProgressBar::new_spinner());
     chunks_pb.set_style(
@@ -223,6 +795,9 @@ async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
             .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
 
         let chunks = chunker.chunk_file(&file.path, &content)?;
+        let blame = blamer
+            .as_ref()
+            .and_then(|blamer| blamer.blame(&file.path, &content));
         total_chunks += chunks.len();
         chunks_pb.set_length(total_chunks as u64);
         chunks_pb.set_style(
@@ -232,36 +807,42 @@ async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
                 .progress_chars("#>-"),
         );
 
-        if !chunks.is_empty() {
-            let file_path = file.path.to_string_lossy().to_string();
+        let file_path = file.path.to_string_lossy().to_string();
+        if resume {
+            // Drop chunks an interrupted run saved before the file was complete
+            ctx.db.delete_file(&file_path)?;
+        }
 
-            // Process chunks and store in database
+        if !chunks.is_empty() {
+            // Embed every chunk, then store the file's chunks in one transaction
+            let mut records = Vec::with_capacity(chunks.len());
             for (chunk_index, chunk) in chunks.iter().enumerate() {
-                // Generate embedding for the chunk
-                let Embedding(embedding) =
-                    ctx.embedder.embed_text(&chunk.content, &file_path).await?;
-
-                // Create longer-lived bindings for the values
-                let chunk_idx = chunk_index as i32;
-
-                // Store chunk and embedding in database
-                ctx.db.save_chunk(
+                let embedding = context::chunk_embedding(
+                    &ctx.db,
+                    &ctx.embedder,
+                    &chunker,
+                    chunk,
                     &file_path,
-                    chunk_idx,
-                    &chunk.kind,
-                    chunk.parent_name.as_deref(),
-                    chunk.start_line,
-                    chunk.end_line,
-                    &chunk.content,
-                    chunk.hash(),
-                    &embedding,
-                )?;
+                    &document_prefix,
+                )
+                .await?;
+                records.push(
+                    db::ChunkRecord::new(&file_path, chunk_index, chunk, embedding)
+                        .with_blame(blame.as_ref()),
+                );
 
                 processed_chunks += 1;
                 chunks_pb.set_position(processed_chunks as u64);
             }
+            ctx.db.save_chunks_batch(&records)?;
         }
 
+        ctx.db
+            .save_symbols(&file_path, &chunker::symbols(&chunks))?;
+        // Record completion last so an interrupted run redoes this file on --resume
+        ctx.db
+            .set_file_hash(&file_path, chunker::content_hash(&content))?;
+
         files_pb.inc(1);
     }
 
@@ -269,41 +850,96 @@ async fn index_codebase(ctx: &mut AppContext, path: PathBuf) -> Result<()> {
     chunks_pb.finish_with_message("Chunks processing complete!");
 
     info!("Indexing complete! {} chunks processed", processed_chunks);
-    debug!(
-        "Database: {}",
-        ctx.ragrep_dir
-            .join(constants::constants::DATABASE_FILENAME)
-            .display()
-    );
+    report_redacted_secrets(&chunker);
+    debug!("Database: {}", ctx.database_path().display());
 
-    Ok(())
+    ctx.finish_indexing()
 }
 
-fn display_search_results(response: &SearchResponse, files_only: bool) -> Result<()> {
-    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
-
-    for result in &response.results {
-        // Print file path in purple with line range
-        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
-        write!(stdout, "{}:", result.file_path)?;
-        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)).set_bold(true))?;
-        writeln!(stdout, "{}:{}", result.start_line, result.end_line)?;
-        stdout.reset()?;
+/// Resolve a root-relative result path, shown relative to the current directory when inside it
+fn display_path(root: &Path, file_path: &str) -> String {
+    let path = root.join(file_path);
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
 
-        debug!(
-            "Match found in {} (lines {}-{}) with relevance score: {:.4}",
-            result.file_path, result.start_line, result.end_line, result.score
-        );
+fn display_search_results(
+    response: &SearchResponse,
+    root: &Path,
+    cli: &Cli,
+    output: &config::OutputConfig,
+) -> Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
 
-        // Print content with line numbers only if not in files-only mode
-        if !files_only && !result.text.is_empty() {
-            for (i, line) in result.text.lines().enumerate() {
-                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true))?;
-                write!(stdout, "{}:", result.start_line + i as i32)?;
+    // Script-friendly modes print plain lines only; stats still go to the log on stderr
+    if cli.count {
+        for (file_path, count) in response.counts_per_file() {
+            writeln!(stdout, "{}:{}", display_path(root, file_path), count)?;
+        }
+    } else if cli.files_with_matches {
+        for file_path in response.matching_files() {
+            writeln!(stdout, "{}", display_path(root, file_path))?;
+        }
+    } else if let Some(template) = &output.template {
+        for result in &response.results {
+            let file = display_path(root, &result.file_path);
+            writeln!(stdout, "{}", output::format_result(template, &file, result))?;
+        }
+    } else {
+        for result in &response.results {
+            // Print file path in purple with line range
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
+            write!(stdout, "{}:", display_path(root, &result.file_path))?;
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)).set_bold(true))?;
+            write!(stdout, "{}:{}", result.start_line, result.end_line)?;
+            stdout.reset()?;
+            match &result.name {
+                Some(name) => write!(stdout, " {} {}", result.kind, name)?,
+                None if !result.kind.is_empty() => write!(stdout, " {}", result.kind)?,
+                None => {}
+            }
+            if let Some(id) = &result.id {
+                stdout.set_color(ColorSpec::new().set_dimmed(true))?;
+                write!(stdout, " #{}", id)?;
                 stdout.reset()?;
-                writeln!(stdout, " {}", line)?;
             }
             writeln!(stdout)?;
+            if let Some(explanation) = &result.explanation {
+                stdout.set_color(ColorSpec::new().set_dimmed(true))?;
+                write!(
+                    stdout,
+                    "  distance {:.4}, rerank score {:.4}",
+                    explanation.distance, explanation.rerank_score
+                )?;
+                for adjustment in &explanation.adjustments {
+                    write!(stdout, ", {} {:+.4}", adjustment.reason, adjustment.delta)?;
+                }
+                writeln!(stdout, ", score {:.4}", result.score)?;
+                stdout.reset()?;
+            }
+
+            debug!(
+                "Match found in {} (lines {}-{}) with relevance score: {:.4}",
+                result.file_path, result.start_line, result.end_line, result.score
+            );
+
+            // Print content with line numbers only if not in files-only mode
+            if !cli.files_only && !result.text.is_empty() {
+                for (i, line) in result.text.lines().enumerate() {
+                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true))?;
+                    write!(stdout, "{}:", result.start_line + i as i32)?;
+                    stdout.reset()?;
+                    writeln!(stdout, " {}", line)?;
+                }
+                writeln!(stdout)?;
+            }
+        }
+        if let Some(explanation) = &response.explanation {
+            print_search_explanation(&mut stdout, explanation, root)?;
         }
     }
 
@@ -312,27 +948,587 @@ fn display_search_results(response: &SearchResponse, files_only: bool) -> Result
         "Found {} results in {}ms (from {} candidates)",
         response.stats.num_results, response.stats.total_time_ms, response.stats.num_candidates
     );
+    if let Some(num_tokens) = response.stats.num_tokens {
+        info!("  {} tokens of results", num_tokens);
+    }
+    if cli.verbose {
+        if response.stats.expand_ms > 0 {
+            info!("  query expansion {}ms", response.stats.expand_ms);
+        }
+        if let Some(num_files) = response.stats.num_candidate_files {
+            info!(
+                "  file search {}ms, picked {} files",
+                response.stats.file_search_ms, num_files
+            );
+        }
+        info!(
+            "  embed {}ms, vector search {}ms, rerank {}ms",
+            response.stats.embed_ms, response.stats.vector_search_ms, response.stats.rerank_ms
+        );
+    }
+
+    if let Some(rank) = cli.open {
+        open_result(response, root, rank)?;
+    }
 
     Ok(())
 }
 
-async fn query_codebase(ctx: &mut AppContext, query: String, files_only: bool) -> Result<()> {
-    debug!("Searching for: {}", query);
+/// Most cut candidates `--explain` lists before summing up the rest
+const MAX_EXPLAINED_CUTS: usize = 10;
 
-    let request = SearchRequest {
-        query,
-        top_n: 10,
-        files_only,
+/// Print the steps of an explained search and the best candidates it cut
+fn print_search_explanation(
+    stdout: &mut StandardStream,
+    explanation: &SearchExplanation,
+    root: &Path,
+) -> Result<()> {
+    stdout.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(stdout, "How the results were found:")?;
+    stdout.reset()?;
+    for step in &explanation.steps {
+        writeln!(stdout, "  {}", step)?;
+    }
+
+    if explanation.cut.is_empty() {
+        return Ok(());
+    }
+    stdout.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(stdout, "Cut candidates:")?;
+    stdout.reset()?;
+    for cut in explanation.cut.iter().take(MAX_EXPLAINED_CUTS) {
+        write!(
+            stdout,
+            "  {}:{}:{}",
+            display_path(root, &cut.file_path),
+            cut.start_line,
+            cut.end_line
+        )?;
+        match &cut.name {
+            Some(name) => write!(stdout, " {} {}", cut.kind, name)?,
+            None if !cut.kind.is_empty() => write!(stdout, " {}", cut.kind)?,
+            None => {}
+        }
+        stdout.set_color(ColorSpec::new().set_dimmed(true))?;
+        match cut.score {
+            Some(score) => writeln!(stdout, " (score {:.4}): {}", score, cut.reason)?,
+            None => writeln!(stdout, ": {}", cut.reason)?,
+        }
+        stdout.reset()?;
+    }
+    if explanation.cut.len() > MAX_EXPLAINED_CUTS {
+        writeln!(
+            stdout,
+            "  ... and {} more",
+            explanation.cut.len() - MAX_EXPLAINED_CUTS
+        )?;
+    }
+    Ok(())
+}
+
+/// Parse a chunk id as printed next to search results, with or without the leading `#`
+fn parse_chunk_id(value: &str) -> Result<String, String> {
+    let id = value.trim_start_matches('#').to_ascii_lowercase();
+    if id.len() != 12 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid chunk id '{}'", value));
+    }
+    Ok(id)
+}
+
+/// Print a stored chunk: its metadata, leading comments and numbered text
+fn print_chunk(db: &db::Database, root: &Path, id: &str) -> Result<()> {
+    let Some(chunk) = db.get_chunk(id)? else {
+        bail!(
+            "No chunk with id {} (it changes when the chunk's code does; search again)",
+            id
+        );
     };
 
-    let response = server::execute_search(ctx, request).await?;
+    println!("id:       {}", id);
+    println!(
+        "file:     {}:{}-{}",
+        display_path(root, &chunk.file_path),
+        chunk.start_line,
+        chunk.end_line
+    );
+    match &chunk.node_name {
+        Some(name) => println!("kind:     {} {}", chunk.node_type, name),
+        None => println!("kind:     {}", chunk.node_type),
+    }
+    println!("language: {}", chunk.language.as_deref().unwrap_or("-"));
+    println!("category: {}", chunk.category.as_deref().unwrap_or("-"));
+    println!("position: chunk {} of its file", chunk.chunk_index + 1);
+    if let (Some(author), Some(time)) = (&chunk.author, chunk.committed_at) {
+        println!("changed:  {} by {}", blame::format_date(time), author);
+    }
+    println!("hash:     {:016x}", chunk.hash as u64);
+    println!("indexed:  {}", chunk.created_at);
 
-    if response.results.is_empty() {
-        info!("No similar code found");
+    if !chunk.comments.trim().is_empty() {
+        println!("\n{}", chunk.comments.trim_end());
+    }
+    println!();
+    for (i, line) in chunk.text.lines().enumerate() {
+        println!("{}: {}", chunk.start_line + i as i32, line);
+    }
+    Ok(())
+}
+
+/// Print the files the indexer would index under `root`, relative to the current directory
+fn list_files(config: &config::IndexConfig, root: &Path) -> Result<()> {
+    let mut files: Vec<String> = indexer::Indexer::new(config)
+        .index_directory(root)?
+        .into_iter()
+        .map(|file| display_path(root, &file.path.to_string_lossy()))
+        .collect();
+    files.sort();
+
+    let mut stdout = std::io::stdout().lock();
+    for file in files {
+        writeln!(stdout, "{}", file)?;
+    }
+    Ok(())
+}
+
+/// Print whether `file` is indexed and, if not, why: skipped by the walk of `root`, or
+/// left without chunks as generated or minified
+fn explain_exclusion(config: &config::Config, root: &Path, file: &Path) -> Result<()> {
+    let indexer = indexer::Indexer::new(&config.index);
+    let reason = match indexer.why_excluded(root, file)? {
+        Some(reason) => Some(reason),
+        None if config.chunking.skip_generated => {
+            let content = std::fs::read_to_string(file).unwrap_or_default();
+            if normalize::is_generated(&content) {
+                Some("its first lines mark it as generated (see chunking.skip_generated)".into())
+            } else if normalize::is_minified(&content) {
+                Some("it looks minified (see chunking.skip_generated)".into())
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+
+    match reason {
+        Some(reason) => println!("{} is not indexed: {}", file.display(), reason),
+        None => println!("{} is indexed", file.display()),
+    }
+    Ok(())
+}
+
+/// Print the number of files, chunks and definitions in the index and its size against
+/// `index.max_size_mb`
+fn print_status(db: &db::Database, config: &config::Config) -> Result<()> {
+    let evicted = db.evicted_files()?.len();
+    let files = db.get_indexed_files()?.len();
+    if evicted > 0 {
+        println!("files:    {} ({} evicted)", files, evicted);
+    } else {
+        println!("files:    {}", files);
+    }
+    let (chunks, vectors) = (db.count_chunks()?, db.count_vectors()?);
+    if vectors < chunks {
+        // Copies of the same content share a vector
+        println!("chunks:   {} ({} distinct)", chunks, vectors);
+    } else {
+        println!("chunks:   {}", chunks);
+    }
+    println!("symbols:  {}", db.count_symbols()?);
+    let vectors = |storage: config::VectorStorage, dimensions: usize| {
+        format!("{} x {}", storage.name(), dimensions)
+    };
+    let stored = vectors(
+        db.vector_storage(),
+        db.dimensions()
+            .unwrap_or(constants::constants::EMBEDDING_DIMENSIONS),
+    );
+    let configured = vectors(
+        config.index.vector_storage,
+        config
+            .embedding
+            .dimensions
+            .unwrap_or(constants::constants::EMBEDDING_DIMENSIONS),
+    );
+    if stored == configured {
+        println!("vectors:  {}", stored);
+    } else {
+        println!(
+            "vectors:  {} ({} after `ragrep index --full`)",
+            stored, configured
+        );
+    }
+
+    let size_mb = db.size_bytes()? as f64 / (1024.0 * 1024.0);
+    match config.index.max_size_mb {
+        // The budget counts the data, not the file's pages (see `Database::data_bytes`)
+        Some(max_size_mb) => {
+            let data_mb = db.data_bytes()? as f64 / (1024.0 * 1024.0);
+            println!(
+                "size:     {:.1} MB, {:.1} MB of data of {} MB ({:.0}%), evicting {} first",
+                size_mb,
+                data_mb,
+                max_size_mb,
+                100.0 * data_mb / max_size_mb.max(1) as f64,
+                config.index.eviction.name()
+            )
+        }
+        None => println!("size:     {:.1} MB (no budget)", size_mb),
+    }
+    Ok(())
+}
+
+/// Print the definitions of `name` as `path:line: kind name`
+fn print_definitions(db: &db::Database, name: &str, fuzzy: bool, limit: usize) -> Result<()> {
+    let definitions = db.find_symbols(name, fuzzy, limit)?;
+    if definitions.is_empty() {
+        if db.count_symbols()? == 0 && db.count_chunks()? > 0 {
+            warn!("This index has no symbol table yet; run `ragrep index --full` to build it");
+        } else if fuzzy {
+            info!("No definitions matching '{}'", name);
+        } else {
+            info!("No definitions of '{}' (try --fuzzy)", name);
+        }
         return Ok(());
     }
 
-    display_search_results(&response, files_only)?;
+    let cwd = std::env::current_dir()?;
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    for (symbol, kind, file_path, line) in definitions {
+        let path = db.resolve_path(&file_path);
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
+        write!(stdout, "{}:", display_path(&cwd, &path.to_string_lossy()))?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)).set_bold(true))?;
+        write!(stdout, "{}:", line)?;
+        stdout.reset()?;
+        writeln!(stdout, " {} {}", kind, symbol)?;
+    }
+    Ok(())
+}
+
+/// List the indexed todo comments by file and line, without loading any models
+fn print_todos(db: &db::Database, limit: usize) -> Result<()> {
+    let todos = db.find_chunks_of_kind(chunker::TODO, limit)?;
+    if todos.is_empty() {
+        let has_comments = !db.find_chunks_of_kind(chunker::COMMENT, 1)?.is_empty();
+        if !has_comments && db.count_chunks()? > 0 {
+            warn!("This index may predate comment chunks; run `ragrep index --full` to add them");
+        } else {
+            info!("No TODO, FIXME, HACK or XXX comments found");
+        }
+        return Ok(());
+    }
+
+    let cwd = std::env::current_dir()?;
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    for todo in todos {
+        let path = db.resolve_path(&todo.file_path);
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
+        write!(stdout, "{}:", display_path(&cwd, &path.to_string_lossy()))?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)).set_bold(true))?;
+        write!(stdout, "{}:", todo.start_line)?;
+        stdout.reset()?;
+        let line = todo
+            .text
+            .lines()
+            .find(|line| chunker::todo_marker(line).is_some())
+            .unwrap_or_default();
+        writeln!(stdout, " {}", line.trim())?;
+    }
+    Ok(())
+}
+
+/// Write the `--pack` context block to stdout or the `--pack-output` file
+fn write_pack(query: &str, response: &SearchResponse, cli: &Cli) -> Result<()> {
+    let pack = pack::build_pack(query, &response.results, cli.pack_budget);
+    match &cli.pack_output {
+        Some(path) => {
+            std::fs::write(path, pack)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            info!("Wrote context pack to {}", path.display());
+        }
+        None => print!("{}", pack),
+    }
+    Ok(())
+}
+
+/// Launch the configured editor at the `rank`-th result (1-based)
+fn open_result(response: &SearchResponse, root: &Path, rank: usize) -> Result<()> {
+    let Some(result) = response.results.get(rank.max(1) - 1) else {
+        warn!(
+            "Cannot open result {}: only {} results",
+            rank,
+            response.results.len()
+        );
+        return Ok(());
+    };
+
+    let config_manager = config::ConfigManager::new(Some(root))?;
+    editor::open(
+        &root.join(&result.file_path),
+        result.start_line,
+        config_manager.config().editor.command.as_deref(),
+    )
+}
+
+/// Run a search through the server when one is available, otherwise standalone
+///
+/// Returns the response together with the project root its paths are relative to.
+async fn run_search(
+    current_dir: &Path,
+    cli: &Cli,
+    request: SearchRequest,
+) -> Result<(SearchResponse, PathBuf)> {
+    debug!("Searching for: {}", request.query);
+
+    if let Some(found) = server_search(current_dir, request.clone()).await? {
+        return Ok(found);
+    }
+
+    let mut context = AppContext::new(current_dir, cli.profile.as_deref(), cli.read_only).await?;
+    let response = server::execute_search(&mut context, request).await?;
+    Ok((response, context.db.root().to_path_buf()))
+}
+
+/// Run a search through the server of the project at `current_dir`
+///
+/// Returns `None` when there is no server or it failed in a way standalone mode may not.
+async fn server_search(
+    current_dir: &Path,
+    mut request: SearchRequest,
+) -> Result<Option<(SearchResponse, PathBuf)>> {
+    if !client::RagrepClient::is_server_available(current_dir) {
+        warn!("No server detected. Start one with: ragrep serve");
+        info!("Running in standalone mode...");
+        return Ok(None);
+    }
+    info!("Server detected, using fast mode");
+
+    let client = client::RagrepClient::new(current_dir)?;
+    info!("Connected to server at {}", client.socket_path().display());
+
+    request.project_root = Some(client.project_root().to_string_lossy().to_string());
+    let mut backoff = BUSY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        let e = match client.search(request.clone()).await {
+            Ok(response) => return Ok(Some((response, client.project_root().to_path_buf()))),
+            Err(e) => e,
+        };
+        let code = e
+            .downcast_ref::<client::ServerError>()
+            .and_then(|error| error.code);
+        match code {
+            // A busy server is back shortly; loading the models standalone would only add
+            // to the load
+            Some(ErrorCode::Busy) if retries < BUSY_RETRIES => {
+                debug!("Server busy, retrying in {:?}", backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            // Standalone mode would run into the same problem
+            Some(code @ (ErrorCode::IndexEmpty | ErrorCode::BadRequest | ErrorCode::Busy)) => {
+                return Err(e.context(error_hint(code)));
+            }
+            _ => {
+                warn!("Server query failed: {}, falling back to standalone", e);
+                if let Some(code) = code {
+                    info!("{}", error_hint(code));
+                }
+                warn!("Running in standalone mode (slower, loads models for each query)");
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Replace the `@name` queries with the saved searches they name
+///
+/// Returns the queries to run and the filters of the saved searches used, combined.
+fn expand_aliases(
+    current_dir: &Path,
+    queries: &[String],
+) -> Result<(Vec<String>, config::SavedSearch)> {
+    if !queries.iter().any(|query| query.starts_with('@')) {
+        return Ok((queries.to_vec(), config::SavedSearch::default()));
+    }
+
+    let config_manager = config::ConfigManager::new(Some(current_dir))?;
+    let mut presets = config::SavedSearch::default();
+    let mut expanded = Vec::new();
+    for query in queries {
+        let Some(name) = query.strip_prefix('@') else {
+            expanded.push(query.clone());
+            continue;
+        };
+        let search = config_manager.config().alias(name)?;
+        debug!("@{} expands to: {}", name, search.query);
+        expanded.push(search.query);
+        presets.paths.extend(search.paths);
+        presets.kinds.extend(search.kinds);
+        presets.languages.extend(search.languages);
+        presets.top = presets.top.or(search.top);
+    }
+    Ok((expanded, presets))
+}
+
+/// Search each of the `--project` directories and rank their results together
+///
+/// Projects with a server are searched through it, the others standalone, loading the
+/// models once for each embedding setup they use. Their results are then reranked
+/// together, so one model's scores decide the merged order.
+async fn search_projects(
+    current_dir: &Path,
+    cli: &Cli,
+    request: SearchRequest,
+) -> Result<SearchResponse> {
+    let dirs = cli
+        .projects
+        .iter()
+        .map(|project| {
+            current_dir
+                .join(project)
+                .canonicalize()
+                .with_context(|| format!("Project not found: {}", project.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut models: HashMap<context::ModelsKey, context::Models> = HashMap::new();
+    let mut responses = Vec::new();
+    for (dir, name) in dirs.iter().zip(project_names(&dirs)) {
+        let dir = dir.as_path();
+        info!("Searching {}", name);
+
+        let response = if cli.fixed_strings {
+            let db = open_selected_database(dir, cli)?;
+            server::fixed_string_search(&db, &request)?
+        } else if let Some((response, _)) = server_search(dir, request.clone()).await? {
+            response
+        } else {
+            let config_manager = config::ConfigManager::new(Some(dir))?;
+            let models = match models.entry(context::Models::key(&config_manager)?) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => entry
+                    .insert(context::Models::load(&config_manager)?)
+                    .clone(),
+            };
+            let profile = context::resolve_profile(
+                dir,
+                &config_manager.config().index,
+                cli.profile.as_deref(),
+            );
+            let mut context =
+                AppContext::with_models(dir, models, profile.as_deref(), cli.read_only)?;
+            server::execute_search(&mut context, request.clone()).await?
+        };
+        responses.push((name, response));
+    }
+
+    // Each project scored its results with its own models and config; one rerank of them
+    // all makes the scores compare. Fixed-string counts and file lists have nothing to
+    // rerank.
+    if responses.len() > 1 && !cli.fixed_strings && !request.files_only {
+        let config_manager = config::ConfigManager::new(Some(current_dir))?;
+        let reranker = match models.get(&context::Models::key(&config_manager)?) {
+            Some(models) => Arc::clone(&models.reranker),
+            None => Arc::new(context::Models::load_reranker(&config_manager)?),
+        };
+        let mut results: Vec<&mut SearchResult> = responses
+            .iter_mut()
+            .flat_map(|(_, response)| response.results.iter_mut())
+            .collect();
+        server::rerank_results(
+            &reranker,
+            &config_manager.config().search.rerank_template,
+            &request,
+            &mut results,
+        )?;
+    }
+    Ok(SearchResponse::merge_projects(responses, request.top_n))
+}
+
+/// Names the results of each project are shown under: the directory's name, with as many
+/// parent directories as it takes to tell apart projects of the same name
+fn project_names(dirs: &[PathBuf]) -> Vec<String> {
+    let suffix = |dir: &Path, depth: usize| {
+        let components: Vec<_> = dir.components().collect();
+        let start = components.len().saturating_sub(depth);
+        components[start..]
+            .iter()
+            .collect::<PathBuf>()
+            .display()
+            .to_string()
+    };
+    dirs.iter()
+        .map(|dir| {
+            let depth = (1..=dir.components().count())
+                .find(|&depth| {
+                    let name = suffix(dir, depth);
+                    dirs.iter()
+                        .filter(|other| *other != dir)
+                        .all(|other| suffix(other, depth) != name)
+                })
+                .unwrap_or(usize::MAX);
+            suffix(dir, depth)
+        })
+        .collect()
+}
+
+/// What the user can do about a server error
+fn error_hint(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::IndexEmpty => "Nothing is indexed yet; build the index with `ragrep index`",
+        ErrorCode::ModelError => {
+            "The server's models failed; check its log or restart it with `ragrep serve`"
+        }
+        ErrorCode::BadRequest => {
+            "The server rejected the request; if it runs another ragrep version, restart it"
+        }
+        ErrorCode::Busy => {
+            "The index is locked by a write (e.g. `ragrep index`) or the server is at its \
+             query limits (`[server]` in the config); try again shortly"
+        }
+        ErrorCode::Internal => "The server failed unexpectedly; its log has the details",
+    }
+}
+
+/// Answer `question` from the top retrieved chunks and list the cited locations
+async fn ask(current_dir: &Path, cli: &Cli, question: &str, chunks: Option<usize>) -> Result<()> {
+    let llm_config = config::ConfigManager::new(Some(current_dir))?
+        .config()
+        .llm
+        .clone();
+    let top_n = chunks.unwrap_or(llm_config.context_chunks);
+
+    let (response, root) =
+        run_search(current_dir, cli, cli.search_request(question, top_n, false)).await?;
+    if response.results.is_empty() {
+        bail!("No relevant code found to answer from; has the project been indexed?");
+    }
+
+    info!(
+        "Asking {} with {} code excerpts...",
+        llm_config.model,
+        response.results.len()
+    );
+    let prompt = llm::build_prompt(question, &response.results);
+    let answer = llm::complete(&llm_config, &prompt).await?;
+
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    writeln!(stdout, "{}\n", answer)?;
+    stdout.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(stdout, "References:")?;
+    stdout.reset()?;
+    for (i, result) in response.results.iter().enumerate() {
+        writeln!(
+            stdout,
+            "[{}] {}:{}-{}",
+            i + 1,
+            display_path(&root, &result.file_path),
+            result.start_line,
+            result.end_line
+        )?;
+    }
 
     Ok(())
 }
@@ -349,68 +1545,173 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
     let current_dir = std::env::current_dir().context("Failed to get current directory")?;
+    config::set_path_overrides(config::PathOverrides {
+        data_dir: cli.data_dir.as_ref().map(|dir| current_dir.join(dir)),
+        socket_path: cli.socket_path.as_ref().map(|path| current_dir.join(path)),
+    });
 
-    match (&cli.query, &cli.command) {
-        (Some(query), None) => {
-            // Try to use server first
-            if client::RagrepClient::is_server_available(&current_dir) {
-                info!("Server detected, using fast mode");
-
-                let client = client::RagrepClient::new(&current_dir)?;
-                info!("Connected to server at {}", client.socket_path().display());
-
-                let request = protocol::SearchRequest {
-                    query: query.clone(),
-                    top_n: 10,
-                    files_only: cli.files_only,
-                };
-
-                match client.search(request).await {
-                    Ok(response) => {
-                        display_search_results(&response, cli.files_only)?;
-                    }
-                    Err(e) => {
-                        warn!("Server query failed: {}, falling back to standalone", e);
-                        warn!("Running in standalone mode (slower, loads models for each query)");
-                        // Fall back to standalone
-                        let mut context = AppContext::new(&current_dir).await?;
-                        query_codebase(&mut context, query.clone(), cli.files_only).await?;
-                    }
-                }
+    match (cli.queries.split_first(), &cli.command) {
+        (Some(_), None) => {
+            let (queries, presets) = expand_aliases(&current_dir, &cli.queries)?;
+            let top_n = cli.top_n.or(presets.top).unwrap_or(DEFAULT_TOP_N);
+            // A token budget, not the result count, bounds the output
+            let top_n = if cli.max_tokens.is_some() {
+                MAX_TOKENS_CANDIDATES.max(top_n)
+            } else {
+                top_n
+            };
+            let mut request = SearchRequest {
+                queries: queries[1..].to_vec(),
+                ..cli.search_request(&queries[0], top_n, cli.omits_text())
+            };
+            request.paths.extend(presets.paths);
+            request.kinds.extend(presets.kinds);
+            request.languages.extend(presets.languages);
+            let (mut response, root) = if !cli.projects.is_empty() {
+                // Merged paths start with the project name rather than being under one root
+                let response = search_projects(&current_dir, &cli, request).await?;
+                (response, PathBuf::new())
+            } else if cli.fixed_strings {
+                let db = open_selected_database(&current_dir, &cli)?;
+                let response = server::fixed_string_search(&db, &request)?;
+                (response, db.root().to_path_buf())
+            } else {
+                run_search(&current_dir, &cli, request).await?
+            };
+            response.sort(cli.sort);
+            let output_config = config::ConfigManager::new(Some(&current_dir))?
+                .config()
+                .output
+                .clone();
+            if cli.pack {
+                write_pack(&queries.join("; "), &response, &cli)?;
+            } else if response.results.is_empty() {
+                info!("No similar code found");
             } else {
-                // No server found, run standalone
-                warn!("No server detected. Start one with: ragrep serve");
-                info!("Running in standalone mode...");
-                let mut context = AppContext::new(&current_dir).await?;
-                query_codebase(&mut context, query.clone(), cli.files_only).await?;
+                display_search_results(&response, &root, &cli, &output_config)?;
             }
+            if let Some(hook) = &output_config.hook {
+                output::run_hook(hook, &response, &root)?;
+            }
+        }
+        (None, Some(Commands::Ask { question, chunks })) => {
+            ask(&current_dir, &cli, question, *chunks).await?;
         }
-        (None, Some(Commands::Index { path, full })) => {
+        (
+            None,
+            Some(Commands::Index {
+                path,
+                full,
+                resume,
+                rebuild,
+            }),
+        ) => {
+            if cli.read_only {
+                bail!("Cannot index with --read-only");
+            }
             let index_path = path
                 .clone()
                 .map(PathBuf::from)
                 .unwrap_or(current_dir.clone());
-            let mut context = AppContext::new(&current_dir).await?;
-            
-            if *full {
+            let mut context =
+                AppContext::new(&current_dir, cli.profile.as_deref(), cli.read_only).await?;
+            // Held until indexing finishes; a concurrent run waits here
+            let _lock = context.lock_index().await?;
+
+            if *full || *rebuild {
                 info!("Performing full reindex (clearing database)");
-                context.db.clear_all()?;
-                index_codebase(&mut context, index_path).await?;
+                context.clear_index()?;
+                if *rebuild {
+                    let storage = context.config_manager.config().index.vector_storage;
+                    let dimensions = context.embedder.dimensions();
+                    context.db.rebuild_vector_table(storage, dimensions)?;
+                }
+                index_codebase(&mut context, index_path, false).await?;
+            } else if *resume {
+                index_codebase(&mut context, index_path, true).await?;
             } else {
                 // Incremental index: only index new files
                 incremental_index(&mut context, index_path).await?;
             }
         }
-        (None, Some(Commands::Serve {})) => {
-            // Create AppContext (loads models)
-            let context = AppContext::new(&current_dir).await?;
-
-            // Create server
-            let mut server = server::RagrepServer::new(context, &current_dir);
+        (None, Some(Commands::LsFiles { path, why_excluded })) => {
+            let root = path.clone().unwrap_or(current_dir.clone());
+            let config_manager = config::ConfigManager::new(Some(&current_dir))?;
+            match why_excluded {
+                Some(file) => explain_exclusion(config_manager.config(), &root, file)?,
+                None => list_files(&config_manager.config().index, &root)?,
+            }
+        }
+        (None, Some(Commands::Defs { name, fuzzy, limit })) => {
+            let db = open_selected_database(&current_dir, &cli)?;
+            print_definitions(&db, name, *fuzzy, *limit)?;
+        }
+        (None, Some(Commands::Todos { query, limit })) => match query {
+            Some(query) => {
+                let mut request = cli.search_request(query, *limit, cli.omits_text());
+                request.kinds = vec![chunker::TODO.to_string()];
+                let (mut response, root) = run_search(&current_dir, &cli, request).await?;
+                response.sort(cli.sort);
+                let output_config = config::ConfigManager::new(Some(&current_dir))?
+                    .config()
+                    .output
+                    .clone();
+                if response.results.is_empty() {
+                    info!("No matching todos");
+                } else {
+                    display_search_results(&response, &root, &cli, &output_config)?;
+                }
+                if let Some(hook) = &output_config.hook {
+                    output::run_hook(hook, &response, &root)?;
+                }
+            }
+            None => {
+                let db = open_selected_database(&current_dir, &cli)?;
+                print_todos(&db, *limit)?;
+            }
+        },
+        (None, Some(Commands::Show { id })) => {
+            let db = open_selected_database(&current_dir, &cli)?;
+            print_chunk(&db, &current_dir, id)?;
+        }
+        (None, Some(Commands::Serve { global, stdio })) => {
+            if *global && cli.data_dir.is_some() {
+                bail!("--data-dir applies to a single project; use --socket-path with --global");
+            }
+            // Create server (loads models)
+            let mut server = if *global {
+                let config_manager = config::ConfigManager::new(None)?;
+                server::RagrepServer::new_global(
+                    context::Models::load_for_server(&config_manager)?,
+                    &config_manager.config().server,
+                    cli.read_only,
+                )?
+            } else {
+                let config_manager = config::ConfigManager::new(Some(&current_dir))?;
+                let models = context::Models::load_for_server(&config_manager)?;
+                let profile = context::resolve_profile(
+                    &current_dir,
+                    &config_manager.config().index,
+                    cli.profile.as_deref(),
+                );
+                let mut context = AppContext::with_models(
+                    &current_dir,
+                    models,
+                    profile.as_deref(),
+                    cli.read_only,
+                )?;
+                context.verify_on_open().await?;
+                server::RagrepServer::new(context, &current_dir)?
+            };
+            if *stdio {
+                // The client owns this process; there is no socket or PID file to clean up
+                return server.serve_stdio().await;
+            }
             let pid_path = server.pid_path().clone();
             let socket_path = server.socket_path().clone();
 
-            // Handle Ctrl+C gracefully
+            // Shut down gracefully on Ctrl+C and `kill` alike (SIGHUP reloads the config)
+            let mut sigterm = signal(SignalKind::terminate())?;
             let server_task = tokio::spawn(async move { server.serve().await });
 
             tokio::select! {
@@ -420,6 +1721,9 @@ async fn main() -> Result<()> {
                 _ = tokio::signal::ctrl_c() => {
                     info!("Received Ctrl+C, shutting down...");
                 }
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, shutting down...");
+                }
             }
 
             // Clean up PID file and socket
@@ -427,6 +1731,186 @@ async fn main() -> Result<()> {
             let _ = std::fs::remove_file(&socket_path);
             info!("Server stopped");
         }
+        (None, Some(Commands::Service { action })) => {
+            let (ServiceAction::Install { global }
+            | ServiceAction::Uninstall { global }
+            | ServiceAction::Status { global }) = action;
+            let service = service::Service::new((!*global).then_some(current_dir.as_path()))?;
+            match action {
+                ServiceAction::Install { .. } => service.install()?,
+                ServiceAction::Uninstall { .. } => service.uninstall()?,
+                ServiceAction::Status { .. } => service.status()?,
+            }
+        }
+        (None, Some(Commands::Doctor { fix })) => {
+            doctor(&current_dir, &cli, *fix).await?;
+        }
+        (None, Some(Commands::Status)) => {
+            let db = open_selected_database(&current_dir, &cli)?;
+            let config_manager = config::ConfigManager::new(Some(&current_dir))?;
+            print_status(&db, config_manager.config())?;
+        }
+        (
+            None,
+            Some(Commands::ModelWorker {
+                model,
+                model_cache_dir,
+                threads,
+                quantized,
+            }),
+        ) => {
+            worker::run(*model, model_cache_dir, *quantized, *threads)?;
+        }
+        (None, Some(Commands::Metrics)) => {
+            let client = client::RagrepClient::new(&current_dir)
+                .context("`ragrep metrics` needs a running server (ragrep serve)")?;
+            print!("{}", client.metrics().await?);
+        }
+        (None, Some(Commands::Manifest)) => {
+            let db = open_selected_database(&current_dir, &cli)?;
+            print!("{}", manifest::Manifest::build(&db)?.to_json()?);
+        }
+        (None, Some(Commands::Export { output })) => {
+            let db = open_selected_database(&current_dir, &cli)?;
+            let header = archive::export_index(&db, output)?;
+            info!(
+                "Exported {} chunks ({}) to {}",
+                header.chunk_count,
+                header.embedding_model,
+                output.display()
+            );
+        }
+        (None, Some(Commands::Import { input })) => {
+            if cli.read_only {
+                bail!("Cannot import into a --read-only index");
+            }
+            let mut db = open_selected_database(&current_dir, &cli)?;
+            let header = archive::import_index(&mut db, input)?;
+            info!(
+                "Imported {} chunks from {}",
+                header.chunk_count,
+                input.display()
+            );
+        }
+        (None, Some(Commands::Config { action })) => match action {
+            ConfigAction::Check => check_config(&current_dir)?,
+            ConfigAction::Show { origins } => show_config(&current_dir, *origins)?,
+            ConfigAction::Init { local, force } => {
+                let path = if *local {
+                    config::local_config_path(&current_dir)
+                } else {
+                    config::global_config_path()?
+                };
+                config::write_config_template(&path, *force)?;
+                info!("Wrote config template to {}", path.display());
+            }
+        },
+        (
+            None,
+            Some(Commands::Bench {
+                queries,
+                iterations,
+                sample,
+                sample_files,
+            }),
+        ) => {
+            let queries = match queries {
+                Some(path) => bench::load_queries(path)?,
+                None => bench::DEFAULT_QUERIES
+                    .iter()
+                    .map(|q| q.to_string())
+                    .collect(),
+            };
+            if queries.is_empty() {
+                return Err(anyhow::anyhow!("No benchmark queries to run"));
+            }
+
+            let mut context =
+                AppContext::new(&current_dir, cli.profile.as_deref(), cli.read_only).await?;
+            if context.db.count_chunks()? == 0 {
+                warn!("Index is empty; run `ragrep index` first for meaningful numbers");
+            }
+
+            info!(
+                "Running {} queries x {} iterations",
+                queries.len(),
+                iterations
+            );
+            let latencies = bench::bench_search(&mut context, &queries, *iterations).await?;
+
+            let throughput = if *sample_files > 0 {
+                let sample_dir = sample.clone().unwrap_or(current_dir.clone());
+                info!("Measuring indexing throughput on {}", sample_dir.display());
+                Some(bench::bench_indexing(&context, &sample_dir, *sample_files).await?)
+            } else {
+                None
+            };
+
+            bench::print_report(&latencies, queries.len() * iterations, throughput.as_ref());
+        }
+        (
+            None,
+            Some(Commands::Eval {
+                queries,
+                generate: true,
+                commits,
+                ..
+            }),
+        ) => {
+            // Only the symbol table and the chunker are needed, not the models
+            let db = open_selected_database(&current_dir, &cli)?;
+            if db.count_symbols()? == 0 {
+                bail!("The index has no symbols to expect; run `ragrep index` first");
+            }
+            let config = config::ConfigManager::new(Some(&current_dir))?;
+            let mut chunker = chunker::Chunker::new(&config.config().chunking)?
+                .with_plugins(&config.config().chunkers, db.root())?;
+
+            let cases = eval::generate_cases(&db, &mut chunker, *commits)?;
+            if cases.is_empty() {
+                bail!(
+                    "No queries found in the last {} commits; try a larger --commits",
+                    commits
+                );
+            }
+            eval::write_cases(queries, &cases)?;
+            println!("Wrote {} queries to {}", cases.len(), queries.display());
+        }
+        (None, Some(Commands::Eval { queries, k, .. })) => {
+            let cases = eval::load_cases(queries)?;
+            if cases.is_empty() {
+                bail!("No queries in {}", queries.display());
+            }
+
+            let mut context =
+                AppContext::new(&current_dir, cli.profile.as_deref(), cli.read_only).await?;
+            if context.db.count_chunks()? == 0 {
+                warn!("Index is empty; run `ragrep index` first for meaningful numbers");
+            }
+
+            // Search filters given on the command line apply to every query
+            let report = eval::run_eval(&mut context, &cases, |query| {
+                cli.search_request(query, *k, false)
+            })
+            .await?;
+            eval::print_report(&cases, &report);
+        }
+        (None, Some(Commands::Completions { shell })) => {
+            // Complete the name the binary was invoked as, so renamed installs work too
+            let bin_name = std::env::args()
+                .next()
+                .as_deref()
+                .map(Path::new)
+                .and_then(Path::file_name)
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "rag".to_string());
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                bin_name,
+                &mut std::io::stdout(),
+            );
+        }
         (None, None) => {
             info!("No command or query specified. Use --help to see available commands.");
             info!("Example usage:");
@@ -0,0 +1,149 @@
+use anyhow::{Context as AnyhowContext, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::config::VectorStorage;
+use crate::constants::constants;
+use crate::db::{self, Database};
+
+/// Version of the manifest layout
+const MANIFEST_FORMAT_VERSION: u32 = 1;
+
+/// What an index holds and how it was built, for build systems caching the index
+///
+/// Holds nothing that changes between two runs over the same files (no timestamps), so an
+/// unchanged manifest means an unchanged index.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub ragrep_version: String,
+    pub schema_version: i32,
+    pub embedding_model: String,
+    pub embedding_quantization: String,
+    pub embedding_dimensions: usize,
+    pub vector_storage: VectorStorage,
+    pub chunk_count: usize,
+    /// Indexed files, sorted by path
+    pub files: Vec<ManifestFile>,
+}
+
+/// An indexed file in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestFile {
+    /// Path relative to the project root
+    pub path: String,
+    /// FNV-1a hash of the content that was indexed, as 16 hex digits
+    pub content_hash: String,
+    pub chunks: usize,
+}
+
+impl Manifest {
+    /// Summarize the index in `db`
+    pub fn build(db: &Database) -> Result<Self> {
+        let files: Vec<ManifestFile> = db
+            .file_chunk_counts()?
+            .into_iter()
+            .map(|(path, hash, chunks)| ManifestFile {
+                path,
+                content_hash: format!("{:016x}", hash),
+                chunks,
+            })
+            .collect();
+        Ok(Self {
+            format_version: MANIFEST_FORMAT_VERSION,
+            ragrep_version: env!("CARGO_PKG_VERSION").to_string(),
+            schema_version: db::SCHEMA_VERSION,
+            embedding_model: db
+                .get_metadata(db::META_EMBEDDING_MODEL)?
+                .unwrap_or_else(|| constants::EMBEDDING_MODEL.to_string()),
+            embedding_quantization: db
+                .get_metadata(db::META_EMBEDDING_QUANTIZATION)?
+                .unwrap_or_else(|| "none".to_string()),
+            embedding_dimensions: db.dimensions().unwrap_or(constants::EMBEDDING_DIMENSIONS),
+            vector_storage: db.vector_storage(),
+            chunk_count: files.iter().map(|file| file.chunks).sum(),
+            files,
+        })
+    }
+
+    /// Pretty-printed JSON, ending in a newline
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)? + "\n")
+    }
+
+    /// Write the manifest to `path`, leaving the file untouched when it is already current
+    /// so build systems watching its modification time see no change
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = self.to_json()?;
+        if fs::read_to_string(path).is_ok_and(|current| current == json) {
+            return Ok(());
+        }
+
+        // Readers never see a half-written manifest
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, json)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        debug!("Wrote index manifest to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let root = std::env::temp_dir().join(format!("ragrep-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let mut db = Database::new(&root.join("ragrep.db"), &root).unwrap();
+        let embedding = vec![0.0; constants::EMBEDDING_DIMENSIONS];
+        for (file, chunks) in [("src/b.rs", 2), ("src/a.rs", 1)] {
+            let file = root.join(file).to_string_lossy().to_string();
+            for i in 0..chunks {
+                let text = format!("fn f{}() {{}}", i);
+                let hash = (chunks * 10 + i) as u64;
+                db.save_chunk(
+                    &file, i, "function", None, 1, 1, &text, "", hash, &embedding,
+                )
+                .unwrap();
+            }
+            db.set_file_hash(&file, 255).unwrap();
+        }
+
+        let manifest = Manifest::build(&db).unwrap();
+        assert_eq!(manifest.chunk_count, 3);
+        assert_eq!(
+            manifest.files,
+            [
+                ManifestFile {
+                    path: "src/a.rs".to_string(),
+                    content_hash: "00000000000000ff".to_string(),
+                    chunks: 1,
+                },
+                ManifestFile {
+                    path: "src/b.rs".to_string(),
+                    content_hash: "00000000000000ff".to_string(),
+                    chunks: 2,
+                },
+            ]
+        );
+
+        // Rewriting an unchanged manifest leaves the file alone
+        let path = root.join(constants::MANIFEST_FILENAME);
+        manifest.write(&path).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        Manifest::build(&db).unwrap().write(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+        let read: Manifest = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(read, manifest);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
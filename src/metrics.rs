@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the query latency histogram buckets
+pub const LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// What a server has done since its metrics file was created, kept across restarts so
+/// counters only ever grow
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Metrics {
+    /// Queries answered, including failed ones
+    pub queries: u64,
+    pub query_errors: u64,
+    /// Queries per latency bucket (not cumulative), with one more for slower queries
+    pub query_latency_counts: Vec<u64>,
+    pub query_latency_seconds: f64,
+    /// Reindexes of changed files, including failed ones
+    pub reindexes: u64,
+    pub reindex_errors: u64,
+    pub chunks_indexed: u64,
+    /// Chunks whose embedding was reused from the previous version of their file
+    pub embedding_cache_hits: u64,
+    pub embedding_cache_misses: u64,
+}
+
+impl Metrics {
+    /// Metrics saved at `path`; a missing or unreadable file starts from zero
+    pub fn load(path: &Path) -> Self {
+        let Ok(json) = fs::read_to_string(path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable metrics {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Write the metrics to `path`, unless it already holds them
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        if fs::read_to_string(path).is_ok_and(|current| current == json) {
+            return Ok(());
+        }
+
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, json)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        debug!("Saved server metrics to {}", path.display());
+        Ok(())
+    }
+
+    pub fn record_query(&mut self, latency: Duration, ok: bool) {
+        self.queries += 1;
+        if !ok {
+            self.query_errors += 1;
+        }
+
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.query_latency_counts
+            .resize(LATENCY_BUCKETS.len() + 1, 0);
+        self.query_latency_counts[bucket] += 1;
+        self.query_latency_seconds += seconds;
+    }
+
+    /// Count a reindex and the chunks it embedded (`computed`) or took over (`reused`);
+    /// a failed reindex has no chunks to count
+    pub fn record_reindex(&mut self, ok: bool, reused: usize, computed: usize) {
+        self.reindexes += 1;
+        if !ok {
+            self.reindex_errors += 1;
+        }
+        self.chunks_indexed += (reused + computed) as u64;
+        self.embedding_cache_hits += reused as u64;
+        self.embedding_cache_misses += computed as u64;
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(text, "# HELP ragrep_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE ragrep_{} counter", name);
+            let _ = writeln!(text, "ragrep_{} {}", name, value);
+        };
+        counter("queries_total", "Queries answered.", self.queries);
+        counter(
+            "query_errors_total",
+            "Queries that failed.",
+            self.query_errors,
+        );
+        counter(
+            "reindexes_total",
+            "Reindexes of changed files.",
+            self.reindexes,
+        );
+        counter(
+            "reindex_errors_total",
+            "Reindexes that failed.",
+            self.reindex_errors,
+        );
+        counter(
+            "chunks_indexed_total",
+            "Chunks saved by reindexes.",
+            self.chunks_indexed,
+        );
+        counter(
+            "embedding_cache_hits_total",
+            "Chunks whose embedding was reused.",
+            self.embedding_cache_hits,
+        );
+        counter(
+            "embedding_cache_misses_total",
+            "Chunks that had to be embedded.",
+            self.embedding_cache_misses,
+        );
+
+        let name = "ragrep_query_duration_seconds";
+        let _ = writeln!(text, "# HELP {} Time taken to answer a query.", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            cumulative += self.query_latency_counts.get(i).copied().unwrap_or(0);
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, self.queries);
+        let _ = writeln!(text, "{}_sum {}", name, self.query_latency_seconds);
+        let _ = writeln!(text, "{}_count {}", name, self.queries);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_export() {
+        let mut metrics = Metrics::default();
+        metrics.record_query(Duration::from_millis(30), true);
+        metrics.record_query(Duration::from_millis(80), true);
+        metrics.record_query(Duration::from_secs(20), false);
+        metrics.record_reindex(true, 7, 3);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("ragrep_queries_total 3\n"));
+        assert!(text.contains("ragrep_query_errors_total 1\n"));
+        assert!(text.contains("ragrep_query_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(text.contains("ragrep_query_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("ragrep_query_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("ragrep_query_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("ragrep_query_duration_seconds_count 3\n"));
+        assert!(text.contains("ragrep_chunks_indexed_total 10\n"));
+        assert!(text.contains("ragrep_embedding_cache_hits_total 7\n"));
+
+        let dir = std::env::temp_dir().join(format!("ragrep-metrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.json");
+        metrics.save(&path).unwrap();
+        assert_eq!(Metrics::load(&path), metrics);
+        assert_eq!(Metrics::load(&dir.join("missing.json")), Metrics::default());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
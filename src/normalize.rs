@@ -0,0 +1,164 @@
+//! Index-time cleanup of boilerplate that would otherwise dominate similarity: license
+//! headers, files written by code generators or minifiers and runs of whitespace
+
+use std::ops::Range;
+
+/// Lines near the top of a file searched for a generator's marker
+const GENERATED_MARKER_LINES: usize = 10;
+
+/// Markers code generators leave in a comment near the top of their output
+pub const GENERATED_MARKERS: &[&str] = &["@generated", "DO NOT EDIT"];
+
+/// Average line length above which a file counts as minified
+const MINIFIED_AVERAGE_LINE_LENGTH: usize = 200;
+
+/// Files smaller than this are never considered minified
+const MINIFIED_MIN_SIZE: usize = 1024;
+
+/// Words of a comment block that make it a license header
+const LICENSE_WORDS: &[&str] = &[
+    "license",
+    "licence",
+    "copyright",
+    "spdx-license-identifier",
+    "all rights reserved",
+];
+
+/// Whether `line` is (part of) a comment in one of the indexed languages
+fn is_comment_line(line: &str) -> bool {
+    let line = line.trim_start();
+    ["//", "#", "/*", "*", "--", "<!--", ";"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// Byte range of the license header at the top of `content`, if it has one
+///
+/// The header is the first run of comment lines (after a shebang and blank lines) up to a
+/// blank or code line, and counts as a license if it mentions a license or copyright.
+pub fn license_header(content: &str) -> Option<Range<usize>> {
+    let mut offset = 0;
+    let mut start = None;
+    let mut in_block = false;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let is_comment = in_block || is_comment_line(line);
+        if start.is_none() && (trimmed.is_empty() || trimmed.starts_with("#!")) {
+            offset += line.len();
+            continue;
+        }
+        if trimmed.is_empty() || !is_comment {
+            break;
+        }
+
+        start.get_or_insert(offset);
+        if trimmed.starts_with("/*") || trimmed.starts_with("<!--") {
+            in_block = true;
+        }
+        if trimmed.ends_with("*/") || trimmed.ends_with("-->") {
+            in_block = false;
+        }
+        offset += line.len();
+    }
+
+    let header = start?..offset;
+    let text = content[header.clone()].to_lowercase();
+    LICENSE_WORDS
+        .iter()
+        .any(|word| text.contains(word))
+        .then_some(header)
+}
+
+/// Whether a code generator's marker appears in a comment near the top of `content`
+pub fn is_generated(content: &str) -> bool {
+    content
+        .lines()
+        .take(GENERATED_MARKER_LINES)
+        .filter(|line| is_comment_line(line))
+        .any(|line| GENERATED_MARKERS.iter().any(|marker| line.contains(marker)))
+}
+
+/// Whether `content` looks minified: long, with lines far longer than anyone writes by hand
+pub fn is_minified(content: &str) -> bool {
+    content.len() >= MINIFIED_MIN_SIZE
+        && content.len() / content.lines().count().max(1) > MINIFIED_AVERAGE_LINE_LENGTH
+}
+
+/// `text` with every run of spaces and tabs shortened to one space, trailing whitespace
+/// dropped and runs of blank lines shortened to one
+pub fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !collapsed.is_empty() {
+            collapsed.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+        blank_lines = 0;
+        collapsed.push_str(&words.join(" "));
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_license_header() {
+        let content = "#!/usr/bin/env python\n# Copyright 2024 Example Corp.\n# Licensed under the Apache License, Version 2.0\n\nimport os\n";
+        let header = license_header(content).unwrap();
+        assert_eq!(
+            &content[header],
+            "# Copyright 2024 Example Corp.\n# Licensed under the Apache License, Version 2.0\n"
+        );
+
+        let block = "/*\n * SPDX-License-Identifier: MIT\n */\nuse std::io;\n";
+        assert_eq!(license_header(block), Some(0..block.len() - 13));
+
+        // Module docs are not a license
+        assert_eq!(
+            license_header("//! Parses config files\n\nuse std::io;\n"),
+            None
+        );
+        assert_eq!(license_header("use std::io;\n// Copyright\n"), None);
+    }
+
+    #[test]
+    fn test_is_generated() {
+        assert!(is_generated(
+            "// Code generated by protoc-gen-go. DO NOT EDIT.\npackage api\n"
+        ));
+        assert!(is_generated(
+            "# @generated by pip-compile\nrequests==2.31\n"
+        ));
+        // Only comments near the top count
+        assert!(!is_generated("const MARKER = \"@generated\";\n"));
+        assert!(!is_generated(&format!(
+            "{}// @generated\n",
+            "x = 1\n".repeat(20)
+        )));
+    }
+
+    #[test]
+    fn test_is_minified() {
+        let bundle = "var a=function(b){return b+1};".repeat(100);
+        assert!(is_minified(&bundle));
+        assert!(!is_minified(&"let x = 1;\n".repeat(200)));
+        // A short one-liner is not a bundle
+        assert!(!is_minified("export const a = 1; export const b = 2;"));
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        assert_eq!(
+            collapse_whitespace("fn a() {\n        let  x = 1;   \n\n\n\n    x\n}\n"),
+            "fn a() {\nlet x = 1;\n\nx\n}"
+        );
+    }
+}
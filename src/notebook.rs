@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+}
+
+#[derive(Deserialize)]
+struct Cell {
+    cell_type: String,
+    #[serde(default)]
+    source: Option<CellSource>,
+}
+
+/// nbformat stores cell sources either as one string or as a list of lines
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CellSource {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+/// A code cell's position in the notebook and in the concatenated source
+pub struct CodeCell {
+    /// 1-based position among all cells of the notebook, markdown included
+    pub index: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// 1-based line of the cell's first line in the concatenated source
+    pub start_line: usize,
+    pub line_count: usize,
+}
+
+/// Code cells of a notebook concatenated into a single Python source
+pub struct NotebookCode {
+    pub source: String,
+    pub cells: Vec<CodeCell>,
+}
+
+impl NotebookCode {
+    /// Map a line of the concatenated source to its cell and the 1-based line within it
+    pub fn locate(&self, line: usize) -> Option<(&CodeCell, usize)> {
+        self.cells
+            .iter()
+            .find(|cell| line >= cell.start_line && line < cell.start_line + cell.line_count)
+            .map(|cell| (cell, line - cell.start_line + 1))
+    }
+
+    pub fn cell_source(&self, cell: &CodeCell) -> &str {
+        &self.source[cell.start_byte..cell.end_byte]
+    }
+}
+
+/// Parse an `.ipynb` file and concatenate its non-empty code cells
+pub fn extract_code(content: &str) -> Result<NotebookCode> {
+    let notebook: Notebook =
+        serde_json::from_str(content).context("Failed to parse notebook JSON")?;
+
+    let mut code = NotebookCode {
+        source: String::new(),
+        cells: Vec::new(),
+    };
+    let mut next_line = 1;
+
+    for (i, cell) in notebook.cells.into_iter().enumerate() {
+        if cell.cell_type != "code" {
+            continue;
+        }
+        let text = match cell.source {
+            Some(CellSource::Text(text)) => text,
+            Some(CellSource::Lines(lines)) => lines.concat(),
+            None => continue,
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let start_byte = code.source.len();
+        code.source.push_str(&text);
+        if !text.ends_with('\n') {
+            code.source.push('\n');
+        }
+
+        let line_count = text.lines().count();
+        code.cells.push(CodeCell {
+            index: i + 1,
+            start_byte,
+            end_byte: code.source.len(),
+            start_line: next_line,
+            line_count,
+        });
+        next_line += line_count;
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_code_cells() {
+        let content = r##"{
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title\n"]},
+                {"cell_type": "code", "source": ["import pandas as pd\n", "df = pd.read_csv('a.csv')"]},
+                {"cell_type": "code", "source": []},
+                {"cell_type": "code", "source": "def clean(df):\n    return df.dropna()\n"}
+            ],
+            "metadata": {},
+            "nbformat": 4,
+            "nbformat_minor": 5
+        }"##;
+        let code = extract_code(content).unwrap();
+
+        assert_eq!(
+            code.source,
+            "import pandas as pd\ndf = pd.read_csv('a.csv')\ndef clean(df):\n    return df.dropna()\n"
+        );
+        assert_eq!(code.cells.len(), 2);
+        assert_eq!(code.cells[0].index, 2);
+        assert_eq!(code.cells[1].index, 4);
+        assert_eq!(
+            code.cell_source(&code.cells[1]),
+            "def clean(df):\n    return df.dropna()\n"
+        );
+
+        let (cell, line) = code.locate(4).unwrap();
+        assert_eq!((cell.index, line), (4, 2));
+        assert!(code.locate(5).is_none());
+    }
+
+    #[test]
+    fn test_invalid_notebook() {
+        assert!(extract_code("not json").is_err());
+    }
+}
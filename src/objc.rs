@@ -0,0 +1,158 @@
+use std::path::Path;
+
+/// Whether a file is Objective-C: `.m` and `.mm` sources, and headers that import or declare
+/// Objective-C (other headers are C and chunked as text)
+pub fn is_objc(path: &Path, content: &str) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("m" | "mm") => true,
+        Some("h") => content.lines().any(|line| {
+            let line = line.trim_start();
+            line.starts_with("#import") || header(line).is_some()
+        }),
+        _ => false,
+    }
+}
+
+/// Name of a definition the grammar found: the selector of a method (`kind` "function"),
+/// otherwise the class or protocol name, with its category if any
+///
+/// The grammar has no name fields for these, so they are read from the definition's text.
+pub fn definition_name(kind: &str, text: &str) -> Option<String> {
+    if kind == "function" {
+        selector(text)
+    } else {
+        header(text.lines().next()?.trim()).and_then(|(_, name)| name)
+    }
+}
+
+/// Kind and name of the block a line opens, if it opens one
+///
+/// Forward declarations (`@protocol Delegate;`) open nothing.
+fn header(line: &str) -> Option<(&'static str, Option<String>)> {
+    let (kind, rest) = [
+        ("@interface", "interface"),
+        ("@implementation", "implementation"),
+        ("@protocol", "protocol"),
+    ]
+    .into_iter()
+    .find_map(|(keyword, kind)| Some((kind, line.strip_prefix(keyword)?)))?;
+    if !rest.starts_with(char::is_whitespace) || rest.trim_end().ends_with(';') {
+        return None;
+    }
+
+    // A category keeps its name: `@interface NSString (Trimming)` is "NSString (Trimming)"
+    let rest = rest.trim_start();
+    let name = &rest[..identifier_len(rest)];
+    let category = rest[name.len()..]
+        .trim_start()
+        .strip_prefix('(')
+        .and_then(|category| category.split_once(')'))
+        .map(|(category, _)| category.trim());
+    let name = match category {
+        _ if name.is_empty() => None,
+        Some(category) => Some(format!("{} ({})", name, category)),
+        None => Some(name.to_string()),
+    };
+    Some((kind, name))
+}
+
+fn identifier_len(text: &str) -> usize {
+    text.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(text.len())
+}
+
+/// `text` after a leading parenthesized type such as `(NSString *)`, or all of it
+fn skip_type(text: &str) -> &str {
+    if !text.starts_with('(') {
+        return text;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return &text[i + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+    ""
+}
+
+/// Selector of a method signature, e.g. `setName:age:` for
+/// `- (void)setName:(NSString *)name age:(int)age`
+fn selector(signature: &str) -> Option<String> {
+    let mut rest = signature.trim_start().strip_prefix(['-', '+'])?;
+    let mut name = String::new();
+    loop {
+        rest = skip_type(rest.trim_start());
+        let (part, after) = rest.split_at(identifier_len(rest));
+        if part.is_empty() {
+            break;
+        }
+        let Some(after) = after.trim_start().strip_prefix(':') else {
+            if name.is_empty() {
+                name.push_str(part);
+            }
+            break;
+        };
+        name.push_str(part);
+        name.push(':');
+        // Skip the parameter's type and name
+        let after = skip_type(after.trim_start()).trim_start();
+        rest = &after[identifier_len(after)..];
+    }
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_objc() {
+        assert!(is_objc(Path::new("Invoice.m"), ""));
+        assert!(is_objc(
+            Path::new("Invoice.h"),
+            "#import <Foundation/Foundation.h>\n"
+        ));
+        assert!(!is_objc(Path::new("invoice.h"), "int total(int *items);\n"));
+        assert!(!is_objc(Path::new("Invoice.swift"), "@interface"));
+    }
+
+    #[test]
+    fn test_selector() {
+        assert_eq!(selector("- (void)reset {").as_deref(), Some("reset"));
+        assert_eq!(
+            selector("- (void)setName:(NSString *)name\n             age:(int)age {").as_deref(),
+            Some("setName:age:")
+        );
+        assert_eq!(
+            selector("+ (instancetype)invoiceWithItems:(NSArray<Item *> *)items {").as_deref(),
+            Some("invoiceWithItems:")
+        );
+    }
+
+    #[test]
+    fn test_definition_name() {
+        assert_eq!(
+            definition_name(
+                "implementation",
+                "@implementation NSString (Trimming)\n@end"
+            )
+            .as_deref(),
+            Some("NSString (Trimming)")
+        );
+        assert_eq!(
+            definition_name("interface", "@interface Invoice : NSObject\n@end").as_deref(),
+            Some("Invoice")
+        );
+        assert_eq!(
+            definition_name("function", "- (int)total {\n    return 0;\n}").as_deref(),
+            Some("total")
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! User-defined output of searches: a line template per result (`[output] template`) and a
+//! hook command given the whole response as JSON (`[output] hook`)
+
+use crate::protocol::{SearchResponse, SearchResult};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::debug;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Format `result` with `template`, whose `{file}`, `{start}`, `{end}`, `{score}`, `{kind}`,
+/// `{name}`, `{id}` and `{text}` placeholders are substituted; `file` is the path to show
+pub fn format_result(template: &str, file: &str, result: &SearchResult) -> String {
+    let mut line = String::with_capacity(template.len());
+    let mut rest = template;
+    // Substituted in one pass, so placeholders inside the code or a name stay as they are
+    while let Some(open) = rest.find('{') {
+        line.push_str(&rest[..open]);
+        let after = &rest[open..];
+        let Some(close) = after.find('}') else {
+            rest = after;
+            break;
+        };
+        let value = match &after[1..close] {
+            "file" => file.to_string(),
+            "start" => result.start_line.to_string(),
+            "end" => result.end_line.to_string(),
+            "score" => format!("{:.4}", result.score),
+            "kind" => result.kind.clone(),
+            "name" => result.name.clone().unwrap_or_default(),
+            "id" => result.id.clone().unwrap_or_default(),
+            "text" => result.text.clone(),
+            _ => {
+                line.push('{');
+                rest = &after[1..];
+                continue;
+            }
+        };
+        line.push_str(&value);
+        rest = &after[close + 1..];
+    }
+    line.push_str(rest);
+    line
+}
+
+/// Run hook `command` with `response` as JSON on stdin, in the directory its paths are
+/// relative to (`root`); its output goes to ours
+pub fn run_hook(command: &str, response: &SearchResponse, root: &Path) -> Result<()> {
+    let json = serde_json::to_vec(response)?;
+    let mut hook = Command::new("sh");
+    hook.arg("-c").arg(command).stdin(Stdio::piped());
+    if !root.as_os_str().is_empty() {
+        hook.current_dir(root);
+    }
+
+    debug!("Running search hook: {}", command);
+    let mut child = hook
+        .spawn()
+        .with_context(|| format!("Failed to run search hook '{}'", command))?;
+    let mut stdin = child.stdin.take().expect("hook stdin is piped");
+    // A hook that exits without reading all of its input closes the pipe; not an error
+    if let Err(e) = stdin.write_all(&json) {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(e).context("Failed to write to search hook");
+        }
+    }
+    drop(stdin);
+
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("Search hook '{}' exited with {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SearchStats;
+
+    fn result() -> SearchResult {
+        SearchResult {
+            id: Some("3f2a9c01b7d4".to_string()),
+            file_path: "src/db.rs".to_string(),
+            start_line: 10,
+            end_line: 24,
+            text: "fn connect() {}".to_string(),
+            score: 0.87654,
+            kind: "function".to_string(),
+            name: Some("connect".to_string()),
+            modified_at: None,
+            explanation: None,
+        }
+    }
+
+    #[test]
+    fn test_format_result() {
+        let result = result();
+        assert_eq!(
+            format_result("{file}:{start}:{score}", "../src/db.rs", &result),
+            "../src/db.rs:10:0.8765"
+        );
+        assert_eq!(
+            format_result("#{id}\t{kind} {name} ({start}-{end})", "src/db.rs", &result),
+            "#3f2a9c01b7d4\tfunction connect (10-24)"
+        );
+        // Unknown placeholders and unmatched braces are kept
+        assert_eq!(
+            format_result("{file} {other} {", "src/db.rs", &result),
+            "src/db.rs {other} {"
+        );
+        // Braces in the code are not placeholders
+        let result = SearchResult {
+            text: "let s = \"{file}\";".to_string(),
+            ..result
+        };
+        assert_eq!(
+            format_result("{text}", "src/db.rs", &result),
+            "let s = \"{file}\";"
+        );
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let response = SearchResponse {
+            results: vec![result()],
+            stats: SearchStats {
+                total_time_ms: 0,
+                expand_ms: 0,
+                embed_ms: 0,
+                file_search_ms: 0,
+                vector_search_ms: 0,
+                rerank_ms: 0,
+                num_candidates: 1,
+                num_candidate_files: None,
+                num_results: 1,
+                num_tokens: None,
+            },
+            explanation: None,
+        };
+        let dir = std::env::temp_dir().join(format!("ragrep-hook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        run_hook("cat > response.json", &response, &dir).unwrap();
+        let written: SearchResponse =
+            serde_json::from_slice(&std::fs::read(dir.join("response.json")).unwrap()).unwrap();
+        assert_eq!(written.results[0].file_path, "src/db.rs");
+
+        assert!(run_hook("exit 2", &response, &dir).is_err());
+        // Hooks don't have to read the response
+        run_hook("true", &response, &dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
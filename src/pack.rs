@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::chunker;
+use crate::protocol::SearchResult;
+
+/// Default token budget of a context pack
+pub const DEFAULT_BUDGET: usize = 4000;
+
+/// Render results as one prompt-ready block of fenced code, grouped by file
+///
+/// Results are taken in score order while they fit in `budget` (estimated tokens of the
+/// code; headers are not counted). Overlapping or adjacent ranges of a file are merged, so
+/// lines shared by several chunks appear, and are paid for, only once.
+pub fn build_pack(query: &str, results: &[SearchResult], budget: usize) -> String {
+    // Included lines per file, files in the order of their best result
+    let mut files: Vec<(&str, BTreeMap<i32, &str>)> = Vec::new();
+    let mut used = 0;
+
+    for result in results {
+        let file_index = match files.iter().position(|(path, _)| *path == result.file_path) {
+            Some(index) => index,
+            None => {
+                files.push((&result.file_path, BTreeMap::new()));
+                files.len() - 1
+            }
+        };
+        let lines = &files[file_index].1;
+
+        let new_lines: Vec<(i32, &str)> = result
+            .text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (result.start_line + i as i32, line))
+            .filter(|(number, _)| !lines.contains_key(number))
+            .collect();
+        let cost: usize = new_lines
+            .iter()
+            .map(|(_, line)| chunker::estimate_tokens(line) + 1)
+            .sum();
+        if used + cost > budget {
+            continue;
+        }
+
+        used += cost;
+        files[file_index].1.extend(new_lines);
+    }
+
+    let mut pack = format!("Code context for: {}\n", query);
+    for (path, lines) in files.iter().filter(|(_, lines)| !lines.is_empty()) {
+        let language = chunker::language_for_path(Path::new(path));
+        for range in contiguous_ranges(lines) {
+            let (first, last) = (range[0].0, range[range.len() - 1].0);
+            let _ = write!(pack, "\n{}:{}-{}\n```{}\n", path, first, last, language);
+            for (_, line) in range {
+                let _ = writeln!(pack, "{}", line);
+            }
+            pack.push_str("```\n");
+        }
+    }
+    pack
+}
+
+/// Split numbered lines into runs of consecutive line numbers
+fn contiguous_ranges<'a>(lines: &BTreeMap<i32, &'a str>) -> Vec<Vec<(i32, &'a str)>> {
+    let mut ranges: Vec<Vec<(i32, &str)>> = Vec::new();
+    for (&number, &line) in lines {
+        match ranges.last_mut() {
+            Some(range) if range[range.len() - 1].0 + 1 == number => range.push((number, line)),
+            _ => ranges.push(vec![(number, line)]),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(file_path: &str, start_line: i32, text: &str) -> SearchResult {
+        SearchResult {
+            id: None,
+            file_path: file_path.to_string(),
+            start_line,
+            end_line: start_line + text.lines().count() as i32 - 1,
+            text: text.to_string(),
+            score: 0.5,
+            kind: "function".to_string(),
+            name: None,
+            modified_at: None,
+            explanation: None,
+        }
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_merged() {
+        let results = vec![
+            result("src/a.rs", 2, "b\nc"),
+            result("lib/b.py", 1, "x = 1"),
+            result("src/a.rs", 3, "c\nd"),
+            result("src/a.rs", 10, "z"),
+        ];
+
+        assert_eq!(
+            build_pack("q", &results, DEFAULT_BUDGET),
+            "Code context for: q\n\
+             \nsrc/a.rs:2-4\n```rust\nb\nc\nd\n```\n\
+             \nsrc/a.rs:10-10\n```rust\nz\n```\n\
+             \nlib/b.py:1-1\n```python\nx = 1\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_budget_skips_results_that_do_not_fit() {
+        let long = "let value = compute(first, second);\n".repeat(20);
+        let results = vec![
+            result("a.rs", 1, "fn a() {}"),
+            result("b.rs", 1, &long),
+            result("c.rs", 1, "fn c() {}"),
+        ];
+
+        let pack = build_pack("q", &results, 30);
+        assert!(pack.contains("a.rs:1-1"));
+        assert!(!pack.contains("b.rs"));
+        assert!(pack.contains("c.rs:1-1"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Chunkers provided by external commands (`[chunkers."<pattern>"]`), for languages ragrep
+//! has no grammar for
+//!
+//! The command gets a file's content on stdin and its path as its first argument (`$1`), and
+//! prints a JSON array of chunks, each a line range of the file:
+//!
+//! ```json
+//! [{"start_line": 3, "end_line": 9, "kind": "message", "name": "User"}]
+//! ```
+
+use crate::config::ChunkerPluginConfig;
+use anyhow::{bail, Context as AnyhowContext, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A chunk as printed by a chunker command
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct PluginChunk {
+    /// First line of the chunk, counted from 1
+    pub start_line: usize,
+    /// Last line of the chunk, inclusive
+    pub end_line: usize,
+    /// Kind of code the chunk holds; "block" if not given
+    #[serde(default)]
+    pub kind: Option<String>,
+    /// Name of what the chunk defines, stored as a symbol for `ragrep def`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Language of the chunk if it differs from the file's (see `--lang`)
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+/// The configured chunker commands, by file pattern (gitignore syntax, relative to the
+/// indexed directory)
+#[derive(Default)]
+pub struct ChunkerPlugins {
+    rules: Vec<(Gitignore, String)>,
+    root: PathBuf,
+}
+
+impl ChunkerPlugins {
+    pub fn new(chunkers: &BTreeMap<String, ChunkerPluginConfig>, root: &Path) -> Result<Self> {
+        let rules = chunkers
+            .iter()
+            .map(|(pattern, plugin)| {
+                let mut builder = GitignoreBuilder::new("");
+                builder.add_line(None, pattern)?;
+                Ok((builder.build()?, plugin.command.clone()))
+            })
+            .collect::<Result<_>>()
+            .context("Invalid [chunkers] pattern")?;
+        Ok(Self {
+            rules,
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Command chunking `path`: the one of the first pattern (in sorted order) matching it
+    ///
+    /// Patterns match paths relative to the indexed directory, so `schemas/*.def` is
+    /// anchored there like in a `.gitignore` at its top.
+    pub fn command_for(&self, path: &Path) -> Option<&str> {
+        let path = path
+            .strip_prefix(&self.root)
+            .or_else(|_| path.strip_prefix(Component::RootDir.as_os_str()))
+            .unwrap_or(path);
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matched_path_or_any_parents(path, false).is_ignore())
+            .map(|(_, command)| command.as_str())
+    }
+}
+
+/// Run chunker `command` on the file at `path` with `content`
+pub fn run(command: &str, path: &Path, content: &str) -> Result<Vec<PluginChunk>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run chunker '{}'", command))?;
+
+    // Written from another thread, so a chunker printing before it has read all of its
+    // input can't deadlock; one that doesn't read it at all is fine too
+    let mut stdin = child.stdin.take().expect("chunker stdin is piped");
+    let input = content.to_string();
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    if !output.status.success() {
+        bail!(
+            "Chunker '{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let chunks: Vec<PluginChunk> = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Chunker '{}' printed invalid chunks", command))?;
+
+    let num_lines = content.lines().count();
+    for chunk in &chunks {
+        if chunk.start_line == 0 || chunk.start_line > chunk.end_line || chunk.end_line > num_lines
+        {
+            bail!(
+                "Chunker '{}' printed lines {}-{} for a file of {} lines",
+                command,
+                chunk.start_line,
+                chunk.end_line,
+                num_lines
+            );
+        }
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugins(patterns: &[(&str, &str)]) -> ChunkerPlugins {
+        let chunkers = patterns
+            .iter()
+            .map(|(pattern, command)| {
+                let plugin = ChunkerPluginConfig {
+                    command: command.to_string(),
+                };
+                (pattern.to_string(), plugin)
+            })
+            .collect();
+        ChunkerPlugins::new(&chunkers, Path::new("/repo")).unwrap()
+    }
+
+    #[test]
+    fn test_command_for() {
+        let plugins = plugins(&[("*.proto", "proto-chunker"), ("schemas/*.def", "def")]);
+        assert_eq!(
+            plugins.command_for(Path::new("/repo/api/user.proto")),
+            Some("proto-chunker")
+        );
+        assert_eq!(plugins.command_for(Path::new("schemas/a.def")), Some("def"));
+        // Anchored patterns match indexed (absolute) paths relative to the root
+        assert_eq!(
+            plugins.command_for(Path::new("/repo/schemas/a.def")),
+            Some("def")
+        );
+        assert_eq!(
+            plugins.command_for(Path::new("/repo/api/schemas/a.def")),
+            None
+        );
+        assert_eq!(plugins.command_for(Path::new("src/main.rs")), None);
+    }
+
+    #[test]
+    fn test_run() {
+        let content = "syntax = \"proto3\";\nmessage User {\n  string name = 1;\n}\n";
+        // The path is the command's first argument
+        let command = r#"cat > /dev/null; echo "[{\"start_line\": 2, \"end_line\": 4, \"kind\": \"message\", \"name\": \"$(basename $1 .proto)\"}]""#;
+        let chunks = run(command, Path::new("api/User.proto"), content).unwrap();
+        assert_eq!(
+            chunks,
+            vec![PluginChunk {
+                start_line: 2,
+                end_line: 4,
+                kind: Some("message".to_string()),
+                name: Some("User".to_string()),
+                language: None,
+            }]
+        );
+
+        let out_of_range = r#"echo '[{"start_line": 3, "end_line": 9}]'"#;
+        assert!(run(out_of_range, Path::new("a.proto"), content).is_err());
+        assert!(run("echo oops >&2; exit 3", Path::new("a.proto"), content).is_err());
+        assert!(run("echo not json", Path::new("a.proto"), content).is_err());
+    }
+}
@@ -1,46 +1,616 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Version of the protocol spoken by this build; bumped on incompatible message changes
+///
+/// Version 2 made result ids strings (stable chunk handles) instead of row ids.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this build still speaks
+///
+/// Version 1 peers are sent results without ids (see [`Message::for_version`]), and their
+/// row ids read as none.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features this build supports, exchanged in [`Message::Hello`]
+pub const CAPABILITIES: &[&str] = &["batch", "subscribe", "status", "metrics", "explain"];
+
+/// Version spoken with a peer that offered `version`: the older of both, or `None` if
+/// this build no longer speaks it
+pub fn negotiate(version: u32) -> Option<u32> {
+    (version >= MIN_PROTOCOL_VERSION).then(|| version.min(PROTOCOL_VERSION))
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SearchRequest {
     pub query: String,
     pub top_n: usize,
     pub files_only: bool,
+    /// Project to search; required by global servers, ignored by per-project servers
+    #[serde(default)]
+    pub project_root: Option<String>,
+    /// Only return chunks of these languages (e.g. "rust", "python"); empty means all
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Index profile to search; by default the server picks it from the project config
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Only return chunks of these path categories ("src", "test", "vendor"); empty means all
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Never return chunks of these path categories
+    #[serde(default)]
+    pub exclude_categories: Vec<String>,
+    /// Return the best results only while their text fits in this many tokens
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    /// Only return chunks of these kinds (e.g. "todo"); empty means all
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    /// Only return chunks of files under these directories or matching these globs,
+    /// relative to the project root; empty means all
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Only search comment text: comment and todo chunks, and the doc comments of
+    /// definitions, which are returned instead of their code
+    #[serde(default)]
+    pub comments: bool,
+    /// Only return chunks last changed by an author containing this text (name or email,
+    /// case-insensitive); needs an index built with `index.blame`
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Only return chunks last changed at or after this time, in seconds since the Unix epoch
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Only return chunks (re)indexed at or after this time, in seconds since the Unix epoch
+    #[serde(default)]
+    pub changed_since: Option<i64>,
+    /// Candidates to rerank before returning `top_n`; the server's `search.rerank_depth`
+    /// when unset
+    #[serde(default)]
+    pub rerank_depth: Option<usize>,
+    /// Further queries searched together with `query`, combined as `combine` says
+    #[serde(default)]
+    pub queries: Vec<String>,
+    /// How the results of several queries are combined
+    #[serde(default)]
+    pub combine: QueryCombination,
+    /// Explain how each result was scored and why other candidates were cut
+    #[serde(default)]
+    pub explain: bool,
+}
+
+impl SearchRequest {
+    /// `query` followed by the further `queries`
+    pub fn all_queries(&self) -> Vec<&str> {
+        std::iter::once(self.query.as_str())
+            .chain(self.queries.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// How the results of a search for several queries are combined
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryCombination {
+    /// Results matching any query, scored by the query they match best
+    #[default]
+    Any,
+    /// Results matching every query, scored by the query they match worst
+    All,
+}
+
+/// Order search results are shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ResultOrder {
+    /// Best match first
+    #[default]
+    Score,
+    /// By file path, then line, so the output diffs well between runs
+    Path,
+    /// Most recently modified file first, its results by line
+    Recency,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SearchResult {
+    /// Chunk handle for `ragrep show`; stays the same while the chunk's content does,
+    /// even when its file is reindexed
+    #[serde(default, deserialize_with = "deserialize_handle")]
+    pub id: Option<String>,
     pub file_path: String,
     pub start_line: i32,
     pub end_line: i32,
     pub text: String,
     pub score: f32,
+    /// Kind of code the chunk holds ("function", "class", "file_summary", ...)
+    #[serde(default)]
+    pub kind: String,
+    /// Name of the function, class, ... the chunk holds, when it has one
+    #[serde(default)]
+    pub name: Option<String>,
+    /// When the chunk's file was last modified before it was indexed (Unix seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<i64>,
+    /// How the score came about, for requests with `explain`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Explanation>,
+}
+
+/// How a result's score came about
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Explanation {
+    /// Distance between the chunk's embedding and the nearest query's
+    pub distance: f32,
+    /// Score the reranker gave the chunk, combined over the queries
+    pub rerank_score: f32,
+    /// Changes made to the rerank score afterwards, in the order they were applied
+    pub adjustments: Vec<ScoreAdjustment>,
+}
+
+/// A change to a result's score after reranking, e.g. by a path weight
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ScoreAdjustment {
+    /// What changed the score ("path weight", "recency boost", "language boost")
+    pub reason: String,
+    /// Amount added to the score (negative when it was lowered)
+    pub delta: f32,
+}
+
+/// How a search arrived at its results, for requests with `explain`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SearchExplanation {
+    /// How candidates were found and what restricted them, in order
+    pub steps: Vec<String>,
+    /// Candidates that didn't make it into the results, best first
+    pub cut: Vec<CutCandidate>,
+}
+
+/// A candidate left out of the results, and why
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CutCandidate {
+    pub file_path: String,
+    pub start_line: i32,
+    pub end_line: i32,
+    pub kind: String,
+    pub name: Option<String>,
+    /// Score it had when it was cut; `None` if it was cut before reranking
+    pub score: Option<f32>,
+    pub reason: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SearchResponse {
     pub results: Vec<SearchResult>,
     pub stats: SearchStats,
+    /// How the search went, for requests with `explain`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<SearchExplanation>,
+}
+
+impl SearchResponse {
+    /// Number of matching chunks per file, in the order files first appear in the results
+    pub fn counts_per_file(&self) -> Vec<(&str, usize)> {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for result in &self.results {
+            match counts
+                .iter_mut()
+                .find(|(file, _)| *file == result.file_path)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&result.file_path, 1)),
+            }
+        }
+        counts
+    }
+
+    /// Put the results in `order`; they come ordered by score
+    pub fn sort(&mut self, order: ResultOrder) {
+        let by_position = |a: &SearchResult, b: &SearchResult| {
+            (&a.file_path, a.start_line).cmp(&(&b.file_path, b.start_line))
+        };
+        match order {
+            ResultOrder::Score => {}
+            ResultOrder::Path => self.results.sort_by(by_position),
+            // Files without a recorded modification time go last
+            ResultOrder::Recency => self.results.sort_by(|a, b| {
+                b.modified_at
+                    .cmp(&a.modified_at)
+                    .then_with(|| by_position(a, b))
+            }),
+        }
+    }
+
+    /// Unique files with at least one match, in result order
+    pub fn matching_files(&self) -> Vec<&str> {
+        self.counts_per_file()
+            .into_iter()
+            .map(|(file, _)| file)
+            .collect()
+    }
+
+    /// The `top_n` best results of several projects' responses, paths prefixed with the
+    /// project's name
+    ///
+    /// The scores must compare across projects, e.g. from one rerank of all the results
+    /// (see `server::rerank_results`). Chunk ids are dropped since they only identify a
+    /// chunk within its own project.
+    pub fn merge_projects(responses: Vec<(String, SearchResponse)>, top_n: usize) -> Self {
+        let mut results = Vec::new();
+        let mut stats = SearchStats {
+            total_time_ms: 0,
+            expand_ms: 0,
+            embed_ms: 0,
+            file_search_ms: 0,
+            vector_search_ms: 0,
+            rerank_ms: 0,
+            num_candidates: 0,
+            num_candidate_files: None,
+            num_results: 0,
+            num_tokens: None,
+        };
+        for (project, response) in responses {
+            stats.total_time_ms += response.stats.total_time_ms;
+            stats.expand_ms += response.stats.expand_ms;
+            stats.embed_ms += response.stats.embed_ms;
+            stats.file_search_ms += response.stats.file_search_ms;
+            stats.vector_search_ms += response.stats.vector_search_ms;
+            stats.rerank_ms += response.stats.rerank_ms;
+            stats.num_candidates += response.stats.num_candidates;
+            results.extend(response.results.into_iter().map(|result| SearchResult {
+                id: None,
+                file_path: format!("{}/{}", project, result.file_path),
+                ..result
+            }));
+        }
+
+        // Stable, so equal scores (e.g. fixed-string matches) keep project order
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        results.truncate(top_n);
+        stats.num_results = results.len();
+        Self {
+            results,
+            stats,
+            explanation: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct SearchStats {
     pub total_time_ms: u64,
+    /// Time spent having the LLM expand the query (`search.hyde`)
+    #[serde(default)]
+    pub expand_ms: u64,
+    /// Time spent embedding the query
+    #[serde(default)]
+    pub embed_ms: u64,
+    /// Time spent picking the files to search (`search.two_stage`)
+    #[serde(default)]
+    pub file_search_ms: u64,
+    /// Time spent finding candidate chunks in the vector index
+    #[serde(default)]
+    pub vector_search_ms: u64,
+    /// Time spent reranking the candidates
+    #[serde(default)]
+    pub rerank_ms: u64,
     pub num_candidates: usize,
+    /// Files the chunk search was restricted to (`search.two_stage`)
+    #[serde(default)]
+    pub num_candidate_files: Option<usize>,
     pub num_results: usize,
+    /// Tokens in the returned results' text, counted when a token budget was requested
+    #[serde(default)]
+    pub num_tokens: Option<usize>,
+}
+
+/// Kind of failure reported with an error, so clients can react to it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The project has no indexed chunks
+    IndexEmpty,
+    /// The embedding or reranking model failed to load or run
+    ModelError,
+    /// The message or its parameters are invalid (e.g. from another ragrep version)
+    BadRequest,
+    /// The index is locked by a write that didn't finish in time, or the server refused the
+    /// query under its concurrency or rate limits; retry after a short wait
+    Busy,
+    /// Any other failure of the server; the message says what went wrong
+    Internal,
+}
+
+/// Outcome of one query of a [`Message::BatchRequest`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BatchResult {
+    /// Position of the query in the request's `queries`
+    pub sub_id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<SearchResponse>,
+    /// Why this query failed; the other queries of the batch are unaffected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+}
+
+/// Progress the server reports for a request before its response is ready
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerStatus {
+    /// The request waits for the models: the server just started and is warming them up,
+    /// or they were unloaded while idle and are being reloaded
+    WarmingUp,
+}
+
+/// Indexing activity the server pushes to subscribed clients, e.g. for an editor status bar
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IndexEvent {
+    /// The file watcher reported changed files and a reindex was queued
+    ReindexTriggered { project_root: String, files: usize },
+    /// Reindexing of `files` indexable files started
+    Started { project_root: String, files: usize },
+    /// `done` of `total` files have been reindexed
+    Progress {
+        project_root: String,
+        done: usize,
+        total: usize,
+    },
+    Completed {
+        project_root: String,
+        duration_ms: u64,
+    },
+    Failed {
+        project_root: String,
+        message: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 pub enum Message {
-    Request { id: u64, request: SearchRequest },
-    Response { id: u64, response: SearchResponse },
-    Error { id: u64, message: String },
+    /// Sent by clients first, answered by the server with the version both will speak and
+    /// its own capabilities; connections without it speak version 1
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Request {
+        id: u64,
+        request: SearchRequest,
+    },
+    Response {
+        id: u64,
+        response: SearchResponse,
+    },
+    Error {
+        id: u64,
+        message: String,
+        /// Absent in errors of servers that predate error codes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+    Status {
+        id: u64,
+        status: ServerStatus,
+    },
+    /// Several queries answered together, their embeddings computed in one batch
+    BatchRequest {
+        id: u64,
+        queries: Vec<SearchRequest>,
+    },
+    BatchResponse {
+        id: u64,
+        responses: Vec<BatchResult>,
+    },
+    /// Receive an `Event` for every indexing step on this connection until it closes
+    Subscribe {
+        id: u64,
+    },
+    Event {
+        event: IndexEvent,
+    },
+    /// Ask for the server's query and indexing counters
+    MetricsRequest {
+        id: u64,
+    },
+    /// The counters in the Prometheus text exposition format
+    MetricsResponse {
+        id: u64,
+        metrics: String,
+    },
+}
+
+impl Message {
+    /// This message as a peer speaking protocol `version` can parse it
+    pub fn for_version(mut self, version: u32) -> Self {
+        if version < 2 {
+            // Version 1 ids were row ids; a handle would fail to parse
+            let responses: Vec<&mut SearchResponse> = match &mut self {
+                Message::Response { response, .. } => vec![response],
+                Message::BatchResponse { responses, .. } => responses
+                    .iter_mut()
+                    .filter_map(|result| result.response.as_mut())
+                    .collect(),
+                _ => vec![],
+            };
+            for response in responses {
+                for result in &mut response.results {
+                    result.id = None;
+                }
+            }
+        }
+        self
+    }
+}
+
+/// A result id: a chunk handle, or none for the row ids of version 1 servers, which
+/// `ragrep show` no longer takes
+fn deserialize_handle<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(handle) => Ok(Some(handle)),
+        serde_json::Value::Number(_) | serde_json::Value::Null => Ok(None),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a chunk handle, got {}",
+            other
+        ))),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_counts_per_file() {
+        let result = |file_path: &str| SearchResult {
+            id: None,
+            file_path: file_path.to_string(),
+            start_line: 1,
+            end_line: 2,
+            text: String::new(),
+            score: 0.5,
+            kind: "function".to_string(),
+            name: None,
+            modified_at: None,
+            explanation: None,
+        };
+        let response = SearchResponse {
+            results: vec![result("b.rs"), result("a.rs"), result("b.rs")],
+            stats: SearchStats {
+                total_time_ms: 0,
+                expand_ms: 0,
+                embed_ms: 0,
+                file_search_ms: 0,
+                vector_search_ms: 0,
+                rerank_ms: 0,
+                num_candidates: 3,
+                num_candidate_files: None,
+                num_results: 3,
+                num_tokens: None,
+            },
+            explanation: None,
+        };
+
+        assert_eq!(response.counts_per_file(), vec![("b.rs", 2), ("a.rs", 1)]);
+        assert_eq!(response.matching_files(), vec!["b.rs", "a.rs"]);
+    }
+
+    #[test]
+    fn test_sort() {
+        let result = |file_path: &str, start_line: i32, modified_at: Option<i64>| SearchResult {
+            id: None,
+            file_path: file_path.to_string(),
+            start_line,
+            end_line: start_line + 1,
+            text: String::new(),
+            score: 0.5,
+            kind: "function".to_string(),
+            name: None,
+            modified_at,
+            explanation: None,
+        };
+        let mut response = SearchResponse {
+            results: vec![
+                result("b.rs", 30, Some(100)),
+                result("a.rs", 9, None),
+                result("c.rs", 5, Some(200)),
+                result("b.rs", 2, Some(100)),
+            ],
+            stats: SearchStats {
+                total_time_ms: 0,
+                expand_ms: 0,
+                embed_ms: 0,
+                file_search_ms: 0,
+                vector_search_ms: 0,
+                rerank_ms: 0,
+                num_candidates: 4,
+                num_candidate_files: None,
+                num_results: 4,
+                num_tokens: None,
+            },
+            explanation: None,
+        };
+        let positions = |response: &SearchResponse| {
+            response
+                .results
+                .iter()
+                .map(|result| format!("{}:{}", result.file_path, result.start_line))
+                .collect::<Vec<_>>()
+        };
+
+        response.sort(ResultOrder::Score);
+        assert_eq!(
+            positions(&response),
+            ["b.rs:30", "a.rs:9", "c.rs:5", "b.rs:2"]
+        );
+        response.sort(ResultOrder::Path);
+        assert_eq!(
+            positions(&response),
+            ["a.rs:9", "b.rs:2", "b.rs:30", "c.rs:5"]
+        );
+        response.sort(ResultOrder::Recency);
+        assert_eq!(
+            positions(&response),
+            ["c.rs:5", "b.rs:2", "b.rs:30", "a.rs:9"]
+        );
+    }
+
+    #[test]
+    fn test_merge_projects() {
+        let response = |scores: &[(&str, f32)]| SearchResponse {
+            results: scores
+                .iter()
+                .map(|(file_path, score)| SearchResult {
+                    id: None,
+                    file_path: file_path.to_string(),
+                    start_line: 1,
+                    end_line: 2,
+                    text: String::new(),
+                    score: *score,
+                    kind: "function".to_string(),
+                    name: None,
+                    modified_at: None,
+                    explanation: None,
+                })
+                .collect(),
+            stats: SearchStats {
+                total_time_ms: 10,
+                expand_ms: 0,
+                embed_ms: 0,
+                file_search_ms: 0,
+                vector_search_ms: 0,
+                rerank_ms: 0,
+                num_candidates: 5,
+                num_candidate_files: None,
+                num_results: scores.len(),
+                num_tokens: None,
+            },
+            explanation: None,
+        };
+
+        let merged = SearchResponse::merge_projects(
+            vec![
+                ("api".to_string(), response(&[("a.rs", 0.9), ("b.rs", 0.2)])),
+                ("web".to_string(), response(&[("c.ts", 0.5)])),
+            ],
+            2,
+        );
+        let files: Vec<&str> = merged
+            .results
+            .iter()
+            .map(|r| r.file_path.as_str())
+            .collect();
+        assert_eq!(files, ["api/a.rs", "web/c.ts"]);
+        assert!(merged.results.iter().all(|result| result.id.is_none()));
+        assert_eq!(merged.stats.num_candidates, 10);
+        assert_eq!(merged.stats.num_results, 2);
+    }
+
     #[test]
     fn test_message_serialization() {
         let request = Message::Request {
@@ -49,10 +619,162 @@ mod tests {
                 query: "test".to_string(),
                 top_n: 10,
                 files_only: false,
+                project_root: Some("/tmp/project".to_string()),
+                languages: vec!["rust".to_string()],
+                profile: None,
+                categories: Vec::new(),
+                exclude_categories: Vec::new(),
+                max_tokens: None,
+                kinds: Vec::new(),
+                paths: vec!["src/auth".to_string()],
+                comments: false,
+                author: Some("alice".to_string()),
+                since: Some(1_704_067_200),
+                changed_since: Some(1_704_240_000),
+                rerank_depth: Some(100),
+                queries: vec!["token refresh".to_string()],
+                combine: QueryCombination::All,
+                explain: true,
             },
         };
         let serialized = serde_json::to_string(&request).unwrap();
         let deserialized: Message = serde_json::from_str(&serialized).unwrap();
         assert_eq!(request, deserialized);
     }
+
+    #[test]
+    fn test_batch_response_serialization() {
+        let response = Message::BatchResponse {
+            id: 7,
+            responses: vec![BatchResult {
+                sub_id: 1,
+                response: None,
+                error: Some("Search failed: no index".to_string()),
+                code: Some(ErrorCode::IndexEmpty),
+            }],
+        };
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"type":"BatchResponse","id":7,"responses":[{"sub_id":1,"error":"Search failed: no index","code":"index_empty"}]}"#
+        );
+        let deserialized: Message = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(response, deserialized);
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Message::Event {
+            event: IndexEvent::Progress {
+                project_root: "/tmp/project".to_string(),
+                done: 3,
+                total: 10,
+            },
+        };
+        let serialized = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"type":"Event","event":{"kind":"progress","project_root":"/tmp/project","done":3,"total":10}}"#
+        );
+        let deserialized: Message = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(event, deserialized);
+    }
+
+    #[test]
+    fn test_result_without_kind() {
+        // Results from servers that predate chunk ids, kinds and names still parse
+        let result: SearchResult = serde_json::from_str(
+            r#"{"file_path":"a.rs","start_line":1,"end_line":2,"text":"","score":0.5}"#,
+        )
+        .unwrap();
+        assert_eq!(result.id, None);
+        assert_eq!(result.kind, "");
+        assert_eq!(result.name, None);
+
+        // Version 1 servers send row ids
+        let result: SearchResult = serde_json::from_str(
+            r#"{"id":42,"file_path":"a.rs","start_line":1,"end_line":2,"text":"","score":0.5}"#,
+        )
+        .unwrap();
+        assert_eq!(result.id, None);
+    }
+
+    #[test]
+    fn test_response_for_version_1() {
+        let response = |id: Option<&str>| Message::Response {
+            id: 1,
+            response: SearchResponse {
+                results: vec![SearchResult {
+                    id: id.map(str::to_string),
+                    file_path: "a.rs".to_string(),
+                    start_line: 1,
+                    end_line: 2,
+                    text: String::new(),
+                    score: 0.5,
+                    kind: "function".to_string(),
+                    name: None,
+                    modified_at: None,
+                    explanation: None,
+                }],
+                stats: SearchStats {
+                    total_time_ms: 0,
+                    expand_ms: 0,
+                    embed_ms: 0,
+                    file_search_ms: 0,
+                    vector_search_ms: 0,
+                    rerank_ms: 0,
+                    num_candidates: 1,
+                    num_candidate_files: None,
+                    num_results: 1,
+                    num_tokens: None,
+                },
+                explanation: None,
+            },
+        };
+        let message = response(Some("3f2a9c01b7d4"));
+        assert_eq!(message.clone().for_version(2), message);
+        assert_eq!(message.for_version(1), response(None));
+    }
+
+    #[test]
+    fn test_stats_without_stage_timings() {
+        // Responses from servers that predate per-stage timings still parse
+        let stats: SearchStats =
+            serde_json::from_str(r#"{"total_time_ms":12,"num_candidates":3,"num_results":2}"#)
+                .unwrap();
+        assert_eq!(stats.total_time_ms, 12);
+        assert_eq!(stats.rerank_ms, 0);
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let status = Message::Status {
+            id: 1,
+            status: ServerStatus::WarmingUp,
+        };
+        let serialized = serde_json::to_string(&status).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"type":"Status","id":1,"status":"warming_up"}"#
+        );
+        let deserialized: Message = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(status, deserialized);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        // Newer clients are answered with the version this build speaks
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION - 1), None);
+
+        let hello: Message = serde_json::from_str(r#"{"type": "Hello", "version": 1}"#).unwrap();
+        assert_eq!(
+            hello,
+            Message::Hello {
+                version: 1,
+                capabilities: vec![]
+            }
+        );
+    }
 }
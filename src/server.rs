@@ -1,36 +1,475 @@
+use crate::chunker;
+use crate::config::{
+    self, global_data_dir, ConfigManager, EmbeddingConfig, EvictionPolicy, LlmConfig, ServerConfig,
+    WatchBackend,
+};
 use crate::constants::constants;
-use crate::context::AppContext;
-use crate::embedder::Embedding;
-use crate::git_watcher::GitFileWatcher;
-use crate::protocol::{Message, SearchRequest, SearchResponse, SearchResult, SearchStats};
+use crate::context::{self, AppContext, Models};
+use crate::db::{self, ChunkFilter, ChunkMatch, Database};
+use crate::embedder::{self, Embedder, Embedding};
+use crate::git_watcher::{FileChange, GitFileWatcher, WatchHandle};
+use crate::indexer::IgnoreCache;
+use crate::llm;
+use crate::metrics::Metrics;
+use crate::protocol::{
+    self, BatchResult, CutCandidate, ErrorCode, Explanation, IndexEvent, Message, QueryCombination,
+    ScoreAdjustment, SearchExplanation, SearchRequest, SearchResponse, SearchResult, SearchStats,
+    ServerStatus, CAPABILITIES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::reranker::Reranker;
+use crate::weights::PathWeights;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use log::{debug, error, info, warn};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs::Permissions;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::unix::SocketAddr;
+use tokio::net::{UnixListener, UnixSocket, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Changes reported by a project's watcher, tagged with the project root
+type ProjectChanges = (PathBuf, Vec<FileChange>);
+
+/// Canonical project root and index profile (`None` for the default index)
+type ProjectKey = (PathBuf, Option<String>);
+
+/// How often the server checks whether the models have been idle long enough to unload
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the server saves its metrics
+const METRICS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Indexing events kept for subscribers that fall behind before older ones are dropped
+const EVENT_BUFFER: usize = 256;
+
+/// How long a query waits for a search permit (`server.max_concurrent_searches`) before
+/// it is refused as busy
+const SEARCH_QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connections the socket queues before the server accepts them
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Config keys only read at startup (models and server limits); changing them needs a restart
+const RESTART_KEYS: &[&str] = &[
+    "model_cache_dir",
+    "embedding.quantized",
+    "embedding.dimensions",
+    "embedding.threads",
+    "reranker.",
+    "server.allow_group",
+    "server.verify_index",
+    "server.max_concurrent_searches",
+    "server.max_queries_per_minute",
+    "server.model_worker",
+];
+
+/// Config keys read when a project's file watcher starts; changing them restarts it
+const WATCHER_KEYS: &[&str] = &[
+    "git_watch.",
+    "index.extensions",
+    "index.exclude_dirs",
+    "index.include_config_files",
+];
+
+/// Embeddings searched for one query: the query's own, then its expansion's (`search.hyde`)
+type QueryEmbeddings = Vec<Vec<f32>>;
+
+/// Project contexts served by one server, one per project root and index profile
+#[derive(Clone)]
+struct ProjectRegistry {
+    models: Models,
+    /// Project every request goes to in per-project mode; `None` for a global server
+    default_root: Option<PathBuf>,
+    projects: Arc<Mutex<HashMap<ProjectKey, Arc<Mutex<AppContext>>>>>,
+    /// Running file watchers by project root; dropping one stops it
+    watchers: Arc<std::sync::Mutex<HashMap<PathBuf, WatchHandle>>>,
+    changes_tx: UnboundedSender<ProjectChanges>,
+    /// Indexing events for subscribed clients
+    events: broadcast::Sender<IndexEvent>,
+    /// When the models were last used by a query or reindex
+    last_activity: Arc<std::sync::Mutex<Instant>>,
+    /// Idle time after which the models are unloaded; `None` keeps them loaded
+    idle_unload: Option<Duration>,
+    /// Open indexes read-only and never watch or reindex them
+    read_only: bool,
+    /// Held while reindexing, so changes are applied one batch at a time
+    reindexing: Arc<Mutex<()>>,
+    /// Queries and reindexes served, exported with `ragrep metrics`
+    metrics: Arc<std::sync::Mutex<Metrics>>,
+    metrics_path: PathBuf,
+    /// Permits for running searches (`server.max_concurrent_searches`); `None` is unlimited
+    searches: Option<Arc<Semaphore>>,
+    /// Queries each connection may send per minute (`server.max_queries_per_minute`)
+    max_queries_per_minute: Option<u32>,
+    /// Set once the models have been warmed up; queries wait for it
+    ready: Arc<watch::Sender<bool>>,
+}
+
+impl ProjectRegistry {
+    /// Record that the models were just used, postponing the idle unload
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Write the metrics to disk, so they survive a restart
+    fn save_metrics(&self) {
+        let metrics = self.metrics.lock().unwrap().clone();
+        if let Err(e) = metrics.save(&self.metrics_path) {
+            warn!("Failed to save metrics: {:#}", e);
+        }
+    }
+
+    /// Unload the models if nothing has used them for the configured idle time
+    fn unload_if_idle(&self) {
+        let Some(idle_unload) = self.idle_unload else {
+            return;
+        };
+        let idle = self.last_activity.lock().unwrap().elapsed();
+        if idle >= idle_unload && self.models.is_loaded() {
+            info!(
+                "No queries for {} minutes, unloading models",
+                idle.as_secs() / 60
+            );
+            self.models.unload();
+        }
+    }
+
+    /// Find the context for a request, opening the project database on first use
+    async fn get(
+        &self,
+        project_root: Option<&str>,
+        profile: Option<&str>,
+    ) -> Result<Arc<Mutex<AppContext>>> {
+        let root = match (&self.default_root, project_root) {
+            (Some(default_root), _) => default_root.clone(),
+            (None, Some(project_root)) => Path::new(project_root)
+                .canonicalize()
+                .with_context(|| format!("Invalid project root: {}", project_root))
+                .map_err(|e| CodedError::wrap(ErrorCode::BadRequest, e))?,
+            (None, None) => {
+                return Err(CodedError::wrap(
+                    ErrorCode::BadRequest,
+                    anyhow!("Global server requires a project root"),
+                ))
+            }
+        };
+
+        self.open(root, profile).await
+    }
+
+    /// Find or open the context of the index profile selected for `root`
+    ///
+    /// The profile is resolved on every call so per-branch indexes follow branch switches.
+    async fn open(&self, root: PathBuf, profile: Option<&str>) -> Result<Arc<Mutex<AppContext>>> {
+        let index_config = ConfigManager::new(Some(&root))?.config().index.clone();
+        let profile = context::resolve_profile(&root, &index_config, profile);
+        let key = (root.clone(), profile);
+
+        let mut projects = self.projects.lock().await;
+        if let Some(context) = projects.get(&key) {
+            return Ok(Arc::clone(context));
+        }
+
+        // One watcher per project, shared by all of its profiles
+        let watched = projects
+            .keys()
+            .any(|(project_root, _)| *project_root == root);
+
+        info!("Opening project {}", root.display());
+        let mut context =
+            AppContext::with_models(&root, self.models.clone(), key.1.as_deref(), self.read_only)?;
+        if context.db.count_chunks()? == 0 {
+            warn!(
+                "Index {} is empty; run `ragrep index` in {}",
+                context.database_path().display(),
+                root.display()
+            );
+        }
+        context.verify_on_open().await?;
+        context.build_ann_index()?;
+        let context = Arc::new(Mutex::new(context));
+        projects.insert(key, Arc::clone(&context));
+        drop(projects);
+
+        if !watched {
+            self.watch(root, &context).await;
+        }
+        Ok(context)
+    }
+
+    /// Start the project's file watcher, forwarding its changes to the server loop
+    async fn watch(&self, root: PathBuf, context: &Arc<Mutex<AppContext>>) {
+        if self.read_only {
+            info!(
+                "Read-only index, file watching disabled for {}",
+                root.display()
+            );
+            return;
+        }
+
+        let (handle, blocking_rx) = match start_git_watcher(&root, context).await {
+            Ok(Some(watch)) => watch,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to start file watcher for {}: {}", root.display(), e);
+                return;
+            }
+        };
+
+        self.watchers.lock().unwrap().insert(root.clone(), handle);
+
+        // Run the blocking receiver in a blocking task bridged to the async channel, until
+        // the watcher stops
+        let tx = self.changes_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            while let Ok(changes) = blocking_rx.recv() {
+                if tx.send((root.clone(), changes)).is_err() {
+                    break; // Server loop stopped
+                }
+            }
+        });
+    }
+
+    /// Restart the file watcher of the project at `root` with its current config
+    async fn rewatch(&self, root: PathBuf, context: &Arc<Mutex<AppContext>>) {
+        if let Some(mut handle) = self.watchers.lock().unwrap().remove(&root) {
+            handle.stop();
+        }
+        self.watch(root, context).await;
+    }
+
+    /// Stop every project's file watcher
+    fn stop_watchers(&self) {
+        for (_, mut handle) in self.watchers.lock().unwrap().drain() {
+            handle.stop();
+        }
+    }
+
+    /// Any open context of the project at `root`
+    async fn lookup(&self, root: &Path) -> Option<Arc<Mutex<AppContext>>> {
+        self.projects
+            .lock()
+            .await
+            .iter()
+            .find(|((project_root, _), _)| project_root == root)
+            .map(|(_, context)| Arc::clone(context))
+    }
+
+    /// Reindex the files of the project at `root` touched by `changes`, reporting progress
+    /// to subscribers
+    ///
+    /// Runs alongside the searches; reindexes run one at a time.
+    async fn reindex(&self, root: &Path, changes: Vec<FileChange>) {
+        if self.lookup(root).await.is_none() {
+            warn!("Changes reported for unknown project {}", root.display());
+            return;
+        }
+
+        // Reindex into the profile of the branch checked out now
+        let context = match self.open(root.to_path_buf(), None).await {
+            Ok(context) => context,
+            Err(e) => {
+                error!("Failed to open index for {}: {}", root.display(), e);
+                return;
+            }
+        };
+
+        // An edited ignore file changes which results searches leave out, in every profile
+        if changes
+            .iter()
+            .any(|change| matches!(change, FileChange::IgnoreFile(_)))
+        {
+            let contexts: Vec<Arc<Mutex<AppContext>>> = self
+                .projects
+                .lock()
+                .await
+                .iter()
+                .filter(|((project_root, _), _)| project_root == root)
+                .map(|(_, context)| Arc::clone(context))
+                .collect();
+            for context in contexts {
+                context.lock().await.ignored_files.clear();
+            }
+        }
+
+        let changed_files = match context.lock().await.changed_files(changes) {
+            Ok(changed_files) => changed_files,
+            Err(e) => {
+                error!("Failed to list changed files in {}: {}", root.display(), e);
+                return;
+            }
+        };
+        info!(
+            "Detected {} changed files in {}, reindexing...",
+            changed_files.len(),
+            root.display()
+        );
+        for file in &changed_files {
+            debug!("  - {}", file.display());
+        }
+
+        // Sending only fails without subscribers
+        let events = &self.events;
+        let project_root = root.to_string_lossy().to_string();
+        let _ = events.send(IndexEvent::ReindexTriggered {
+            project_root: project_root.clone(),
+            files: changed_files.len(),
+        });
+
+        let _reindexing = self.reindexing.lock().await;
+        let start = Instant::now();
+        let result = AppContext::reindex_files(&context, changed_files, |done, total| {
+            let project_root = project_root.clone();
+            let _ = events.send(match done {
+                0 => IndexEvent::Started {
+                    project_root,
+                    files: total,
+                },
+                _ => IndexEvent::Progress {
+                    project_root,
+                    done,
+                    total,
+                },
+            });
+        })
+        .await;
+        self.touch();
+        {
+            let stats = result.as_ref().copied().unwrap_or_default();
+            self.metrics.lock().unwrap().record_reindex(
+                result.is_ok(),
+                stats.reused_embeddings,
+                stats.new_embeddings,
+            );
+        }
+        self.save_metrics();
+        match result {
+            Ok(_) => {
+                info!("Reindex complete");
+                let _ = events.send(IndexEvent::Completed {
+                    project_root,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                });
+            }
+            Err(e) => {
+                error!("Reindex failed: {}", e);
+                let _ = events.send(IndexEvent::Failed {
+                    project_root,
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+}
 
 pub struct RagrepServer {
-    context: Arc<Mutex<AppContext>>,
+    registry: ProjectRegistry,
+    changes_rx: UnboundedReceiver<ProjectChanges>,
     socket_path: PathBuf,
     pid_path: PathBuf,
+    /// Accept clients of other users (`server.allow_group`)
+    allow_group: bool,
 }
 
 impl RagrepServer {
-    /// Create a new server instance
-    pub fn new(context: AppContext, base_path: &std::path::Path) -> Self {
-        let ragrep_dir = base_path.join(constants::RAGREP_DIR_NAME);
-        let socket_path = ragrep_dir.join(constants::SOCKET_FILENAME);
-        let pid_path = ragrep_dir.join(constants::PID_FILENAME);
+    /// Create a server for the single project at `base_path`
+    pub fn new(mut context: AppContext, base_path: &Path) -> Result<Self> {
+        let ragrep_dir = config::project_data_dir(base_path);
+        let root = base_path
+            .canonicalize()
+            .with_context(|| format!("Failed to canonicalize {}", base_path.display()))?;
+
+        context.build_ann_index()?;
+        let models = context.models();
+        let server_config = context.config_manager.config().server.clone();
+        let read_only = context.read_only;
+        let key = (root.clone(), context.profile.clone());
+        let projects = HashMap::from([(key, Arc::new(Mutex::new(context)))]);
+
+        Ok(Self::with_registry(
+            models,
+            &server_config,
+            Some(root),
+            projects,
+            read_only,
+            config::socket_path_override()
+                .unwrap_or_else(|| ragrep_dir.join(constants::SOCKET_FILENAME)),
+            ragrep_dir.join(constants::PID_FILENAME),
+        ))
+    }
+
+    /// Create a global server that shares one set of models between all projects
+    ///
+    /// Projects are opened on demand from the root sent with each request.
+    pub fn new_global(
+        models: Models,
+        server_config: &ServerConfig,
+        read_only: bool,
+    ) -> Result<Self> {
+        let server_dir = global_data_dir()?;
+        config::create_private_dir(&server_dir)?;
+
+        Ok(Self::with_registry(
+            models,
+            server_config,
+            None,
+            HashMap::new(),
+            read_only,
+            config::socket_path_override()
+                .unwrap_or_else(|| server_dir.join(constants::SOCKET_FILENAME)),
+            server_dir.join(constants::PID_FILENAME),
+        ))
+    }
+
+    fn with_registry(
+        models: Models,
+        server_config: &ServerConfig,
+        default_root: Option<PathBuf>,
+        projects: HashMap<ProjectKey, Arc<Mutex<AppContext>>>,
+        read_only: bool,
+        socket_path: PathBuf,
+        pid_path: PathBuf,
+    ) -> Self {
+        let (changes_tx, changes_rx) = unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let metrics_path = pid_path.with_file_name(constants::METRICS_FILENAME);
 
         Self {
-            context: Arc::new(Mutex::new(context)),
+            registry: ProjectRegistry {
+                models,
+                default_root,
+                projects: Arc::new(Mutex::new(projects)),
+                watchers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+                changes_tx,
+                events,
+                last_activity: Arc::new(std::sync::Mutex::new(Instant::now())),
+                idle_unload: server_config
+                    .idle_unload_minutes
+                    .map(|minutes| Duration::from_secs(minutes * 60)),
+                read_only,
+                reindexing: Arc::new(Mutex::new(())),
+                metrics: Arc::new(std::sync::Mutex::new(Metrics::load(&metrics_path))),
+                metrics_path,
+                searches: server_config
+                    .max_concurrent_searches
+                    .map(|permits| Arc::new(Semaphore::new(permits.max(1)))),
+                max_queries_per_minute: server_config.max_queries_per_minute,
+                ready: Arc::new(watch::channel(false).0),
+            },
+            changes_rx,
             socket_path,
             pid_path,
+            allow_group: server_config.allow_group,
         }
     }
 
@@ -53,9 +492,25 @@ impl RagrepServer {
             }
         }
 
+        // The socket answers queries about the code, so keep other users out
+        let (dir_mode, file_mode) = if self.allow_group {
+            (0o750, 0o660)
+        } else {
+            (0o700, 0o600)
+        };
+        if let Some(dir) = self.pid_path.parent() {
+            set_mode(dir, dir_mode);
+        }
+
         // Write our PID
         let pid = std::process::id();
-        std::fs::write(&self.pid_path, pid.to_string()).context("Failed to write PID file")?;
+        std::fs::write(&self.pid_path, pid.to_string()).with_context(|| {
+            format!(
+                "Failed to write PID file {} (--data-dir moves it to a writable directory)",
+                self.pid_path.display()
+            )
+        })?;
+        set_mode(&self.pid_path, file_mode);
 
         info!("Server PID: {}", pid);
 
@@ -64,54 +519,99 @@ impl RagrepServer {
             std::fs::remove_file(&self.socket_path).context("Failed to remove old socket")?;
         }
 
-        // Create the listener
-        let listener =
-            UnixListener::bind(&self.socket_path).context("Failed to bind Unix socket")?;
-
-        // Start git watcher if enabled and in a git repo
-        let git_watcher_rx = self.start_git_watcher().await?;
+        // Restrict the socket between `bind` and `listen`, so it is never connectable by
+        // others with the default mode
+        let socket = UnixSocket::new_stream()
+            .and_then(|socket| socket.bind(&self.socket_path).map(|()| socket))
+            .with_context(|| {
+                format!("Failed to bind Unix socket {}", self.socket_path.display())
+            })?;
+        set_mode(&self.socket_path, file_mode);
+        let listener = socket.listen(LISTEN_BACKLOG).with_context(|| {
+            format!(
+                "Failed to listen on Unix socket {}",
+                self.socket_path.display()
+            )
+        })?;
 
         info!("Server listening on {}", self.socket_path.display());
+        self.run(Some(listener), None).await
+    }
 
-        // Convert blocking receiver to async if watcher exists
-        let mut git_rx_async = if let Some(blocking_rx) = git_watcher_rx {
-            let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Vec<PathBuf>>();
-            let tx_clone = tx.clone();
-            // Spawn task to bridge blocking receiver to async channel
-            tokio::spawn(async move {
-                // Run the blocking receiver in a blocking task
-                tokio::task::spawn_blocking(move || {
-                    loop {
-                        match blocking_rx.recv() {
-                            Ok(files) => {
-                                if tx_clone.send(files).is_err() {
-                                    break; // Receiver dropped
-                                }
-                            }
-                            Err(_) => {
-                                break; // Channel closed or error
-                            }
-                        }
-                    }
-                })
-                .await
-                .ok();
-            });
-            Some(rx)
+    /// Serve a single client speaking the same protocol over stdin and stdout
+    ///
+    /// For editor plugins that manage the server process themselves: no socket or PID file
+    /// is created, and the server exits once the client closes stdin.
+    pub async fn serve_stdio(&mut self) -> Result<()> {
+        let registry = self.registry.clone();
+        let client = tokio::spawn(async move {
+            handle_connection(tokio::io::stdin(), tokio::io::stdout(), registry).await
+        });
+
+        info!("Server listening on stdin");
+        self.run(None, Some(client)).await
+    }
+
+    /// Answer clients and handle file changes until the stdio client (if any) disconnects
+    async fn run(
+        &mut self,
+        listener: Option<UnixListener>,
+        mut stdio_client: Option<JoinHandle<Result<()>>>,
+    ) -> Result<()> {
+        // Clients can connect right away; their queries wait until the models are warm
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            match registry.models.warm_up().await {
+                Ok(()) => info!("Models warmed up in {}ms", start.elapsed().as_millis()),
+                // Queries will report the model error themselves
+                Err(e) => warn!("Failed to warm up the models: {:#}", e),
+            }
+            registry.ready.send_replace(true);
+        });
+
+        // Start git watcher for the served project (global servers watch projects as they open)
+        if let Some(root) = self.registry.default_root.clone() {
+            if let Some(context) = self.registry.lookup(&root).await {
+                self.registry.watch(root, &context).await;
+            }
         } else {
-            None
+            info!("Global server: projects are opened on first request");
+        }
+
+        if let Some(idle_unload) = self.registry.idle_unload {
+            info!(
+                "Models are unloaded after {} minutes without queries",
+                idle_unload.as_secs() / 60
+            );
+        }
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        let mut metrics_save = tokio::time::interval(METRICS_SAVE_INTERVAL);
+        let mut sighup = signal(SignalKind::hangup()).context("Failed to handle SIGHUP")?;
+        let group = match (&listener, self.allow_group) {
+            (Some(_), true) => Some(
+                std::fs::metadata(&self.socket_path)
+                    .with_context(|| format!("Failed to read {}", self.socket_path.display()))?
+                    .gid(),
+            ),
+            _ => None,
         };
 
         // Accept connections and handle git changes in a loop
         loop {
             tokio::select! {
                 // Handle client connections
-                accept_result = listener.accept() => {
+                accept_result = accept(listener.as_ref()) => {
                     match accept_result {
                         Ok((stream, _addr)) => {
-                            let context = Arc::clone(&self.context);
+                            if let Err(e) = authorize_peer(&stream, group) {
+                                warn!("Rejected connection: {}", e);
+                                continue;
+                            }
+                            let registry = self.registry.clone();
                             tokio::spawn(async move {
-                                if let Err(e) = handle_connection(stream, context).await {
+                                let (reader, writer) = stream.into_split();
+                                if let Err(e) = handle_connection(reader, writer, registry).await {
                                     error!("Connection error: {}", e);
                                 }
                             });
@@ -122,81 +622,111 @@ impl RagrepServer {
                     }
                 }
 
-                // Handle git changes
-                changed_files_result = async {
-                    if let Some(ref mut rx) = git_rx_async {
-                        rx.recv().await
-                    } else {
-                        // Wait forever if no watcher (this branch will never be selected)
-                        std::future::pending::<Option<Vec<PathBuf>>>().await
-                    }
-                } => {
-                    if let Some(changed_files) = changed_files_result {
-                        self.handle_git_changes(changed_files).await;
-                    }
+                // The stdio client closed stdin
+                result = wait_for(stdio_client.as_mut()) => {
+                    info!("Client disconnected, shutting down");
+                    self.registry.stop_watchers();
+                    self.registry.save_metrics();
+                    return result?;
                 }
-            }
-        }
-    }
 
-    async fn start_git_watcher(&self) -> Result<Option<Receiver<Vec<PathBuf>>>> {
-        // Check config
-        let config_enabled = {
-            let context = self.context.lock().await;
-            context.config_manager.config().git_watch.enabled
-        };
+                // Handle git changes
+                Some((root, changed_files)) = self.changes_rx.recv() => {
+                    // Searches keep being accepted while the files are reindexed
+                    let registry = self.registry.clone();
+                    tokio::spawn(async move { registry.reindex(&root, changed_files).await });
+                }
 
-        if !config_enabled {
-            info!("File watching disabled in config");
-            return Ok(None);
-        }
+                // Free the models once the server has been idle long enough
+                _ = idle_check.tick(), if self.registry.idle_unload.is_some() => {
+                    self.registry.unload_if_idle();
+                }
 
-        // Check if in git repo
-        // Get base path from socket path (go up from .ragrep/ragrep.sock)
-        let base_path = self
-            .socket_path
-            .parent()
-            .and_then(|p| p.parent())
-            .ok_or_else(|| anyhow!("Invalid socket path"))?;
+                _ = metrics_save.tick() => {
+                    self.registry.save_metrics();
+                }
 
-        if !GitFileWatcher::is_git_repo(base_path) {
-            warn!("Not in a git repository, file watching disabled");
-            return Ok(None);
+                // Apply config edits without reloading the models
+                _ = sighup.recv() => {
+                    self.reload_config().await;
+                }
+            }
         }
+    }
 
-        // Start file watcher (watches .rs, .py, .js, .ts files)
-        let watcher = GitFileWatcher::new(base_path)?;
-        let debounce = {
-            let context = self.context.lock().await;
-            context.config_manager.config().git_watch.debounce_ms
-        };
-        let rx = watcher.watch_debounced(debounce)?;
+    /// Re-read the config of every open project and apply what can change without a restart
+    async fn reload_config(&mut self) {
+        info!("Received SIGHUP, reloading config");
 
-        info!("File watcher started (debounce: {}ms)", debounce);
-        info!("Watching .rs, .py, .js, .ts files (respecting .gitignore)");
+        let contexts: Vec<(PathBuf, Arc<Mutex<AppContext>>)> = self
+            .registry
+            .projects
+            .lock()
+            .await
+            .iter()
+            .map(|((root, _), context)| (root.clone(), Arc::clone(context)))
+            .collect();
 
-        Ok(Some(rx))
-    }
+        // Profiles of one project share its config, so report each project once
+        let mut reported = HashSet::new();
+        for (root, context) in contexts {
+            let changed = match context.lock().await.reload_config() {
+                Ok(changed) => changed,
+                Err(e) => {
+                    error!("Keeping the previous config of {}: {:#}", root.display(), e);
+                    continue;
+                }
+            };
+            if !reported.insert(root.clone()) {
+                continue;
+            }
 
-    async fn handle_git_changes(&mut self, changed_files: Vec<PathBuf>) {
-        info!(
-            "Detected {} changed files, reindexing...",
-            changed_files.len()
-        );
+            for key in &changed {
+                if RESTART_KEYS.iter().any(|prefix| key.starts_with(prefix)) {
+                    warn!("{} changed; restart the server to apply it", key);
+                } else {
+                    info!("{} changed in {}", key, root.display());
+                }
+            }
 
-        for file in &changed_files {
-            debug!("  - {}", file.display());
+            let watcher_changed = changed
+                .iter()
+                .any(|key| WATCHER_KEYS.iter().any(|prefix| key.starts_with(prefix)));
+            if watcher_changed {
+                info!("Restarting the file watcher of {}", root.display());
+                self.registry.rewatch(root, &context).await;
+            }
         }
 
-        let mut context = self.context.lock().await;
-        match context.reindex_files(changed_files).await {
-            Ok(()) => {
-                info!("Reindex complete");
+        match self.server_config().await {
+            Ok(server_config) => {
+                let idle_unload = server_config
+                    .idle_unload_minutes
+                    .map(|minutes| Duration::from_secs(minutes * 60));
+                if idle_unload != self.registry.idle_unload {
+                    match idle_unload {
+                        Some(idle_unload) => info!(
+                            "Models are now unloaded after {} minutes without queries",
+                            idle_unload.as_secs() / 60
+                        ),
+                        None => info!("Models now stay loaded while idle"),
+                    }
+                    self.registry.idle_unload = idle_unload;
+                }
             }
-            Err(e) => {
-                error!("Reindex failed: {}", e);
+            Err(e) => error!("Keeping the previous server config: {:#}", e),
+        }
+    }
+
+    /// The `[server]` settings: the served project's for a per-project server, the global
+    /// config's otherwise
+    async fn server_config(&self) -> Result<ServerConfig> {
+        if let Some(root) = &self.registry.default_root {
+            if let Some(context) = self.registry.lookup(root).await {
+                return Ok(context.lock().await.config_manager.config().server.clone());
             }
         }
+        Ok(ConfigManager::new(None)?.reload()?.config().server.clone())
     }
 
     /// Get the PID file path
@@ -210,146 +740,2014 @@ impl RagrepServer {
     }
 }
 
-/// Execute a search query and return results (shared implementation)
-pub async fn execute_search(
-    context: &mut AppContext,
-    request: SearchRequest,
-) -> Result<SearchResponse> {
-    let start = Instant::now();
-
-    debug!("Executing search: {}", request.query);
+async fn start_git_watcher(
+    base_path: &Path,
+    context: &Arc<Mutex<AppContext>>,
+) -> Result<Option<(WatchHandle, Receiver<Vec<FileChange>>)>> {
+    // Check config
+    let (watch_config, index_config) = {
+        let context = context.lock().await;
+        let config = context.config_manager.config();
+        (config.git_watch.clone(), config.index.clone())
+    };
+    let debounce = watch_config.debounce_ms;
 
-    // Step 1: Generate embedding for the query
-    let Embedding(query_embedding) = context.embedder.embed_query(&request.query).await?;
-
-    // Step 2: Search the database
-    let initial_results = context
-        .db
-        .find_similar_chunks(&query_embedding, request.top_n)?;
+    if !watch_config.enabled {
+        info!("File watching disabled in config");
+        return Ok(None);
+    }
 
-    if initial_results.is_empty() {
-        return Ok(SearchResponse {
-            results: vec![],
-            stats: SearchStats {
-                total_time_ms: start.elapsed().as_millis() as u64,
-                num_candidates: 0,
-                num_results: 0,
-            },
-        });
+    // Check if in git repo
+    if !GitFileWatcher::is_git_repo(base_path) {
+        warn!("Not in a git repository, file watching disabled");
+        return Ok(None);
     }
 
-    // Step 3: Rerank results
-    let documents: Vec<String> = initial_results
-        .iter()
-        .map(|(text, _, _, _, _, _)| text.clone())
-        .collect();
+    // Start file watcher (watches the configured extensions)
+    let watcher = GitFileWatcher::new(base_path, &index_config, &watch_config)?;
+    let watch = watcher.watch_debounced(debounce)?;
 
-    let reranked_indices =
-        context
-            .reranker
-            .rerank(&request.query, &documents, Some(request.top_n))?;
+    match watch_config.backend {
+        WatchBackend::Native => info!("File watcher started (debounce: {}ms)", debounce),
+        WatchBackend::Poll => info!(
+            "File watcher started, polling every {}ms (debounce: {}ms)",
+            watch_config.poll_interval_ms, debounce
+        ),
+    }
+    info!(
+        "Watching .{} files (respecting .gitignore)",
+        index_config.extensions.join(", .")
+    );
 
-    // Step 4: Convert to SearchResult format and filter out non-existent files
-    let results: Vec<SearchResult> = reranked_indices
-        .iter()
-        .filter_map(|(idx, score)| {
-            let (text, file_path, start_line, end_line, _node_type, _distance) =
-                &initial_results[*idx];
-            
-            // Filter out files that no longer exist
-            if !std::path::Path::new(file_path).exists() {
-                debug!("Filtering out non-existent file from results: {}", file_path);
-                return None;
-            }
-            
-            Some(SearchResult {
-                file_path: file_path.clone(),
-                start_line: *start_line,
-                end_line: *end_line,
-                text: if request.files_only {
-                    String::new()
-                } else {
-                    text.clone()
-                },
-                score: *score,
-            })
-        })
-        .collect();
+    Ok(Some(watch))
+}
 
-    let elapsed = start.elapsed();
-    let num_results = results.len();
+/// Time spent in each stage of a search
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchTimings {
+    pub expand: Duration,
+    pub embed: Duration,
+    pub file_search: Duration,
+    pub vector_search: Duration,
+    pub rerank: Duration,
+    pub total: Duration,
+}
 
-    Ok(SearchResponse {
-        results,
-        stats: SearchStats {
-            total_time_ms: elapsed.as_millis() as u64,
-            num_candidates: initial_results.len(),
+impl SearchStats {
+    fn new(timings: &SearchTimings, num_candidates: usize, num_results: usize) -> Self {
+        Self {
+            total_time_ms: timings.total.as_millis() as u64,
+            expand_ms: timings.expand.as_millis() as u64,
+            embed_ms: timings.embed.as_millis() as u64,
+            file_search_ms: timings.file_search.as_millis() as u64,
+            vector_search_ms: timings.vector_search.as_millis() as u64,
+            rerank_ms: timings.rerank.as_millis() as u64,
+            num_candidates,
+            num_candidate_files: None,
             num_results,
-        },
-    })
+            num_tokens: None,
+        }
+    }
 }
 
-/// Execute a search query and return results (server version with Arc<Mutex>)
-async fn handle_search(
-    context: Arc<Mutex<AppContext>>,
-    request: SearchRequest,
-) -> Result<SearchResponse> {
-    let mut context_guard = context.lock().await;
-    execute_search(&mut *context_guard, request).await
+/// Share of the full recency boost a file of the given age gets: 1 when brand new, halving
+/// every `half_life_days`
+fn recency_weight(age_secs: i64, half_life_days: f32) -> f32 {
+    let age_days = age_secs.max(0) as f32 / 86_400.0;
+    0.5f32.powf(age_days / half_life_days.max(f32::EPSILON))
 }
 
-/// Handle a single client connection
-async fn handle_connection(stream: UnixStream, context: Arc<Mutex<AppContext>>) -> Result<()> {
-    debug!("New connection");
+/// Multiply each result's score by its path's `[search.weights]` and re-sort by score
+fn apply_path_weights(context: &AppContext, results: &mut [SearchResult]) -> Result<()> {
+    let weights = PathWeights::new(&context.config_manager.config().search.weights)?;
+    if weights.is_empty() {
+        return Ok(());
+    }
 
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    for result in results.iter_mut() {
+        result.score = weights.adjust(result.score, Path::new(&result.file_path));
+    }
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(())
+}
 
-    while reader.read_line(&mut line).await? > 0 {
-        // Parse the message
-        let message: Message = serde_json::from_str(&line).context("Failed to parse message")?;
+/// Record when each result's file was modified, for sorting by recency
+fn set_modified_times(db: &Database, results: &mut [SearchResult]) -> Result<()> {
+    for result in results {
+        result.modified_at = db.get_file_modified(&result.file_path)?;
+    }
+    Ok(())
+}
 
-        debug!("Received message: {:?}", message);
+/// Add the configured recency boost to each result's score and re-sort by score
+fn apply_recency_boost(context: &AppContext, results: &mut [SearchResult]) -> Result<()> {
+    let config = &context.config_manager.config().search;
+    if config.recency_boost == 0.0 {
+        return Ok(());
+    }
 
-        let response = match message {
-            Message::Request { id, request } => {
-                match handle_search(Arc::clone(&context), request).await {
-                    Ok(search_response) => Message::Response {
-                        id,
-                        response: search_response,
-                    },
-                    Err(e) => Message::Error {
-                        id,
-                        message: format!("Search failed: {}", e),
-                    },
-                }
-            }
-            _ => {
-                warn!("Unexpected message type");
-                continue;
-            }
-        };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    for result in results.iter_mut() {
+        // Files indexed before modification times were recorded get no boost
+        if let Some(modified_at) = context.db.get_file_modified(&result.file_path)? {
+            result.score += config.recency_boost
+                * recency_weight(now - modified_at, config.recency_half_life_days);
+        }
+    }
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(())
+}
 
-        // Send response
-        let response_json = serde_json::to_string(&response)?;
-        writer.write_all(response_json.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
+/// Code a query may contain, by the language it points to
+///
+/// JavaScript's markers count for TypeScript as well, which shares its syntax.
+const LANGUAGE_MARKERS: &[(&str, &[&str])] = &[
+    (
+        "rust",
+        &[
+            "fn ", "impl ", "let mut ", "&self", "&mut ", "-> ", "::<", "#[", "unwrap()",
+        ],
+    ),
+    (
+        "python",
+        &["def ", "elif ", "__init__", "self.", "lambda ", "except "],
+    ),
+    (
+        "javascript",
+        &["=>", "const ", "===", "console.log", "require("],
+    ),
+    (
+        "typescript",
+        &[
+            "=>",
+            "const ",
+            "===",
+            "console.log",
+            "require(",
+            ": string",
+            ": number",
+            "interface ",
+        ],
+    ),
+    (
+        "ruby",
+        &["def ", "do |", "attr_accessor", "puts ", ".each "],
+    ),
+    ("php", &["<?php", "$this->", "public function", "::class"]),
+    (
+        "csharp",
+        &["public class", "using System", "async Task", "{ get;"],
+    ),
+    ("shell", &["#!/bin", "$(", "echo ", "; then", "esac"]),
+];
+
+/// Markers that are also English words ("except when", "echo the input"); they only count
+/// in queries with code punctuation
+const WORD_MARKERS: &[&str] = &[
+    "def ",
+    "except ",
+    "self.",
+    "lambda ",
+    "puts ",
+    "echo ",
+    "const ",
+    "interface ",
+];
+
+/// Characters that rarely show up in a question but often in code
+const CODE_PUNCTUATION: &[char] = &[
+    '(', ')', '[', ']', '{', '}', ';', '=', '<', '>', '$', '|', '_',
+];
 
-        line.clear();
+/// Whether `query` contains `marker` as code: not inside a longer word (`itself.`), and,
+/// for [`WORD_MARKERS`], in a query with [`CODE_PUNCTUATION`]
+fn contains_marker(query: &str, marker: &str) -> bool {
+    if WORD_MARKERS.contains(&marker) && !query.contains(CODE_PUNCTUATION) {
+        return false;
     }
+    let starts_word = marker.starts_with(|c: char| c.is_alphanumeric());
+    query.match_indices(marker).any(|(start, _)| {
+        !starts_word
+            || !query[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
 
-    debug!("Connection closed");
-    Ok(())
+/// Languages whose syntax a query uses most, if it contains any code at all
+///
+/// Ties keep every language involved: `def ` alone could be Python or Ruby.
+fn detect_languages(query: &str) -> Vec<&'static str> {
+    let hits: Vec<(&str, usize)> = LANGUAGE_MARKERS
+        .iter()
+        .map(|(language, markers)| {
+            let count = markers
+                .iter()
+                .filter(|marker| contains_marker(query, marker))
+                .count();
+            (*language, count)
+        })
+        .collect();
+    let best = hits.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    if best == 0 {
+        return Vec::new();
+    }
+    hits.into_iter()
+        .filter(|(_, count)| *count == best)
+        .map(|(language, _)| language)
+        .collect()
 }
 
-/// Check if a process with the given PID is still running
-fn is_process_running(pid: u32) -> bool {
-    // Use `kill -0` which is portable across Unix systems (Linux, macOS, etc.)
-    // It sends signal 0 which doesn't kill the process, just checks if it exists
-    Command::new("kill")
+/// Add the configured language boost to results in the language the queries' syntax
+/// points to and re-sort by score
+fn apply_language_boost(
+    context: &AppContext,
+    request: &SearchRequest,
+    results: &mut [SearchResult],
+) {
+    let boost = context.config_manager.config().search.language_boost;
+    if boost == 0.0 {
+        return;
+    }
+    let languages = detect_languages(&request.all_queries().join("\n"));
+    if languages.is_empty() {
+        return;
+    }
+
+    debug!("Query looks like {}", languages.join(" or "));
+    for result in results.iter_mut() {
+        let language = chunker::language_for_path(Path::new(&result.file_path));
+        if languages.contains(&language.as_str()) {
+            result.score += boost;
+        }
+    }
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+/// Run `adjust` on `results` and note each score it changed in the result's explanation,
+/// if it has one, as an adjustment for `reason`
+fn adjust_scores(
+    results: &mut [SearchResult],
+    reason: &str,
+    adjust: impl FnOnce(&mut [SearchResult]) -> Result<()>,
+) -> Result<()> {
+    // Adjustments re-sort the results, so scores are matched up by chunk id
+    let before: HashMap<Option<String>, f32> = results
+        .iter()
+        .map(|result| (result.id.clone(), result.score))
+        .collect();
+    adjust(results)?;
+    for result in results.iter_mut() {
+        let delta = result.score - before[&result.id];
+        if let Some(explanation) = &mut result.explanation {
+            if delta != 0.0 {
+                explanation.adjustments.push(ScoreAdjustment {
+                    reason: reason.to_string(),
+                    delta,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The restrictions of `filter` in words, one per step, for an explained search
+fn describe_filter(filter: &ChunkFilter) -> Vec<String> {
+    let mut steps = Vec::new();
+    let mut only = |what: &str, values: &[String]| {
+        if !values.is_empty() {
+            steps.push(format!("Only {} {}", what, values.join(", ")));
+        }
+    };
+    only("chunks in", &filter.languages);
+    only("chunks of the categories", &filter.categories);
+    only("chunks of the kinds", &filter.kinds);
+    only("chunks under", &filter.paths);
+    if !filter.exclude_categories.is_empty() {
+        steps.push(format!(
+            "No chunks of the categories {}",
+            filter.exclude_categories.join(", ")
+        ));
+    }
+    if filter.comments {
+        steps.push("Only comment text".to_string());
+    }
+    if let Some(author) = &filter.author {
+        steps.push(format!("Only chunks last changed by {}", author));
+    }
+    if let Some(since) = filter.since {
+        steps.push(format!(
+            "Only chunks last changed since {}",
+            crate::blame::format_date(since)
+        ));
+    }
+    if let Some(changed_since) = filter.changed_since {
+        steps.push(format!(
+            "Only chunks reindexed since {}",
+            crate::blame::format_date(changed_since)
+        ));
+    }
+    steps
+}
+
+/// A candidate cut before reranking, or with the given score after it
+fn cut_chunk(chunk: &ChunkMatch, score: Option<f32>, reason: &str) -> CutCandidate {
+    CutCandidate {
+        file_path: chunk.file_path.clone(),
+        start_line: chunk.start_line,
+        end_line: chunk.end_line,
+        kind: chunk.node_type.clone(),
+        name: chunk.node_name.clone(),
+        score,
+        reason: reason.to_string(),
+    }
+}
+
+/// A reranked result cut from the results
+fn cut_result(result: &SearchResult, reason: &str) -> CutCandidate {
+    CutCandidate {
+        file_path: result.file_path.clone(),
+        start_line: result.start_line,
+        end_line: result.end_line,
+        kind: result.kind.clone(),
+        name: result.name.clone(),
+        score: Some(result.score),
+        reason: reason.to_string(),
+    }
+}
+
+/// Remember which files had results, for evicting the least recently matched files first
+/// from an index with a size budget
+fn record_matches(context: &AppContext, results: &[SearchResult]) {
+    let config = &context.config_manager.config().index;
+    if context.read_only
+        || config.max_size_mb.is_none()
+        || config.eviction != EvictionPolicy::LeastRecentlyMatched
+    {
+        return;
+    }
+
+    let file_paths: HashSet<&str> = results
+        .iter()
+        .map(|result| result.file_path.as_str())
+        .collect();
+    let file_paths: Vec<&str> = file_paths.into_iter().collect();
+    // Losing a timestamp only makes eviction a little less accurate
+    if let Err(e) = context.db.record_matches(&file_paths) {
+        debug!("Failed to record matched files: {}", e);
+    }
+}
+
+/// Keep the leading results whose text fits in `budget` tokens and return the tokens kept
+///
+/// Results are ranked, so the first one that doesn't fit ends the list rather than being
+/// skipped in favor of smaller, less relevant ones.
+fn truncate_to_token_budget(
+    results: &mut Vec<SearchResult>,
+    budget: usize,
+    mut count_tokens: impl FnMut(&str) -> Result<usize>,
+) -> Result<usize> {
+    let mut used = 0;
+    let mut kept = results.len();
+    for (i, result) in results.iter().enumerate() {
+        let tokens = count_tokens(&result.text)?;
+        if used + tokens > budget {
+            kept = i;
+            break;
+        }
+        used += tokens;
+    }
+    results.truncate(kept);
+    Ok(used)
+}
+
+/// Execute a search query and return results (shared implementation)
+pub async fn execute_search(
+    context: &mut AppContext,
+    request: SearchRequest,
+) -> Result<SearchResponse> {
+    let (response, _) = execute_search_timed(context, request).await?;
+    Ok(response)
+}
+
+/// Execute a search query, also reporting how long each stage took
+pub async fn execute_search_timed(
+    context: &mut AppContext,
+    request: SearchRequest,
+) -> Result<(SearchResponse, SearchTimings)> {
+    debug!("Executing search: {}", request.query);
+
+    // Step 1: Generate embeddings for the queries (and their expansions, if enabled)
+    let hyde = hyde_config(context);
+    let prefixes = &context.config_manager.config().embedding;
+    let (embeddings, timings) = embed_queries(
+        &context.embedder,
+        hyde.as_ref(),
+        prefixes,
+        &request.all_queries(),
+    )
+    .await?;
+
+    let embeddings: QueryEmbeddings = embeddings.into_iter().flatten().collect();
+    search_embedded(context, request, &embeddings, timings)
+}
+
+/// Execute several queries against one shared project, embedding all of them in a single
+/// batch
+///
+/// Like [`handle_search`], the context is only locked once the queries are embedded, and
+/// then only for the index lookups before and after reranking: the queries' candidates are
+/// reranked without it, one query after another, since the reranker runs one model call at
+/// a time. Responses are returned in request order and each query succeeds or fails on
+/// its own.
+async fn execute_search_batch(
+    context: &Mutex<AppContext>,
+    requests: Vec<SearchRequest>,
+) -> Result<Vec<Result<SearchResponse>>> {
+    debug!("Executing batch of {} searches", requests.len());
+
+    let queries: Vec<&str> = requests
+        .iter()
+        .flat_map(|request| request.all_queries())
+        .collect();
+    let (embedder, hyde, prefixes) = query_models(context).await;
+    let (embeddings, timings) =
+        embed_queries(&embedder, hyde.as_ref(), &prefixes, &queries).await?;
+
+    let (gathered, reranker) = {
+        let context = context.lock().await;
+        let mut embeddings = embeddings.into_iter();
+        let gathered: Vec<Result<Gathered>> = requests
+            .into_iter()
+            .map(|request| {
+                let query_embeddings: QueryEmbeddings = embeddings
+                    .by_ref()
+                    .take(request.all_queries().len())
+                    .flatten()
+                    .collect();
+                gather_candidates(&context, request, &query_embeddings, timings)
+            })
+            .collect();
+        (gathered, Arc::clone(&context.reranker))
+    };
+
+    /// A query of the batch after reranking
+    enum Reranked {
+        Ranked(Box<Candidates>, Ranking),
+        Done(Box<SearchResponse>),
+    }
+    let reranked: Vec<Result<Reranked>> = tokio::task::spawn_blocking(move || {
+        gathered
+            .into_iter()
+            .map(|gathered| match gathered? {
+                Gathered::Candidates(candidates) => {
+                    let ranking = rerank_candidates(&reranker, &candidates)?;
+                    Ok(Reranked::Ranked(candidates, ranking))
+                }
+                Gathered::Done(response, _) => Ok(Reranked::Done(response)),
+            })
+            .collect()
+    })
+    .await?;
+
+    let context = context.lock().await;
+    Ok(reranked
+        .into_iter()
+        .map(|reranked| match reranked? {
+            Reranked::Ranked(candidates, ranking) => {
+                finish_search(&context, *candidates, ranking).map(|(response, _)| response)
+            }
+            Reranked::Done(response) => Ok(*response),
+        })
+        .collect())
+}
+
+/// The LLM that expands queries, if `search.hyde` is enabled
+fn hyde_config(context: &AppContext) -> Option<LlmConfig> {
+    let config = context.config_manager.config();
+    config.search.hyde.then(|| config.llm.clone())
+}
+
+/// What embedding a query needs from a shared context (the embedder, the HyDE LLM and the
+/// embedding prefixes), taken without holding its lock while embedding
+async fn query_models(
+    context: &Mutex<AppContext>,
+) -> (Arc<Embedder>, Option<LlmConfig>, EmbeddingConfig) {
+    let context = context.lock().await;
+    (
+        Arc::clone(&context.embedder),
+        hyde_config(&context),
+        context.config_manager.config().embedding.clone(),
+    )
+}
+
+/// Texts searched for a query: the query itself, plus a hypothetical code snippet written
+/// by the `hyde` LLM if one is given
+///
+/// A failed expansion only costs recall, so the query is then searched alone.
+async fn query_texts(hyde: Option<&LlmConfig>, query: &str) -> Vec<String> {
+    let mut texts = vec![query.to_string()];
+    if let Some(llm_config) = hyde {
+        match llm::hypothetical_code(llm_config, query).await {
+            Ok(snippet) if !snippet.is_empty() => {
+                debug!("Expanded query to:\n{}", snippet);
+                texts.push(snippet);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Query expansion failed, searching the query alone: {:#}", e),
+        }
+    }
+    texts
+}
+
+/// Expand and embed `queries`, computing every embedding in one batch
+///
+/// Queries are embedded after `embedding.query_prefix`, and the code snippets they are
+/// expanded to after `embedding.document_prefix`, like the chunks they should be near.
+/// Returns each query's embeddings and the time both stages took.
+async fn embed_queries(
+    embedder: &Embedder,
+    hyde: Option<&LlmConfig>,
+    prefixes: &EmbeddingConfig,
+    queries: &[&str],
+) -> Result<(Vec<QueryEmbeddings>, SearchTimings)> {
+    let start = Instant::now();
+    let mut texts = Vec::with_capacity(queries.len());
+    for query in queries {
+        texts.push(query_texts(hyde, query).await);
+    }
+    let expand = start.elapsed();
+
+    let all_texts: Vec<String> = texts
+        .iter()
+        .flat_map(|texts| {
+            texts.iter().enumerate().map(|(i, text)| match i {
+                0 => embedder::with_prefix(&prefixes.query_prefix, text),
+                _ => embedder::with_prefix(&prefixes.document_prefix, text),
+            })
+        })
+        .collect();
+    let all_texts: Vec<&str> = all_texts.iter().map(String::as_str).collect();
+    let mut embeddings = embedder
+        .embed_queries(&all_texts, "")
+        .await
+        .map_err(|e| CodedError::wrap(ErrorCode::ModelError, e))?
+        .into_iter();
+    let grouped = texts
+        .iter()
+        .map(|texts| {
+            embeddings
+                .by_ref()
+                .take(texts.len())
+                .map(|Embedding(embedding)| embedding)
+                .collect()
+        })
+        .collect();
+
+    let timings = SearchTimings {
+        expand,
+        embed: start.elapsed() - expand,
+        ..SearchTimings::default()
+    };
+    Ok((grouped, timings))
+}
+
+/// Merge the candidates found for each of a query's embeddings, keeping every chunk once at
+/// its smallest distance, closest first
+fn fuse_candidates(mut candidate_lists: Vec<Vec<ChunkMatch>>) -> Vec<ChunkMatch> {
+    if candidate_lists.len() == 1 {
+        return candidate_lists.remove(0);
+    }
+
+    let mut fused: Vec<ChunkMatch> = Vec::new();
+    for candidate in candidate_lists.into_iter().flatten() {
+        match fused.iter_mut().find(|chunk| chunk.id == candidate.id) {
+            Some(existing) => existing.distance = existing.distance.min(candidate.distance),
+            None => fused.push(candidate),
+        }
+    }
+    fused.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    fused
+}
+
+/// Combine the rankings of the same candidates for several queries into one: `Any` scores
+/// each candidate by its best query, `All` by its worst, so only candidates matching every
+/// query rank high
+fn combine_rankings(
+    mut rankings: Vec<Vec<(usize, f32)>>,
+    combine: QueryCombination,
+) -> Vec<(usize, f32)> {
+    if rankings.len() == 1 {
+        return rankings.remove(0);
+    }
+
+    let mut scores: HashMap<usize, f32> = HashMap::new();
+    for (index, score) in rankings.into_iter().flatten() {
+        scores
+            .entry(index)
+            .and_modify(|combined| {
+                *combined = match combine {
+                    QueryCombination::Any => combined.max(score),
+                    QueryCombination::All => combined.min(score),
+                }
+            })
+            .or_insert(score);
+    }
+    let mut combined: Vec<(usize, f32)> = scores.into_iter().collect();
+    combined.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    combined
+}
+
+/// The document the reranker scores for `chunk`, from the `search.rerank_template`
+///
+/// Chunks without a name (file summaries, plain text) stand for their kind instead.
+fn rerank_document(template: &str, chunk: &ChunkMatch) -> String {
+    let symbol = chunk.node_name.as_deref().unwrap_or(&chunk.node_type);
+    fill_rerank_template(
+        template,
+        &chunk.file_path,
+        symbol,
+        &chunk.node_type,
+        &chunk.text,
+    )
+}
+
+/// Fill in the placeholders of a `search.rerank_template`
+fn fill_rerank_template(
+    template: &str,
+    file_path: &str,
+    symbol: &str,
+    kind: &str,
+    text: &str,
+) -> String {
+    // The text goes in last so placeholders that appear in code are left alone
+    template
+        .replace("{file_path}", file_path)
+        .replace("{symbol}", symbol)
+        .replace("{kind}", kind)
+        .replace("{text}", text)
+}
+
+/// Score `results` (of several projects) anew in one rerank against `request`'s queries,
+/// so they are ranked by the same model whatever searched each project
+///
+/// The score adjustments a project's config made (path weights, boosts) are dropped; they
+/// don't compare across projects.
+pub fn rerank_results(
+    reranker: &Reranker,
+    template: &str,
+    request: &SearchRequest,
+    results: &mut [&mut SearchResult],
+) -> Result<()> {
+    let documents: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let symbol = result.name.as_deref().unwrap_or(&result.kind);
+            fill_rerank_template(
+                template,
+                &result.file_path,
+                symbol,
+                &result.kind,
+                &result.text,
+            )
+        })
+        .collect();
+    let mut rankings = Vec::with_capacity(1 + request.queries.len());
+    for query in request.all_queries() {
+        rankings.push(reranker.rerank(query, &documents)?);
+    }
+    for (index, score) in combine_rankings(rankings, request.combine) {
+        results[index].score = score;
+        results[index].explanation = None;
+    }
+    Ok(())
+}
+
+/// The chunks `request` is restricted to
+fn chunk_filter(request: &SearchRequest) -> ChunkFilter {
+    // Accept extensions as well as language names ("rs" and "rust" are the same filter)
+    ChunkFilter {
+        languages: request
+            .languages
+            .iter()
+            .map(|language| chunker::language_for_extension(&language.trim().to_lowercase()))
+            .collect(),
+        categories: request.categories.clone(),
+        exclude_categories: request.exclude_categories.clone(),
+        kinds: request.kinds.clone(),
+        paths: request
+            .paths
+            .iter()
+            .map(|path| {
+                let path = path.trim().trim_end_matches('/');
+                path.strip_prefix("./").unwrap_or(path).to_string()
+            })
+            .collect(),
+        comments: request.comments,
+        author: request.author.clone(),
+        since: request.since,
+        changed_since: request.changed_since,
+        files: Vec::new(),
+        unsummarized_files: false,
+    }
+}
+
+/// Search the chunks of `db` for the queries of `request` as literal, case-sensitive
+/// text, without any model: results contain any of them (all with `QueryCombination::All`)
+/// and come in file and line order
+pub fn fixed_string_search(db: &Database, request: &SearchRequest) -> Result<SearchResponse> {
+    let start = Instant::now();
+    let filter = chunk_filter(request);
+    let (chunks, _) = fetch_unignored(db, &IgnoreCache::new(db.root()), request.top_n, |limit| {
+        db.find_chunks_containing(
+            &request.all_queries(),
+            request.combine == QueryCombination::All,
+            limit,
+            &filter,
+        )
+    })?;
+
+    let mut results: Vec<SearchResult> = chunks
+        .into_iter()
+        .map(|chunk| SearchResult {
+            id: Some(db::chunk_handle(&chunk.file_path, chunk.hash)),
+            file_path: chunk.file_path,
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            text: if request.files_only {
+                String::new()
+            } else {
+                chunk.text
+            },
+            score: 1.0,
+            kind: chunk.node_type,
+            name: chunk.node_name,
+            modified_at: None,
+            explanation: None,
+        })
+        .collect();
+    set_modified_times(db, &mut results)?;
+
+    let timings = SearchTimings {
+        total: start.elapsed(),
+        ..SearchTimings::default()
+    };
+    Ok(SearchResponse {
+        stats: SearchStats::new(&timings, results.len(), results.len()),
+        results,
+        explanation: None,
+    })
+}
+
+/// Files (paths as stored) whose summary chunks are among the `limit` closest to any of
+/// `query_embeddings`, among the files `filter`'s languages and categories allow
+fn candidate_files(
+    db: &Database,
+    query_embeddings: &[Vec<f32>],
+    filter: &ChunkFilter,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let summaries = ChunkFilter {
+        languages: filter.languages.clone(),
+        categories: filter.categories.clone(),
+        exclude_categories: filter.exclude_categories.clone(),
+        kinds: vec![chunker::FILE_SUMMARY.to_string()],
+        ..ChunkFilter::default()
+    };
+    let mut files = Vec::new();
+    for query_embedding in query_embeddings {
+        for summary in db.find_similar_chunks(query_embedding, limit, &summaries)? {
+            if !files.contains(&summary.file_path) {
+                files.push(summary.file_path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The first `limit` chunks `fetch` returns (given how many to return) of files that
+/// ignore files don't exclude by now, and the excluded chunks it returned before them
+///
+/// Patterns added after indexing take effect once the watcher reports the ignore file
+/// change (see [`IgnoreCache`]), before its files are removed from the index. Excluded
+/// chunks don't count toward `limit`: `fetch` is asked for more until `limit` remain or it
+/// runs out.
+fn fetch_unignored(
+    db: &Database,
+    ignored_files: &IgnoreCache,
+    limit: usize,
+    mut fetch: impl FnMut(usize) -> Result<Vec<ChunkMatch>>,
+) -> Result<(Vec<ChunkMatch>, Vec<ChunkMatch>)> {
+    let mut fetched = limit;
+    loop {
+        let chunks = fetch(fetched)?;
+        let exhausted = chunks.len() < fetched;
+        let (mut kept, ignored): (Vec<_>, Vec<_>) = chunks
+            .into_iter()
+            .partition(|chunk| !ignored_files.is_ignored(&db.resolve_path(&chunk.file_path)));
+        if kept.len() >= limit || exhausted {
+            for chunk in &ignored {
+                debug!(
+                    "Filtering out ignored file from results: {}",
+                    chunk.file_path
+                );
+            }
+            kept.truncate(limit);
+            return Ok((kept, ignored));
+        }
+        fetched *= 2;
+    }
+}
+
+/// Steps 2-6 of a search, for a query already embedded as `query_embeddings`
+fn search_embedded(
+    context: &mut AppContext,
+    request: SearchRequest,
+    query_embeddings: &[Vec<f32>],
+    timings: SearchTimings,
+) -> Result<(SearchResponse, SearchTimings)> {
+    match gather_candidates(context, request, query_embeddings, timings)? {
+        Gathered::Candidates(candidates) => {
+            let ranking = rerank_candidates(&context.reranker, &candidates)?;
+            finish_search(context, *candidates, ranking)
+        }
+        Gathered::Done(response, timings) => Ok((*response, timings)),
+    }
+}
+
+/// What the index lookup of a search found
+enum Gathered {
+    /// Candidates for the reranker to order
+    Candidates(Box<Candidates>),
+    /// Nothing to rerank; the response is complete
+    Done(Box<SearchResponse>, SearchTimings),
+}
+
+/// A search's candidates, with the documents the reranker scores them by
+struct Candidates {
+    request: SearchRequest,
+    chunks: Vec<ChunkMatch>,
+    documents: Vec<String>,
+    explanation: Option<SearchExplanation>,
+    num_candidate_files: Option<usize>,
+    timings: SearchTimings,
+    start: Instant,
+}
+
+/// Order of a search's candidates after reranking
+struct Ranking {
+    /// Candidate indices and scores, best first
+    indices: Vec<(usize, f32)>,
+    /// Queries the candidates were scored against
+    num_rankings: usize,
+    elapsed: Duration,
+}
+
+/// Steps 2 and 3 of a search: find the candidates nearest to the query embeddings in the
+/// index, ready to be reranked
+fn gather_candidates(
+    context: &AppContext,
+    request: SearchRequest,
+    query_embeddings: &[Vec<f32>],
+    mut timings: SearchTimings,
+) -> Result<Gathered> {
+    let start = Instant::now();
+    let mut filter = chunk_filter(&request);
+    let mut explanation = request.explain.then(|| SearchExplanation {
+        steps: describe_filter(&filter),
+        cut: Vec::new(),
+    });
+
+    // Step 2a: In a two-stage search of a large index, only search the files whose
+    // summaries match best, and the files that have no summary to match. Without summaries
+    // to pick from, all files are searched.
+    let mut num_candidate_files = None;
+    let search_config = &context.config_manager.config().search;
+    if search_config.two_stage && context.db.count_chunks()? >= search_config.two_stage_min_chunks {
+        let files = candidate_files(
+            &context.db,
+            query_embeddings,
+            &filter,
+            search_config.two_stage_files,
+        )?;
+        timings.file_search = start.elapsed();
+        if let Some(explanation) = &mut explanation {
+            explanation.steps.push(if files.is_empty() {
+                "Two-stage search found no file summaries, so every file was searched".to_string()
+            } else {
+                format!(
+                    "Two-stage search: only the {} files whose summaries are closest, and \
+                     files without summaries, were searched",
+                    files.len()
+                )
+            });
+        }
+        if !files.is_empty() {
+            num_candidate_files = Some(files.len());
+            filter.files = files;
+            filter.unsummarized_files = true;
+        }
+    }
+
+    // Step 2b: Search the ANN index if the server built one, otherwise scan the database.
+    // Language and category filters go to the database so they apply before the cut.
+    // With query expansion, the candidates of the query and of its expansion are merged.
+    // More candidates than results are fetched, for the reranker to pick the best of, and
+    // chunks of files ignored by now don't take their place.
+    let rerank_depth = request
+        .rerank_depth
+        .unwrap_or(context.config_manager.config().search.rerank_depth)
+        .max(request.top_n);
+    let vector_search_start = Instant::now();
+    let mut candidate_lists = Vec::with_capacity(query_embeddings.len());
+    let mut ignored: Vec<ChunkMatch> = Vec::new();
+    for query_embedding in query_embeddings {
+        let (candidates, ignored_candidates) =
+            fetch_unignored(&context.db, &context.ignored_files, rerank_depth, |limit| {
+                match &context.ann {
+                    Some(ann) if filter.is_empty() => {
+                        let probes = context.config_manager.config().search.ann_probes;
+                        let hits = ann.search(query_embedding, limit, probes);
+                        context.db.get_chunks_by_ids(&hits)
+                    }
+                    _ => context
+                        .db
+                        .find_similar_chunks(query_embedding, limit, &filter),
+                }
+            })?;
+        candidate_lists.push(candidates);
+        for chunk in ignored_candidates {
+            if !ignored.iter().any(|seen| seen.id == chunk.id) {
+                ignored.push(chunk);
+            }
+        }
+    }
+    let mut initial_results = fuse_candidates(candidate_lists);
+    if let Some(explanation) = &mut explanation {
+        let method = match &context.ann {
+            Some(_) if filter.is_empty() => format!(
+                "the ANN index ({} probes)",
+                context.config_manager.config().search.ann_probes
+            ),
+            _ => "an exact vector scan".to_string(),
+        };
+        let queries = match query_embeddings.len() {
+            1 => "the query".to_string(),
+            n => format!("{} queries", n),
+        };
+        explanation.steps.push(format!(
+            "Found {} candidates nearest to {} with {} (rerank depth {})",
+            initial_results.len(),
+            queries,
+            method,
+            rerank_depth
+        ));
+        for chunk in &ignored {
+            explanation
+                .cut
+                .push(cut_chunk(chunk, None, "its file is ignored by now"));
+        }
+    }
+
+    // Searching comments, definitions stand for their doc comments, in reranking and results
+    if request.comments {
+        for chunk in &mut initial_results {
+            if matches!(chunk.node_type.as_str(), chunker::COMMENT | chunker::TODO) {
+                continue;
+            }
+            if let Some(details) = context.db.get_chunk_by_id(chunk.id)? {
+                chunk.text = details.comments.trim_end().to_string();
+            }
+        }
+    }
+
+    timings.vector_search = vector_search_start.elapsed();
+
+    if initial_results.is_empty() {
+        if context.db.count_chunks()? == 0 {
+            return Err(CodedError::wrap(
+                ErrorCode::IndexEmpty,
+                anyhow!("Index {} is empty", context.database_path().display()),
+            ));
+        }
+        timings.total = timings.expand + timings.embed + start.elapsed();
+        let response = SearchResponse {
+            results: vec![],
+            stats: SearchStats::new(&timings, 0, 0),
+            explanation,
+        };
+        return Ok(Gathered::Done(Box::new(response), timings));
+    }
+
+    // Step 3: Rerank results, on documents that also name the chunk's file and symbol
+    let template = &context.config_manager.config().search.rerank_template;
+    let documents: Vec<String> = initial_results
+        .iter()
+        .map(|chunk| rerank_document(template, chunk))
+        .collect();
+
+    Ok(Gathered::Candidates(Box::new(Candidates {
+        request,
+        chunks: initial_results,
+        documents,
+        explanation,
+        num_candidate_files,
+        timings,
+        start,
+    })))
+}
+
+/// Score `candidates` with the reranker; needs no project context, so it can run without
+/// holding its lock
+fn rerank_candidates(reranker: &Reranker, candidates: &Candidates) -> Result<Ranking> {
+    // With several queries, every candidate is scored against each of them
+    let start = Instant::now();
+    let request = &candidates.request;
+    let mut rankings = Vec::with_capacity(1 + request.queries.len());
+    for query in request.all_queries() {
+        rankings.push(
+            reranker
+                .rerank(query, &candidates.documents)
+                .map_err(|e| CodedError::wrap(ErrorCode::ModelError, e))?,
+        );
+    }
+    Ok(Ranking {
+        num_rankings: rankings.len(),
+        indices: combine_rankings(rankings, request.combine),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Steps 4 to 6 of a search: turn the reranked candidates into the response
+fn finish_search(
+    context: &AppContext,
+    candidates: Candidates,
+    ranking: Ranking,
+) -> Result<(SearchResponse, SearchTimings)> {
+    let Candidates {
+        request,
+        chunks: initial_results,
+        mut explanation,
+        num_candidate_files,
+        mut timings,
+        start,
+        ..
+    } = candidates;
+    let Ranking {
+        indices: reranked_indices,
+        num_rankings,
+        elapsed,
+    } = ranking;
+    timings.rerank = elapsed;
+    if let Some(explanation) = &mut explanation {
+        explanation.steps.push(match num_rankings {
+            1 => format!("Reranked {} candidates", initial_results.len()),
+            n => format!(
+                "Reranked {} candidates against {} queries, scored by the {} match",
+                initial_results.len(),
+                n,
+                match request.combine {
+                    QueryCombination::Any => "best",
+                    QueryCombination::All => "worst",
+                }
+            ),
+        });
+    }
+
+    // Step 4: Convert to SearchResult format and filter out non-existent files
+    let mut results: Vec<SearchResult> = reranked_indices
+        .iter()
+        .filter_map(|(idx, score)| {
+            let chunk = &initial_results[*idx];
+
+            // Filter out files that no longer exist
+            if !context.db.resolve_path(&chunk.file_path).exists() {
+                debug!(
+                    "Filtering out non-existent file from results: {}",
+                    chunk.file_path
+                );
+                if let Some(explanation) = &mut explanation {
+                    let cut = cut_chunk(chunk, Some(*score), "its file no longer exists");
+                    explanation.cut.push(cut);
+                }
+                return None;
+            }
+
+            Some(SearchResult {
+                id: Some(db::chunk_handle(&chunk.file_path, chunk.hash)),
+                file_path: chunk.file_path.clone(),
+                start_line: chunk.start_line,
+                end_line: chunk.end_line,
+                text: if request.files_only {
+                    String::new()
+                } else {
+                    chunk.text.clone()
+                },
+                score: *score,
+                kind: chunk.node_type.clone(),
+                name: chunk.node_name.clone(),
+                modified_at: None,
+                explanation: request.explain.then(|| Explanation {
+                    distance: chunk.distance,
+                    rerank_score: *score,
+                    adjustments: Vec::new(),
+                }),
+            })
+        })
+        .collect();
+
+    // Step 5: Apply the configured path weights and favor recently modified files and the
+    // query's language, then keep the requested number of the reranked candidates
+    adjust_scores(&mut results, "path weight", |results| {
+        apply_path_weights(context, results)
+    })?;
+    adjust_scores(&mut results, "recency boost", |results| {
+        apply_recency_boost(context, results)
+    })?;
+    adjust_scores(&mut results, "language boost", |results| {
+        apply_language_boost(context, &request, results);
+        Ok(())
+    })?;
+    let below_top = results.split_off(request.top_n.min(results.len()));
+    record_matches(context, &results);
+
+    // Step 6: Keep the best results that fit in the token budget, counted with the embedding
+    // model's tokenizer
+    let num_tokens = match request.max_tokens {
+        Some(budget) => {
+            let ranked = explanation.as_ref().map(|_| results.clone());
+            let num_tokens = truncate_to_token_budget(&mut results, budget, |text| {
+                context.embedder.count_tokens(text)
+            })?;
+            // The budget drops the lowest ranked results, so the cut ones are the tail
+            if let (Some(explanation), Some(ranked)) = (&mut explanation, ranked) {
+                let reason = format!("over the budget of {} tokens", budget);
+                let over_budget = &ranked[results.len()..];
+                explanation
+                    .cut
+                    .extend(over_budget.iter().map(|result| cut_result(result, &reason)));
+            }
+            Some(num_tokens)
+        }
+        None => None,
+    };
+    if let Some(explanation) = &mut explanation {
+        let reason = format!("ranked below the top {}", request.top_n);
+        explanation
+            .cut
+            .extend(below_top.iter().map(|result| cut_result(result, &reason)));
+    }
+
+    set_modified_times(&context.db, &mut results)?;
+
+    timings.total = timings.expand + timings.embed + start.elapsed();
+    let num_results = results.len();
+
+    let response = SearchResponse {
+        results,
+        stats: SearchStats {
+            num_tokens,
+            num_candidate_files,
+            ..SearchStats::new(&timings, initial_results.len(), num_results)
+        },
+        explanation,
+    };
+    Ok((response, timings))
+}
+
+/// Execute a search query against a shared project context
+///
+/// The query is expanded and embedded before the context is locked, so it doesn't wait
+/// behind a reindex for that; the lock is held for the vector search and reranking only.
+async fn handle_search(
+    context: Arc<Mutex<AppContext>>,
+    request: SearchRequest,
+) -> Result<SearchResponse> {
+    debug!("Executing search: {}", request.query);
+
+    let (embedder, hyde, prefixes) = query_models(&context).await;
+    let (embeddings, timings) =
+        embed_queries(&embedder, hyde.as_ref(), &prefixes, &request.all_queries()).await?;
+
+    let embeddings: QueryEmbeddings = embeddings.into_iter().flatten().collect();
+    let mut context = context.lock().await;
+    let (response, _) = search_embedded(&mut context, request, &embeddings, timings)?;
+    Ok(response)
+}
+
+/// A query of a batch request with its position in the batch
+type BatchQuery = (usize, SearchRequest);
+
+/// Answer a batch of queries, embedding the queries of each project together
+async fn handle_batch(registry: &ProjectRegistry, queries: Vec<SearchRequest>) -> Vec<BatchResult> {
+    let mut results: Vec<Option<BatchResult>> = queries.iter().map(|_| None).collect();
+    let failed = |sub_id: usize, e: &anyhow::Error| BatchResult {
+        sub_id,
+        response: None,
+        error: Some(format!("Search failed: {}", e)),
+        code: Some(error_code(e)),
+    };
+
+    // Queries may name different projects or profiles; group them by the context they use
+    let mut groups: Vec<(Arc<Mutex<AppContext>>, Vec<BatchQuery>)> = Vec::new();
+    for (sub_id, request) in queries.into_iter().enumerate() {
+        let context = registry
+            .get(request.project_root.as_deref(), request.profile.as_deref())
+            .await;
+        match context {
+            Ok(context) => match groups.iter_mut().find(|(c, _)| Arc::ptr_eq(c, &context)) {
+                Some((_, requests)) => requests.push((sub_id, request)),
+                None => groups.push((context, vec![(sub_id, request)])),
+            },
+            Err(e) => results[sub_id] = Some(failed(sub_id, &e)),
+        }
+    }
+
+    for (context, requests) in groups {
+        let (sub_ids, requests): (Vec<usize>, Vec<SearchRequest>) = requests.into_iter().unzip();
+        match execute_search_batch(&context, requests).await {
+            Ok(responses) => {
+                for (sub_id, response) in sub_ids.into_iter().zip(responses) {
+                    results[sub_id] = Some(match response {
+                        Ok(response) => BatchResult {
+                            sub_id,
+                            response: Some(response),
+                            error: None,
+                            code: None,
+                        },
+                        Err(e) => failed(sub_id, &e),
+                    });
+                }
+            }
+            Err(e) => {
+                for sub_id in sub_ids {
+                    results[sub_id] = Some(failed(sub_id, &e));
+                }
+            }
+        }
+    }
+
+    results.into_iter().flatten().collect()
+}
+
+/// Accept the next connection on `listener`; never completes without a listener
+async fn accept(listener: Option<&UnixListener>) -> std::io::Result<(UnixStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for `task` to finish; never completes without a task
+async fn wait_for<T>(task: Option<&mut JoinHandle<T>>) -> Result<T> {
+    match task {
+        Some(task) => Ok(task.await?),
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the next indexing event; never completes without a subscription
+async fn next_event(events: Option<&mut broadcast::Receiver<IndexEvent>>) -> Option<IndexEvent> {
+    let Some(events) = events else {
+        return std::future::pending().await;
+    };
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Subscriber fell behind, dropped {} indexing events",
+                    skipped
+                )
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Write one newline-terminated message to the client
+async fn send(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> Result<()> {
+    writer
+        .write_all(serde_json::to_string(message)?.as_bytes())
+        .await?;
+    writer.write_all(b"\n").await?;
+    // Stdout is buffered, unlike sockets
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Tell the client the models are being reloaded for request `id`
+async fn send_warming_up(writer: &mut (impl AsyncWrite + Unpin), id: u64) -> Result<()> {
+    let status = Message::Status {
+        id,
+        status: ServerStatus::WarmingUp,
+    };
+    send(writer, &status).await
+}
+
+/// Hold request `id` until the models are warmed up, telling clients that understand
+/// statuses why it waits (also when models unloaded while idle have to be reloaded for it)
+async fn wait_until_ready(
+    registry: &ProjectRegistry,
+    writer: &mut (impl AsyncWrite + Unpin),
+    id: u64,
+    wants_status: bool,
+) -> Result<()> {
+    let mut ready = registry.ready.subscribe();
+    let warming_up = !*ready.borrow() || !registry.models.is_loaded();
+    if warming_up && wants_status {
+        send_warming_up(writer, id).await?;
+    }
+    ready.wait_for(|ready| *ready).await?;
+    Ok(())
+}
+
+/// Token bucket limiting the queries of one connection
+struct RateLimiter {
+    /// Queries allowed in a burst, and per minute
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(per_minute.max(1));
+        Self {
+            capacity,
+            tokens: capacity,
+            refilled: now,
+        }
+    }
+
+    /// Take `queries` tokens if there are enough; a batch larger than the whole bucket
+    /// needs a full one
+    fn try_take(&mut self, queries: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.refilled = now;
+
+        let cost = (queries as f64).min(self.capacity);
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+/// Refusal of request `id` that tells the client to back off and retry
+fn busy(id: u64, message: &str) -> Message {
+    Message::Error {
+        id,
+        message: format!("Server busy: {}; try again shortly", message),
+        code: Some(ErrorCode::Busy),
+    }
+}
+
+/// Check the connection's rate limit and take a search permit for `queries` queries, or
+/// say why the server refuses them
+///
+/// With all permits taken, the query queues for one for up to [`SEARCH_QUEUE_TIMEOUT`], so
+/// a short burst of interactive queries waits its turn instead of being refused.
+async fn admit(
+    registry: &ProjectRegistry,
+    limiter: &mut Option<RateLimiter>,
+    queries: usize,
+) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, String> {
+    if let Some(limiter) = limiter {
+        if !limiter.try_take(queries, Instant::now()) {
+            warn!("Refusing a query: connection exceeded its rate limit");
+            return Err(format!(
+                "more than {} queries per minute on this connection",
+                registry.max_queries_per_minute.unwrap_or_default()
+            ));
+        }
+    }
+
+    let Some(searches) = &registry.searches else {
+        return Ok(None);
+    };
+    let permit = Arc::clone(searches).acquire_owned();
+    match tokio::time::timeout(SEARCH_QUEUE_TIMEOUT, permit).await {
+        Ok(Ok(permit)) => Ok(Some(permit)),
+        _ => {
+            warn!("Refusing a query: too many searches running");
+            Err("too many searches are running".to_string())
+        }
+    }
+}
+
+/// Handle a single client connection
+async fn handle_connection(
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    registry: ProjectRegistry,
+) -> Result<()> {
+    debug!("New connection");
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut events = None;
+    // Clients that don't say Hello predate it and handle every message of version 1
+    let mut client_capabilities: Option<Vec<String>> = None;
+    let mut protocol_version = 1;
+    let mut limiter = registry
+        .max_queries_per_minute
+        .map(|per_minute| RateLimiter::new(per_minute, Instant::now()));
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            event = next_event(events.as_mut()) => {
+                match event {
+                    Some(event) => send(&mut writer, &Message::Event { event }).await?,
+                    None => events = None,
+                }
+                continue;
+            }
+        };
+
+        // Parse the message
+        let message: Message = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                // Most likely a client of another ragrep version
+                warn!("Failed to parse message: {}", e);
+                let error = Message::Error {
+                    id: 0,
+                    message: format!(
+                        "Failed to parse message (server speaks protocol version {}): {}",
+                        PROTOCOL_VERSION, e
+                    ),
+                    code: Some(ErrorCode::BadRequest),
+                };
+                send(&mut writer, &error).await?;
+                continue;
+            }
+        };
+
+        debug!("Received message: {:?}", message);
+
+        let wants_status = client_capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.iter().any(|c| c == "status"));
+        let response = match message {
+            Message::Hello {
+                version,
+                capabilities,
+            } => {
+                let Some(negotiated) = protocol::negotiate(version) else {
+                    let error = Message::Error {
+                        id: 0,
+                        message: format!(
+                            "Client speaks protocol version {}, but this server ({}) needs \
+                             version {} to {}; upgrade the client",
+                            version,
+                            env!("CARGO_PKG_VERSION"),
+                            MIN_PROTOCOL_VERSION,
+                            PROTOCOL_VERSION
+                        ),
+                        code: Some(ErrorCode::BadRequest),
+                    };
+                    send(&mut writer, &error).await?;
+                    break;
+                };
+                debug!(
+                    "Client speaks protocol version {} ({}), using {}",
+                    version,
+                    capabilities.join(", "),
+                    negotiated
+                );
+                client_capabilities = Some(capabilities);
+                protocol_version = negotiated;
+                Message::Hello {
+                    version: negotiated,
+                    capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                }
+            }
+            Message::Request { id, request } => {
+                // Queries waiting for the warm-up hold no search permit and don't pause
+                // reindexing yet
+                registry.touch();
+                wait_until_ready(&registry, &mut writer, id, wants_status).await?;
+                let _permit = match admit(&registry, &mut limiter, 1).await {
+                    Ok(permit) => permit,
+                    Err(reason) => {
+                        send(&mut writer, &busy(id, &reason)).await?;
+                        continue;
+                    }
+                };
+                // Background reindexing pauses until the query is answered
+                let _priority = registry.models.embedder.prioritize_query();
+
+                let start = Instant::now();
+                let search = async {
+                    let context = registry
+                        .get(request.project_root.as_deref(), request.profile.as_deref())
+                        .await?;
+                    handle_search(context, request).await
+                };
+                let result = search.await;
+                registry.touch();
+                registry
+                    .metrics
+                    .lock()
+                    .unwrap()
+                    .record_query(start.elapsed(), result.is_ok());
+                match result {
+                    Ok(search_response) => Message::Response {
+                        id,
+                        response: search_response,
+                    },
+                    Err(e) => Message::Error {
+                        id,
+                        message: format!("Search failed: {}", e),
+                        code: Some(error_code(&e)),
+                    },
+                }
+            }
+            Message::BatchRequest { id, queries } => {
+                registry.touch();
+                wait_until_ready(&registry, &mut writer, id, wants_status).await?;
+                let _permit = match admit(&registry, &mut limiter, queries.len()).await {
+                    Ok(permit) => permit,
+                    Err(reason) => {
+                        send(&mut writer, &busy(id, &reason)).await?;
+                        continue;
+                    }
+                };
+                let _priority = registry.models.embedder.prioritize_query();
+
+                let responses = handle_batch(&registry, queries).await;
+                registry.touch();
+                {
+                    let mut metrics = registry.metrics.lock().unwrap();
+                    for result in &responses {
+                        let latency = result
+                            .response
+                            .as_ref()
+                            .map_or(0, |response| response.stats.total_time_ms);
+                        metrics.record_query(
+                            Duration::from_millis(latency),
+                            result.response.is_some(),
+                        );
+                    }
+                }
+                Message::BatchResponse { id, responses }
+            }
+            Message::Subscribe { id } => {
+                debug!("Client subscribed to indexing events ({})", id);
+                events = Some(registry.events.subscribe());
+                continue;
+            }
+            Message::MetricsRequest { id } => Message::MetricsResponse {
+                id,
+                metrics: registry.metrics.lock().unwrap().to_prometheus(),
+            },
+            _ => {
+                warn!("Unexpected message type");
+                continue;
+            }
+        };
+
+        // Send response
+        send(&mut writer, &response.for_version(protocol_version)).await?;
+    }
+
+    debug!("Connection closed");
+    Ok(())
+}
+
+/// An error tagged with the code clients receive it under; displays as the error it wraps
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    error: anyhow::Error,
+}
+
+impl CodedError {
+    fn wrap(code: ErrorCode, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Self { code, error })
+    }
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for CodedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Code of a failed request: the one it was tagged with, `Busy` for a locked database,
+/// `Internal` for anything unexpected
+fn error_code(error: &anyhow::Error) -> ErrorCode {
+    match error.chain().find_map(|e| e.downcast_ref::<CodedError>()) {
+        Some(coded) => coded.code,
+        None if db::is_busy(error) => ErrorCode::Busy,
+        None => ErrorCode::Internal,
+    }
+}
+
+/// Set the permission bits of `path`
+///
+/// Failures (e.g. on a bind mount owned by another user) are only logged, as the server
+/// works regardless.
+fn set_mode(path: &Path, mode: u32) {
+    if let Err(e) = std::fs::set_permissions(path, Permissions::from_mode(mode)) {
+        warn!(
+            "Failed to set permissions of {} to {:o}: {}",
+            path.display(),
+            mode,
+            e
+        );
+    }
+}
+
+/// Reject clients running as another user, unless `server.allow_group` is set and they
+/// run with the socket's `group`
+fn authorize_peer(stream: &UnixStream, group: Option<u32>) -> Result<()> {
+    let peer = stream
+        .peer_cred()
+        .context("Failed to read peer credentials")?;
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    check_peer(peer.uid(), peer.gid(), uid, group)
+}
+
+/// Whether a client running as `peer_uid`/`peer_gid` may use a server running as `uid`
+fn check_peer(peer_uid: u32, peer_gid: u32, uid: u32, group: Option<u32>) -> Result<()> {
+    match group {
+        _ if peer_uid == uid => Ok(()),
+        Some(gid) if peer_gid == gid => Ok(()),
+        Some(gid) => Err(anyhow!(
+            "client runs as UID {} with GID {}, but only the socket's group (GID {}) may connect",
+            peer_uid,
+            peer_gid,
+            gid
+        )),
+        None => Err(anyhow!(
+            "client runs as UID {}, server as UID {} (set server.allow_group to allow)",
+            peer_uid,
+            uid
+        )),
+    }
+}
+
+/// Check if a process with the given PID is still running
+pub fn is_process_running(pid: u32) -> bool {
+    // Use `kill -0` which is portable across Unix systems (Linux, macOS, etc.)
+    // It sends signal 0 which doesn't kill the process, just checks if it exists
+    Command::new("kill")
         .args(&["-0", &pid.to_string()])
         .output()
         .map(|output| output.status.success())
         .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fixture};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_search_batch() {
+        let fixture = Fixture::indexed("batch").await;
+        let requests = vec![
+            testing::request("parse config values"),
+            testing::request("retry the request"),
+            SearchRequest {
+                paths: vec!["missing/".to_string()],
+                ..testing::request("retry")
+            },
+        ];
+
+        let batch = execute_search_batch(&fixture.context, requests.clone())
+            .await
+            .unwrap();
+        assert_eq!(batch.len(), requests.len());
+        // Each query gets the results it gets on its own, in request order
+        for (request, response) in requests.into_iter().zip(batch) {
+            let alone = fixture.search(request).await;
+            let results = response.unwrap().results;
+            assert_eq!(
+                results.iter().map(|r| &r.id).collect::<Vec<_>>(),
+                alone.iter().map(|r| &r.id).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    /// Registry of a server for the fixture's project, with its models warmed up
+    fn serve_fixture(fixture: Fixture, server_config: &ServerConfig) -> ProjectRegistry {
+        let root = fixture.root;
+        let context = fixture.context.into_inner();
+        let models = context.models();
+        let key = (root.clone(), context.profile.clone());
+        let server = RagrepServer::with_registry(
+            models,
+            server_config,
+            Some(root.clone()),
+            HashMap::from([(key, Arc::new(Mutex::new(context)))]),
+            false,
+            root.join("test.sock"),
+            root.join("test.pid"),
+        );
+        server.registry.ready.send_replace(true);
+        server.registry
+    }
+
+    type ClientLines = tokio::io::Lines<BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>>;
+
+    /// Connect a client to `registry` over an in-memory pipe
+    fn connect(
+        registry: &ProjectRegistry,
+    ) -> (ClientLines, tokio::io::WriteHalf<tokio::io::DuplexStream>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (reader, writer) = tokio::io::split(server);
+        tokio::spawn(handle_connection(reader, writer, registry.clone()));
+        let (reader, writer) = tokio::io::split(client);
+        (BufReader::new(reader).lines(), writer)
+    }
+
+    /// The server's next answer, skipping status updates
+    async fn answer(lines: &mut ClientLines) -> Message {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            match serde_json::from_str(&line).unwrap() {
+                Message::Status { .. } => continue,
+                message => return message,
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queries_queue_for_a_search_permit() {
+        let server_config = ServerConfig {
+            max_concurrent_searches: Some(1),
+            ..ServerConfig::default()
+        };
+        let registry = serve_fixture(Fixture::indexed("queue").await, &server_config);
+        let (mut lines, mut writer) = connect(&registry);
+        let searches = registry.searches.clone().unwrap();
+
+        // A query waits for the running search instead of being refused
+        let running = Arc::clone(&searches).acquire_owned().await.unwrap();
+        let request = Message::Request {
+            id: 1,
+            request: testing::request("retry the request"),
+        };
+        send(&mut writer, &request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        drop(running);
+        match answer(&mut lines).await {
+            Message::Response { id, response } => {
+                assert_eq!(id, 1);
+                assert!(!response.results.is_empty());
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+
+        // One that waits too long is refused as busy
+        let _running = Arc::clone(&searches).acquire_owned().await.unwrap();
+        let request = Message::Request {
+            id: 2,
+            request: testing::request("retry the request"),
+        };
+        send(&mut writer, &request).await.unwrap();
+        match answer(&mut lines).await {
+            Message::Error { id, code, .. } => {
+                assert_eq!(id, 2);
+                assert_eq!(code, Some(ErrorCode::Busy));
+            }
+            other => panic!("expected a busy error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queries_wait_for_warm_up() {
+        let server_config = ServerConfig {
+            max_concurrent_searches: Some(1),
+            ..ServerConfig::default()
+        };
+        let registry = serve_fixture(Fixture::indexed("warm-up").await, &server_config);
+        registry.ready.send_replace(false);
+        let (mut lines, mut writer) = connect(&registry);
+        let request = Message::Request {
+            id: 1,
+            request: testing::request("retry the request"),
+        };
+        send(&mut writer, &request).await.unwrap();
+
+        // The client hears why the query waits, which holds no search permit meanwhile
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_str(&line).unwrap(),
+            Message::Status {
+                id: 1,
+                status: ServerStatus::WarmingUp
+            }
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registry.searches.as_ref().unwrap().available_permits(), 1);
+
+        registry.ready.send_replace(true);
+        match answer(&mut lines).await {
+            Message::Response { id, response } => {
+                assert_eq!(id, 1);
+                assert!(!response.results.is_empty());
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_peer() {
+        // The server's own user is always allowed
+        assert!(check_peer(1000, 1000, 1000, None).is_ok());
+        assert!(check_peer(1000, 50, 1000, Some(100)).is_ok());
+        // Other users only with `allow_group`, and only in the socket's group
+        assert!(check_peer(1001, 100, 1000, None).is_err());
+        assert!(check_peer(1001, 100, 1000, Some(100)).is_ok());
+        assert!(check_peer(1001, 1001, 1000, Some(100)).is_err());
+    }
+
+    #[test]
+    fn test_error_code() {
+        let tagged = CodedError::wrap(ErrorCode::ModelError, anyhow!("model missing"));
+        assert_eq!(tagged.to_string(), "model missing");
+        assert_eq!(error_code(&tagged), ErrorCode::ModelError);
+        // Context added on top keeps the code
+        assert_eq!(
+            error_code(&tagged.context("Search failed")),
+            ErrorCode::ModelError
+        );
+
+        let busy = anyhow::Error::new(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        ));
+        assert_eq!(error_code(&busy), ErrorCode::Busy);
+        assert_eq!(error_code(&anyhow!("oops")), ErrorCode::Internal);
+    }
+
+    #[test]
+    fn test_recency_weight() {
+        assert_eq!(recency_weight(0, 30.0), 1.0);
+        assert!((recency_weight(30 * 86_400, 30.0) - 0.5).abs() < 1e-6);
+        assert!((recency_weight(60 * 86_400, 30.0) - 0.25).abs() < 1e-6);
+        // Clock skew can make files look modified in the future
+        assert_eq!(recency_weight(-100, 30.0), 1.0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(60, start);
+        assert!(limiter.try_take(50, start));
+        assert!(limiter.try_take(10, start));
+        assert!(!limiter.try_take(1, start));
+
+        // One query per second comes back
+        assert!(limiter.try_take(2, start + Duration::from_secs(2)));
+        assert!(!limiter.try_take(1, start + Duration::from_secs(2)));
+
+        // The bucket never holds more than a minute's worth, which a huge batch may use
+        let later = start + Duration::from_secs(3600);
+        assert!(limiter.try_take(500, later));
+        assert!(!limiter.try_take(1, later));
+    }
+
+    #[test]
+    fn test_detect_languages() {
+        assert_eq!(detect_languages("impl Display for Invoice"), ["rust"]);
+        assert_eq!(
+            detect_languages("def total(self): return self.sum"),
+            ["python"]
+        );
+        assert_eq!(detect_languages("def total(items)"), ["python", "ruby"]);
+        assert_eq!(
+            detect_languages("items.map(item => item.price)"),
+            ["javascript", "typescript"]
+        );
+        assert_eq!(
+            detect_languages("const total = (items: number) =>"),
+            ["typescript"]
+        );
+        assert!(detect_languages("where do we retry failed payments").is_empty());
+        // English that happens to contain markers isn't code
+        assert!(detect_languages("def not the except case, puts itself.").is_empty());
+        assert!(detect_languages("echo the const interface").is_empty());
+        assert!(detect_languages("retry_payment calls itself.").is_empty());
+    }
+
+    #[test]
+    fn test_fuse_candidates() {
+        let chunk = |id: i64, file_path: &str, distance: f32| ChunkMatch {
+            id,
+            text: String::new(),
+            file_path: file_path.to_string(),
+            start_line: 1,
+            end_line: 5,
+            node_type: "function".to_string(),
+            node_name: None,
+            hash: id,
+            distance,
+        };
+        let query = vec![chunk(1, "a.rs", 0.4), chunk(2, "b.rs", 0.6)];
+        let expansion = vec![chunk(3, "c.rs", 0.2), chunk(2, "b.rs", 0.3)];
+
+        let fused: Vec<(String, f32)> = fuse_candidates(vec![query, expansion])
+            .into_iter()
+            .map(|chunk| (chunk.file_path, chunk.distance))
+            .collect();
+        assert_eq!(
+            fused,
+            [
+                ("c.rs".to_string(), 0.2),
+                ("b.rs".to_string(), 0.3),
+                ("a.rs".to_string(), 0.4)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_combine_rankings() {
+        // Candidate 0 matches the first query only, 1 both moderately, 2 the second only
+        let rankings = || {
+            vec![
+                vec![(0, 5.0), (1, 2.0), (2, -4.0)],
+                vec![(2, 4.0), (1, 3.0), (0, -5.0)],
+            ]
+        };
+        assert_eq!(
+            combine_rankings(rankings(), QueryCombination::Any),
+            [(0, 5.0), (2, 4.0), (1, 3.0)]
+        );
+        assert_eq!(
+            combine_rankings(rankings(), QueryCombination::All),
+            [(1, 2.0), (2, -4.0), (0, -5.0)]
+        );
+    }
+
+    #[test]
+    fn test_rerank_document() {
+        let mut chunk = ChunkMatch {
+            id: 1,
+            text: "fn parse() { format!(\"{text}\") }".to_string(),
+            file_path: "src/config/parse.rs".to_string(),
+            start_line: 1,
+            end_line: 1,
+            node_type: "function".to_string(),
+            node_name: Some("parse".to_string()),
+            hash: 1,
+            distance: 0.0,
+        };
+        assert_eq!(
+            rerank_document(crate::config::DEFAULT_RERANK_TEMPLATE, &chunk),
+            "src/config/parse.rs :: parse\nfn parse() { format!(\"{text}\") }"
+        );
+
+        chunk.node_name = None;
+        assert_eq!(
+            rerank_document("{kind} {symbol} in {file_path}", &chunk),
+            "function function in src/config/parse.rs"
+        );
+        assert_eq!(rerank_document("{text}", &chunk), chunk.text);
+    }
+
+    #[test]
+    fn test_truncate_to_token_budget() {
+        let result = |text: &str| SearchResult {
+            id: None,
+            file_path: "a.rs".to_string(),
+            start_line: 1,
+            end_line: 1,
+            text: text.to_string(),
+            score: 0.5,
+            kind: "function".to_string(),
+            name: None,
+            modified_at: None,
+            explanation: None,
+        };
+        let words = |text: &str| Ok(text.split_whitespace().count());
+
+        let mut results = vec![result("a b c"), result("d e f g"), result("h")];
+        assert_eq!(truncate_to_token_budget(&mut results, 7, words).unwrap(), 7);
+        assert_eq!(results.len(), 2);
+
+        // A lower-ranked result that would still fit is not pulled ahead
+        let mut results = vec![result("a b c"), result("d e f g"), result("h")];
+        assert_eq!(truncate_to_token_budget(&mut results, 5, words).unwrap(), 3);
+        assert_eq!(results.len(), 1);
+    }
+}
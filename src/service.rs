@@ -0,0 +1,376 @@
+use anyhow::{bail, Context as AnyhowContext, Result};
+use log::{debug, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Prefix of launchd agent labels; `local.` is the convention for agents without a vendor
+const LAUNCHD_LABEL_PREFIX: &str = "local.";
+
+/// Service manager that starts user services at login
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Manager {
+    /// systemd user units (Linux)
+    Systemd,
+    /// launchd agents (macOS)
+    Launchd,
+}
+
+impl Manager {
+    fn current() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::Launchd
+        } else {
+            Self::Systemd
+        }
+    }
+}
+
+/// A `ragrep serve` process run by the user's service manager, for one project or the
+/// global server
+///
+/// The service manager supervises the server in the foreground, restarting it if it
+/// fails, so it runs from login on without anyone having to start it.
+pub struct Service {
+    manager: Manager,
+    /// Unit or agent name, derived from the project path
+    name: String,
+    /// Project served; `None` for `serve --global`
+    project: Option<PathBuf>,
+    exe: PathBuf,
+}
+
+impl Service {
+    pub fn new(project: Option<&Path>) -> Result<Self> {
+        let exe = std::env::current_exe().context("Failed to locate the ragrep executable")?;
+        let project = project
+            .map(|root| {
+                root.canonicalize()
+                    .with_context(|| format!("Invalid project root: {}", root.display()))
+            })
+            .transpose()?;
+        Ok(Self::with_manager(Manager::current(), project, exe))
+    }
+
+    fn with_manager(manager: Manager, project: Option<PathBuf>, exe: PathBuf) -> Self {
+        let name = match &project {
+            Some(root) => format!("ragrep-{}", escape_path(root)),
+            None => "ragrep-global".to_string(),
+        };
+        Self {
+            manager,
+            name,
+            project,
+            exe,
+        }
+    }
+
+    /// Where the unit file or agent plist is installed
+    pub fn path(&self) -> Result<PathBuf> {
+        Ok(match self.manager {
+            Manager::Systemd => dirs::config_dir()
+                .context("Could not find config directory")?
+                .join("systemd/user")
+                .join(format!("{}.service", self.name)),
+            Manager::Launchd => dirs::home_dir()
+                .context("Could not find home directory")?
+                .join("Library/LaunchAgents")
+                .join(format!("{}.plist", self.label())),
+        })
+    }
+
+    /// Write the service definition, then enable and (re)start it
+    pub fn install(&self) -> Result<()> {
+        let path = self.path()?;
+        let definition = match self.manager {
+            Manager::Systemd => self.systemd_unit(&self.working_dir()?),
+            Manager::Launchd => self.launchd_plist(&self.working_dir()?, &self.log_path()?),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, definition)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Wrote {}", path.display());
+
+        match self.manager {
+            Manager::Systemd => {
+                let unit = format!("{}.service", self.name);
+                run("systemctl", &["--user", "daemon-reload"])?;
+                run("systemctl", &["--user", "enable", &unit])?;
+                // Picks up a changed definition when reinstalling
+                run("systemctl", &["--user", "restart", &unit])?;
+            }
+            Manager::Launchd => {
+                // launchd doesn't create the log's directory
+                if let Some(log_dir) = self.log_path()?.parent() {
+                    fs::create_dir_all(log_dir)
+                        .with_context(|| format!("Failed to create {}", log_dir.display()))?;
+                }
+                let plist = path.to_string_lossy();
+                // Only succeeds when reinstalling
+                let _ = run("launchctl", &["unload", &plist]);
+                run("launchctl", &["load", "-w", &plist])?;
+            }
+        }
+        info!("Service {} installed and started", self.name);
+        Ok(())
+    }
+
+    /// Stop and disable the service and remove its definition
+    pub fn uninstall(&self) -> Result<()> {
+        let path = self.path()?;
+        if !path.exists() {
+            bail!(
+                "Service {} is not installed ({})",
+                self.name,
+                path.display()
+            );
+        }
+
+        match self.manager {
+            Manager::Systemd => {
+                run(
+                    "systemctl",
+                    &[
+                        "--user",
+                        "disable",
+                        "--now",
+                        &format!("{}.service", self.name),
+                    ],
+                )?;
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                run("systemctl", &["--user", "daemon-reload"])?;
+            }
+            Manager::Launchd => {
+                run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        info!("Service {} uninstalled", self.name);
+        Ok(())
+    }
+
+    /// Print whether the service is installed and what the service manager reports
+    pub fn status(&self) -> Result<()> {
+        let path = self.path()?;
+        if !path.exists() {
+            println!("Service {} is not installed", self.name);
+            return Ok(());
+        }
+        println!("Service {} is installed at {}", self.name, path.display());
+
+        // Both exit with an error for stopped services; their output says why
+        let (program, args) = match self.manager {
+            Manager::Systemd => (
+                "systemctl",
+                vec![
+                    "--user".to_string(),
+                    "status".to_string(),
+                    "--no-pager".to_string(),
+                    format!("{}.service", self.name),
+                ],
+            ),
+            Manager::Launchd => ("launchctl", vec!["list".to_string(), self.label()]),
+        };
+        Command::new(program)
+            .args(&args)
+            .status()
+            .with_context(|| format!("Failed to run {}", program))?;
+        Ok(())
+    }
+
+    fn label(&self) -> String {
+        format!("{}{}", LAUNCHD_LABEL_PREFIX, self.name)
+    }
+
+    /// Arguments of the `ragrep` invocation the service runs
+    fn serve_args(&self) -> Vec<String> {
+        let mut args = vec![self.exe.to_string_lossy().to_string(), "serve".to_string()];
+        if self.project.is_none() {
+            args.push("--global".to_string());
+        }
+        args
+    }
+
+    /// The served project; the home directory for the global server
+    fn working_dir(&self) -> Result<PathBuf> {
+        match &self.project {
+            Some(root) => Ok(root.clone()),
+            None => dirs::home_dir().context("Could not find home directory"),
+        }
+    }
+
+    /// launchd has no journal, so the server log goes to a file
+    fn log_path(&self) -> Result<PathBuf> {
+        Ok(dirs::home_dir()
+            .context("Could not find home directory")?
+            .join("Library/Logs/ragrep")
+            .join(format!("{}.log", self.name)))
+    }
+
+    fn systemd_unit(&self, working_dir: &Path) -> String {
+        let exec_start: Vec<String> = self
+            .serve_args()
+            .iter()
+            .map(|arg| systemd_quote(arg))
+            .collect();
+        format!(
+            "[Unit]\n\
+             Description=ragrep server for {description}\n\
+             \n\
+             [Service]\n\
+             Type=simple\n\
+             WorkingDirectory={working_dir}\n\
+             ExecStart={exec_start}\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            description = self.description().replace('%', "%%"),
+            working_dir = working_dir.to_string_lossy().replace('%', "%%"),
+            exec_start = exec_start.join(" "),
+        )
+    }
+
+    fn launchd_plist(&self, working_dir: &Path, log_path: &Path) -> String {
+        let arguments: String = self
+            .serve_args()
+            .iter()
+            .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+            .collect();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+             \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+             <plist version=\"1.0\">\n\
+             <dict>\n\
+             \x20   <key>Label</key>\n\
+             \x20   <string>{label}</string>\n\
+             \x20   <key>ProgramArguments</key>\n\
+             \x20   <array>\n\
+             {arguments}\
+             \x20   </array>\n\
+             \x20   <key>WorkingDirectory</key>\n\
+             \x20   <string>{working_dir}</string>\n\
+             \x20   <key>RunAtLoad</key>\n\
+             \x20   <true/>\n\
+             \x20   <key>KeepAlive</key>\n\
+             \x20   <dict>\n\
+             \x20       <key>SuccessfulExit</key>\n\
+             \x20       <false/>\n\
+             \x20   </dict>\n\
+             \x20   <key>StandardErrorPath</key>\n\
+             \x20   <string>{log_path}</string>\n\
+             </dict>\n\
+             </plist>\n",
+            label = xml_escape(&self.label()),
+            working_dir = xml_escape(&working_dir.to_string_lossy()),
+            log_path = xml_escape(&log_path.to_string_lossy()),
+        )
+    }
+
+    fn description(&self) -> String {
+        match &self.project {
+            Some(root) => root.display().to_string(),
+            None => "all projects".to_string(),
+        }
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    debug!("Running {} {}", program, args.join(" "));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        bail!("`{} {}` exited with {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Service name fragment for a path: components joined with `-`, other characters
+/// outside `[A-Za-z0-9_.]` replaced by `_`
+fn escape_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .map(|part| {
+            part.chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Quote a command line argument for a systemd unit file
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+    if arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        escaped
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_systemd_unit() {
+        let service = Service::with_manager(
+            Manager::Systemd,
+            Some(PathBuf::from("/home/dev/my app")),
+            PathBuf::from("/opt/rag tools/rag"),
+        );
+        assert_eq!(service.name, "ragrep-home-dev-my_app");
+        assert_eq!(
+            service.systemd_unit(Path::new("/home/dev/my app")),
+            "[Unit]\n\
+             Description=ragrep server for /home/dev/my app\n\
+             \n\
+             [Service]\n\
+             Type=simple\n\
+             WorkingDirectory=/home/dev/my app\n\
+             ExecStart=\"/opt/rag tools/rag\" serve\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n"
+        );
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let service = Service::with_manager(Manager::Launchd, None, PathBuf::from("/usr/bin/rag"));
+        let plist = service.launchd_plist(Path::new("/Users/a&b"), Path::new("/tmp/rag.log"));
+
+        assert_eq!(service.label(), "local.ragrep-global");
+        assert!(plist.contains(
+            "    <array>\n        <string>/usr/bin/rag</string>\n        \
+             <string>serve</string>\n        <string>--global</string>\n    </array>\n"
+        ));
+        assert!(plist.contains("<string>/Users/a&amp;b</string>"));
+    }
+}
@@ -0,0 +1,791 @@
+//! In-process test harness: a copy of the fixture repository in `tests/fixtures/mini`,
+//! indexed with deterministic stand-ins for the models, so tests of chunking, indexing and
+//! search need neither the models nor a network
+
+use crate::constants::constants;
+use crate::context::{AppContext, Models};
+use crate::embedder::{Embed, Embedder, Embedding};
+use crate::indexer::Indexer;
+use crate::protocol::{QueryCombination, SearchRequest, SearchResult};
+use crate::reranker::{Rerank, Reranker};
+use crate::server;
+use anyhow::Result;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A small repository with Rust, Python, JavaScript and TypeScript sources and a test
+pub const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mini");
+
+/// Lowercase words of `text`, with identifiers split into their parts so `parseConfig`,
+/// `parse_config` and "parse config" have the same words
+fn words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for token in text.split(|c: char| !c.is_alphanumeric()) {
+        let mut word = String::new();
+        for c in token.chars() {
+            if c.is_uppercase() && !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            word.extend(c.to_lowercase());
+        }
+        words.push(word);
+    }
+    words.retain(|word| word.len() > 1);
+    words
+}
+
+/// The normalized counts of the words of `text`, hashed into the model's dimensions
+fn word_vector(text: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; constants::EMBEDDING_DIMENSIONS];
+    for word in words(text) {
+        // The default hasher has fixed keys, so vectors are the same in every run
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        word.hash(&mut hasher);
+        let dimension = hasher.finish() as usize % embedding.len();
+        embedding[dimension] += 1.0;
+    }
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+/// Stand-in for the embedding model: texts sharing words get similar vectors
+pub struct WordEmbedder;
+
+impl Embed for WordEmbedder {
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        Ok(texts
+            .iter()
+            .map(|text| Embedding(word_vector(text)))
+            .collect())
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(text.split_whitespace().count())
+    }
+}
+
+/// Stand-in for the reranker: the cosine similarity of the word vectors of a document and
+/// the query
+pub struct WordReranker;
+
+impl Rerank for WordReranker {
+    fn score(&self, query: &str, documents: &[&str]) -> Result<Vec<f32>> {
+        let query = word_vector(query);
+        Ok(documents
+            .iter()
+            .map(|document| {
+                let document = word_vector(document);
+                document.iter().zip(&query).map(|(a, b)| a * b).sum()
+            })
+            .collect())
+    }
+}
+
+/// Search request for `query` without filters
+pub fn request(query: &str) -> SearchRequest {
+    SearchRequest {
+        query: query.to_string(),
+        top_n: 5,
+        files_only: false,
+        project_root: None,
+        languages: Vec::new(),
+        profile: None,
+        categories: Vec::new(),
+        exclude_categories: Vec::new(),
+        max_tokens: None,
+        kinds: Vec::new(),
+        paths: Vec::new(),
+        comments: false,
+        author: None,
+        since: None,
+        changed_since: None,
+        rerank_depth: None,
+        queries: Vec::new(),
+        combine: QueryCombination::default(),
+        explain: false,
+    }
+}
+
+/// A fresh copy of the fixture repository and its index
+pub struct Fixture {
+    pub root: PathBuf,
+    pub context: Mutex<AppContext>,
+}
+
+impl Fixture {
+    /// Copy the fixture repository to a directory of its own, named after `name`, and
+    /// index it with the deterministic models
+    ///
+    /// Indexing blocks in place, so tests using this need the multi-threaded runtime.
+    pub async fn indexed(name: &str) -> Self {
+        Self::indexed_with_config(name, "").await
+    }
+
+    /// Like [`Fixture::indexed`], with `config` as the project's `.ragrep/config.toml`
+    pub async fn indexed_with_config(name: &str, config: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("ragrep-fixture-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        copy_dir(Path::new(FIXTURE), &root);
+        let root = root.canonicalize().unwrap();
+        if !config.is_empty() {
+            let path = crate::config::local_config_path(&root);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, config).unwrap();
+        }
+
+        let models = Models {
+            embedder: Arc::new(Embedder::with_model(Box::new(WordEmbedder))),
+            reranker: Arc::new(Reranker::with_model(Box::new(WordReranker))),
+        };
+        let context = AppContext::with_models(&root, models, None, false).unwrap();
+        let indexer = Indexer::new(&context.config_manager.config().index);
+        let files = indexer
+            .index_directory(&root)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+
+        let context = Mutex::new(context);
+        AppContext::reindex_files(&context, files, |_, _| {})
+            .await
+            .unwrap();
+        Self { root, context }
+    }
+
+    /// Run `request` against the index
+    pub async fn search(&self, request: SearchRequest) -> Vec<SearchResult> {
+        let mut context = self.context.lock().await;
+        server::execute_search(&mut context, request)
+            .await
+            .unwrap()
+            .results
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Chunker;
+    use crate::config::ChunkingConfig;
+    use crate::git_watcher::FileChange;
+
+    /// Kind, name and lines of each chunk of a fixture file
+    fn outline(path: &str) -> Vec<(String, Option<String>, usize, usize)> {
+        let path = Path::new(FIXTURE).join(path);
+        let content = std::fs::read_to_string(&path).unwrap();
+        Chunker::new(&ChunkingConfig::default())
+            .unwrap()
+            .chunk_file(&path, &content)
+            .unwrap()
+            .into_iter()
+            .map(|chunk| (chunk.kind, chunk.name, chunk.start_line, chunk.end_line))
+            .collect()
+    }
+
+    fn names(results: &[SearchResult]) -> Vec<String> {
+        results
+            .iter()
+            .map(|result| result.name.clone().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(words("parseConfig(text)"), ["parse", "config", "text"]);
+        assert_eq!(words("fn parse_config"), ["fn", "parse", "config"]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_embeddings() {
+        let embedder = Embedder::with_model(Box::new(WordEmbedder))
+            .with_dimensions(Some(512))
+            .unwrap();
+        assert_eq!(embedder.dimensions(), 512);
+        let query = "parse the configuration file and load every setting from the environment";
+        let embedding = embedder
+            .embed_queries(&[query], "")
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(embedding.0.len(), 512);
+        let norm = embedding.0.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5, "{}", norm);
+
+        for dimensions in [0, 100, 2048] {
+            assert!(Embedder::with_model(Box::new(WordEmbedder))
+                .with_dimensions(Some(dimensions))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_project_dimensions_must_match_the_models() {
+        let root = std::env::temp_dir().join(format!("ragrep-dimensions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        // A global server whose own config truncates embeddings to 512 dimensions
+        let models = || Models {
+            embedder: Arc::new(
+                Embedder::with_model(Box::new(WordEmbedder))
+                    .with_dimensions(Some(512))
+                    .unwrap(),
+            ),
+            reranker: Arc::new(Reranker::with_model(Box::new(WordReranker))),
+        };
+
+        let error = AppContext::with_models(&root, models(), None, false)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("1024-dimensional"), "{}", error);
+
+        let config = crate::config::local_config_path(&root);
+        std::fs::create_dir_all(config.parent().unwrap()).unwrap();
+        std::fs::write(&config, "[embedding]\ndimensions = 512\n").unwrap();
+        AppContext::with_models(&root, models(), None, false).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_embedding_prefixes() {
+        let embedder = Embedder::with_model(Box::new(WordEmbedder));
+        let query = "parse config";
+        let plain = embedder.embed_queries(&[query], "").await.unwrap();
+        let prefixed = embedder.embed_queries(&[query], "firmware:").await.unwrap();
+        assert_ne!(plain[0].0, prefixed[0].0);
+        assert_eq!(prefixed[0].0, word_vector("firmware: parse config"));
+
+        // Cached embeddings of a text are kept apart by prefix
+        let code = "fn parse_config() {}";
+        let plain = embedder.embed_text(code, "a.rs", "").await.unwrap();
+        let prefixed = embedder.embed_text(code, "a.rs", "firmware").await.unwrap();
+        assert_ne!(plain.0, prefixed.0);
+    }
+
+    #[test]
+    fn test_fixture_chunk_boundaries() {
+        let chunk = |kind: &str, name: &str, start, end| {
+            (kind.to_string(), Some(name.to_string()), start, end)
+        };
+
+        let config = outline("src/config.rs");
+        assert!(config.contains(&chunk("function", "parse_config", 10, 18)));
+        assert!(config.contains(&chunk("function", "load_config_file", 21, 23)));
+        assert_eq!(
+            outline("app/server.py")[1..],
+            [
+                chunk("function", "do_GET", 8, 12),
+                chunk("function", "handle_error", 14, 18),
+                chunk("function", "parse_query_string", 21, 24),
+            ]
+        );
+        // The comment above an exported function documents it rather than standing alone
+        assert_eq!(
+            outline("web/format.ts"),
+            [
+                chunk("file_summary", "format.ts", 1, 13),
+                chunk("function", "formatDate", 7, 9),
+                chunk("function", "describeUser", 11, 13),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_index_round_trip() {
+        let fixture = Fixture::indexed("round-trip").await;
+        let context = fixture.context.lock().await;
+
+        let mut files: Vec<String> = context
+            .db
+            .get_indexed_files()
+            .unwrap()
+            .iter()
+            .map(|path| {
+                let path = Path::new(path).strip_prefix(&fixture.root).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "app/server.py",
+                "src/config.rs",
+                "src/retry.rs",
+                "tests/config_test.rs",
+                "web/api.js",
+                "web/format.ts"
+            ]
+        );
+        assert_eq!(
+            context.db.find_symbols("parse_config", false, 10).unwrap(),
+            [(
+                "parse_config".to_string(),
+                "function".to_string(),
+                "src/config.rs".to_string(),
+                10
+            )]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repair_index() {
+        // Neighbor context is embedded with each chunk, so repairs must include it too
+        let fixture =
+            Fixture::indexed_with_config("repair", "[chunking]\nneighbor_context_lines = 3\n")
+                .await;
+        let mut context = fixture.context.lock().await;
+        let mut hashes = Vec::new();
+        context
+            .db
+            .for_each_chunk(|chunk| {
+                hashes.push(chunk.hash);
+                Ok(())
+            })
+            .unwrap();
+        hashes.sort_unstable();
+        hashes.dedup();
+        let vectors: Vec<_> = hashes
+            .iter()
+            .map(|&hash| context.db.get_embedding(hash as u64).unwrap())
+            .collect();
+
+        context.db.delete_vectors(&hashes[..3]).unwrap();
+        let report = context.db.verify().unwrap();
+        assert!(!report.chunks_without_vectors.is_empty());
+        context.repair_index(&report).await.unwrap();
+
+        assert!(context.db.verify().unwrap().is_clean());
+        let repaired: Vec<_> = hashes
+            .iter()
+            .map(|&hash| context.db.get_embedding(hash as u64).unwrap())
+            .collect();
+        assert_eq!(repaired, vectors);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reindex_yields_to_queries() {
+        let fixture = Fixture::indexed("yield").await;
+        let retry = fixture.root.join("src/retry.rs");
+        std::fs::write(&retry, "/// Give up after the attempts\nfn give_up() {}\n").unwrap();
+
+        let embedder = Arc::clone(&fixture.context.lock().await.embedder);
+        let query = embedder.prioritize_query();
+        let reindex = AppContext::reindex_files(&fixture.context, vec![retry], |_, _| {});
+        tokio::pin!(reindex);
+        let paused = std::time::Duration::from_millis(300);
+        assert!(tokio::time::timeout(paused, &mut reindex).await.is_err());
+        // Searches aren't locked out while the reindex waits
+        assert!(!fixture
+            .search(request("retry the request"))
+            .await
+            .is_empty());
+
+        drop(query);
+        reindex.await.unwrap();
+        let context = fixture.context.lock().await;
+        assert_eq!(
+            context.db.find_symbols("give_up", false, 10).unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reindex_keeps_times_of_unchanged_chunks() {
+        let fixture = Fixture::indexed("times").await;
+        let retry = fixture.root.join("src/retry.rs");
+        let retry_path = retry.to_string_lossy().to_string();
+        let before = fixture
+            .context
+            .lock()
+            .await
+            .db
+            .updated_times(&retry_path)
+            .unwrap();
+        assert!(!before.is_empty());
+        fixture
+            .context
+            .lock()
+            .await
+            .db
+            .backdate_chunks(&retry_path, 1_000)
+            .unwrap();
+
+        let mut content = std::fs::read_to_string(&retry).unwrap();
+        content.push_str("\n/// Give up after the attempts\npub fn give_up() {}\n");
+        std::fs::write(&retry, content).unwrap();
+        AppContext::reindex_files(&fixture.context, vec![retry], |_, _| {})
+            .await
+            .unwrap();
+
+        let times = fixture
+            .context
+            .lock()
+            .await
+            .db
+            .updated_times(&retry_path)
+            .unwrap();
+        assert_eq!(times.len(), before.len() + 1);
+        for (hash, time) in times {
+            assert_eq!(time == 1_000, before.contains_key(&hash));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reindex_renamed_directory() {
+        let fixture = Fixture::indexed("rename").await;
+        std::fs::rename(fixture.root.join("web"), fixture.root.join("frontend")).unwrap();
+
+        let changes = vec![FileChange::Renamed {
+            from: fixture.root.join("web"),
+            to: fixture.root.join("frontend"),
+        }];
+        let files = fixture.context.lock().await.changed_files(changes).unwrap();
+        AppContext::reindex_files(&fixture.context, files, |_, _| {})
+            .await
+            .unwrap();
+
+        let context = fixture.context.lock().await;
+        let mut files: Vec<String> = context
+            .db
+            .get_indexed_files()
+            .unwrap()
+            .iter()
+            .filter_map(|path| {
+                let path = Path::new(path).strip_prefix(&fixture.root).ok()?;
+                Some(path.to_string_lossy().to_string())
+            })
+            .filter(|path| !path.contains(".rs") && !path.ends_with(".py"))
+            .collect();
+        files.sort();
+        assert_eq!(files, ["frontend/api.js", "frontend/format.ts"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ignore_files_added_after_indexing() {
+        let fixture = Fixture::indexed("ignore").await;
+        let js = || SearchRequest {
+            languages: vec!["js".to_string()],
+            ..request("retry the request")
+        };
+        assert!(!fixture.search(js()).await.is_empty());
+
+        // Results are hidden once the watcher reports the change, before the index catches up
+        let ignore_file = fixture.root.join(constants::RAGREP_IGNORE_FILENAME);
+        std::fs::write(&ignore_file, "web/\n").unwrap();
+        assert!(!fixture.search(js()).await.is_empty());
+        fixture.context.lock().await.ignored_files.clear();
+        assert!(fixture.search(js()).await.is_empty());
+
+        // Files under web/ left in the index once the ignore file change is reindexed
+        async fn indexed_web_files(fixture: &Fixture, ignore_file: &Path) -> usize {
+            let changes = vec![FileChange::IgnoreFile(ignore_file.to_path_buf())];
+            fixture.context.lock().await.ignored_files.clear();
+            let files = fixture.context.lock().await.changed_files(changes).unwrap();
+            AppContext::reindex_files(&fixture.context, files, |_, _| {})
+                .await
+                .unwrap();
+            let context = fixture.context.lock().await;
+            let files = context.db.get_indexed_files().unwrap();
+            files.iter().filter(|file| file.contains("/web/")).count()
+        }
+        assert_eq!(indexed_web_files(&fixture, &ignore_file).await, 0);
+
+        // Dropping the pattern brings the files back
+        std::fs::write(&ignore_file, "").unwrap();
+        assert_eq!(indexed_web_files(&fixture, &ignore_file).await, 2);
+        assert!(!fixture.search(js()).await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ignored_files_leave_room_for_other_candidates() {
+        let fixture = Fixture::indexed("ignore-depth").await;
+        let shallow = || SearchRequest {
+            top_n: 3,
+            rerank_depth: Some(3),
+            ..request("retry the request")
+        };
+        let results = fixture.search(shallow()).await;
+        assert_eq!(results.len(), 3);
+
+        // Ignoring the best result's directory doesn't shrink the candidates
+        let dir = Path::new(&results[0].file_path).parent().unwrap();
+        assert_ne!(dir, Path::new(""));
+        let ignore_file = fixture.root.join(constants::RAGREP_IGNORE_FILENAME);
+        std::fs::write(&ignore_file, format!("{}/\n", dir.display())).unwrap();
+        fixture.context.lock().await.ignored_files.clear();
+        let results = fixture.search(shallow()).await;
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|result| !result.file_path.starts_with(&format!("{}/", dir.display()))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_search() {
+        let fixture = Fixture::indexed("search").await;
+
+        let results = fixture
+            .search(SearchRequest {
+                kinds: vec!["function".to_string()],
+                exclude_categories: vec![crate::category::TEST.to_string()],
+                ..request("parse config values from text lines")
+            })
+            .await;
+        assert_eq!(names(&results)[0], "parse_config");
+        let details = {
+            let context = fixture.context.lock().await;
+            context
+                .db
+                .get_chunk(results[0].id.as_deref().unwrap())
+                .unwrap()
+                .unwrap()
+        };
+        assert!(details
+            .text
+            .starts_with("pub fn parse_config(text: &str) -> Config {"));
+        assert!(details
+            .comments
+            .contains("/// Parse the lines of a config file"));
+
+        let results = fixture
+            .search(SearchRequest {
+                languages: vec!["js".to_string()],
+                ..request("retry the request")
+            })
+            .await;
+        assert_eq!(names(&results)[0], "retryRequest");
+        assert!(results
+            .iter()
+            .all(|result| result.file_path == "web/api.js"));
+
+        // Directories match the files under them, globs match paths
+        for paths in [vec!["src/"], vec!["*/retry.rs", "app"]] {
+            let results = fixture
+                .search(SearchRequest {
+                    paths: paths.iter().map(|path| path.to_string()).collect(),
+                    ..request("retry the request")
+                })
+                .await;
+            assert!(!results.is_empty(), "{:?}", paths);
+            assert!(
+                results.iter().all(|result| {
+                    result.file_path.starts_with("src/") || result.file_path.starts_with("app/")
+                }),
+                "{:?}",
+                paths
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_stage_search() {
+        let fixture = Fixture::indexed("two-stage").await;
+        std::fs::write(
+            fixture.root.join(".ragrep").join("config.toml"),
+            "[search]\ntwo_stage = true\ntwo_stage_files = 1\ntwo_stage_min_chunks = 0\n",
+        )
+        .unwrap();
+        let mut context = fixture.context.lock().await;
+        context.reload_config().unwrap();
+
+        let response =
+            server::execute_search(&mut context, request("parse config values from text lines"))
+                .await
+                .unwrap();
+        assert_eq!(response.stats.num_candidate_files, Some(1));
+        assert_eq!(names(&response.results)[0], "parse_config");
+        assert!(response
+            .results
+            .iter()
+            .all(|result| result.file_path == "src/config.rs"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_stage_search_keeps_unsummarized_files() {
+        let fixture = Fixture::indexed_with_config(
+            "two-stage-text",
+            "[index]\nextensions = [\"rs\", \"py\", \"ts\", \"md\"]\n\n\
+             [search]\ntwo_stage = true\ntwo_stage_files = 1\ntwo_stage_min_chunks = 0\n",
+        )
+        .await;
+        let notes = fixture.root.join("NOTES.md");
+        std::fs::write(
+            &notes,
+            "# Deployment\n\nRotate the staging credentials before every deployment.\n",
+        )
+        .unwrap();
+        AppContext::reindex_files(&fixture.context, vec![notes], |_, _| {})
+            .await
+            .unwrap();
+
+        let mut context = fixture.context.lock().await;
+        let response = server::execute_search(
+            &mut context,
+            request("rotate staging credentials deployment"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.stats.num_candidate_files, Some(1));
+        assert!(response
+            .results
+            .iter()
+            .any(|result| result.file_path == "NOTES.md"));
+
+        // Below the threshold, every file is searched in one stage
+        std::fs::write(
+            fixture.root.join(".ragrep").join("config.toml"),
+            "[search]\ntwo_stage = true\ntwo_stage_files = 1\n",
+        )
+        .unwrap();
+        context.reload_config().unwrap();
+        let response =
+            server::execute_search(&mut context, request("parse config values from text lines"))
+                .await
+                .unwrap();
+        assert_eq!(response.stats.num_candidate_files, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_explained_search() {
+        let fixture = Fixture::indexed("explain").await;
+        let mut context = fixture.context.lock().await;
+
+        let response = server::execute_search(
+            &mut context,
+            SearchRequest {
+                top_n: 2,
+                languages: vec!["rs".to_string()],
+                explain: true,
+                ..request("parse config values from text lines")
+            },
+        )
+        .await
+        .unwrap();
+        for result in &response.results {
+            let explanation = result.explanation.as_ref().unwrap();
+            assert!(explanation.distance >= 0.0);
+            let adjusted: f32 = explanation.adjustments.iter().map(|a| a.delta).sum();
+            assert!((explanation.rerank_score + adjusted - result.score).abs() < 1e-4);
+        }
+        let explanation = response.explanation.unwrap();
+        assert_eq!(explanation.steps[0], "Only chunks in rust");
+        assert!(!explanation.cut.is_empty());
+        assert!(explanation
+            .cut
+            .iter()
+            .all(|cut| cut.reason == "ranked below the top 2"));
+
+        let response = server::execute_search(&mut context, request("retry the request"))
+            .await
+            .unwrap();
+        assert!(response.explanation.is_none());
+        assert!(response.results[0].explanation.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_search_several_queries() {
+        let fixture = Fixture::indexed("several-queries").await;
+        let functions = |query: &str, more: &str, combine| SearchRequest {
+            kinds: vec!["function".to_string()],
+            exclude_categories: vec![crate::category::TEST.to_string()],
+            queries: vec![more.to_string()],
+            combine,
+            ..request(query)
+        };
+
+        let any = names(
+            &fixture
+                .search(functions(
+                    "parse config values from text lines",
+                    "retry the request",
+                    QueryCombination::Any,
+                ))
+                .await,
+        );
+        assert!(any[..2].contains(&"parse_config".to_string()));
+        assert!(any[..2].contains(&"retryRequest".to_string()));
+
+        let all = names(
+            &fixture
+                .search(functions("config", "load file", QueryCombination::All))
+                .await,
+        );
+        assert_eq!(all[0], "load_config_file");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fixed_string_search() {
+        let fixture = Fixture::indexed("fixed-strings").await;
+        let context = fixture.context.lock().await;
+        let search = |request| server::fixed_string_search(&context.db, &request).unwrap();
+
+        let results = search(SearchRequest {
+            kinds: vec!["function".to_string()],
+            ..request("retryRequest(")
+        });
+        assert_eq!(names(&results.results), ["retryRequest"]);
+
+        // Case-sensitive, and every query must appear with `All`
+        assert!(search(request("RETRYREQUEST")).results.is_empty());
+        let both = search(SearchRequest {
+            queries: vec!["trim".to_string()],
+            combine: QueryCombination::All,
+            ..request("split")
+        });
+        assert!(!both.results.is_empty());
+        assert!(both
+            .results
+            .iter()
+            .all(|result| result.text.contains("split") && result.text.contains("trim")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_search_categories() {
+        let fixture = Fixture::indexed("categories").await;
+        let paths = |results: Vec<SearchResult>| -> Vec<String> {
+            results.into_iter().map(|result| result.file_path).collect()
+        };
+
+        let tests = paths(
+            fixture
+                .search(SearchRequest {
+                    categories: vec![crate::category::TEST.to_string()],
+                    ..request("parse config")
+                })
+                .await,
+        );
+        assert!(!tests.is_empty());
+        assert!(tests.iter().all(|path| path == "tests/config_test.rs"));
+
+        let sources = paths(
+            fixture
+                .search(SearchRequest {
+                    exclude_categories: vec![crate::category::TEST.to_string()],
+                    ..request("parse config")
+                })
+                .await,
+        );
+        assert_eq!(sources[0], "src/config.rs");
+        assert!(!sources.contains(&"tests/config_test.rs".to_string()));
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+/// Relevance multipliers for files matching `[search.weights]` patterns (gitignore syntax)
+pub struct PathWeights {
+    rules: Vec<(Gitignore, f32)>,
+}
+
+impl PathWeights {
+    pub fn new(weights: &BTreeMap<String, f32>) -> Result<Self> {
+        let rules = weights
+            .iter()
+            .map(|(pattern, &weight)| {
+                let mut builder = GitignoreBuilder::new("");
+                builder.add_line(None, pattern)?;
+                Ok((builder.build()?, weight))
+            })
+            .collect::<Result<_>>()
+            .context("Invalid search.weights pattern")?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Product of the weights of every pattern matching `path`; 1 when none match
+    pub fn weight(&self, path: &Path) -> f32 {
+        let path: &Path = path
+            .strip_prefix(Component::RootDir.as_os_str())
+            .unwrap_or(path);
+
+        self.rules
+            .iter()
+            .filter(|(matcher, _)| matcher.matched_path_or_any_parents(path, false).is_ignore())
+            .map(|(_, weight)| weight)
+            .product()
+    }
+
+    /// Apply the weight of `path` to a relevance score
+    ///
+    /// Reranker scores can be negative, so those are divided instead: a weight above 1
+    /// always moves a result up and one below 1 always moves it down.
+    pub fn adjust(&self, score: f32, path: &Path) -> f32 {
+        let weight = self.weight(path);
+        if score >= 0.0 {
+            score * weight
+        } else {
+            score / weight.max(f32::EPSILON)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weights() {
+        let weights = PathWeights::new(&BTreeMap::from([
+            ("src/core/**".to_string(), 1.5),
+            ("examples/".to_string(), 0.5),
+            ("*.rs".to_string(), 2.0),
+        ]))
+        .unwrap();
+        let weight = |path: &str| weights.weight(Path::new(path));
+
+        assert_eq!(weight("src/core/db.py"), 1.5);
+        assert_eq!(weight("src/core/db.rs"), 3.0);
+        assert_eq!(weight("examples/demo/main.py"), 0.5);
+        assert_eq!(weight("scripts/run.py"), 1.0);
+
+        assert_eq!(weights.adjust(2.0, Path::new("examples/a.py")), 1.0);
+        assert_eq!(weights.adjust(-2.0, Path::new("examples/a.py")), -4.0);
+    }
+}
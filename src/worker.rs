@@ -0,0 +1,429 @@
+//! Models run in child processes (`server.model_worker`), so a crash in ONNX Runtime (a bad
+//! model file, running out of memory) fails the requests it was serving instead of taking
+//! down the server with its indexes
+//!
+//! Each model gets its own worker, a `ragrep model-worker` process answering one JSON line on
+//! its stdout for each request line on its stdin. A worker that died is started again for
+//! the next request.
+
+use crate::embedder::{Embed, Embedding, MxbaiEmbedder};
+use crate::reranker::{BgeReranker, Rerank};
+use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+/// Model a worker runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WorkerModel {
+    Embed,
+    Rerank,
+}
+
+/// A request to a worker, one JSON line on its stdin
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WorkerRequest {
+    Embed {
+        texts: Vec<String>,
+    },
+    CountTokens {
+        text: String,
+    },
+    Rerank {
+        query: String,
+        documents: Vec<String>,
+    },
+}
+
+/// A worker's answer, one JSON line on its stdout
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WorkerResponse {
+    Embeddings {
+        embeddings: Vec<Embedding>,
+    },
+    Tokens {
+        count: usize,
+    },
+    Scores {
+        scores: Vec<f32>,
+    },
+    /// The model failed without crashing the worker
+    Error {
+        message: String,
+    },
+}
+
+/// Load `model` and answer requests on stdin until it closes; the body of
+/// `ragrep model-worker`
+pub fn run(
+    model: WorkerModel,
+    model_cache_dir: &Path,
+    quantized: bool,
+    threads: usize,
+) -> Result<()> {
+    let stdin = std::io::stdin().lock();
+    let stdout = std::io::stdout().lock();
+    match model {
+        WorkerModel::Embed => {
+            let embedder = MxbaiEmbedder::new(model_cache_dir, quantized, threads)?;
+            serve(stdin, stdout, Some(&embedder), None)
+        }
+        WorkerModel::Rerank => {
+            let reranker = BgeReranker::new(model_cache_dir, threads)?;
+            serve(stdin, stdout, None, Some(&reranker))
+        }
+    }
+}
+
+/// Answer each request line of `reader` with a response line on `writer`
+fn serve(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    embedder: Option<&dyn Embed>,
+    reranker: Option<&dyn Rerank>,
+) -> Result<()> {
+    for line in reader.lines() {
+        let response = match serde_json::from_str(&line?) {
+            Ok(request) => {
+                answer(request, embedder, reranker).unwrap_or_else(|e| WorkerResponse::Error {
+                    message: format!("{:#}", e),
+                })
+            }
+            Err(e) => WorkerResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn answer(
+    request: WorkerRequest,
+    embedder: Option<&dyn Embed>,
+    reranker: Option<&dyn Rerank>,
+) -> Result<WorkerResponse> {
+    let embedder = || embedder.ok_or_else(|| anyhow!("This worker runs no embedding model"));
+    Ok(match request {
+        WorkerRequest::Embed { texts } => {
+            let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+            WorkerResponse::Embeddings {
+                embeddings: embedder()?.embed(&texts)?,
+            }
+        }
+        WorkerRequest::CountTokens { text } => WorkerResponse::Tokens {
+            count: embedder()?.count_tokens(&text)?,
+        },
+        WorkerRequest::Rerank { query, documents } => {
+            let reranker = reranker.ok_or_else(|| anyhow!("This worker runs no reranker"))?;
+            let documents: Vec<&str> = documents.iter().map(String::as_str).collect();
+            WorkerResponse::Scores {
+                scores: reranker.score(&query, &documents)?,
+            }
+        }
+    })
+}
+
+/// A running worker and its pipes; killed when dropped
+struct Running {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Running {
+    /// Send `request` and read the answer; fails if the worker died
+    fn exchange(&mut self, request: &WorkerRequest) -> Result<WorkerResponse> {
+        writeln!(self.stdin, "{}", serde_json::to_string(request)?)?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            let status = self.child.wait()?;
+            bail!("model worker exited ({})", status);
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Handle to a worker process, started on first use and again after it dies
+pub struct WorkerProcess {
+    program: PathBuf,
+    args: Vec<String>,
+    /// `None` until started, after a crash and while unloaded
+    running: Mutex<Option<Running>>,
+}
+
+impl WorkerProcess {
+    /// Worker running `program` with `args`
+    pub fn new(program: PathBuf, args: Vec<String>) -> Self {
+        Self {
+            program,
+            args,
+            running: Mutex::new(None),
+        }
+    }
+
+    /// `ragrep model-worker` of this executable for `model`
+    pub fn for_model(
+        model: WorkerModel,
+        model_cache_dir: &Path,
+        quantized: bool,
+        threads: usize,
+    ) -> Result<Self> {
+        let program = std::env::current_exe().context("Failed to find the ragrep executable")?;
+        let mut args = vec![
+            "model-worker".to_string(),
+            match model {
+                WorkerModel::Embed => "embed",
+                WorkerModel::Rerank => "rerank",
+            }
+            .to_string(),
+            "--model-cache-dir".to_string(),
+            model_cache_dir.display().to_string(),
+            "--threads".to_string(),
+            threads.to_string(),
+        ];
+        if quantized {
+            args.push("--quantized".to_string());
+        }
+        Ok(Self::new(program, args))
+    }
+
+    fn start(&self) -> Result<Running> {
+        debug!(
+            "Starting model worker: {} {}",
+            self.program.display(),
+            self.args.join(" ")
+        );
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start model worker {}", self.program.display()))?;
+        let stdin = child.stdin.take().expect("worker stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("worker stdout is piped"));
+        Ok(Running {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Send `request` to the worker, starting it if needed; a worker that died is restarted
+    /// and asked once more, so only a request that crashes it every time fails
+    fn call(&self, request: &WorkerRequest) -> Result<WorkerResponse> {
+        let mut running = self.running.lock().unwrap();
+        let mut retried = false;
+        loop {
+            let worker = match running.as_mut() {
+                Some(worker) => worker,
+                None => running.insert(self.start()?),
+            };
+            match worker.exchange(request) {
+                Ok(WorkerResponse::Error { message }) => bail!(message),
+                Ok(response) => return Ok(response),
+                Err(e) if !retried => {
+                    warn!("Model worker failed, restarting it: {:#}", e);
+                    *running = None;
+                    retried = true;
+                }
+                Err(e) => {
+                    *running = None;
+                    return Err(e.context("Model worker crashed on this request"));
+                }
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.lock().unwrap().is_some()
+    }
+
+    /// Stop the worker, freeing its model's memory until the next request starts it again
+    fn stop(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+}
+
+/// The embedding model, run by a worker
+pub struct WorkerEmbedder {
+    worker: WorkerProcess,
+    quantized: bool,
+}
+
+impl WorkerEmbedder {
+    pub fn new(worker: WorkerProcess, quantized: bool) -> Self {
+        Self { worker, quantized }
+    }
+}
+
+impl Embed for WorkerEmbedder {
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        let request = WorkerRequest::Embed {
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+        };
+        match self.worker.call(&request)? {
+            WorkerResponse::Embeddings { embeddings } => Ok(embeddings),
+            response => bail!("Unexpected model worker response: {:?}", response),
+        }
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        let request = WorkerRequest::CountTokens {
+            text: text.to_string(),
+        };
+        match self.worker.call(&request)? {
+            WorkerResponse::Tokens { count } => Ok(count),
+            response => bail!("Unexpected model worker response: {:?}", response),
+        }
+    }
+
+    fn quantization(&self) -> &'static str {
+        if self.quantized {
+            "int8"
+        } else {
+            "none"
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    fn unload(&self) {
+        self.worker.stop();
+    }
+}
+
+/// The reranking model, run by a worker
+pub struct WorkerReranker {
+    worker: WorkerProcess,
+}
+
+impl WorkerReranker {
+    pub fn new(worker: WorkerProcess) -> Self {
+        Self { worker }
+    }
+}
+
+impl Rerank for WorkerReranker {
+    fn score(&self, query: &str, documents: &[&str]) -> Result<Vec<f32>> {
+        let request = WorkerRequest::Rerank {
+            query: query.to_string(),
+            documents: documents
+                .iter()
+                .map(|document| document.to_string())
+                .collect(),
+        };
+        match self.worker.call(&request)? {
+            WorkerResponse::Scores { scores } => Ok(scores),
+            response => bail!("Unexpected model worker response: {:?}", response),
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    fn unload(&self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{WordEmbedder, WordReranker};
+
+    #[test]
+    fn test_serve() {
+        let requests = [
+            WorkerRequest::Embed {
+                texts: vec!["parse config".to_string()],
+            },
+            WorkerRequest::Rerank {
+                query: "parse config".to_string(),
+                documents: vec!["fn parse_config() {}".to_string()],
+            },
+        ];
+        let input: String = requests
+            .iter()
+            .map(|request| serde_json::to_string(request).unwrap() + "\n")
+            .chain(["not json\n".to_string()])
+            .collect();
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, Some(&WordEmbedder), None).unwrap();
+
+        let responses: Vec<WorkerResponse> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected = WordEmbedder.embed(&["parse config"]).unwrap();
+        assert!(matches!(
+            &responses[0],
+            WorkerResponse::Embeddings { embeddings } if embeddings[0].0 == expected[0].0
+        ));
+        // This worker has no reranker, and the last line isn't a request
+        assert!(matches!(&responses[1], WorkerResponse::Error { .. }));
+        assert!(matches!(&responses[2], WorkerResponse::Error { .. }));
+
+        let mut output = Vec::new();
+        let input = serde_json::to_string(&requests[1]).unwrap() + "\n";
+        serve(input.as_bytes(), &mut output, None, Some(&WordReranker)).unwrap();
+        let response: WorkerResponse =
+            serde_json::from_str(String::from_utf8(output).unwrap().trim()).unwrap();
+        assert!(matches!(response, WorkerResponse::Scores { scores } if scores.len() == 1));
+    }
+
+    #[test]
+    fn test_worker_restarts_after_crash() {
+        let crashed = std::env::temp_dir().join(format!("ragrep-worker-{}", std::process::id()));
+        let _ = std::fs::remove_file(&crashed);
+        // Dies on its first request, then answers every request with a token count
+        let script = format!(
+            "if [ -e {0} ]; then while read line; do echo '{{\"type\":\"tokens\",\"count\":3}}'; \
+             done; else touch {0}; read line; exit 1; fi",
+            crashed.display()
+        );
+        let worker = WorkerEmbedder::new(
+            WorkerProcess::new(PathBuf::from("sh"), vec!["-c".to_string(), script]),
+            false,
+        );
+        assert!(!worker.is_loaded());
+        assert_eq!(worker.count_tokens("a b c").unwrap(), 3);
+        assert!(crashed.exists());
+        assert!(worker.is_loaded());
+
+        worker.unload();
+        assert!(!worker.is_loaded());
+        assert_eq!(worker.count_tokens("a b c").unwrap(), 3);
+
+        // A worker dying on every request fails the request rather than looping
+        let worker = WorkerEmbedder::new(
+            WorkerProcess::new(
+                PathBuf::from("sh"),
+                vec!["-c".to_string(), "read line; exit 1".to_string()],
+            ),
+            false,
+        );
+        assert!(worker.count_tokens("a").is_err());
+        assert!(!worker.is_loaded());
+
+        std::fs::remove_file(&crashed).unwrap();
+    }
+}
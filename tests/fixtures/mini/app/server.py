@@ -0,0 +1,24 @@
+from http.server import BaseHTTPRequestHandler
+from urllib.parse import parse_qs
+
+
+class RequestHandler(BaseHTTPRequestHandler):
+    """Serves the user listing over HTTP."""
+
+    def do_GET(self):
+        query = parse_query_string(self.path)
+        self.send_response(200)
+        self.end_headers()
+        self.wfile.write(str(query).encode())
+
+    def handle_error(self, status, message):
+        """Reply with an error status and message."""
+        self.send_response(status)
+        self.end_headers()
+        self.wfile.write(message.encode())
+
+
+def parse_query_string(path):
+    """Split the query string of a request path into parameters."""
+    _, _, query = path.partition("?")
+    return parse_qs(query)
@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+use std::fs;
+
+/// Settings read from a `key = value` config file
+pub struct Config {
+    pub values: HashMap<String, String>,
+}
+
+/// Parse the lines of a config file into settings, skipping comments
+pub fn parse_config(text: &str) -> Config {
+    let values = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Config { values }
+}
+
+/// Read and parse the config file at `path`
+pub fn load_config_file(path: &str) -> std::io::Result<Config> {
+    Ok(parse_config(&fs::read_to_string(path)?))
+}
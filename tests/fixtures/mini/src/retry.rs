@@ -0,0 +1,22 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Call `operation` until it succeeds, doubling the delay between attempts
+pub fn retry_with_backoff<T, E>(
+    attempts: u32,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt >= attempts => return Err(error),
+            Err(_) => {
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
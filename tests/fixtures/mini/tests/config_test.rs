@@ -0,0 +1,7 @@
+use mini::config::parse_config;
+
+#[test]
+fn test_parse_config() {
+    let config = parse_config("# comment\nport = 8080\n");
+    assert_eq!(config.values["port"], "8080");
+}
@@ -0,0 +1,20 @@
+const BASE_URL = "https://api.example.com";
+
+// Fetch the list of users from the API
+async function fetchUsers() {
+  const response = await fetch(`${BASE_URL}/users`);
+  return response.json();
+}
+
+// Retry a request a few times before giving up
+async function retryRequest(request, attempts = 3) {
+  for (let attempt = 1; ; attempt++) {
+    try {
+      return await request();
+    } catch (error) {
+      if (attempt >= attempts) throw error;
+    }
+  }
+}
+
+module.exports = { fetchUsers, retryRequest };
@@ -0,0 +1,13 @@
+export interface User {
+  name: string;
+  joinedAt: Date;
+}
+
+// Format a date as YYYY-MM-DD
+export function formatDate(date: Date): string {
+  return date.toISOString().slice(0, 10);
+}
+
+export function describeUser(user: User): string {
+  return `${user.name} (joined ${formatDate(user.joinedAt)})`;
+}